@@ -0,0 +1,61 @@
+//! Escaping partition paths for patterns, borrowing names without
+//! metacharacters against the allocating escape it replaced.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use gen_file_contexts::utils::regex_utils::escape_regex;
+use std::borrow::Cow;
+
+/// Paths shaped like an extracted vendor partition: plain folders and
+/// firmware names, dotted libraries, configs and HAL services, and the odd
+/// `+`.
+fn corpus() -> Vec<String> {
+    let mut paths = Vec::new();
+    for index in 0..200 {
+        paths.push(format!("lib64/libqti_service_{}.so", index));
+        paths.push(format!("etc/init/vendor.qti.service{}.rc", index));
+        paths.push(format!("firmware/wlan/qca_cld/WCNSS_qcom_cfg_{}", index));
+        paths.push(format!("bin/hw/android.hardware.sensors@2.{}-service", index));
+        paths.push(format!("app/QtiService{}/QtiService{}", index, index));
+        paths.push(format!("etc/acdbdata/MTP/MTP_Bluetooth_cal_{}", index));
+        paths.push(format!("lib/modules/qca_cld3_{}", index));
+        paths.push(format!("media/audio/c++/tone_{}", index));
+    }
+    paths
+}
+
+/// The escape before it returned `Cow`: one new `String` per path.
+fn escape_regex_owned(s: &str) -> String {
+    let mut result = String::with_capacity(s.len() + 8);
+    for c in s.chars() {
+        if matches!(c, '\\' | '[' | ']' | '.' | '^' | '$' | '*' | '+' | '?' | '{' | '}' | '|' | '(' | ')') {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result
+}
+
+fn escape(c: &mut Criterion) {
+    let paths = corpus();
+    for path in &paths {
+        assert_eq!(escape_regex(path), escape_regex_owned(path));
+    }
+
+    // Folders and extensionless names, which need no escaping at all.
+    let plain: Vec<String> = paths.iter().filter(|path| matches!(escape_regex(path), Cow::Borrowed(_))).cloned().collect();
+
+    for (name, paths) in [("escape_regex/all", &paths), ("escape_regex/plain", &plain)] {
+        let mut group = c.benchmark_group(name);
+        group.throughput(Throughput::Elements(paths.len() as u64));
+        group.bench_function("cow", |b| {
+            b.iter(|| paths.iter().map(|path| escape_regex(black_box(path)).len()).sum::<usize>())
+        });
+        group.bench_function("owned", |b| {
+            b.iter(|| paths.iter().map(|path| escape_regex_owned(black_box(path)).len()).sum::<usize>())
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, escape);
+criterion_main!(benches);
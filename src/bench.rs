@@ -0,0 +1,156 @@
+//! Timing of the whole pipeline over a synthetic partition, for tracking
+//! performance across changes. Built with the `bench` feature.
+
+use crate::config::{Config, FilesystemType, Mode};
+use crate::error::{Error, Result};
+use crate::processor::generate_missing_entries;
+use crate::progress::bar::ProgressMode;
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Top-level folders files are spread over, so every heuristic gets work.
+const TOP_LEVEL_DIRS: &[&str] = &[
+    "app", "bin", "bin/hw", "etc", "etc/init", "firmware", "framework", "lib", "lib/modules", "lib64", "overlay",
+];
+const EXTENSIONS: &[&str] = &["", ".so", ".rc", ".xml", ".bin", ".ko", ".apk", ".sh"];
+/// Distinct folder names per level, so deeper files share their parents.
+const DIRS_PER_LEVEL: u64 = 6;
+
+/// Shape of a synthetic partition. The same spec always gives the same
+/// tree.
+#[derive(Debug, Clone, Copy)]
+pub struct TreeSpec {
+    pub files: usize,
+    /// Most folders between a top-level folder and a file.
+    pub depth: usize,
+    /// Length of the random part of file and folder names.
+    pub name_len: usize,
+    pub seed: u64,
+}
+
+/// Wall times of the benchmark runs, fastest first.
+#[derive(Debug, Clone)]
+pub struct BenchSummary {
+    /// Files and folders in the tree, root excluded.
+    pub paths: usize,
+    pub runs: Vec<Duration>,
+}
+
+impl BenchSummary {
+    pub fn min(&self) -> Duration {
+        self.runs[0]
+    }
+
+    pub fn median(&self) -> Duration {
+        self.runs[self.runs.len() / 2]
+    }
+
+    pub fn max(&self) -> Duration {
+        self.runs[self.runs.len() - 1]
+    }
+
+    /// Paths per second at the median time.
+    pub fn throughput(&self) -> f64 {
+        self.paths as f64 / self.median().as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// Builds the tree for `spec` in a temporary folder and times `runs` dry
+/// runs over it with `threads` threads. The folder is removed afterwards.
+pub fn run_bench(spec: &TreeSpec, runs: usize, threads: usize) -> Result<BenchSummary> {
+    let workspace = TempDir::create()?;
+    let partition = workspace.0.join("vendor");
+    let paths = generate_tree(&partition, spec).map_err(|e| Error::write(&partition, e))?;
+    let file_contexts = workspace.0.join("vendor_file_contexts");
+    File::create(&file_contexts).map_err(|e| Error::write(&file_contexts, e))?;
+
+    let config = Config::builder()
+        .extracted_dir(&partition)
+        .file_contexts(&file_contexts)
+        .fstype(FilesystemType::Ext4)
+        .mode(Mode::All)
+        .threads(threads)
+        .dry_run(true)
+        .quiet(true)
+        .progress(ProgressMode::Never)
+        .build()?;
+
+    let mut times = Vec::with_capacity(runs);
+    for _ in 0..runs.max(1) {
+        let started = Instant::now();
+        generate_missing_entries(&config)?;
+        times.push(started.elapsed());
+    }
+    times.sort();
+    Ok(BenchSummary { paths, runs: times })
+}
+
+/// Writes the empty files of `spec` below `root`, returning how many files
+/// and folders the tree has.
+pub fn generate_tree(root: &Path, spec: &TreeSpec) -> std::io::Result<usize> {
+    let mut rng = SplitMix64(spec.seed);
+    let mut paths = BTreeSet::new();
+    for _ in 0..spec.files {
+        let mut path = PathBuf::from(TOP_LEVEL_DIRS[rng.below(TOP_LEVEL_DIRS.len() as u64) as usize]);
+        for level in 0..rng.below(spec.depth as u64 + 1) {
+            // Folder names come from a small seeded pool per level.
+            let mut dir_rng = SplitMix64(spec.seed ^ (level << 32) ^ rng.below(DIRS_PER_LEVEL));
+            path.push(format!("d_{}", dir_rng.name(spec.name_len)));
+        }
+        let extension = EXTENSIONS[rng.below(EXTENSIONS.len() as u64) as usize];
+        path.push(format!("f_{}{}", rng.name(spec.name_len), extension));
+        paths.insert(path);
+    }
+
+    let mut dirs = BTreeSet::new();
+    for path in &paths {
+        dirs.extend(path.ancestors().skip(1).filter(|dir| !dir.as_os_str().is_empty()).map(Path::to_path_buf));
+    }
+    for dir in &dirs {
+        fs::create_dir_all(root.join(dir))?;
+    }
+    for path in &paths {
+        File::create(root.join(path))?;
+    }
+    Ok(paths.len() + dirs.len())
+}
+
+/// Small seeded generator, so trees are identical on every machine.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound.max(1)
+    }
+
+    fn name(&mut self, len: usize) -> String {
+        (0..len.max(1)).map(|_| (b'a' + self.below(26) as u8) as char).collect()
+    }
+}
+
+/// Folder removed when dropped, including when a run fails.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn create() -> Result<Self> {
+        let path = std::env::temp_dir().join(format!("gen_file-contexts-bench-{}", std::process::id()));
+        fs::create_dir_all(&path).map_err(|e| Error::write(&path, e))?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,31 @@
+use serde::Serialize;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Short hash of the commit the binary was built from, `unknown` outside a
+/// git checkout.
+pub const GIT_COMMIT: &str = env!("BUILD_GIT_COMMIT");
+pub const BUILD_DATE: &str = env!("BUILD_DATE");
+
+/// Text printed by `--version`.
+pub const LONG_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    "\ncommit: ",
+    env!("BUILD_GIT_COMMIT"),
+    "\nbuilt: ",
+    env!("BUILD_DATE"),
+);
+
+/// Identifies the build that produced a report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub commit: &'static str,
+    pub build_date: &'static str,
+}
+
+impl Default for BuildInfo {
+    /// The running build.
+    fn default() -> Self {
+        Self { version: VERSION, commit: GIT_COMMIT, build_date: BUILD_DATE }
+    }
+}
@@ -0,0 +1,466 @@
+use super::detect::detect_fstype;
+use super::labels::LabelPolicy;
+use super::types::{Config, DirPattern, FilesystemType, Mode, PartitionKind};
+use crate::contexts_file::{is_compiled_file, Align};
+use crate::error::{Error, Result};
+use crate::utils::archive::ArchiveKind;
+use crate::processor::{Interrupt, OnInterrupt};
+use crate::progress::bar::ProgressMode;
+use crate::progress::sink::ProgressSink;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Generates setters that store their argument in the config unchanged.
+macro_rules! setters {
+    ($($(#[$doc:meta])* $name:ident: $field:ident: $ty:ty;)*) => {
+        $(
+            $(#[$doc])*
+            pub fn $name(mut self, value: $ty) -> Self {
+                self.config.$field = value;
+                self
+            }
+        )*
+    };
+}
+
+/// Builds a [`Config`] without going through the command line.
+///
+/// The partition folder, the filesystem type and a contexts file are
+/// required; everything else defaults to what the CLI uses without flags.
+///
+/// ```
+/// use gen_file_contexts::{Config, FilesystemType, Mode};
+///
+/// let dir = tempfile::tempdir()?;
+/// std::fs::create_dir(dir.path().join("vendor"))?;
+/// let config = Config::builder()
+///     .extracted_dir(dir.path().join("vendor"))
+///     .file_contexts(dir.path().join("vendor_file_contexts"))
+///     .fstype(FilesystemType::Ext4)
+///     .mode(Mode::Bin)
+///     .threads(2)
+///     .build()?;
+/// assert_eq!(config.cores, 2);
+/// assert_eq!(config.mount_point("vendor"), "/vendor");
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// Settings are checked by [`build`](Self::build):
+///
+/// ```
+/// use gen_file_contexts::{Config, Error, FilesystemType, Mode};
+///
+/// let builder = Config::builder()
+///     .extracted_dir("/nonexistent/vendor")
+///     .file_contexts("vendor_file_contexts")
+///     .fstype(FilesystemType::Ext4)
+///     .mode(Mode::All);
+///
+/// let error = builder.clone().threads(0).build().unwrap_err();
+/// assert_eq!(error.to_string(), "Thread count must be at least 1");
+/// assert!(matches!(builder.build(), Err(Error::MissingPartition(dir)) if dir.ends_with("vendor")));
+/// ```
+#[derive(Debug, Clone)]
+pub struct ConfigBuilder {
+    config: Config,
+    fstype: Option<FilesystemType>,
+    detect_fstype: bool,
+    extracted_dir: Option<PathBuf>,
+    file_contexts: Option<PathBuf>,
+}
+
+impl Config {
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::new()
+    }
+
+    /// What the CLI uses without flags, with the required settings left empty.
+    pub(crate) fn defaults() -> Self {
+        Self {
+            modes: Vec::new(),
+            fstype: FilesystemType::Ext4,
+            extracted_dir: PathBuf::new(),
+            file_contexts: PathBuf::new(),
+            existing_contexts: Vec::new(),
+            cores: 4,
+            silent: false,
+            summary: false,
+            dry_run: false,
+            nested_bin: false,
+            no_sort: false,
+            rules_file: None,
+            image: None,
+            source_contexts: None,
+            skip_symlinks: false,
+            follow_symlinks: false,
+            max_depth: None,
+            lossy_paths: false,
+            fail_on_invalid_path: false,
+            ci_match: false,
+            paths_from: None,
+            assume_files: false,
+            prefix: None,
+            no_partition_prefix: false,
+            collapse: false,
+            check: false,
+            inspect_content: false,
+            from_xattr: false,
+            validate: true,
+            force: false,
+            stream: false,
+            insert_sorted: false,
+            lock: false,
+            wait_lock: None,
+            strict: false,
+            verbose: 0,
+            emit_type_field: false,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            default_excludes: true,
+            ignore_file: None,
+            partition_name: None,
+            treat_as: None,
+            dir_pattern: None,
+            legacy_matching: false,
+            markers: true,
+            align: Align::None,
+            align_column: None,
+            explain: false,
+            cache: None,
+            valid_types: Vec::new(),
+            type_fallback: None,
+            compile_output: None,
+            progress: ProgressMode::Auto,
+            labels: LabelPolicy::default(),
+            seuser: "u".to_string(),
+            serole: "object_r".to_string(),
+            selevel: "s0".to_string(),
+            progress_sink: None,
+            on_interrupt: OnInterrupt::Discard,
+            interrupt: Interrupt::default(),
+        }
+    }
+}
+
+impl Default for ConfigBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConfigBuilder {
+    pub fn new() -> Self {
+        Self {
+            config: Config::defaults(),
+            fstype: None,
+            detect_fstype: false,
+            extracted_dir: None,
+            file_contexts: None,
+        }
+    }
+
+    pub(super) fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Adds a generation mode; modes accumulate.
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.config.modes.push(mode);
+        self
+    }
+
+    pub fn fstype(mut self, fstype: FilesystemType) -> Self {
+        self.fstype = Some(fstype);
+        self.detect_fstype = false;
+        self
+    }
+
+    /// Detects the filesystem type when building, from the `image` if set
+    /// and otherwise from metadata files extraction tools write.
+    pub fn detect_fstype(mut self) -> Self {
+        self.fstype = None;
+        self.detect_fstype = true;
+        self
+    }
+
+    pub fn extracted_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.extracted_dir = Some(dir.into());
+        self
+    }
+
+    /// Contexts file receiving the generated entries. It is also the file
+    /// checked for existing entries unless `existing_contexts` is set.
+    pub fn file_contexts(mut self, path: impl Into<PathBuf>) -> Self {
+        self.file_contexts = Some(path.into());
+        self
+    }
+
+    /// Contexts files checked for existing entries.
+    pub fn existing_contexts(mut self, paths: Vec<PathBuf>) -> Self {
+        self.config.existing_contexts = paths;
+        self
+    }
+
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.config.cores = threads;
+        self
+    }
+
+    pub fn quiet(mut self, quiet: bool) -> Self {
+        self.config.silent = quiet;
+        self
+    }
+
+    /// Accepts a full context or a bare type, like `--vbin`; bare types get
+    /// the user, role and level set with `seuser`, `serole` and `selevel`.
+    pub fn vendor_bin_context(mut self, context: &str) -> Self {
+        self.config.labels.vendor_bin = context.to_string();
+        self
+    }
+
+    pub fn system_bin_context(mut self, context: &str) -> Self {
+        self.config.labels.system_bin = context.to_string();
+        self
+    }
+
+    pub fn kernel_module_context(mut self, context: &str) -> Self {
+        self.config.labels.kernel_module = context.to_string();
+        self
+    }
+
+    pub fn hal_fallback_context(mut self, context: &str) -> Self {
+        self.config.labels.hal_fallback = context.to_string();
+        self
+    }
+
+    /// Replaces all labels, including ones set by the context setters above.
+    pub fn labels(mut self, labels: LabelPolicy) -> Self {
+        self.config.labels = labels;
+        self
+    }
+
+    /// Mount point used for generated entries, e.g. `/vendor`.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.config.prefix = Some(prefix.into());
+        self
+    }
+
+    /// Entries start at the root of the tree, e.g. `/sbin/recovery` for a
+    /// recovery ramdisk, instead of under the mount point.
+    pub fn no_partition_prefix(mut self, enabled: bool) -> Self {
+        self.config.no_partition_prefix = enabled;
+        self
+    }
+
+    pub fn partition_name(mut self, name: impl Into<String>) -> Self {
+        self.config.partition_name = Some(name.into());
+        self
+    }
+
+    pub fn progress_sink(mut self, sink: Arc<dyn ProgressSink>) -> Self {
+        self.config.progress_sink = Some(sink);
+        self
+    }
+
+    /// Shares `interrupt` with the run, to stop it early from elsewhere.
+    pub fn interrupt(mut self, interrupt: Interrupt) -> Self {
+        self.config.interrupt = interrupt;
+        self
+    }
+
+    setters! {
+        /// Replaces all previously added modes.
+        modes: modes: Vec<Mode>;
+        dry_run: dry_run: bool;
+        summary: summary: bool;
+        nested_bin: nested_bin: bool;
+        no_sort: no_sort: bool;
+        rules_file: rules_file: Option<PathBuf>;
+        image: image: Option<PathBuf>;
+        source_contexts: source_contexts: Option<PathBuf>;
+        skip_symlinks: skip_symlinks: bool;
+        follow_symlinks: follow_symlinks: bool;
+        max_depth: max_depth: Option<usize>;
+        lossy_paths: lossy_paths: bool;
+        fail_on_invalid_path: fail_on_invalid_path: bool;
+        ci_match: ci_match: bool;
+        paths_from: paths_from: Option<PathBuf>;
+        assume_files: assume_files: bool;
+        collapse: collapse: bool;
+        check: check: bool;
+        inspect_content: inspect_content: bool;
+        from_xattr: from_xattr: bool;
+        validate: validate: bool;
+        force: force: bool;
+        /// Streaming appends entries as they are labeled, so it never
+        /// maintains a marked block.
+        stream: stream: bool;
+        insert_sorted: insert_sorted: bool;
+        lock: lock: bool;
+        wait_lock: wait_lock: Option<u64>;
+        strict: strict: bool;
+        verbose: verbose: u8;
+        emit_type_field: emit_type_field: bool;
+        include: include: Vec<String>;
+        exclude: exclude: Vec<String>;
+        default_excludes: default_excludes: bool;
+        ignore_file: ignore_file: Option<PathBuf>;
+        markers: markers: bool;
+        align: align: Align;
+        align_column: align_column: Option<usize>;
+        explain: explain: bool;
+        treat_as: treat_as: Option<PartitionKind>;
+        dir_pattern: dir_pattern: Option<DirPattern>;
+        legacy_matching: legacy_matching: bool;
+        cache: cache: Option<PathBuf>;
+        valid_types: valid_types: Vec<PathBuf>;
+        /// Bare type or full context.
+        type_fallback: type_fallback: Option<String>;
+        compile_output: compile_output: Option<PathBuf>;
+        progress: progress: ProgressMode;
+        on_interrupt: on_interrupt: OnInterrupt;
+        seuser: seuser: String;
+        serole: serole: String;
+        /// MLS level, e.g. `s0:c512,c768`.
+        selevel: selevel: String;
+    }
+
+    /// Validates the settings and checks that the partition folder exists.
+    pub fn build(self) -> Result<Config> {
+        self.finish(true)
+    }
+
+    /// Like `build`, optionally leaving the partition folder check to the
+    /// caller.
+    pub(crate) fn finish(self, check_partition: bool) -> Result<Config> {
+        let mut config = self.config;
+        if config.modes.is_empty() {
+            return Err(Error::InvalidArgument("Must specify a generation mode with --mode".to_string()));
+        }
+        if config.cores == 0 {
+            return Err(Error::InvalidArgument("Thread count must be at least 1".to_string()));
+        }
+        if config.max_depth == Some(0) {
+            return Err(Error::InvalidArgument("--max-depth must be at least 1".to_string()));
+        }
+        config.extracted_dir = self.extracted_dir
+            .or_else(|| config.image.clone())
+            .ok_or_else(|| Error::InvalidArgument("Must specify a partition folder with --partition or an image with --image".to_string()))?;
+        let image = config.image.clone().or_else(|| {
+            let is_image = config.partition_is_file() && ArchiveKind::from_path(&config.extracted_dir).is_none();
+            is_image.then(|| config.extracted_dir.clone())
+        });
+        config.fstype = match self.fstype {
+            Some(fstype) => fstype,
+            None if self.detect_fstype => detect_fstype(&config.extracted_dir, image.as_deref())?,
+            None => return Err(Error::InvalidArgument("Must specify a filesystem type with --fstype".to_string())),
+        };
+
+        match (self.file_contexts, config.existing_contexts.first()) {
+            (Some(path), _) => {
+                if config.existing_contexts.is_empty() {
+                    config.existing_contexts.push(path.clone());
+                }
+                config.file_contexts = path;
+            }
+            (None, Some(first)) => config.file_contexts = first.clone(),
+            (None, None) => {
+                return Err(Error::InvalidArgument("Must specify a contexts file with --contexts".to_string()));
+            }
+        }
+
+        if let Some(prefix) = &config.prefix {
+            if !prefix.starts_with('/') {
+                return Err(Error::InvalidArgument(format!("Prefix must start with '/': {}", prefix)));
+            }
+            let trimmed = prefix.trim_end_matches('/');
+            if trimmed.is_empty() {
+                return Err(Error::InvalidArgument(
+                    "Prefix must name a mount point, not the root directory".to_string(),
+                ));
+            }
+            config.prefix = Some(trimmed.to_string());
+        }
+        if config.prefix.is_some() && config.no_partition_prefix {
+            return Err(Error::InvalidArgument("--prefix cannot be used with --no-partition-prefix".to_string()));
+        }
+
+        if let Some(name) = &config.partition_name
+            && (name.is_empty() || name.contains(|c: char| c == '/' || c.is_whitespace()))
+        {
+            return Err(Error::InvalidArgument(format!("Invalid partition name: {:?}", name)));
+        }
+
+        for (name, part) in [("user", &config.seuser), ("role", &config.serole), ("level", &config.selevel)] {
+            let has_colon = name != "level" && part.contains(':');
+            if part.is_empty() || has_colon || part.contains(char::is_whitespace) {
+                return Err(Error::InvalidArgument(format!("Invalid SELinux {}: {:?}", name, part)));
+            }
+        }
+        if let Some(label) = &config.type_fallback {
+            config.security_context(label)
+                .map_err(|e| Error::InvalidArgument(format!("Invalid --type-fallback: {}", e)))?;
+        }
+        for (category, label) in config.labels.entries() {
+            config.security_context(label)
+                .map_err(|e| Error::InvalidArgument(format!("Invalid label for {}: {}", category, e)))?;
+        }
+
+        if config.partition_is_file() {
+            for (option, set) in [
+                ("--inspect-content", config.inspect_content),
+                ("--from-xattr", config.from_xattr),
+                ("--cache", config.cache.is_some()),
+            ] {
+                if set {
+                    return Err(Error::InvalidArgument(format!(
+                        "{} needs an extracted partition folder, not an image or archive",
+                        option
+                    )));
+                }
+            }
+        }
+        if config.follow_symlinks && config.cache.is_some() {
+            return Err(Error::InvalidArgument(
+                "--follow-symlinks cannot be used with --cache, which stores scans that do not follow links".to_string(),
+            ));
+        }
+        if !config.dry_run && !config.check && is_compiled_file(&config.file_contexts) {
+            return Err(Error::InvalidArgument(format!(
+                "{:?} is a compiled file_contexts.bin and cannot be written to, pass a text file with --write-to",
+                config.file_contexts
+            )));
+        }
+        if config.compile_output.is_some() && (config.dry_run || config.check) {
+            return Err(Error::InvalidArgument(
+                "--compile-output compiles the written contexts file and cannot be used with --dry-run or --check".to_string(),
+            ));
+        }
+        if config.stream && config.align != Align::None && config.align_column.is_none() {
+            return Err(Error::InvalidArgument(
+                "--align with --stream needs --align-column, since entries are written before the longest is known".to_string(),
+            ));
+        }
+        if config.lossy_paths && config.fail_on_invalid_path {
+            return Err(Error::InvalidArgument(
+                "--lossy-paths and --fail-on-invalid-path cannot be used together".to_string(),
+            ));
+        }
+        if config.assume_files && config.paths_from.is_none() {
+            return Err(Error::InvalidArgument("--assume-files requires --paths-from".to_string()));
+        }
+        if cfg!(not(target_os = "linux")) && config.from_xattr {
+            return Err(Error::InvalidArgument(
+                "--from-xattr reads security.selinux attributes and is only supported on Linux".to_string(),
+            ));
+        }
+
+        config.markers &= !config.stream;
+
+        if check_partition {
+            config.ensure_partition_exists()?;
+        }
+        Ok(config)
+    }
+}
@@ -0,0 +1,172 @@
+use super::types::FilesystemType;
+use crate::error::{Error, Result};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// Start of the ext4, erofs and f2fs superblocks.
+const SUPERBLOCK_OFFSET: usize = 1024;
+const EXT4_MAGIC_OFFSET: usize = SUPERBLOCK_OFFSET + 0x38;
+const EXT4_MAGIC: u16 = 0xEF53;
+const EROFS_MAGIC: u32 = 0xE0F5_E1E2;
+const F2FS_MAGIC: u32 = 0xF2F5_2010;
+const SQUASHFS_MAGIC: &[u8; 4] = b"hsqs";
+const SPARSE_MAGIC: u32 = 0xED26_FF3A;
+
+/// Extractors write these next to the partition folder or below `config/`.
+const FS_OPTIONS_SUFFIX: &str = "_fs_options";
+const FS_CONFIG_NAME: &str = "filesystem_config.txt";
+
+impl FilesystemType {
+    /// Reads the superblock magic of a raw partition image. `None` when the
+    /// image holds none of the supported filesystems.
+    pub fn detect_from_image(path: &Path) -> io::Result<Option<Self>> {
+        let mut header = Vec::with_capacity(SUPERBLOCK_OFFSET + 64);
+        File::open(path)?.take((SUPERBLOCK_OFFSET + 64) as u64).read_to_end(&mut header)?;
+        Ok(Self::from_header(&header))
+    }
+
+    fn from_header(header: &[u8]) -> Option<Self> {
+        let u16_at = |offset: usize| header.get(offset..offset + 2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+        let u32_at = |offset: usize| header.get(offset..offset + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+
+        match () {
+            _ if header.starts_with(SQUASHFS_MAGIC) => Some(Self::Squashfs),
+            _ if u32_at(SUPERBLOCK_OFFSET) == Some(EROFS_MAGIC) => Some(Self::Erofs),
+            _ if u32_at(SUPERBLOCK_OFFSET) == Some(F2FS_MAGIC) => Some(Self::F2fs),
+            _ if u16_at(EXT4_MAGIC_OFFSET) == Some(EXT4_MAGIC) => Some(Self::Ext4),
+            _ => None,
+        }
+    }
+
+    /// The one filesystem named in an extractor's metadata file, e.g. the
+    /// `erofs` in `mkfs.erofs -zlz4hc`.
+    fn from_metadata(content: &str) -> Option<Self> {
+        let content = content.to_ascii_lowercase();
+        let named: Vec<Self> = [(Self::Ext4, "ext4"), (Self::Erofs, "erofs"), (Self::F2fs, "f2fs"), (Self::Squashfs, "squashfs")]
+            .into_iter()
+            .filter(|(_, name)| {
+                content
+                    .split(|c: char| !c.is_ascii_alphanumeric())
+                    .any(|word| word == *name)
+            })
+            .map(|(fstype, _)| fstype)
+            .collect();
+        match named[..] {
+            [fstype] => Some(fstype),
+            _ => None,
+        }
+    }
+}
+
+/// Picks the filesystem type for `--fstype auto`, from `image` first and
+/// then from metadata files left by the extraction tool. The error lists
+/// everything that was tried.
+pub(crate) fn detect_fstype(extracted_dir: &Path, image: Option<&Path>) -> Result<FilesystemType> {
+    let mut tried = Vec::new();
+
+    if let Some(image) = image {
+        match FilesystemType::detect_from_image(image) {
+            Ok(Some(fstype)) => return Ok(fstype),
+            Ok(None) if is_sparse(image) => {
+                tried.push(format!("{:?}: Android sparse image, convert it with simg2img first", image));
+            }
+            Ok(None) => tried.push(format!("{:?}: no ext4, erofs, f2fs or squashfs superblock", image)),
+            Err(e) => tried.push(format!("{:?}: {}", image, e)),
+        }
+    }
+
+    for path in metadata_candidates(extracted_dir) {
+        match fs::read(&path) {
+            Ok(content) => match FilesystemType::from_metadata(&String::from_utf8_lossy(&content)) {
+                Some(fstype) => return Ok(fstype),
+                None => tried.push(format!("{:?}: names no single filesystem type", path)),
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => tried.push(format!("{:?}: not found", path)),
+            Err(e) => tried.push(format!("{:?}: {}", path, e)),
+        }
+    }
+
+    Err(Error::InvalidArgument(format!(
+        "Could not detect the filesystem type of {:?}, pass --fstype or --image. Tried:\n  {}",
+        extracted_dir,
+        tried.join("\n  ")
+    )))
+}
+
+fn is_sparse(image: &Path) -> bool {
+    let mut magic = [0u8; 4];
+    File::open(image).and_then(|mut file| file.read_exact(&mut magic)).is_ok()
+        && u32::from_le_bytes(magic) == SPARSE_MAGIC
+}
+
+/// `<name>_fs_options` and `filesystem_config.txt` inside the partition
+/// folder, beside it and in a sibling `config/` folder.
+fn metadata_candidates(extracted_dir: &Path) -> Vec<PathBuf> {
+    let name = extracted_dir.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    let parent = extracted_dir.parent().unwrap_or(Path::new(""));
+    let options = format!("{}{}", name, FS_OPTIONS_SUFFIX);
+    let fs_config = format!("{}_{}", name, FS_CONFIG_NAME);
+
+    vec![
+        extracted_dir.join(&options),
+        parent.join("config").join(&options),
+        parent.join(&options),
+        extracted_dir.join(FS_CONFIG_NAME),
+        parent.join("config").join(&fs_config),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// A zeroed image header with `magic` written at `offset`.
+    fn header(offset: usize, magic: &[u8]) -> Vec<u8> {
+        let mut header = vec![0u8; SUPERBLOCK_OFFSET + 64];
+        header[offset..offset + magic.len()].copy_from_slice(magic);
+        header
+    }
+
+    #[test]
+    fn superblock_magics() {
+        let detect = |header: Vec<u8>| {
+            let dir = TempDir::new().unwrap();
+            let image = dir.path().join("vendor.img");
+            fs::write(&image, header).unwrap();
+            FilesystemType::detect_from_image(&image).unwrap()
+        };
+        assert_eq!(detect(header(EXT4_MAGIC_OFFSET, &EXT4_MAGIC.to_le_bytes())), Some(FilesystemType::Ext4));
+        assert_eq!(detect(header(SUPERBLOCK_OFFSET, &EROFS_MAGIC.to_le_bytes())), Some(FilesystemType::Erofs));
+        assert_eq!(detect(header(SUPERBLOCK_OFFSET, &F2FS_MAGIC.to_le_bytes())), Some(FilesystemType::F2fs));
+        assert_eq!(detect(header(0, SQUASHFS_MAGIC)), Some(FilesystemType::Squashfs));
+        assert_eq!(detect(header(0, &SPARSE_MAGIC.to_le_bytes())), None);
+        assert_eq!(detect(vec![0u8; 16]), None);
+    }
+
+    #[test]
+    fn metadata_must_name_one_filesystem() {
+        assert_eq!(FilesystemType::from_metadata("mkfs.erofs -zlz4hc"), Some(FilesystemType::Erofs));
+        assert_eq!(FilesystemType::from_metadata("fs_type=EXT4\nblock_size=4096"), Some(FilesystemType::Ext4));
+        assert_eq!(FilesystemType::from_metadata("ext4 or erofs"), None);
+        assert_eq!(FilesystemType::from_metadata("ext4fs"), None);
+    }
+
+    #[test]
+    fn detection_reads_extractor_metadata_and_lists_what_was_tried() {
+        let dir = TempDir::new().unwrap();
+        let partition = dir.path().join("vendor");
+        fs::create_dir(&partition).unwrap();
+        let image = dir.path().join("vendor.img");
+        fs::write(&image, header(0, &SPARSE_MAGIC.to_le_bytes())).unwrap();
+
+        let error = detect_fstype(&partition, Some(&image)).unwrap_err().to_string();
+        assert!(error.contains("sparse image"), "{}", error);
+        assert!(error.contains("vendor_fs_options"), "{}", error);
+
+        fs::create_dir(dir.path().join("config")).unwrap();
+        fs::write(dir.path().join("config/vendor_fs_options"), "mkfs.erofs -zlz4hc\n").unwrap();
+        assert_eq!(detect_fstype(&partition, Some(&image)).unwrap(), FilesystemType::Erofs);
+    }
+}
@@ -0,0 +1,146 @@
+use super::builder::ConfigBuilder;
+use super::types::Config;
+use crate::error::{Error, Result};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+impl ConfigBuilder {
+    /// Starts from the settings in a TOML file, keyed like the command line
+    /// options, e.g. `fstype = "erofs"` or `mode = ["bin", "lib"]`, where
+    /// `fstype = "auto"` detects the type like `--fstype auto`.
+    ///
+    /// Relative paths are resolved against the file's own directory.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path).map_err(|e| Error::read(path, e))?;
+        // Defaulting to ext4 would silently give erofs images recursive
+        // entries, so the type stays required unless the file sets it.
+        let fstype = toml::from_str::<FstypeSetting>(&content).ok().and_then(|setting| setting.fstype);
+        let has_fstype = fstype.is_some();
+        let detect_fstype = fstype.as_ref().and_then(|value| value.get_ref().as_str()) == Some("auto");
+
+        // `auto` is not a filesystem type; a placeholder in its place keeps
+        // the lines of the file, so errors still point at the right one.
+        let source = match &fstype {
+            Some(value) if detect_fstype => {
+                let span = value.span();
+                format!("{}\"ext4\"{}", &content[..span.start], &content[span.end..])
+            }
+            _ => content,
+        };
+        let mut config: Config = toml::from_str(&source).map_err(|e| Error::invalid_file("config file", path, e))?;
+
+        let base = path.parent().unwrap_or(Path::new(""));
+        let resolve = |path: &mut PathBuf| {
+            if !path.as_os_str().is_empty() {
+                *path = base.join(&*path);
+            }
+        };
+        resolve(&mut config.extracted_dir);
+        resolve(&mut config.file_contexts);
+        config.existing_contexts.iter_mut().for_each(resolve);
+        config.rules_file.iter_mut().for_each(resolve);
+        config.image.iter_mut().for_each(resolve);
+        config.source_contexts.iter_mut().for_each(resolve);
+        config.ignore_file.iter_mut().for_each(resolve);
+        config.paths_from.iter_mut().filter(|path| *path != Path::new("-")).for_each(resolve);
+        config.cache.iter_mut().for_each(resolve);
+        config.valid_types.iter_mut().for_each(resolve);
+        config.compile_output.iter_mut().for_each(resolve);
+
+        let mut builder = Config::builder();
+        if detect_fstype {
+            builder = builder.detect_fstype();
+        } else if has_fstype {
+            builder = builder.fstype(config.fstype);
+        }
+        if !config.extracted_dir.as_os_str().is_empty() {
+            builder = builder.extracted_dir(config.extracted_dir.clone());
+        }
+        if !config.file_contexts.as_os_str().is_empty() {
+            builder = builder.file_contexts(config.file_contexts.clone());
+        }
+        Ok(builder.config(config))
+    }
+}
+
+/// The `fstype` value of a config file, read ahead of the other settings.
+#[derive(Deserialize)]
+struct FstypeSetting {
+    fstype: Option<toml::Spanned<toml::Value>>,
+}
+
+impl Config {
+    /// The settings as a TOML file `ConfigBuilder::from_file` reads back.
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string(self).map_err(|e| Error::Serialize { what: "config", message: e.to_string() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{FilesystemType, Mode};
+    use tempfile::TempDir;
+
+    fn write_config(dir: &TempDir, content: &str) -> PathBuf {
+        fs::create_dir_all(dir.path().join("vendor")).unwrap();
+        let path = dir.path().join("fc-gen.toml");
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn unknown_keys_are_reported_with_their_line() {
+        let dir = TempDir::new().unwrap();
+        for fstype in ["ext4", "auto"] {
+            let path = write_config(&dir, &format!("partition = \"vendor\"\nfstype = \"{}\"\nthreds = 8\n", fstype));
+            let error = ConfigBuilder::from_file(&path).unwrap_err().to_string();
+            assert!(error.contains("threds") && error.contains("line 3"), "{}: {}", fstype, error);
+        }
+    }
+
+    #[test]
+    fn paths_are_relative_to_the_file() {
+        let dir = TempDir::new().unwrap();
+        let path = write_config(&dir, "mode = [\"bin\", \"lib\"]\nfstype = \"erofs\"\npartition = \"vendor\"\ncontexts = [\"vendor_file_contexts\"]\n");
+        let config = ConfigBuilder::from_file(&path).unwrap().build().unwrap();
+        assert_eq!(config.extracted_dir, dir.path().join("vendor"));
+        assert_eq!(config.file_contexts, dir.path().join("vendor_file_contexts"));
+        assert_eq!(config.modes, [Mode::Bin, Mode::Lib]);
+        assert_eq!(config.fstype, FilesystemType::Erofs);
+    }
+
+    #[test]
+    fn fstype_is_required_unless_the_file_sets_it() {
+        let dir = TempDir::new().unwrap();
+        let path = write_config(&dir, "mode = [\"all\"]\npartition = \"vendor\"\ncontexts = [\"vendor_file_contexts\"]\n");
+        assert!(ConfigBuilder::from_file(&path).unwrap().build().is_err());
+
+        // No image or extraction metadata to detect the type from.
+        let path = write_config(&dir, "mode = [\"all\"]\nfstype = \"auto\"\npartition = \"vendor\"\ncontexts = [\"vendor_file_contexts\"]\n");
+        let error = ConfigBuilder::from_file(&path).unwrap().build().unwrap_err().to_string();
+        assert!(error.starts_with("Could not detect the filesystem type"), "{}", error);
+    }
+
+    #[test]
+    fn dumped_config_reads_back_the_same() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("vendor")).unwrap();
+        let config = Config::builder()
+            .extracted_dir(dir.path().join("vendor"))
+            .file_contexts(dir.path().join("vendor_file_contexts"))
+            .fstype(FilesystemType::F2fs)
+            .mode(Mode::Etc)
+            .threads(3)
+            .prefix("/odm")
+            .build()
+            .unwrap();
+        let dumped = config.to_toml().unwrap();
+        let path = dir.path().join("dumped.toml");
+        fs::write(&path, &dumped).unwrap();
+        let read_back = ConfigBuilder::from_file(&path).unwrap().build().unwrap();
+        assert_eq!(read_back.to_toml().unwrap(), dumped);
+        assert_eq!((read_back.cores, read_back.fstype), (3, FilesystemType::F2fs));
+    }
+}
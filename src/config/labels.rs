@@ -0,0 +1,88 @@
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// Declares the policy fields with their defaults in one place.
+macro_rules! label_policy {
+    ($($(#[$doc:meta])* $field:ident: $default:literal,)*) => {
+        /// Labels the built-in heuristics assign, one per category of path.
+        ///
+        /// Read from a `--labels` TOML file keyed like the fields, e.g.
+        /// `vendor-etc = "vendor_configs_file"`. Bare types get the user, role
+        /// and level from `--seuser`, `--serole` and `--selevel`, full
+        /// contexts are used as they are. Categories the file leaves out keep
+        /// their defaults.
+        #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+        #[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+        pub struct LabelPolicy {
+            $($(#[$doc])* pub $field: String,)*
+        }
+
+        impl Default for LabelPolicy {
+            fn default() -> Self {
+                Self { $($field: $default.to_string(),)* }
+            }
+        }
+
+        impl LabelPolicy {
+            /// Each category's key in a labels file, with its label.
+            pub(crate) fn entries(&self) -> Vec<(String, &str)> {
+                vec![$((stringify!($field).replace('_', "-"), self.$field.as_str()),)*]
+            }
+        }
+    };
+}
+
+label_policy! {
+    /// Files and directories on system-like partitions without a better match.
+    system_file: "system_file",
+    /// Files below lib/ and lib64/, and shared objects with `--inspect-content`.
+    system_lib: "system_lib_file",
+    /// Files below bin/, set by `--sbin`.
+    system_bin: "system_file",
+    /// media/bootanimation.zip on system-like partitions.
+    bootanimation: "bootanimation_file",
+    /// Ringtones and other sounds below media/audio/ on system-like
+    /// partitions.
+    media_audio: "system_file",
+    /// Files and folders below fonts/ on system-like partitions.
+    fonts: "system_file",
+    /// Files and folders below usr/share/ on system-like partitions.
+    usr_share: "system_file",
+    /// Files and folders below etc/firmware/ on system-like partitions,
+    /// which some devices keep there instead of on vendor.
+    system_firmware: "system_file",
+    /// Everything on system_dlkm.
+    system_dlkm: "system_dlkm_file",
+    /// Files and directories on vendor-like partitions without a better match.
+    vendor_file: "vendor_file",
+    vendor_etc: "vendor_configs_file",
+    vendor_firmware: "vendor_firmware_file",
+    vendor_app: "vendor_app_file",
+    vendor_framework: "vendor_framework_file",
+    /// APEX packages and the apex/ folders holding them on vendor-like
+    /// partitions.
+    vendor_apex: "vendor_apex_file",
+    /// Jars, odex files and folders below framework/ on product and
+    /// system_ext.
+    product_framework: "system_file",
+    /// Overlays on vendor-like partitions and on product and system_ext.
+    vendor_overlay: "vendor_overlay_file",
+    /// Files below bin/ other than shell scripts and toolbox, set by `--vbin`.
+    vendor_bin: "vendor_qti_init_shell_exec",
+    /// `*.sh` scripts below bin/.
+    vendor_shell: "vendor_shell_exec",
+    vendor_toolbox: "vendor_toolbox_exec",
+    /// Kernel modules, set by `--kmod`.
+    kernel_module: "vendor_kernel_modules",
+    /// bin/hw/ services whose HAL name cannot be derived, set by `--hal`.
+    hal_fallback: "hal_allocator_default_exec",
+}
+
+impl LabelPolicy {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path).map_err(|e| Error::read(path, e))?;
+        toml::from_str(&content).map_err(|e| Error::invalid_file("labels file", path, e))
+    }
+}
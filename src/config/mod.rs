@@ -1,2 +1,10 @@
+mod builder;
+mod detect;
+mod file;
+mod labels;
+mod targets;
 mod types;
-pub use types::{Config, Mode, FilesystemType};
\ No newline at end of file
+pub use builder::ConfigBuilder;
+pub use labels::LabelPolicy;
+pub use targets::PartitionTarget;
+pub use types::{normalize_partition_name, Config, DirPattern, KNOWN_PARTITIONS, Mode, FilesystemType, PartitionKind};
\ No newline at end of file
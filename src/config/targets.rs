@@ -0,0 +1,128 @@
+use super::types::{normalize_partition_name, KNOWN_PARTITIONS};
+use crate::error::{Error, Result};
+use crate::progress::reporter::errln;
+use clap::ArgMatches;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// One extracted partition folder, or image, and the contexts files it is
+/// checked against.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartitionTarget {
+    pub extracted_dir: PathBuf,
+    pub existing_contexts: Vec<PathBuf>,
+}
+
+/// Partitions given on the command line, `None` when they are left to the
+/// `--config` file.
+///
+/// A target without contexts files keeps the ones from the config file.
+pub fn targets_from_matches(matches: &ArgMatches) -> Result<Option<Vec<PartitionTarget>>> {
+    if let Some(manifest) = matches.get_one::<String>("manifest") {
+        return read_manifest(Path::new(manifest)).map(Some);
+    }
+    if matches.get_flag("super") {
+        let [root] = matches.get_many::<String>("partition").unwrap().collect::<Vec<_>>()[..] else {
+            return Err(Error::InvalidArgument("--super takes a single extracted super image folder".to_string()));
+        };
+        let contexts_dir = Path::new(matches.get_one::<String>("contexts-dir").unwrap());
+        return discover_super_partitions(Path::new(root), contexts_dir).map(Some);
+    }
+
+    // Without a folder, an --image is read in its place.
+    let partitions: Vec<PathBuf> = match (matches.get_many::<String>("partition"), matches.get_one::<String>("image")) {
+        (Some(partitions), _) => partitions.map(PathBuf::from).collect(),
+        (None, Some(image)) => vec![PathBuf::from(image)],
+        (None, None) => return Ok(None),
+    };
+    let contexts: Vec<PathBuf> = matches.get_many::<String>("contexts")
+        .map(|values| values.map(PathBuf::from).collect())
+        .unwrap_or_default();
+
+    if partitions.len() == 1 {
+        return Ok(Some(vec![PartitionTarget {
+            extracted_dir: partitions.into_iter().next().unwrap(),
+            existing_contexts: contexts,
+        }]));
+    }
+    if partitions.len() != contexts.len() {
+        return Err(Error::InvalidArgument(format!(
+            "{} partitions were given but {} contexts files, pass one -c per -p",
+            partitions.len(),
+            contexts.len()
+        )));
+    }
+
+    Ok(Some(partitions
+        .into_iter()
+        .zip(contexts)
+        .map(|(extracted_dir, contexts)| PartitionTarget {
+            extracted_dir,
+            existing_contexts: vec![contexts],
+        })
+        .collect()))
+}
+
+/// Treats each first-level folder of an extracted super image named after a
+/// known partition as its own target, checked against
+/// `<contexts_dir>/<partition>_file_contexts`.
+fn discover_super_partitions(root: &Path, contexts_dir: &Path) -> Result<Vec<PartitionTarget>> {
+    let mut dirs: Vec<PathBuf> = fs::read_dir(root)
+        .map_err(|e| Error::read(root, e))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_dir()))
+        .map(|entry| entry.path())
+        .collect();
+    dirs.sort();
+
+    let mut targets = Vec::new();
+    for dir in dirs {
+        let folder_name = dir.file_name().unwrap_or_default().to_string_lossy();
+        let name = normalize_partition_name(&folder_name);
+        if !KNOWN_PARTITIONS.contains(&name.as_str()) {
+            errln!("Warning: skipping {:?}, not a known partition name.", dir);
+            continue;
+        }
+        targets.push(PartitionTarget {
+            existing_contexts: vec![contexts_dir.join(format!("{}_file_contexts", name))],
+            extracted_dir: dir,
+        });
+    }
+
+    if targets.is_empty() {
+        return Err(Error::InvalidArgument(format!("No known partitions found in {:?}", root)));
+    }
+    Ok(targets)
+}
+
+/// Reads `<partition-dir> <contexts-file>[,<contexts-file>...]` lines, with
+/// relative paths resolved against the manifest's own directory.
+fn read_manifest(path: &Path) -> Result<Vec<PartitionTarget>> {
+    let content = fs::read_to_string(path).map_err(|e| Error::read(path, e))?;
+    let base = path.parent().unwrap_or(Path::new(""));
+    let mut targets = Vec::new();
+
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [partition, contexts] = fields.as_slice() else {
+            return Err(Error::invalid_file(
+                "manifest",
+                path,
+                format!("line {}: expected a partition folder and a contexts file", index + 1),
+            ));
+        };
+        targets.push(PartitionTarget {
+            extracted_dir: base.join(partition),
+            existing_contexts: contexts.split(',').map(|contexts| base.join(contexts)).collect(),
+        });
+    }
+
+    if targets.is_empty() {
+        return Err(Error::invalid_file("manifest", path, "lists no partitions"));
+    }
+    Ok(targets)
+}
@@ -1,102 +1,537 @@
-use anyhow::{anyhow, Result};
-use clap::ArgMatches;
-use std::path::PathBuf;
+use crate::contexts_file::{Align, Alignment, FileType, SecurityContext};
+use crate::error::{Error, Result};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, ValueEnum};
+use super::builder::ConfigBuilder;
+use super::labels::LabelPolicy;
+use super::targets::targets_from_matches;
+use crate::processor::{Interrupt, OnInterrupt};
+use crate::progress::bar::ProgressMode;
+use crate::progress::json::JsonSink;
+use crate::progress::sink::ProgressSink;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-#[derive(Debug, Clone)]
+/// Settings of one run over one partition.
+///
+/// Serialized with the command line option names as keys, which is the
+/// format `--config` reads and `--dump-config` prints.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default = "Config::defaults", deny_unknown_fields, rename_all = "kebab-case")]
 pub struct Config {
-    pub mode: Mode,
+    #[serde(rename = "mode")]
+    pub modes: Vec<Mode>,
     pub fstype: FilesystemType,
+    #[serde(rename = "partition")]
     pub extracted_dir: PathBuf,
+    #[serde(rename = "write-to")]
     pub file_contexts: PathBuf,
+    #[serde(rename = "contexts")]
+    pub existing_contexts: Vec<PathBuf>,
+    #[serde(rename = "threads")]
     pub cores: usize,
+    #[serde(rename = "quiet")]
     pub silent: bool,
-    pub vendor_bin_context: String,
-    pub system_bin_context: String,
+    /// Print the one-line totals of each partition to stderr instead of
+    /// the summary table, also with `silent`.
+    pub summary: bool,
+    pub dry_run: bool,
+    pub nested_bin: bool,
+    pub no_sort: bool,
+    #[serde(rename = "rules")]
+    pub rules_file: Option<PathBuf>,
+    /// Raw partition image, read instead of a partition folder when none is
+    /// given, and by `--fstype auto`.
+    pub image: Option<PathBuf>,
+    /// Original labels of the stock image, used before the heuristics.
+    pub source_contexts: Option<PathBuf>,
+    pub skip_symlinks: bool,
+    /// Walk into directories that symlinks point to, labeling what is
+    /// there under the link's path. Links that loop back are labeled as
+    /// links and not followed.
+    pub follow_symlinks: bool,
+    /// Deepest level below the partition root that is walked, top-level
+    /// paths being at depth 1; unlimited when unset.
+    pub max_depth: Option<usize>,
+    /// Label paths whose names are not valid UTF-8, matching the invalid
+    /// bytes with a wildcard, instead of skipping them.
+    pub lossy_paths: bool,
+    /// Abort when a path name is not valid UTF-8.
+    pub fail_on_invalid_path: bool,
+    /// Ignore case when looking paths up in the existing contexts, for
+    /// partitions extracted on case-insensitive filesystems.
+    pub ci_match: bool,
+    /// Newline-separated paths to process instead of walking the
+    /// partition, `-` for stdin.
+    pub paths_from: Option<PathBuf>,
+    /// Take every `paths_from` entry for a regular file instead of looking
+    /// it up in the partition folder.
+    pub assume_files: bool,
+    pub prefix: Option<String>,
+    /// Start entries at the root of the tree instead of under the partition
+    /// mount point, for ramdisk and recovery trees.
+    pub no_partition_prefix: bool,
+    pub collapse: bool,
+    pub check: bool,
+    pub inspect_content: bool,
+    /// Use the `security.selinux` attribute of extracted paths when set,
+    /// falling back to the heuristics.
+    pub from_xattr: bool,
+    pub validate: bool,
+    pub force: bool,
+    pub stream: bool,
+    /// Place each generated entry after the existing entries matching its
+    /// path instead of in a marked block, so it takes precedence where the
+    /// last match applies.
+    pub insert_sorted: bool,
+    /// Hold an advisory lock on the contexts file for the whole run, so
+    /// runs against the same file take turns.
+    pub lock: bool,
+    /// Seconds to wait for another run to release the contexts file before
+    /// failing; unlimited when unset.
+    pub wait_lock: Option<u64>,
+    pub strict: bool,
+    pub verbose: u8,
+    pub emit_type_field: bool,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    /// Skip extraction leftovers such as `lost+found`, see
+    /// [`DEFAULT_EXCLUDES`](crate::processor::DEFAULT_EXCLUDES).
+    pub default_excludes: bool,
+    pub ignore_file: Option<PathBuf>,
+    pub partition_name: Option<String>,
+    /// Partition class used by the labeling heuristics instead of the one
+    /// inferred from the partition name.
+    pub treat_as: Option<PartitionKind>,
+    /// Match heuristic directories by substring, as before component-based
+    /// matching, e.g. `etc` also inside `fetch/`.
+    pub legacy_matching: bool,
+    /// Overrides the fstype's default for directory entries.
+    pub dir_pattern: Option<DirPattern>,
+    pub markers: bool,
+    /// How generated entries line up their contexts.
+    pub align: Align,
+    /// Column contexts start at with `align`, instead of after the longest
+    /// pattern.
+    pub align_column: Option<usize>,
+    pub explain: bool,
+    pub cache: Option<PathBuf>,
+    /// Policies or type lists whose declared types generated contexts
+    /// must use.
+    pub valid_types: Vec<PathBuf>,
+    /// Label for entries whose type `valid_types` does not declare,
+    /// defaulting to the partition's `vendor_file` or `system_file` label.
+    pub type_fallback: Option<String>,
+    /// Binary `file_contexts.bin` compiled from the contexts files after
+    /// writing.
+    pub compile_output: Option<PathBuf>,
+    pub progress: ProgressMode,
+    /// Labels for the heuristic categories, the `[labels]` table in a
+    /// config file.
+    pub labels: LabelPolicy,
+    /// User, role and level of generated contexts whose label is a bare type.
+    pub seuser: String,
+    pub serole: String,
+    pub selevel: String,
+    /// Receives progress instead of the terminal bar when set.
+    #[serde(skip)]
+    pub progress_sink: Option<Arc<dyn ProgressSink>>,
+    /// What happens to the entries generated before `interrupt` is
+    /// triggered.
+    pub on_interrupt: OnInterrupt,
+    /// Stops the run early when triggered.
+    #[serde(skip)]
+    pub interrupt: Interrupt,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Mode {
+    /// Every file and directory in the partition
     All,
+    /// Only paths below bin/
     Bin,
+    /// Only paths below lib/ and lib64/
+    Lib,
+    /// Only paths below etc/
+    Etc,
+    /// Only paths below firmware/
+    Firmware,
+    /// Only paths below app/ and priv-app/
+    App,
+    /// Only paths below overlay/
+    Overlay,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+impl Mode {
+    /// Top-level directories a mode is limited to, empty for `All`.
+    pub fn top_level_dirs(&self) -> &'static [&'static str] {
+        match self {
+            Self::All => &[],
+            Self::Bin => &["bin"],
+            Self::Lib => &["lib", "lib64"],
+            Self::Etc => &["etc"],
+            Self::Firmware => &["firmware"],
+            Self::App => &["app", "priv-app"],
+            Self::Overlay => &["overlay"],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum FilesystemType {
     Ext4,
     Erofs,
     F2fs,
+    Squashfs,
+}
+
+/// Whether directory entries also cover everything below them.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DirPattern {
+    /// Only the directory itself, e.g. `/vendor/etc`
+    Exact,
+    /// The directory and a `(/.*)?` entry for anything added below it
+    Recursive,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PartitionKind {
+    System,
+    SystemExt,
+    Product,
+    Vendor,
+    Odm,
+    SystemDlkm,
+    VendorDlkm,
+    OdmDlkm,
+}
+
+impl PartitionKind {
+    /// Infers the class from the partition name, treating names it does not
+    /// recognize as system.
+    pub fn from_name(name: &str) -> Self {
+        Self::recognize(name).unwrap_or(Self::System)
+    }
+
+    pub fn recognize(name: &str) -> Option<Self> {
+        match () {
+            _ if name.contains("system_dlkm") => Some(Self::SystemDlkm),
+            _ if name.contains("vendor_dlkm") => Some(Self::VendorDlkm),
+            _ if name.contains("odm_dlkm") => Some(Self::OdmDlkm),
+            _ if name.contains("vendor") => Some(Self::Vendor),
+            _ if name.contains("odm") => Some(Self::Odm),
+            _ if name.contains("system_ext") => Some(Self::SystemExt),
+            _ if name.contains("product") => Some(Self::Product),
+            _ if name.contains("system") => Some(Self::System),
+            _ => None,
+        }
+    }
+
+    pub fn is_vendor_like(&self) -> bool {
+        matches!(self, Self::Vendor | Self::Odm | Self::VendorDlkm | Self::OdmDlkm)
+    }
 }
 
 impl FilesystemType {
-    pub fn from_str(s: &str) -> Result<Self> {
-        match s.to_lowercase().as_str() {
-            "ext4" => Ok(Self::Ext4),
-            "erofs" => Ok(Self::Erofs),
-            "f2fs" => Ok(Self::F2fs),
-            _ => Err(anyhow!("Unsupported filesystem type: {}", s)),
+    /// The name `--fstype` accepts, e.g. `erofs`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Ext4 => "ext4",
+            Self::Erofs => "erofs",
+            Self::F2fs => "f2fs",
+            Self::Squashfs => "squashfs",
+        }
+    }
+
+    /// Directory pattern used without `--dir-pattern`. Images of the
+    /// read-only filesystems cannot gain files after they are built, so
+    /// every path gets its own exact entry there.
+    pub fn default_dir_pattern(&self) -> DirPattern {
+        match self {
+            Self::Ext4 => DirPattern::Recursive,
+            Self::Erofs | Self::F2fs | Self::Squashfs => DirPattern::Exact,
         }
     }
 
     pub fn folder_pattern(&self) -> &'static str {
         match self {
             Self::Ext4 => "(/.*)?",
-            Self::Erofs | Self::F2fs => "",
+            Self::Erofs | Self::F2fs | Self::Squashfs => "",
         }
     }
 }
 
+const PARTITION_NAME_SUFFIXES: &[&str] = &[".extracted", ".out", ".img", ".tar", ".tar.gz", ".tgz", ".zip", "_a", "_b"];
+
+pub const KNOWN_PARTITIONS: &[&str] = &[
+    "system", "system_ext", "product", "vendor", "odm", "system_dlkm", "vendor_dlkm", "odm_dlkm",
+];
+
+/// Strips suffixes extraction tools commonly append, e.g. `vendor_a.img.out` -> `vendor`.
+pub fn normalize_partition_name(folder_name: &str) -> String {
+    let mut name = folder_name;
+    while let Some(stripped) = PARTITION_NAME_SUFFIXES
+        .iter()
+        .find_map(|suffix| name.strip_suffix(suffix))
+        .filter(|stripped| !stripped.is_empty())
+    {
+        name = stripped;
+    }
+    name.to_string()
+}
+
 impl Config {
-    pub fn from_matches(matches: &ArgMatches) -> Result<Self> {
-        let mode = if matches.get_flag("bin") {
-            Mode::Bin
-        } else if matches.get_flag("all") {
-            Mode::All
-        } else {
-            return Err(anyhow!("Must specify either -a or -b mode"));
+    /// Builds one configuration per partition given on the command line.
+    ///
+    /// With `--config`, the file provides the starting values and only
+    /// options given on the command line or through their `FCGEN_*`
+    /// variable replace them.
+    pub fn from_matches(matches: &ArgMatches) -> Result<Vec<Self>> {
+        // Environment variables count as given; only built-in defaults
+        // leave the config file value in place.
+        let explicit = |id: &str| {
+            matches!(matches.value_source(id), Some(ValueSource::CommandLine | ValueSource::EnvVariable))
         };
 
-        let fstype = FilesystemType::from_str(
-            matches.get_one::<String>("fstype").unwrap()
-        )?;
-
-        let extracted_dir = PathBuf::from(matches.get_one::<String>("partition").unwrap());
-        let file_contexts = PathBuf::from(matches.get_one::<String>("contexts").unwrap());
+        let targets = targets_from_matches(matches)?;
+        if targets.as_ref().is_some_and(|targets| targets.len() > 1) {
+            for arg in ["write-to", "prefix", "partition-name", "paths-from", "image", "compile-output"] {
+                if matches.contains_id(arg) {
+                    return Err(Error::InvalidArgument(format!("--{} cannot be used with multiple partitions", arg)));
+                }
+            }
+        }
 
-        let cores = matches.get_one::<String>("threads")
-            .unwrap()
-            .parse::<usize>()
-            .map_err(|_| anyhow!("Invalid thread count"))?;
+        let mut base = match matches.get_one::<String>("config") {
+            Some(path) => ConfigBuilder::from_file(Path::new(path))?,
+            None => Config::builder(),
+        };
 
-        let silent = matches.get_flag("quiet");
+        match matches.get_many::<Mode>("mode") {
+            Some(modes) => base = base.modes(modes.copied().collect()),
+            None if matches.get_flag("bin") => base = base.modes(vec![Mode::Bin]),
+            None if matches.get_flag("all") => base = base.modes(vec![Mode::All]),
+            None => {}
+        }
+        match matches.get_one::<String>("fstype").map(String::as_str) {
+            Some(fstype) if fstype.eq_ignore_ascii_case("auto") => base = base.detect_fstype(),
+            Some(fstype) => base = base.fstype(FilesystemType::from_str(fstype, true).map_err(Error::InvalidArgument)?),
+            None => {}
+        }
+        if let Some(path) = matches.get_one::<String>("image") {
+            base = base.image(Some(PathBuf::from(path)));
+        }
+        if explicit("threads") {
+            base = base.threads(*matches.get_one::<usize>("threads").unwrap());
+        }
 
-        let format_context = |s: &str| -> String {
-            if s.contains(':') {
-                s.to_string()
-            } else {
-                format!("u:object_r:{}:s0", s)
+        type Setter = fn(ConfigBuilder, bool) -> ConfigBuilder;
+        let flags: [(&str, Setter, bool); 27] = [
+            ("quiet", ConfigBuilder::quiet, true),
+            ("summary", ConfigBuilder::summary, true),
+            ("dry-run", ConfigBuilder::dry_run, true),
+            ("nested-bin", ConfigBuilder::nested_bin, true),
+            ("no-sort", ConfigBuilder::no_sort, true),
+            ("skip-symlinks", ConfigBuilder::skip_symlinks, true),
+            ("follow-symlinks", ConfigBuilder::follow_symlinks, true),
+            ("lossy-paths", ConfigBuilder::lossy_paths, true),
+            ("fail-on-invalid-path", ConfigBuilder::fail_on_invalid_path, true),
+            ("ci-match", ConfigBuilder::ci_match, true),
+            ("assume-files", ConfigBuilder::assume_files, true),
+            ("collapse", ConfigBuilder::collapse, true),
+            ("check", ConfigBuilder::check, true),
+            ("inspect-content", ConfigBuilder::inspect_content, true),
+            ("from-xattr", ConfigBuilder::from_xattr, true),
+            ("no-validate", ConfigBuilder::validate, false),
+            ("force", ConfigBuilder::force, true),
+            ("stream", ConfigBuilder::stream, true),
+            ("insert-sorted", ConfigBuilder::insert_sorted, true),
+            ("no-partition-prefix", ConfigBuilder::no_partition_prefix, true),
+            ("lock", ConfigBuilder::lock, true),
+            ("strict", ConfigBuilder::strict, true),
+            ("emit-type-field", ConfigBuilder::emit_type_field, true),
+            ("explain", ConfigBuilder::explain, true),
+            ("legacy-matching", ConfigBuilder::legacy_matching, true),
+            ("no-markers", ConfigBuilder::markers, false),
+            ("no-default-excludes", ConfigBuilder::default_excludes, false),
+        ];
+        for (id, set, value) in flags {
+            if explicit(id) {
+                base = set(base, value);
             }
+        }
+        if explicit("verbose") {
+            base = base.verbose(matches.get_count("verbose"));
+        }
+        if explicit("progress") {
+            base = base.progress(*matches.get_one::<ProgressMode>("progress").unwrap());
+        }
+        if matches.get_flag("progress-json") {
+            base = base.progress_sink(Arc::new(JsonSink::new()));
+        }
+
+        if let Some(include) = matches.get_many::<String>("include") {
+            base = base.include(include.cloned().collect());
+        }
+        if let Some(exclude) = matches.get_many::<String>("exclude") {
+            base = base.exclude(exclude.cloned().collect());
+        }
+        if let Some(path) = matches.get_one::<String>("rules") {
+            base = base.rules_file(Some(PathBuf::from(path)));
+        }
+        if let Some(path) = matches.get_one::<String>("source-contexts") {
+            base = base.source_contexts(Some(PathBuf::from(path)));
+        }
+        if let Some(path) = matches.get_one::<String>("paths-from") {
+            base = base.paths_from(Some(PathBuf::from(path)));
+        }
+        if let Some(path) = matches.get_one::<String>("ignore-file") {
+            base = base.ignore_file(Some(PathBuf::from(path)));
+        }
+        if let Some(path) = matches.get_one::<String>("cache") {
+            base = base.cache(Some(PathBuf::from(path)));
+        }
+        if let Some(paths) = matches.get_many::<String>("valid-types") {
+            base = base.valid_types(paths.map(PathBuf::from).collect());
+        }
+        if let Some(label) = matches.get_one::<String>("type-fallback") {
+            base = base.type_fallback(Some(label.clone()));
+        }
+        if let Some(path) = matches.get_one::<String>("compile-output") {
+            base = base.compile_output(Some(PathBuf::from(path)));
+        }
+        if let Some(path) = matches.get_one::<String>("write-to") {
+            base = base.file_contexts(path);
+        }
+        if let Some(prefix) = matches.get_one::<String>("prefix") {
+            base = base.prefix(prefix);
+        }
+        if let Some(name) = matches.get_one::<String>("partition-name") {
+            base = base.partition_name(name);
+        }
+        if let Some(kind) = matches.get_one::<PartitionKind>("treat-as") {
+            base = base.treat_as(Some(*kind));
+        }
+        if explicit("align") {
+            base = base.align(*matches.get_one::<Align>("align").unwrap());
+        }
+        if let Some(column) = matches.get_one::<usize>("align-column") {
+            base = base.align_column(Some(*column));
+        }
+        if let Some(depth) = matches.get_one::<usize>("max-depth") {
+            base = base.max_depth(Some(*depth));
+        }
+        if let Some(seconds) = matches.get_one::<u64>("wait-lock") {
+            base = base.wait_lock(Some(*seconds));
+        }
+        if let Some(pattern) = matches.get_one::<DirPattern>("dir-pattern") {
+            base = base.dir_pattern(Some(*pattern));
+        }
+        if explicit("on-interrupt") {
+            base = base.on_interrupt(*matches.get_one::<OnInterrupt>("on-interrupt").unwrap());
+        }
+        if let Some(path) = matches.get_one::<String>("labels") {
+            base = base.labels(LabelPolicy::load(Path::new(path))?);
+        }
+        if explicit("seuser") {
+            base = base.seuser(matches.get_one::<String>("seuser").unwrap().clone());
+        }
+        if explicit("serole") {
+            base = base.serole(matches.get_one::<String>("serole").unwrap().clone());
+        }
+        if explicit("selevel") {
+            base = base.selevel(matches.get_one::<String>("selevel").unwrap().clone());
+        }
+        if let Some(context) = matches.get_one::<String>("vbin") {
+            base = base.vendor_bin_context(context);
+        }
+        if let Some(context) = matches.get_one::<String>("sbin") {
+            base = base.system_bin_context(context);
+        }
+        if let Some(context) = matches.get_one::<String>("kmod") {
+            base = base.kernel_module_context(context);
+        }
+        if let Some(context) = matches.get_one::<String>("hal") {
+            base = base.hal_fallback_context(context);
+        }
+
+        let Some(targets) = targets else {
+            return Ok(vec![base.build()?]);
+        };
+        // With several partitions a missing folder is reported per partition
+        // instead, so the others still get processed.
+        let check_partition = targets.len() == 1;
+        targets
+            .into_iter()
+            .map(|target| {
+                let mut builder = base.clone().extracted_dir(target.extracted_dir);
+                if !target.existing_contexts.is_empty() {
+                    builder = builder.existing_contexts(target.existing_contexts);
+                }
+                builder.finish(check_partition)
+            })
+            .collect()
+    }
+
+    /// Context of an entry labeled `label`, a bare type or a full context.
+    pub fn security_context(&self, label: &str) -> Result<SecurityContext> {
+        let base = SecurityContext {
+            user: self.seuser.clone(),
+            role: self.serole.clone(),
+            r#type: String::new(),
+            level: self.selevel.clone(),
         };
+        SecurityContext::from_label(label, &base).map_err(Error::InvalidArgument)
+    }
+
+    /// Alignment of a batch of generated entries, following `--align`.
+    pub fn alignment<'a>(&self, entries: impl IntoIterator<Item = (&'a str, Option<FileType>)>) -> Alignment {
+        Alignment::new(self.align, self.align_column, entries)
+    }
+
+    /// Suffix appended to directory entries, `(/.*)?` or nothing.
+    pub fn folder_pattern(&self) -> &'static str {
+        match self.dir_pattern.unwrap_or_else(|| self.fstype.default_dir_pattern()) {
+            DirPattern::Recursive => "(/.*)?",
+            DirPattern::Exact => "",
+        }
+    }
+
+    /// Whether the partition is an image or archive read in place rather
+    /// than an extracted folder.
+    pub fn partition_is_file(&self) -> bool {
+        self.extracted_dir.is_file()
+    }
+
+    /// Start of the entry paths of `partition`: `/{partition}`, or nothing
+    /// with `no_partition_prefix`.
+    pub fn mount_point(&self, partition: &str) -> String {
+        match self.no_partition_prefix {
+            true => String::new(),
+            false => format!("/{}", partition),
+        }
+    }
 
-        let vendor_bin_context = matches.get_one::<String>("vbin")
-            .map(|s| format_context(s))
-            .unwrap_or_else(|| "u:object_r:vendor_qti_init_shell_exec:s0".to_string());
-
-        let system_bin_context = matches.get_one::<String>("sbin")
-            .map(|s| format_context(s))
-            .unwrap_or_else(|| "u:object_r:system_file:s0".to_string());
-
-        if !extracted_dir.exists() {
-            return Err(anyhow!("Partition directory does not exist: {:?}", extracted_dir));
-        }
-        Ok(Self {
-            mode,
-            fstype,
-            extracted_dir,
-            file_contexts,
-            cores,
-            silent,
-            vendor_bin_context,
-            system_bin_context,
-        })
+    /// Partition class the labeling heuristics use for `partition`.
+    pub fn partition_kind(&self, partition: &str) -> PartitionKind {
+        self.treat_as.unwrap_or_else(|| PartitionKind::from_name(partition))
+    }
+
+    /// Progress display for this run, always off with `--quiet`. Bars are
+    /// drawn on stdout with the rest of the informational output.
+    pub fn progress_mode(&self) -> ProgressMode {
+        if self.silent { ProgressMode::Never } else { self.progress }
+    }
+
+    pub fn ensure_partition_exists(&self) -> Result<()> {
+        if !self.extracted_dir.exists() {
+            return Err(Error::MissingPartition(self.extracted_dir.clone()));
+        }
+        Ok(())
     }
 }
\ No newline at end of file
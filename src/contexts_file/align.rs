@@ -0,0 +1,97 @@
+use super::file_type::FileType;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// Tab stops assumed when aligning with tabs.
+const TAB_WIDTH: usize = 8;
+
+/// How the context column of written entries is lined up.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Align {
+    /// A single space between fields
+    #[default]
+    None,
+    /// Pad with spaces up to a common column
+    Spaces,
+    /// Pad with tabs up to a common column, assuming 8-column tab stops
+    Tabs,
+}
+
+/// Where contexts start in a batch of entries.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Alignment {
+    align: Align,
+    column: usize,
+}
+
+impl Alignment {
+    /// Lines contexts up after the longest `pattern [type]` in `entries`, or
+    /// at `column` when given. Longer entries get a single separator.
+    pub fn new<'a>(align: Align, column: Option<usize>, entries: impl IntoIterator<Item = (&'a str, Option<FileType>)>) -> Self {
+        let column = column.unwrap_or_else(|| {
+            let widest = entries
+                .into_iter()
+                .map(|(pattern, file_type)| prefix_width(pattern, file_type))
+                .max()
+                .unwrap_or(0);
+            match align {
+                Align::Tabs => (widest + 1).next_multiple_of(TAB_WIDTH),
+                Align::None | Align::Spaces => widest + 1,
+            }
+        });
+        Self { align, column }
+    }
+
+    pub fn line(&self, pattern: &str, file_type: Option<FileType>, context: &str) -> String {
+        let mut line = match file_type {
+            Some(file_type) => format!("{} {}", pattern, file_type),
+            None => pattern.to_string(),
+        };
+        let width = prefix_width(pattern, file_type);
+        match self.align {
+            Align::None => line.push(' '),
+            Align::Spaces => line.push_str(&" ".repeat(self.column.saturating_sub(width).max(1))),
+            Align::Tabs => {
+                let mut at = width;
+                loop {
+                    line.push('\t');
+                    at = (at / TAB_WIDTH + 1) * TAB_WIDTH;
+                    if at >= self.column {
+                        break;
+                    }
+                }
+            }
+        }
+        line.push_str(context);
+        line
+    }
+}
+
+fn prefix_width(pattern: &str, file_type: Option<FileType>) -> usize {
+    pattern.chars().count() + file_type.map_or(0, |file_type| 1 + file_type.as_str().len())
+}
+
+/// The style most entries in `content` use after their pattern; ties go to
+/// the plainer style.
+pub fn detect_align(content: &str) -> Align {
+    let mut votes = [(Align::None, 0), (Align::Spaces, 0), (Align::Tabs, 0)];
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some(start) = line.find(char::is_whitespace) else {
+            continue;
+        };
+        let rest = &line[start..];
+        let separator = &rest[..rest.len() - rest.trim_start().len()];
+        let index = match () {
+            _ if separator.contains('\t') => 2,
+            _ if separator.len() > 1 => 1,
+            _ => 0,
+        };
+        votes[index].1 += 1;
+    }
+    votes.iter().fold(votes[0], |best, vote| if vote.1 > best.1 { *vote } else { best }).0
+}
@@ -0,0 +1,60 @@
+use super::file_type::FileType;
+use super::parser::ParsedEntry;
+use clap::ValueEnum;
+use crate::progress::reporter::errln;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// How `sort` settles patterns listed with different labels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Resolution {
+    /// Keep the entry that appears first
+    KeepFirst,
+    /// Keep the entry that appears last, which is the one that wins at runtime
+    KeepLast,
+    /// Refuse to rewrite the file
+    Fail,
+}
+
+/// A pattern and type field listed more than once with different contexts.
+#[derive(Debug, Clone)]
+pub struct LabelConflict<'a> {
+    pub pattern: &'a str,
+    pub file_type: Option<FileType>,
+    pub entries: Vec<(&'a Path, &'a ParsedEntry)>,
+}
+
+/// Groups entries by pattern and type field, in order of first appearance,
+/// keeping only groups whose contexts disagree.
+pub fn find_conflicts<'a, I>(entries: I) -> Vec<LabelConflict<'a>>
+where
+    I: IntoIterator<Item = (&'a Path, &'a ParsedEntry)>,
+{
+    let mut groups: Vec<LabelConflict<'a>> = Vec::new();
+    let mut index: HashMap<(&'a str, Option<FileType>), usize> = HashMap::new();
+
+    for (source, entry) in entries {
+        let key = (entry.pattern.as_str(), entry.file_type);
+        let position = *index.entry(key).or_insert_with(|| {
+            groups.push(LabelConflict {
+                pattern: &entry.pattern,
+                file_type: entry.file_type,
+                entries: Vec::new(),
+            });
+            groups.len() - 1
+        });
+        groups[position].entries.push((source, entry));
+    }
+
+    groups.retain(|group| group.entries.iter().any(|(_, entry)| entry.context != group.entries[0].1.context));
+    groups
+}
+
+pub fn report_conflicts(conflicts: &[LabelConflict]) {
+    for conflict in conflicts {
+        errln!("Conflicting labels for {}:", conflict.pattern);
+        for (source, entry) in &conflict.entries {
+            errln!("  {}:{}: {}", source.display(), entry.line, entry.to_line());
+        }
+    }
+}
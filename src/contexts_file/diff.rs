@@ -0,0 +1,73 @@
+use super::file_type::FileType;
+use super::parser::{read_contexts_file, ContextLine, ParsedEntry};
+use super::security_context::SecurityContext;
+use crate::error::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChangedEntry {
+    pub pattern: String,
+    pub file_type: Option<FileType>,
+    pub context_a: String,
+    pub context_b: String,
+    /// Both contexts have the same SELinux type and differ only in user,
+    /// role or level.
+    pub same_type: bool,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ContextsDiff {
+    pub only_in_a: Vec<ParsedEntry>,
+    pub only_in_b: Vec<ParsedEntry>,
+    pub changed: Vec<ChangedEntry>,
+}
+
+impl ContextsDiff {
+    pub fn is_empty(&self) -> bool {
+        self.only_in_a.is_empty() && self.only_in_b.is_empty() && self.changed.is_empty()
+    }
+}
+
+type EntryKey = (String, Option<FileType>);
+
+fn index_entries(path: &Path) -> Result<BTreeMap<EntryKey, ParsedEntry>> {
+    let mut entries = BTreeMap::new();
+    for line in read_contexts_file(path)? {
+        if let ContextLine::Entry(entry) = line {
+            entries.insert((entry.pattern.clone(), entry.file_type), entry);
+        }
+    }
+    Ok(entries)
+}
+
+pub fn diff_contexts_files(a: &Path, b: &Path) -> Result<ContextsDiff> {
+    let entries_a = index_entries(a)?;
+    let mut entries_b = index_entries(b)?;
+    let mut diff = ContextsDiff::default();
+
+    for (key, entry_a) in entries_a {
+        match entries_b.remove(&key) {
+            Some(entry_b) if entry_b.context != entry_a.context => diff.changed.push(ChangedEntry {
+                pattern: entry_a.pattern,
+                file_type: entry_a.file_type,
+                same_type: same_type(&entry_a.context, &entry_b.context),
+                context_a: entry_a.context,
+                context_b: entry_b.context,
+            }),
+            Some(_) => {}
+            None => diff.only_in_a.push(entry_a),
+        }
+    }
+    diff.only_in_b.extend(entries_b.into_values());
+
+    Ok(diff)
+}
+
+fn same_type(a: &str, b: &str) -> bool {
+    match (a.parse::<SecurityContext>(), b.parse::<SecurityContext>()) {
+        (Ok(a), Ok(b)) => a.r#type == b.r#type,
+        _ => false,
+    }
+}
@@ -0,0 +1,86 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+
+/// The optional type field between a pattern and its context, restricting
+/// which kind of file the entry applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum FileType {
+    Regular,
+    Directory,
+    CharDevice,
+    BlockDevice,
+    Socket,
+    Symlink,
+    Pipe,
+}
+
+impl FileType {
+    pub fn from_fs(file_type: &fs::FileType) -> Self {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileTypeExt;
+            match () {
+                _ if file_type.is_char_device() => return Self::CharDevice,
+                _ if file_type.is_block_device() => return Self::BlockDevice,
+                _ if file_type.is_socket() => return Self::Socket,
+                _ if file_type.is_fifo() => return Self::Pipe,
+                _ => {}
+            }
+        }
+        match () {
+            _ if file_type.is_dir() => Self::Directory,
+            _ if file_type.is_symlink() => Self::Symlink,
+            _ => Self::Regular,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Regular => "--",
+            Self::Directory => "-d",
+            Self::CharDevice => "-c",
+            Self::BlockDevice => "-b",
+            Self::Socket => "-s",
+            Self::Symlink => "-l",
+            Self::Pipe => "-p",
+        }
+    }
+}
+
+impl FromStr for FileType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "--" => Ok(Self::Regular),
+            "-d" => Ok(Self::Directory),
+            "-c" => Ok(Self::CharDevice),
+            "-b" => Ok(Self::BlockDevice),
+            "-s" => Ok(Self::Socket),
+            "-l" => Ok(Self::Symlink),
+            "-p" => Ok(Self::Pipe),
+            _ => Err(format!("Unknown file type field: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for FileType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for FileType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for FileType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
@@ -0,0 +1,197 @@
+use super::line_ending::{split_bom, LineEnding};
+use super::markers::{find_marked_block, replace_marked_block};
+use crate::utils::fs_utils::write_atomic;
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Lines added to a contexts file by one run of the generator.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalRun {
+    pub id: u64,
+    pub timestamp: u64,
+    pub partition: String,
+    pub lines: Vec<String>,
+}
+
+/// Sidecar record of generator runs, stored next to the contexts file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Journal {
+    pub runs: Vec<JournalRun>,
+}
+
+#[derive(Debug)]
+pub struct UndoSummary {
+    pub run: JournalRun,
+    pub removed: usize,
+    pub missing: Vec<String>,
+}
+
+pub fn journal_path(contexts: &Path) -> PathBuf {
+    let mut name = contexts.file_name().unwrap_or_default().to_os_string();
+    name.push(".gen.json");
+    contexts.with_file_name(name)
+}
+
+impl Journal {
+    pub fn load(contexts: &Path) -> Result<Self> {
+        let path = journal_path(contexts);
+        match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).map_err(|e| Error::invalid_file("journal", &path, e)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(Error::read(path, e)),
+        }
+    }
+
+    pub fn save(&self, contexts: &Path) -> Result<()> {
+        let path = journal_path(contexts);
+        if self.runs.is_empty() {
+            return match fs::remove_file(&path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(Error::write(path, e)),
+                _ => Ok(()),
+            };
+        }
+        let content = serde_json::to_string_pretty(self)
+            .map_err(|e| Error::Serialize { what: "journal", message: e.to_string() })?;
+        write_atomic(&path, content.as_bytes())
+    }
+}
+
+pub fn record_run(contexts: &Path, partition: &str, lines: Vec<String>) -> Result<u64> {
+    let mut journal = Journal::load(contexts)?;
+    let id = journal.runs.iter().map(|run| run.id).max().unwrap_or(0) + 1;
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+
+    journal.runs.push(JournalRun {
+        id,
+        timestamp,
+        partition: partition.to_string(),
+        lines,
+    });
+    journal.save(contexts)?;
+    Ok(id)
+}
+
+/// Removes the lines recorded for a run, the most recent one by default,
+/// from the contexts file and drops the run from the journal.
+pub fn undo_run(contexts: &Path, id: Option<u64>) -> Result<UndoSummary> {
+    let mut journal = Journal::load(contexts)?;
+    let index = match id {
+        Some(id) => journal.runs.iter().position(|run| run.id == id)
+            .ok_or_else(|| Error::NoRecordedRun { path: contexts.to_path_buf(), id: Some(id) })?,
+        None => journal.runs.len().checked_sub(1)
+            .ok_or_else(|| Error::NoRecordedRun { path: contexts.to_path_buf(), id: None })?,
+    };
+    let run = journal.runs.remove(index);
+
+    let content = fs::read_to_string(contexts).map_err(|e| Error::read(contexts, e))?;
+    let newline = LineEnding::detect(content.as_bytes()).as_str();
+    let (bom, content) = split_bom(&content);
+    let mut pending: HashMap<&str, usize> = HashMap::new();
+    for line in &run.lines {
+        *pending.entry(line.as_str()).or_default() += 1;
+    }
+
+    // Appended lines are most likely the last copies in the file.
+    let mut kept: Vec<&str> = Vec::new();
+    let mut removed = 0;
+    // Fields are compared with single spaces, so realigned lines still match.
+    for line in content.lines().rev() {
+        match pending.get_mut(line.split_whitespace().collect::<Vec<_>>().join(" ").as_str()) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                removed += 1;
+            }
+            _ => kept.push(line),
+        }
+    }
+    kept.reverse();
+
+    let mut missing = Vec::new();
+    for line in &run.lines {
+        if let Some(count) = pending.get_mut(line.as_str()) && *count > 0 {
+            *count -= 1;
+            missing.push(line.clone());
+        }
+    }
+
+    let mut updated = bom.to_string();
+    updated.extend(kept.iter().map(|line| format!("{}{}", line, newline)));
+    if find_marked_block(&kept, &run.partition)?.is_some_and(|block| block.len() == 2) {
+        updated = replace_marked_block(&updated, &run.partition, &[])?;
+    }
+
+    write_atomic(contexts, updated.as_bytes())?;
+    journal.save(contexts)?;
+    Ok(UndoSummary { run, removed, missing })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const STOCK: &str = "/vendor u:object_r:vendor_file:s0\n";
+
+    fn contexts(content: &str) -> (TempDir, PathBuf) {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("vendor_file_contexts");
+        fs::write(&path, content).unwrap();
+        (dir, path)
+    }
+
+    #[test]
+    fn undo_removes_the_last_run_and_its_empty_block() {
+        let line = "/vendor/bin/foo u:object_r:vendor_file:s0";
+        let (_dir, path) = contexts(&format!(
+            "{}# BEGIN gen_file-context (vendor)\n{}\n# END gen_file-context (vendor)\n",
+            STOCK, line
+        ));
+        assert_eq!(record_run(&path, "vendor", vec![line.to_string()]).unwrap(), 1);
+        assert!(journal_path(&path).exists());
+
+        let summary = undo_run(&path, None).unwrap();
+        assert_eq!((summary.run.id, summary.removed, summary.missing.len()), (1, 1, 0));
+        assert_eq!(fs::read_to_string(&path).unwrap(), STOCK);
+        assert!(!journal_path(&path).exists());
+        assert!(matches!(undo_run(&path, None), Err(Error::NoRecordedRun { id: None, .. })));
+    }
+
+    #[test]
+    fn undo_by_id_matches_realigned_lines_and_reports_missing_ones() {
+        let (_dir, path) = contexts(&format!(
+            "{}/vendor/bin/a    u:object_r:vendor_file:s0\n/odm/bin/b u:object_r:vendor_file:s0\n",
+            STOCK
+        ));
+        let first = record_run(&path, "vendor", vec![
+            "/vendor/bin/a u:object_r:vendor_file:s0".to_string(),
+            "/vendor/bin/gone u:object_r:vendor_file:s0".to_string(),
+        ]).unwrap();
+        let second = record_run(&path, "odm", vec!["/odm/bin/b u:object_r:vendor_file:s0".to_string()]).unwrap();
+        assert!(matches!(undo_run(&path, Some(7)), Err(Error::NoRecordedRun { id: Some(7), .. })));
+
+        let summary = undo_run(&path, Some(first)).unwrap();
+        assert_eq!(summary.removed, 1);
+        assert_eq!(summary.missing, ["/vendor/bin/gone u:object_r:vendor_file:s0"]);
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}/odm/bin/b u:object_r:vendor_file:s0\n", STOCK));
+
+        let journal = Journal::load(&path).unwrap();
+        assert_eq!(journal.runs.iter().map(|run| run.id).collect::<Vec<_>>(), [second]);
+    }
+
+    #[test]
+    fn only_the_last_copy_of_a_line_is_removed() {
+        let line = "/vendor/bin/foo u:object_r:vendor_file:s0";
+        let (_dir, path) = contexts(&format!("{}{}\n# kept\n{}\n", STOCK, line, line));
+        record_run(&path, "vendor", vec![line.to_string()]).unwrap();
+        undo_run(&path, None).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}{}\n# kept\n", STOCK, line));
+    }
+}
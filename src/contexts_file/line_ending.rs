@@ -0,0 +1,91 @@
+use std::fs::File;
+use std::io::{self, ErrorKind, Read};
+use std::path::Path;
+
+/// Byte order mark some Windows editors put at the start of UTF-8 files.
+const BOM: &str = "\u{FEFF}";
+
+/// How much of a file is sampled to tell its line ending.
+const SAMPLE_LEN: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    CrLf,
+}
+
+impl LineEnding {
+    /// The ending most lines in `content` use; LF when there are no line
+    /// breaks or as many of each.
+    pub fn detect(content: &[u8]) -> Self {
+        let lf = content.iter().filter(|&&byte| byte == b'\n').count();
+        let crlf = content.windows(2).filter(|pair| pair == b"\r\n").count();
+        if crlf * 2 > lf { Self::CrLf } else { Self::Lf }
+    }
+
+    /// Detects the ending from the start of the file at `path`; LF when it
+    /// does not exist yet.
+    pub fn of_file(path: &Path) -> io::Result<Self> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::Lf),
+            Err(e) => return Err(e),
+        };
+        let mut sample = Vec::new();
+        file.take(SAMPLE_LEN).read_to_end(&mut sample)?;
+        Ok(Self::detect(&sample))
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Lf => "\n",
+            Self::CrLf => "\r\n",
+        }
+    }
+}
+
+/// Splits a leading byte order mark off `content`, returning it (or an
+/// empty string) and the rest.
+pub fn split_bom(content: &str) -> (&str, &str) {
+    match content.strip_prefix(BOM) {
+        Some(rest) => (BOM, rest),
+        None => ("", content),
+    }
+}
+
+pub fn strip_bom(content: &str) -> &str {
+    split_bom(content).1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn most_lines_decide_the_ending() {
+        assert_eq!(LineEnding::detect(b"a\r\nb\r\nc\n"), LineEnding::CrLf);
+        assert_eq!(LineEnding::detect(b"a\r\nb\nc\n"), LineEnding::Lf);
+        assert_eq!(LineEnding::detect(b"a\r\nb\n"), LineEnding::Lf);
+        assert_eq!(LineEnding::detect(b"no line break"), LineEnding::Lf);
+        assert_eq!(LineEnding::CrLf.as_str(), "\r\n");
+    }
+
+    #[test]
+    fn missing_files_use_lf() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("file_contexts");
+        assert_eq!(LineEnding::of_file(&path).unwrap(), LineEnding::Lf);
+        fs::write(&path, "\u{FEFF}/vendor u:object_r:vendor_file:s0\r\n").unwrap();
+        assert_eq!(LineEnding::of_file(&path).unwrap(), LineEnding::CrLf);
+    }
+
+    #[test]
+    fn byte_order_mark_is_split_off() {
+        assert_eq!(split_bom("\u{FEFF}/vendor\r\n"), ("\u{FEFF}", "/vendor\r\n"));
+        assert_eq!(split_bom("/vendor\n"), ("", "/vendor\n"));
+        assert_eq!(strip_bom("\u{FEFF}\u{FEFF}/vendor"), "\u{FEFF}/vendor");
+    }
+}
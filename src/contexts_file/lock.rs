@@ -0,0 +1,143 @@
+use fs4::{FileExt, TryLockError};
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often a lock held elsewhere is tried again while waiting with a
+/// timeout.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The file locked for `contexts`. Writes replace the contexts file itself
+/// through a rename, which would leave a lock on it behind on the old copy.
+pub fn lock_path(contexts: &Path) -> PathBuf {
+    let mut name = contexts.file_name().unwrap_or_default().to_os_string();
+    name.push(".lock");
+    contexts.with_file_name(name)
+}
+
+/// Advisory lock on a contexts file, released when dropped. Only runs that
+/// also lock the file wait for it.
+#[derive(Debug)]
+pub struct ContextsLock {
+    _file: File,
+}
+
+impl ContextsLock {
+    /// Waits until the lock is free, calling `waiting` first when another
+    /// process holds it. Without a `timeout` it waits as long as it takes;
+    /// running out of time fails with [`ErrorKind::TimedOut`].
+    pub fn acquire(contexts: &Path, timeout: Option<Duration>, waiting: impl FnOnce()) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).truncate(false).write(true).open(lock_path(contexts))?;
+        match FileExt::try_lock(&file) {
+            Ok(()) => return Ok(Self { _file: file }),
+            Err(TryLockError::WouldBlock) => waiting(),
+            Err(TryLockError::Error(e)) => return Err(e),
+        }
+        let Some(timeout) = timeout else {
+            FileExt::lock(&file)?;
+            return Ok(Self { _file: file });
+        };
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::from(ErrorKind::TimedOut));
+            }
+            thread::sleep(POLL_INTERVAL.min(deadline - now));
+            match FileExt::try_lock(&file) {
+                Ok(()) => return Ok(Self { _file: file }),
+                Err(TryLockError::WouldBlock) => {}
+                Err(TryLockError::Error(e)) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Length and modification time of a file, to tell whether it changed
+/// between two points of a run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStamp {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl FileStamp {
+    /// `None` when the file does not exist.
+    pub fn of(path: &Path) -> io::Result<Option<Self>> {
+        match path.metadata() {
+            Ok(metadata) => Ok(Some(Self { len: metadata.len(), modified: metadata.modified().ok() })),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::fs;
+    use std::sync::mpsc;
+    use tempfile::TempDir;
+
+    #[test]
+    fn lock_file_sits_next_to_the_contexts_file() {
+        assert_eq!(lock_path(Path::new("/out/vendor_file_contexts")), Path::new("/out/vendor_file_contexts.lock"));
+    }
+
+    #[test]
+    fn held_lock_times_out() {
+        let dir = TempDir::new().unwrap();
+        let contexts = dir.path().join("file_contexts");
+        let _held = ContextsLock::acquire(&contexts, None, || panic!("lock is free")).unwrap();
+
+        let waited = Cell::new(false);
+        let start = Instant::now();
+        let error = ContextsLock::acquire(&contexts, Some(Duration::from_millis(250)), || waited.set(true)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        assert!(waited.get());
+        assert!(start.elapsed() >= Duration::from_millis(250));
+    }
+
+    #[test]
+    fn waiting_thread_gets_the_lock_once_released() {
+        let dir = TempDir::new().unwrap();
+        let contexts = dir.path().join("file_contexts");
+        let (locked_tx, locked_rx) = mpsc::channel();
+        let (released_tx, released_rx) = mpsc::channel();
+
+        let holder = thread::spawn({
+            let contexts = contexts.clone();
+            move || {
+                let lock = ContextsLock::acquire(&contexts, None, || panic!("lock is free")).unwrap();
+                locked_tx.send(()).unwrap();
+                thread::sleep(Duration::from_millis(200));
+                released_tx.send(()).unwrap();
+                drop(lock);
+            }
+        });
+
+        locked_rx.recv().unwrap();
+        let waited = Cell::new(false);
+        let _lock = ContextsLock::acquire(&contexts, Some(Duration::from_secs(10)), || waited.set(true)).unwrap();
+        assert!(waited.get());
+        assert!(released_rx.try_recv().is_ok(), "lock taken while still held");
+        holder.join().unwrap();
+    }
+
+    #[test]
+    fn stamp_changes_with_the_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("file_contexts");
+        assert_eq!(FileStamp::of(&path).unwrap(), None);
+
+        fs::write(&path, "/vendor u:object_r:vendor_file:s0\n").unwrap();
+        let before = FileStamp::of(&path).unwrap().unwrap();
+        assert_eq!(FileStamp::of(&path).unwrap(), Some(before));
+        fs::write(&path, "/vendor u:object_r:vendor_file:s0\n/vendor/bin u:object_r:vendor_file:s0\n").unwrap();
+        assert_ne!(FileStamp::of(&path).unwrap(), Some(before));
+    }
+}
@@ -0,0 +1,110 @@
+use super::line_ending::{split_bom, LineEnding};
+use crate::error::{Error, Result};
+use std::ops::Range;
+
+pub fn begin_marker(partition: &str) -> String {
+    format!("# BEGIN gen_file-context ({})", partition)
+}
+
+pub fn end_marker(partition: &str) -> String {
+    format!("# END gen_file-context ({})", partition)
+}
+
+/// Line range of the partition's generated block, both markers included.
+pub fn find_marked_block(lines: &[&str], partition: &str) -> Result<Option<Range<usize>>> {
+    let (begin, end) = (begin_marker(partition), end_marker(partition));
+    let Some(start) = lines.iter().position(|line| line.trim() == begin) else {
+        return Ok(None);
+    };
+    match lines[start..].iter().position(|line| line.trim() == end) {
+        Some(offset) => Ok(Some(start..start + offset + 1)),
+        None => Err(Error::UnterminatedBlock { begin, end }),
+    }
+}
+
+/// Replaces the partition's generated block with `entries`, appending a new
+/// block if there is none and dropping the block when `entries` is empty.
+/// A byte order mark and the dominant line ending of `content` are kept.
+pub fn replace_marked_block(content: &str, partition: &str, entries: &[String]) -> Result<String> {
+    let newline = LineEnding::detect(content.as_bytes()).as_str();
+    let (bom, content) = split_bom(content);
+    let lines: Vec<&str> = content.lines().collect();
+    let block = find_marked_block(&lines, partition)?;
+    let (before, after) = match &block {
+        Some(range) => (&lines[..range.start], &lines[range.end..]),
+        None => (&lines[..], &[][..]),
+    };
+
+    let mut output = String::with_capacity(bom.len() + content.len());
+    output.push_str(bom);
+    for line in before {
+        output.push_str(line);
+        output.push_str(newline);
+    }
+    if !entries.is_empty() {
+        output.push_str(&begin_marker(partition));
+        output.push_str(newline);
+        for entry in entries {
+            output.push_str(entry);
+            output.push_str(newline);
+        }
+        output.push_str(&end_marker(partition));
+        output.push_str(newline);
+    }
+    for line in after {
+        output.push_str(line);
+        output.push_str(newline);
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTENT: &str = "\
+/vendor u:object_r:vendor_file:s0
+# BEGIN gen_file-context (vendor)
+/vendor/bin/old u:object_r:vendor_file:s0
+# END gen_file-context (vendor)
+# BEGIN gen_file-context (odm)
+/odm/bin/foo u:object_r:vendor_file:s0
+# END gen_file-context (odm)
+/vendor/etc(/.*)? u:object_r:vendor_configs_file:s0
+";
+
+    #[test]
+    fn block_is_replaced_in_place() {
+        let entries = ["/vendor/bin/new u:object_r:vendor_file:s0".to_string()];
+        let output = replace_marked_block(CONTENT, "vendor", &entries).unwrap();
+        assert_eq!(output, CONTENT.replace("/vendor/bin/old", "/vendor/bin/new"));
+    }
+
+    #[test]
+    fn missing_block_is_appended_and_empty_block_dropped() {
+        let entries = ["/product/app u:object_r:system_file:s0".to_string()];
+        let output = replace_marked_block(CONTENT, "product", &entries).unwrap();
+        assert_eq!(
+            output,
+            format!("{}# BEGIN gen_file-context (product)\n{}\n# END gen_file-context (product)\n", CONTENT, entries[0])
+        );
+
+        let output = replace_marked_block(CONTENT, "odm", &[]).unwrap();
+        assert!(!output.contains("odm"), "{}", output);
+        assert!(output.contains("/vendor/bin/old") && output.ends_with("vendor_configs_file:s0\n"));
+    }
+
+    #[test]
+    fn unterminated_block_is_an_error() {
+        let lines = ["# BEGIN gen_file-context (vendor)", "/vendor/bin/old u:object_r:vendor_file:s0", "# END gen_file-context (odm)"];
+        assert!(matches!(find_marked_block(&lines, "vendor"), Err(Error::UnterminatedBlock { .. })));
+        assert_eq!(find_marked_block(&lines, "product").unwrap(), None);
+    }
+
+    #[test]
+    fn bom_and_crlf_are_kept() {
+        let content = format!("\u{FEFF}{}", CONTENT.replace('\n', "\r\n"));
+        let output = replace_marked_block(&content, "vendor", &["/vendor/bin/new u:object_r:vendor_file:s0".to_string()]).unwrap();
+        assert_eq!(output, content.replace("/vendor/bin/old", "/vendor/bin/new"));
+    }
+}
@@ -0,0 +1,27 @@
+mod align;
+mod compiled;
+mod conflicts;
+mod diff;
+mod file_type;
+mod journal;
+mod line_ending;
+mod lock;
+mod markers;
+mod parser;
+mod security_context;
+mod sort;
+pub use align::{detect_align, Align, Alignment};
+pub use compiled::{
+    compile_contexts_files, compile_entries, is_compiled, is_compiled_file, parse_compiled, CompileSummary,
+    COMPILED_MAGIC, COMPILED_VERSION_NO_PCRE, COMPILED_VERSION_REGEX_ARCH,
+};
+pub use conflicts::{find_conflicts, report_conflicts, LabelConflict, Resolution};
+pub use diff::{diff_contexts_files, ChangedEntry, ContextsDiff};
+pub use file_type::FileType;
+pub use journal::{journal_path, record_run, undo_run, Journal, JournalRun, UndoSummary};
+pub use line_ending::{split_bom, strip_bom, LineEnding};
+pub use lock::{lock_path, ContextsLock, FileStamp};
+pub use markers::{begin_marker, end_marker, find_marked_block, replace_marked_block};
+pub use parser::{parse_contexts, parse_line, read_contexts_file, ContextLine, ParsedEntry};
+pub use security_context::SecurityContext;
+pub use sort::{sort_contexts_file, SortSummary};
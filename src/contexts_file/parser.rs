@@ -0,0 +1,145 @@
+use super::compiled::{is_compiled, parse_compiled};
+use super::file_type::FileType;
+use super::line_ending::strip_bom;
+use crate::error::{Error, Result};
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ParsedEntry {
+    pub pattern: String,
+    pub file_type: Option<FileType>,
+    pub context: String,
+    pub line: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ContextLine {
+    Entry(ParsedEntry),
+    Comment(String),
+    Blank,
+    Invalid(String),
+}
+
+impl ParsedEntry {
+    pub fn to_line(&self) -> String {
+        match &self.file_type {
+            Some(file_type) => format!("{} {} {}", self.pattern, file_type, self.context),
+            None => format!("{} {}", self.pattern, self.context),
+        }
+    }
+}
+
+pub fn parse_line(line: &str, line_number: usize) -> ContextLine {
+    let trimmed = line.trim();
+
+    if trimmed.is_empty() {
+        return ContextLine::Blank;
+    }
+    if trimmed.starts_with('#') {
+        return ContextLine::Comment(trimmed.to_string());
+    }
+
+    let fields: Vec<&str> = trimmed.split_whitespace().collect();
+    match fields.as_slice() {
+        [pattern, context] => ContextLine::Entry(ParsedEntry {
+            pattern: pattern.to_string(),
+            file_type: None,
+            context: context.to_string(),
+            line: line_number,
+        }),
+        [pattern, file_type, context] if let Ok(file_type) = file_type.parse::<FileType>() => ContextLine::Entry(ParsedEntry {
+            pattern: pattern.to_string(),
+            file_type: Some(file_type),
+            context: context.to_string(),
+            line: line_number,
+        }),
+        _ => ContextLine::Invalid(trimmed.to_string()),
+    }
+}
+
+pub fn parse_contexts(content: &str) -> Vec<ContextLine> {
+    strip_bom(content)
+        .lines()
+        .enumerate()
+        .map(|(index, line)| parse_line(line, index + 1))
+        .collect()
+}
+
+/// Reads a text contexts file, or the entries of a compiled one.
+pub fn read_contexts_file(path: &Path) -> Result<Vec<ContextLine>> {
+    let content = fs::read(path).map_err(|e| Error::read(path, e))?;
+    if is_compiled(&content) {
+        let entries = parse_compiled(&content).map_err(|message| Error::invalid_file("compiled contexts file", path, message))?;
+        return Ok(entries.into_iter().map(ContextLine::Entry).collect());
+    }
+    let content = String::from_utf8(content)
+        .map_err(|e| Error::read(path, io::Error::new(io::ErrorKind::InvalidData, e)))?;
+    Ok(parse_contexts(&content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STOCK: &str = "\
+# Vendor file contexts
+#   maintained by oem
+
+/vendor(/.*)?                      u:object_r:vendor_file:s0
+  # /vendor/bin/old                u:object_r:vendor_file:s0
+/vendor/etc           -d           u:object_r:vendor_configs_file:s0
+/vendor/bin/sh        --           u:object_r:vendor_shell_exec:s0
+/vendor/lib(64)?/libfoo\\.so  u:object_r:same_process_hal_file:s0
+/vendor/broken
+";
+
+    fn entries(lines: &[ContextLine]) -> Vec<&ParsedEntry> {
+        lines
+            .iter()
+            .filter_map(|line| match line {
+                ContextLine::Entry(entry) => Some(entry),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn comments_blank_lines_and_type_fields() {
+        let lines = parse_contexts(STOCK);
+        assert_eq!(lines[0], ContextLine::Comment("# Vendor file contexts".to_string()));
+        assert_eq!(lines[2], ContextLine::Blank);
+        assert_eq!(lines[4], ContextLine::Comment("# /vendor/bin/old                u:object_r:vendor_file:s0".to_string()));
+        assert_eq!(lines[8], ContextLine::Invalid("/vendor/broken".to_string()));
+
+        let entries = entries(&lines);
+        let fields: Vec<(&str, Option<FileType>, &str, usize)> = entries
+            .iter()
+            .map(|entry| (entry.pattern.as_str(), entry.file_type, entry.context.as_str(), entry.line))
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                ("/vendor(/.*)?", None, "u:object_r:vendor_file:s0", 4),
+                ("/vendor/etc", Some(FileType::Directory), "u:object_r:vendor_configs_file:s0", 6),
+                ("/vendor/bin/sh", Some(FileType::Regular), "u:object_r:vendor_shell_exec:s0", 7),
+                ("/vendor/lib(64)?/libfoo\\.so", None, "u:object_r:same_process_hal_file:s0", 8),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_type_fields_are_invalid() {
+        assert_eq!(parse_line("/vendor/etc -x u:object_r:vendor_file:s0", 1), ContextLine::Invalid("/vendor/etc -x u:object_r:vendor_file:s0".to_string()));
+    }
+
+    #[test]
+    fn entries_print_back_with_their_type_field() {
+        let lines = parse_contexts(STOCK);
+        let printed: Vec<String> = entries(&lines).iter().map(|entry| entry.to_line()).collect();
+        assert_eq!(printed[1], "/vendor/etc -d u:object_r:vendor_configs_file:s0");
+        assert_eq!(printed[0], "/vendor(/.*)? u:object_r:vendor_file:s0");
+    }
+}
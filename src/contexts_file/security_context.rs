@@ -0,0 +1,72 @@
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// A `user:role:type:level` SELinux context. The level keeps everything
+/// after the third colon, so MLS categories like `s0:c512,c768` survive.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SecurityContext {
+    pub user: String,
+    pub role: String,
+    pub r#type: String,
+    pub level: String,
+}
+
+impl SecurityContext {
+    /// Takes `label` verbatim when it is a full context; a bare type gets
+    /// the user, role and level of `base`.
+    pub fn from_label(label: &str, base: &SecurityContext) -> Result<Self, String> {
+        if label.contains(':') {
+            return label.parse();
+        }
+        if label.is_empty() || label.contains(char::is_whitespace) {
+            return Err(format!("Invalid SELinux type: {:?}", label));
+        }
+        Ok(Self { r#type: label.to_string(), ..base.clone() })
+    }
+}
+
+impl Default for SecurityContext {
+    /// `u:object_r:unlabeled:s0`, the parts Android uses for file labels.
+    fn default() -> Self {
+        Self {
+            user: "u".to_string(),
+            role: "object_r".to_string(),
+            r#type: "unlabeled".to_string(),
+            level: "s0".to_string(),
+        }
+    }
+}
+
+impl FromStr for SecurityContext {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.splitn(4, ':').collect();
+        match parts[..] {
+            [user, role, r#type, level]
+                if !s.contains(char::is_whitespace) && parts.iter().all(|part| !part.is_empty()) =>
+            {
+                Ok(Self {
+                    user: user.to_string(),
+                    role: role.to_string(),
+                    r#type: r#type.to_string(),
+                    level: level.to_string(),
+                })
+            }
+            _ => Err(format!("Invalid SELinux context, expected user:role:type:level: {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for SecurityContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}:{}", self.user, self.role, self.r#type, self.level)
+    }
+}
+
+impl Serialize for SecurityContext {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
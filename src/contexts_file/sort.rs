@@ -0,0 +1,158 @@
+use super::align::{detect_align, Align, Alignment};
+use super::compiled::is_compiled_file;
+use super::conflicts::{find_conflicts, report_conflicts, Resolution};
+use super::line_ending::LineEnding;
+use super::parser::{read_contexts_file, ContextLine, ParsedEntry};
+use crate::utils::fs_utils::write_atomic;
+use crate::error::{Error, Result};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Default)]
+pub struct SortSummary {
+    pub entries: usize,
+    pub duplicates_removed: usize,
+    pub conflicts: usize,
+    pub shadowed_removed: usize,
+}
+
+/// Sorts and deduplicates `input` into `output`. Without `align`, the
+/// alignment style `input` mostly uses is kept.
+pub fn sort_contexts_file(input: &Path, output: &Path, resolve: Option<Resolution>, align: Option<Align>) -> Result<SortSummary> {
+    if input == output && is_compiled_file(input) {
+        return Err(Error::InvalidArgument(format!(
+            "{:?} is a compiled file_contexts.bin, pass --output to write the sorted text elsewhere",
+            input
+        )));
+    }
+    let lines = read_contexts_file(input)?;
+    let mut summary = SortSummary::default();
+
+    let header_len = lines
+        .iter()
+        .take_while(|line| matches!(line, ContextLine::Comment(_) | ContextLine::Blank))
+        .count();
+    let header: Vec<&str> = lines[..header_len]
+        .iter()
+        .filter_map(|line| match line {
+            ContextLine::Comment(comment) => Some(comment.as_str()),
+            _ => None,
+        })
+        .collect();
+
+    let mut entries: Vec<ParsedEntry> = Vec::new();
+    let mut comments: HashMap<usize, Vec<&str>> = HashMap::new();
+    let mut pending_comments: Vec<&str> = Vec::new();
+    let mut invalid: Vec<&str> = Vec::new();
+    let mut first_seen: HashMap<String, usize> = HashMap::new();
+
+    for line in &lines[header_len..] {
+        match line {
+            ContextLine::Entry(entry) => {
+                let owner = *first_seen.entry(entry.to_line()).or_insert(entry.line);
+                if owner == entry.line {
+                    entries.push(entry.clone());
+                } else {
+                    summary.duplicates_removed += 1;
+                }
+                comments.entry(owner).or_default().append(&mut pending_comments);
+            }
+            ContextLine::Comment(comment) => pending_comments.push(comment),
+            ContextLine::Invalid(raw) => invalid.push(raw),
+            ContextLine::Blank => {}
+        }
+    }
+
+    let conflicts = find_conflicts(entries.iter().map(|entry| (input, entry)));
+    report_conflicts(&conflicts);
+    summary.conflicts = conflicts.len();
+
+    let mut shadowed = HashSet::new();
+    for conflict in &conflicts {
+        let keep = match resolve {
+            None => continue,
+            Some(Resolution::Fail) => {
+                return Err(Error::Conflicts { path: input.to_path_buf(), count: conflicts.len() });
+            }
+            Some(Resolution::KeepFirst) => 0,
+            Some(Resolution::KeepLast) => conflict.entries.len() - 1,
+        };
+        for (index, (_, entry)) in conflict.entries.iter().enumerate() {
+            if index != keep {
+                shadowed.insert(entry.line);
+            }
+        }
+    }
+
+    let before = entries.len();
+    entries.retain(|entry| !shadowed.contains(&entry.line));
+    summary.shadowed_removed = before - entries.len();
+
+    entries.sort_by(|a, b| a.pattern.cmp(&b.pattern));
+    summary.entries = entries.len();
+    // Compiled input has no alignment or line endings to keep.
+    let text = fs::read_to_string(input).unwrap_or_default();
+    let align = align.unwrap_or_else(|| detect_align(&text));
+    let newline = LineEnding::detect(text.as_bytes()).as_str();
+    let alignment = Alignment::new(align, None, entries.iter().map(|entry| (entry.pattern.as_str(), entry.file_type)));
+
+    let mut content = String::new();
+    for comment in header {
+        content.push_str(comment);
+        content.push_str(newline);
+    }
+    for entry in &entries {
+        for comment in comments.get(&entry.line).into_iter().flatten() {
+            content.push_str(comment);
+            content.push_str(newline);
+        }
+        content.push_str(&alignment.line(&entry.pattern, entry.file_type, &entry.context));
+        content.push_str(newline);
+    }
+    for comment in pending_comments {
+        content.push_str(comment);
+        content.push_str(newline);
+    }
+    for raw in invalid {
+        content.push_str(raw);
+        content.push_str(newline);
+    }
+
+    write_atomic(output, content.as_bytes())?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn sorting_keeps_comments_with_their_entries() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("vendor_file_contexts");
+        let stock = "\
+# Vendor file contexts
+
+/vendor/bin/sh -- u:object_r:vendor_shell_exec:s0
+# Everything else
+/vendor(/.*)? u:object_r:vendor_file:s0
+/vendor(/.*)? u:object_r:vendor_file:s0
+# trailing note
+";
+        fs::write(&path, stock).unwrap();
+        let summary = sort_contexts_file(&path, &path, None, Some(Align::None)).unwrap();
+        assert_eq!((summary.entries, summary.duplicates_removed), (2, 1));
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "\
+# Vendor file contexts
+# Everything else
+/vendor(/.*)? u:object_r:vendor_file:s0
+/vendor/bin/sh -- u:object_r:vendor_shell_exec:s0
+# trailing note
+"
+        );
+    }
+}
@@ -0,0 +1,88 @@
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Failures of the library API. The offending path or value is carried in
+/// each variant, and the messages are what the CLI prints.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Partition directory does not exist: {0:?}")]
+    MissingPartition(PathBuf),
+
+    #[error("Failed to read {path:?}: {error}")]
+    Read { path: PathBuf, error: io::Error },
+
+    #[error("Failed to write {path:?}: {error}")]
+    Write { path: PathBuf, error: io::Error },
+
+    #[error("Failed to lock {path:?}: {error}")]
+    Lock { path: PathBuf, error: io::Error },
+
+    #[error("Another run held {path:?} for more than {seconds}s, the --wait-lock limit")]
+    LockTimeout { path: PathBuf, seconds: u64 },
+
+    /// Command line options that are invalid or cannot be combined.
+    #[error("{0}")]
+    InvalidArgument(String),
+
+    #[error("Invalid {option} pattern {pattern:?}: {error}")]
+    InvalidGlob { option: &'static str, pattern: String, error: globset::Error },
+
+    #[error("Invalid pattern in existing contexts: {0}")]
+    InvalidPattern(#[from] regex::Error),
+
+    /// A rules file, manifest, ignore file, journal or marker block that
+    /// could be read but not understood.
+    #[error("Invalid {kind} {path:?}: {message}")]
+    InvalidFile { kind: &'static str, path: PathBuf, message: String },
+
+    #[error("Found {begin:?} without a matching {end:?}")]
+    UnterminatedBlock { begin: String, end: String },
+
+    #[error("{0}")]
+    Toml(#[from] toml::de::Error),
+
+    #[error("Rule at line {line}: {message}")]
+    InvalidRule { line: usize, message: String },
+
+    #[error("{0} generated entries failed validation and were not written, use --force to write them anyway")]
+    Validation(usize),
+
+    #[error("{count} patterns in {path:?} have conflicting labels")]
+    Conflicts { path: PathBuf, count: usize },
+
+    #[error("{count} paths have names that are not valid UTF-8, e.g. {example:?}; pass --lossy-paths to label them")]
+    NonUtf8Paths { count: usize, example: PathBuf },
+
+    #[error("Type {0:?} is not declared by --valid-types")]
+    UndeclaredType(String),
+
+    #[error("Failed to label {path:?}: {error}")]
+    Label { path: PathBuf, error: Box<Error> },
+
+    #[error("No {} recorded for {path:?}", .id.map_or("runs".to_string(), |id| format!("run {}", id)))]
+    NoRecordedRun { path: PathBuf, id: Option<u64> },
+
+    #[error("Failed to serialize {what}: {message}")]
+    Serialize { what: &'static str, message: String },
+
+    /// A worker or writer thread panicked or hung up.
+    #[error("{0}")]
+    Thread(String),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    pub(crate) fn read(path: impl Into<PathBuf>, error: io::Error) -> Self {
+        Self::Read { path: path.into(), error }
+    }
+
+    pub(crate) fn write(path: impl Into<PathBuf>, error: io::Error) -> Self {
+        Self::Write { path: path.into(), error }
+    }
+
+    pub(crate) fn invalid_file(kind: &'static str, path: impl Into<PathBuf>, message: impl ToString) -> Self {
+        Self::InvalidFile { kind, path: path.into(), message: message.to_string() }
+    }
+}
@@ -1,6 +1,16 @@
+#[cfg(feature = "bench")]
+pub mod bench;
+pub mod build_info;
 pub mod config;
+pub mod contexts_file;
+pub mod error;
+pub mod policy;
 pub mod processor;
 pub mod progress;
 pub mod utils;
 
-pub use config::{Config, Mode, FilesystemType};
\ No newline at end of file
+pub use config::{Config, ConfigBuilder, DirPattern, LabelPolicy, Mode, FilesystemType, PartitionKind};
+pub use error::{Error, Result};
+pub use processor::{
+    generate_missing_entries, label_for, process_with_hook, write_entries, ContextEntry, EntryKind, HookAction,
+};
\ No newline at end of file
@@ -1,27 +1,780 @@
-mod config;
-mod processor;
-mod progress;
-mod utils;
-
 use anyhow::Result;
-use clap::Command;
-use config::Config;
+use clap::builder::{PossibleValue, PossibleValuesParser};
+use clap::{ArgMatches, Command, ValueEnum};
+use gen_file_contexts::config::normalize_partition_name;
+use gen_file_contexts::processor::ReportFormat;
+use gen_file_contexts::progress::reporter::Reporter;
+use gen_file_contexts::{contexts_file, processor, progress, Config, DirPattern, FilesystemType, Mode, PartitionKind};
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::EnvFilter;
 
-fn main() -> Result<()> {
-    let matches = Command::new("file_contexts_generator")
+/// Exit status of a run stopped with Ctrl-C, as shells report for SIGINT.
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// The command line of the tool and its subcommands.
+fn cli() -> Command {
+    let command = Command::new("file_contexts_generator")
         .about("A tool to automatically generate missing file_contexts based on file/folder location")
+        .after_help("A .file_contexts_override file inside the partition, holding a type or full context on its first \
+            line, labels its folder and everything below it ahead of the built-in heuristics; the deepest one wins.\n\n\
+            The partition name used for labeling and marked blocks is --prefix without its leading /, else \
+            --partition-name, else the folder name. Entry paths start with --prefix, else / and that name, unless \
+            --no-partition-prefix starts them at the root of the tree, which cannot be combined with --prefix.")
         .author("Danda420")
-        .arg(clap::arg!(-a --all "Autogenerate all missing contexts").conflicts_with("bin"))
-        .arg(clap::arg!(-b --bin "Autogenerate only /bin/ missing contexts").conflicts_with("all"))
-        .arg(clap::arg!(-f --fstype <FSTYPE> "Filesystem type: ext4, erofs, f2fs").required(true))
-        .arg(clap::arg!(-p --partition <PARTITION> "Path to extracted partition folder").required(true))
-        .arg(clap::arg!(-c --contexts <CONTEXTS> "Path to partition_file_contexts file").required(true))
-        .arg(clap::arg!(-t --threads <THREADS> "Number of parallel threads to use").default_value("4"))
-        .arg(clap::arg!(-q --quiet "Make file_contexts generator quiet"))
-        .arg(clap::arg!(--vbin <CONTEXT> "Custom context for vendor & odm /bin/ files"))
+        .version(clap::crate_version!())
+        .long_version(gen_file_contexts::build_info::LONG_VERSION)
+        .subcommand_negates_reqs(true)
+        .args_conflicts_with_subcommands(true)
+        .arg(clap::arg!(-m --mode <MODE> "Which missing contexts to autogenerate, repeatable or comma-separated")
+            .action(clap::ArgAction::Append)
+            .value_delimiter(',')
+            .env("FCGEN_MODE")
+            .value_parser(clap::value_parser!(Mode)))
+        .arg(clap::arg!(-a --all "Same as --mode all").hide(true).conflicts_with_all(["bin", "mode"]))
+        .arg(clap::arg!(-b --bin "Same as --mode bin").hide(true).conflicts_with_all(["all", "mode"]))
+        .arg(clap::arg!(--"nested-bin" "Also include bin directories nested deeper in the tree in bin mode, and label their contents as binaries"))
+        .arg(clap::arg!(-f --fstype <FSTYPE> "Filesystem type of the partition image")
+            .required_unless_present("config")
+            .env("FCGEN_FSTYPE")
+            .ignore_case(true)
+            .value_parser(PossibleValuesParser::new(
+                FilesystemType::value_variants()
+                    .iter()
+                    .filter_map(ValueEnum::to_possible_value)
+                    .chain([PossibleValue::new("auto")
+                        .help("Read the superblock of --image, or metadata files the extraction tool left")]),
+            )))
+        .arg(clap::arg!(--image <IMAGE> "Raw partition image, read in place of an extracted folder when --partition is not given (ext4 only) and used by --fstype auto"))
+        .arg(clap::arg!(--"dir-pattern" <PATTERN> "Whether directory entries also match everything below them with (/.*)?")
+            .long_help("Whether directory entries also match everything below them with (/.*)?.\n\n\
+                By default this follows --fstype: ext4 gets recursive entries, since files can be added \
+                to a mounted ext4 partition and should inherit the directory's label. erofs, f2fs and \
+                squashfs images are treated as read-only and fixed at build time, so every path gets its \
+                own exact entry. Stock erofs file_contexts often use recursive entries anyway; pass \
+                --dir-pattern recursive to match them.")
+            .ignore_case(true)
+            .value_parser(clap::value_parser!(DirPattern)))
+        .arg(clap::arg!(-p --partition <PARTITION> "Path to extracted partition folder, or a .tar, .tar.gz or .zip of it, repeatable to process several partitions")
+            .required_unless_present_any(["manifest", "config", "image"])
+            .env("FCGEN_PARTITION")
+            .action(clap::ArgAction::Append))
+        .arg(clap::arg!(-c --contexts <CONTEXTS> "Path to partition_file_contexts file, repeatable or comma-separated; with several -p, one per partition in order")
+            .required_unless_present_any(["manifest", "super", "config"])
+            .env("FCGEN_CONTEXTS")
+            .action(clap::ArgAction::Append)
+            .value_delimiter(','))
+        .arg(clap::arg!(--manifest <FILE> "File listing one partition folder and contexts file pair per line")
+            .conflicts_with_all(["partition", "contexts"]))
+        .arg(clap::arg!(--super "Treat -p as an extracted super image and process each known partition folder inside it")
+            .requires("contexts-dir")
+            .conflicts_with_all(["contexts", "manifest"]))
+        .arg(clap::arg!(--"contexts-dir" <DIR> "Folder holding <partition>_file_contexts files, used with --super")
+            .requires("super"))
+        .arg(clap::arg!(--config <FILE> "TOML file with default settings, keyed like the long options; options given here take precedence"))
+        .arg(clap::arg!(--"dump-config" "Print the effective settings as TOML and exit"))
+        .arg(clap::arg!(--"write-to" <FILE> "Contexts file receiving generated entries, defaults to the first -c"))
+        .arg(clap::arg!(--"partition-name" <NAME> "Partition name used for entries and labeling instead of the folder name"))
+        .arg(clap::arg!(--"treat-as" <KIND> "Partition class used for labeling instead of the one inferred from its name")
+            .value_parser(clap::value_parser!(PartitionKind)))
+        .arg(clap::arg!(--"legacy-matching" "Match heuristic directories like etc/ by substring as older releases did, to be removed in the next release"))
+        .arg(clap::arg!(--prefix <PATH> "Mount point used for generated entries instead of the partition folder name"))
+        .arg(clap::arg!(--"no-partition-prefix" "Start entries at the root of the tree instead of the mount point, for ramdisk and recovery trees")
+            .conflicts_with("prefix"))
+        .arg(clap::arg!(-t --threads <THREADS> "Number of parallel threads to use")
+            .env("FCGEN_THREADS")
+            .value_parser(clap::value_parser!(usize))
+            .default_value("4"))
+        .arg(clap::arg!(-q --quiet "Silence informational output and the progress bar on stdout; warnings and errors still go to stderr"))
+        .arg(clap::arg!(--summary "Print one line of totals per partition to stderr instead of the summary table, even with --quiet")
+            .conflicts_with("check"))
+        .arg(clap::arg!(--progress <WHEN> "When to show progress")
+            .value_parser(clap::value_parser!(progress::bar::ProgressMode))
+            .default_value("auto"))
+        .arg(clap::arg!(--"progress-json" "Write progress as newline-delimited JSON events to stderr instead of drawing a bar")
+            .conflicts_with("progress"))
+        .arg(clap::arg!(-v --verbose... "Log progress and skipped paths, repeat for per-path labeling decisions"))
+        .arg(clap::arg!(--strict "Abort on the first path that cannot be labeled or whose type --valid-types does not declare"))
+        .arg(clap::arg!(--labels <FILE> "TOML file replacing the labels of the built-in heuristics per category"))
+        .arg(clap::arg!(--seuser <USER> "SELinux user of generated contexts").default_value("u"))
+        .arg(clap::arg!(--serole <ROLE> "SELinux role of generated contexts").default_value("object_r"))
+        .arg(clap::arg!(--selevel <LEVEL> "MLS level of generated contexts, e.g. s0:c512,c768").default_value("s0"))
+        .arg(clap::arg!(--vbin <CONTEXT> "Context for vendor & odm /bin/ files other than shell scripts and toolbox, defaults to the QTI init shell label")
+            .visible_alias("bin-context"))
         .arg(clap::arg!(--sbin <CONTEXT> "Custom context for system /bin/ files"))
-        .get_matches();
+        .arg(clap::arg!(--hal <CONTEXT> "Context for /bin/hw/ files whose HAL name cannot be derived"))
+        .arg(clap::arg!(--kmod <CONTEXT> "Custom context for kernel modules on vendor & odm partitions"))
+        .arg(clap::arg!(--rules <RULES> "TOML file with path-to-context rules evaluated before the built-in defaults, and extension-to-context mappings for vendor partitions"))
+        .arg(clap::arg!(--"source-contexts" <FILE> "file_contexts written by the extraction tool, whose labels are used for the paths it lists"))
+        .arg(clap::arg!(--"valid-types" <FILE> "Policy declaring the types generated contexts may use: a binary sepolicy, a .cil file or a list of type names, repeatable")
+            .action(clap::ArgAction::Append))
+        .arg(clap::arg!(--"type-fallback" <CONTEXT> "Context for entries whose type --valid-types does not declare, defaults to vendor_file or system_file by partition")
+            .requires("valid-types"))
+        .arg(clap::arg!(--"inspect-content" "Read ELF headers to label executables and shared libraries outside bin/lib"))
+        .arg(clap::arg!(--"from-xattr" "Use the security.selinux attribute of extracted paths where set, falling back to the heuristics (Linux only)"))
+        .arg(clap::arg!(--collapse "Replace directories whose missing contents share one label with a single recursive entry"))
+        .arg(clap::arg!(--"emit-type-field" "Add -d to directory entries, -l to symlinks and -c, -b, -s or -p to device nodes, sockets and pipes"))
+        .arg(clap::arg!(--include <GLOB> "Only process paths relative to the partition root matching this glob, may be repeated")
+            .action(clap::ArgAction::Append))
+        .arg(clap::arg!(--exclude <GLOB> "Skip paths relative to the partition root matching this glob, may be repeated")
+            .action(clap::ArgAction::Append))
+        .arg(clap::arg!(--"no-default-excludes" "Also process lost+found, .DS_Store and other leftovers of extraction tools"))
+        .arg(clap::arg!(--"ignore-file" <FILE> "Gitignore-style file of paths to skip, defaults to .fcignore in the partition root"))
+        .arg(clap::arg!(--cache <FILE> "Reuse the directory scan stored in this file, re-reading only directories that changed"))
+        .arg(clap::arg!(--"compile-output" <FILE> "After writing, compile the contexts files into this binary file_contexts.bin")
+            .conflicts_with_all(["dry-run", "check"]))
+        .arg(clap::arg!(--"skip-symlinks" "Do not generate entries for symbolic links"))
+        .arg(clap::arg!(--"follow-symlinks" "Walk into directories symlinks point to, labeling their contents under the link's path")
+            .conflicts_with("cache"))
+        .arg(clap::arg!(--"max-depth" <DEPTH> "Do not walk deeper than this many levels below the partition root, top-level paths being level 1")
+            .value_parser(clap::value_parser!(usize)))
+        .arg(clap::arg!(--"lossy-paths" "Label paths whose names are not valid UTF-8, matching the invalid bytes with a wildcard")
+            .conflicts_with("fail-on-invalid-path"))
+        .arg(clap::arg!(--"fail-on-invalid-path" "Abort when a path name is not valid UTF-8 instead of skipping it"))
+        .arg(clap::arg!(--"ci-match" "Ignore case when looking paths up in the existing contexts, for partitions extracted on NTFS or APFS"))
+        .arg(clap::arg!(--"paths-from" <FILE> "Process the newline-separated paths in this file, relative to the partition root, instead of walking the partition; - reads stdin"))
+        .arg(clap::arg!(--"assume-files" "Label every --paths-from entry as a regular file without looking it up in the partition folder"))
+        .arg(clap::arg!(--"no-sort" "Write generated entries in completion order instead of sorting by path"))
+        .arg(clap::arg!(--"no-validate" "Skip checking that generated patterns match their source paths"))
+        .arg(clap::arg!(--force "Write generated entries even if some fail validation"))
+        .arg(clap::arg!(--align <STYLE> "Line up the contexts of written entries")
+            .value_parser(clap::value_parser!(contexts_file::Align)))
+        .arg(clap::arg!(--"align-column" <COLUMN> "Column contexts start at with --align, instead of after the longest pattern")
+            .value_parser(clap::value_parser!(usize))
+            .requires("align"))
+        .arg(clap::arg!(--"no-markers" "Append entries after the existing ones instead of maintaining a marked block"))
+        .arg(clap::arg!(--stream "Append entries to the contexts file as they are generated, unsorted and without markers")
+            .conflicts_with("collapse"))
+        .arg(clap::arg!(--"insert-sorted" "Place each entry after the existing entries matching its path instead of in a marked block, so it wins where the last match applies")
+            .conflicts_with("stream"))
+        .arg(clap::arg!(--"dry-run" "Print generated entries instead of appending them to the contexts file"))
+        .arg(clap::arg!(--lock "Hold the lock on the contexts file for the whole run instead of only while writing")
+            .conflicts_with_all(["dry-run", "check"]))
+        .arg(clap::arg!(--"wait-lock" <SECONDS> "Fail after waiting this long for another run to release the contexts file, instead of waiting as long as it takes")
+            .value_parser(clap::value_parser!(u64)))
+        .arg(clap::arg!(--"on-interrupt" <ACTION> "What to do with the entries generated so far when interrupted with Ctrl-C")
+            .value_parser(clap::value_parser!(processor::OnInterrupt))
+            .default_value("discard"))
+        .arg(clap::arg!(--explain "Print which rule or heuristic produced each generated label"))
+        .arg(clap::arg!(--report <FILE> "Write a summary of the run, including all generated entries, to this file")
+            .conflicts_with("check"))
+        .arg(clap::arg!(--"report-format" <FORMAT> "Format of the --report file")
+            .value_parser(clap::value_parser!(ReportFormat))
+            .default_value("json"))
+        .arg(clap::arg!(--check "Report missing contexts and exit with code 2 if any are found, without writing").conflicts_with("dry-run"))
+        .subcommand(Command::new("sort")
+            .about("Deduplicate and sort an existing file_contexts file")
+            .arg(clap::arg!(<FILE> "Path to the file_contexts file to sort"))
+            .arg(clap::arg!(-o --output <OUTPUT> "Write the sorted file here instead of rewriting FILE"))
+            .arg(clap::arg!(--resolve <RESOLUTION> "How to handle patterns listed with different labels")
+                .value_parser(clap::value_parser!(contexts_file::Resolution)))
+            .arg(clap::arg!(--"drop-shadowed" "Same as --resolve keep-last").hide(true).conflicts_with("resolve"))
+            .arg(clap::arg!(--align <STYLE> "Line up contexts this way instead of keeping the style FILE mostly uses")
+                .value_parser(clap::value_parser!(contexts_file::Align)))
+            .arg(clap::arg!(-q --quiet "Do not print a summary")))
+        .subcommand(Command::new("compile")
+            .about("Compile file_contexts files into the binary file_contexts.bin format")
+            .arg(clap::arg!(<FILE> "file_contexts files to compile, later ones taking precedence")
+                .num_args(1..))
+            .arg(clap::arg!(-o --output <OUTPUT> "Write the compiled file here instead of FILE.bin"))
+            .arg(clap::arg!(--"pcre-version" <VERSION> "Write the current format for a device whose PCRE2 reports this version, e.g. \"10.42 2022-12-11\"; by default the oldest format, which loads with any PCRE, is written"))
+            .arg(clap::arg!(-q --quiet "Do not print a summary")))
+        .subcommand(Command::new("diff")
+            .about("Compare two file_contexts files entry by entry")
+            .arg(clap::arg!(<A> "First file_contexts file"))
+            .arg(clap::arg!(<B> "Second file_contexts file"))
+            .arg(clap::arg!(--format <FORMAT> "Output format")
+                .value_parser(["text", "json"])
+                .default_value("text")))
+        .subcommand(Command::new("verify")
+            .about("Check that every path in a partition is matched by some contexts entry")
+            .arg(clap::arg!(<PARTITION> "Path to extracted partition folder"))
+            .arg(clap::arg!(-c --contexts <CONTEXTS> "Path to a file_contexts file, repeatable or comma-separated")
+                .required(true)
+                .action(clap::ArgAction::Append)
+                .value_delimiter(','))
+            .arg(clap::arg!(--"partition-name" <NAME> "Partition name used in paths instead of the folder name"))
+            .arg(clap::arg!(--format <FORMAT> "Output format")
+                .value_parser(["text", "json"])
+                .default_value("text")))
+        .subcommand(Command::new("prune")
+            .about("List or remove entries that match nothing in a partition, such as those for deleted blobs")
+            .arg(clap::arg!(<PARTITION> "Path to extracted partition folder"))
+            .arg(clap::arg!(-c --contexts <FILE> "Contexts file to prune").required(true))
+            .arg(clap::arg!(--"partition-name" <NAME> "Partition name used in paths instead of the folder name"))
+            .arg(clap::arg!(--apply "Remove the stale entries from the contexts file"))
+            .arg(clap::arg!(--aggressive "Also check recursive and wildcard entries, which may be meant for paths created at runtime"))
+            .arg(clap::arg!(--"all-entries" "Check all entries for the partition, not only the generated block when there is one"))
+            .arg(clap::arg!(--format <FORMAT> "Output format")
+                .value_parser(["text", "json"])
+                .default_value("text")))
+        .subcommand(Command::new("undo")
+            .about("Remove the entries added by a previous run from a contexts file")
+            .arg(clap::arg!(<FILE> "Contexts file the entries were written to"))
+            .arg(clap::arg!(--run <ID> "Run to undo instead of the most recent one")
+                .value_parser(clap::value_parser!(u64)))
+            .arg(clap::arg!(--list "List recorded runs instead of undoing one").conflicts_with("run")));
+    #[cfg(feature = "bench")]
+    let command = command.subcommand(Command::new("bench")
+        .about("Time dry runs over a synthetic partition generated from a seed")
+        .arg(clap::arg!(--files <COUNT> "Files in the synthetic partition")
+            .value_parser(clap::value_parser!(usize))
+            .default_value("10000"))
+        .arg(clap::arg!(--depth <DEPTH> "Most folders between a top-level folder and a file")
+            .value_parser(clap::value_parser!(usize))
+            .default_value("4"))
+        .arg(clap::arg!(--"name-len" <LEN> "Length of the random part of file and folder names")
+            .value_parser(clap::value_parser!(usize))
+            .default_value("12"))
+        .arg(clap::arg!(--seed <SEED> "Seed of the partition layout; the same seed gives the same tree")
+            .value_parser(clap::value_parser!(u64))
+            .default_value("1"))
+        .arg(clap::arg!(--runs <RUNS> "Number of timed runs")
+            .value_parser(clap::value_parser!(usize))
+            .default_value("5"))
+        .arg(clap::arg!(-t --threads <THREADS> "Number of parallel threads to use")
+            .value_parser(clap::value_parser!(usize))
+            .default_value("4")));
+    command
+}
+
+fn main() -> Result<()> {
+    let command = cli();
+    let matches = command.clone().try_get_matches().unwrap_or_else(|e| exit_with_env_hint(command, e));
+
+    match matches.subcommand() {
+        Some(("sort", sub_matches)) => return run_sort(sub_matches),
+        Some(("compile", sub_matches)) => return run_compile(sub_matches),
+        Some(("diff", sub_matches)) => return run_diff(sub_matches),
+        Some(("verify", sub_matches)) => return run_verify(sub_matches),
+        Some(("prune", sub_matches)) => return run_prune(sub_matches),
+        #[cfg(feature = "bench")]
+        Some(("bench", sub_matches)) => return run_bench(sub_matches),
+        Some(("undo", sub_matches)) => return run_undo(sub_matches),
+        _ => {}
+    }
+
+    let mut configs = Config::from_matches(&matches)?;
+    init_logging(configs[0].verbose, configs[0].silent);
+    if matches.get_flag("dump-config") {
+        for (index, config) in configs.iter().enumerate() {
+            if index > 0 {
+                println!();
+            }
+            print!("{}", config.to_toml()?);
+        }
+        return Ok(());
+    }
+    let interrupt = install_interrupt_handler()?;
+    for config in &mut configs {
+        config.interrupt = interrupt.clone();
+    }
+    let report_target = matches.get_one::<String>("report").map(|path| {
+        (PathBuf::from(path), *matches.get_one::<ReportFormat>("report-format").unwrap())
+    });
+
+    if let [config] = configs.as_slice() {
+        if config.check {
+            let missing = processor::check_file_contexts(config)?;
+            exit_if_interrupted(config);
+            if missing > 0 {
+                std::process::exit(2);
+            }
+            return Ok(());
+        }
+        let report = processor::process_file_contexts(config)?;
+        print_summary(config, &report);
+        if let Some((path, format)) = &report_target {
+            processor::write_report(path, &report, *format)?;
+        }
+        exit_if_interrupted(config);
+        return Ok(());
+    }
+    run_partitions(&configs, report_target)
+}
+
+/// The summary table on stdout, or the one-line totals on stderr with
+/// `--summary`, which `--quiet` does not silence.
+fn print_summary(config: &Config, report: &processor::Report) {
+    if config.summary {
+        eprintln!("{}", report.totals());
+    } else if !config.silent {
+        print!("{}", report.summary());
+    }
+}
+
+/// Prints a clap error, naming the environment variable an invalid value
+/// came from since clap only names the option.
+fn exit_with_env_hint(command: Command, error: clap::Error) -> ! {
+    if let Some(note) = env_hint(command, &error) {
+        let _ = error.print();
+        eprintln!("{}", note);
+        std::process::exit(error.exit_code());
+    }
+    error.exit()
+}
+
+/// The note naming the environment variable that held the value `error`
+/// rejects, when it did not come from the command line.
+fn env_hint(mut command: Command, error: &clap::Error) -> Option<String> {
+    use clap::error::{ContextKind, ContextValue, ErrorKind};
+
+    command.build();
+    if !matches!(error.kind(), ErrorKind::InvalidValue | ErrorKind::ValueValidation) {
+        return None;
+    }
+    let Some(ContextValue::String(arg)) = error.get(ContextKind::InvalidArg) else {
+        return None;
+    };
+    let Some(ContextValue::String(value)) = error.get(ContextKind::InvalidValue) else {
+        return None;
+    };
+    let var = command.get_arguments()
+        .find(|candidate| candidate.to_string() == *arg)
+        .and_then(|candidate| candidate.get_env())
+        .filter(|var| std::env::var_os(var).is_some_and(|env_value| {
+            env_value.to_string_lossy().split(',').any(|part| part == value)
+        }))?;
+    Some(format!("note: the value comes from the {} environment variable", var.to_string_lossy()))
+}
+
+/// The first Ctrl-C lets the run stop at the next path and finish writing;
+/// a second one exits right away.
+fn install_interrupt_handler() -> Result<processor::Interrupt> {
+    let interrupt = processor::Interrupt::new();
+    let handler = interrupt.clone();
+    ctrlc::set_handler(move || {
+        if handler.trigger() {
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+        Reporter::global().eprintln(format_args!("Interrupted, stopping after the current paths; press Ctrl-C again to quit now."));
+    })?;
+    Ok(interrupt)
+}
+
+fn exit_if_interrupted(config: &Config) {
+    if !config.interrupt.is_triggered() {
+        return;
+    }
+    let outcome = match (config.on_interrupt, config.dry_run) {
+        (processor::OnInterrupt::Discard, _) => "discarded",
+        (processor::OnInterrupt::Write, true) => "printed",
+        (processor::OnInterrupt::Write, false) => "written",
+    };
+    eprintln!("Run interrupted; the entries generated so far were {}.", outcome);
+    std::process::exit(EXIT_INTERRUPTED);
+}
+
+/// `RUST_LOG` takes precedence over `-v`, and `--quiet` only keeps errors.
+fn init_logging(verbose: u8, quiet: bool) {
+    let default_level = match (quiet, verbose) {
+        (true, _) => LevelFilter::ERROR,
+        (false, 0) => LevelFilter::ERROR,
+        (false, 1) => LevelFilter::INFO,
+        (false, 2) => LevelFilter::DEBUG,
+        (false, _) => LevelFilter::TRACE,
+    };
+    let filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) if !quiet => filter,
+        _ => EnvFilter::new(default_level.to_string()),
+    };
+
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_target(false)
+        .with_ansi(std::io::stderr().is_terminal())
+        .with_writer(Reporter::log_writer)
+        .init();
+}
+
+fn run_partitions(configs: &[Config], report_target: Option<(PathBuf, ReportFormat)>) -> Result<()> {
+    let mut failed = 0;
+    let mut missing = 0;
+    let mut reports = Vec::new();
+    let mut interrupted = None;
+
+    for config in configs {
+        if !config.silent {
+            println!("==> {} <==", config.extracted_dir.display());
+        }
+        let result = config.ensure_partition_exists().and_then(|()| {
+            if config.check {
+                processor::check_file_contexts(config).map(|count| missing += count)
+            } else {
+                processor::process_file_contexts(config).map(|report| {
+                    print_summary(config, &report);
+                    reports.push(report)
+                })
+            }
+        });
+        if let Err(e) = result {
+            if config.strict {
+                return Err(anyhow::Error::new(e).context(format!("Failed to process {:?}", config.extracted_dir)));
+            }
+            eprintln!("Error: failed to process {:?}: {:#}", config.extracted_dir, e);
+            failed += 1;
+        }
+        if config.interrupt.is_triggered() {
+            interrupted = Some(config);
+            break;
+        }
+    }
+
+    if let Some((path, format)) = &report_target {
+        processor::write_report(path, &reports, *format)?;
+    }
+    if let Some(config) = interrupted {
+        exit_if_interrupted(config);
+    }
+    if failed > 0 {
+        eprintln!("{} of {} partitions failed.", failed, configs.len());
+        std::process::exit(1);
+    }
+    if missing > 0 {
+        std::process::exit(2);
+    }
+    Ok(())
+}
+
+fn run_sort(matches: &ArgMatches) -> Result<()> {
+    let input = PathBuf::from(matches.get_one::<String>("FILE").unwrap());
+    let output = matches.get_one::<String>("output")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| input.clone());
+
+    let resolve = match matches.get_one::<contexts_file::Resolution>("resolve") {
+        Some(resolve) => Some(*resolve),
+        None if matches.get_flag("drop-shadowed") => Some(contexts_file::Resolution::KeepLast),
+        None => None,
+    };
+    let align = matches.get_one::<contexts_file::Align>("align").copied();
+    let summary = contexts_file::sort_contexts_file(&input, &output, resolve, align)?;
+
+    if !matches.get_flag("quiet") {
+        println!(
+            "Sorted {} entries into {:?} ({} duplicates removed, {} conflicting patterns, {} shadowed entries removed).",
+            summary.entries, output, summary.duplicates_removed, summary.conflicts, summary.shadowed_removed
+        );
+    }
+    Ok(())
+}
+
+fn run_compile(matches: &ArgMatches) -> Result<()> {
+    let inputs: Vec<PathBuf> = matches.get_many::<String>("FILE").unwrap().map(PathBuf::from).collect();
+    let output = matches.get_one::<String>("output").map(PathBuf::from).unwrap_or_else(|| {
+        let mut output = inputs[0].clone().into_os_string();
+        output.push(".bin");
+        PathBuf::from(output)
+    });
+    let pcre_version = matches.get_one::<String>("pcre-version").map(String::as_str);
+    let summary = contexts_file::compile_contexts_files(&inputs, &output, pcre_version)?;
+
+    if !matches.get_flag("quiet") {
+        println!(
+            "Compiled {} entries with {} stems into {:?} ({} duplicates removed).",
+            summary.entries, summary.stems, output, summary.duplicates_removed
+        );
+    }
+    Ok(())
+}
+
+fn run_diff(matches: &ArgMatches) -> Result<()> {
+    let a = PathBuf::from(matches.get_one::<String>("A").unwrap());
+    let b = PathBuf::from(matches.get_one::<String>("B").unwrap());
+    let diff = contexts_file::diff_contexts_files(&a, &b)?;
+
+    if matches.get_one::<String>("format").unwrap() == "json" {
+        println!("{}", serde_json::to_string_pretty(&diff)?);
+    } else {
+        for entry in &diff.only_in_a {
+            println!("- {}", entry.to_line());
+        }
+        for entry in &diff.only_in_b {
+            println!("+ {}", entry.to_line());
+        }
+        for entry in &diff.changed {
+            println!("~ {} {} -> {}", entry.pattern, entry.context_a, entry.context_b);
+        }
+    }
+
+    if !diff.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn run_verify(matches: &ArgMatches) -> Result<()> {
+    let extracted_dir = PathBuf::from(matches.get_one::<String>("PARTITION").unwrap());
+    let contexts: Vec<PathBuf> = matches.get_many::<String>("contexts")
+        .unwrap()
+        .map(PathBuf::from)
+        .collect();
+    let partition = match matches.get_one::<String>("partition-name") {
+        Some(name) => name.clone(),
+        None => normalize_partition_name(&extracted_dir.file_name().unwrap_or_default().to_string_lossy()),
+    };
+    if !extracted_dir.is_dir() {
+        return Err(anyhow::anyhow!("Partition directory does not exist: {:?}", extracted_dir));
+    }
+
+    let report = processor::verify_partition(&extracted_dir, &partition, &contexts)?;
+
+    if matches.get_one::<String>("format").unwrap() == "json" {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        for path in &report.unmatched {
+            println!("{}", path);
+        }
+        println!("{} of {} paths are not matched by any entry.", report.unmatched.len(), report.checked);
+    }
+
+    if !report.is_complete() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn run_prune(matches: &ArgMatches) -> Result<()> {
+    let extracted_dir = PathBuf::from(matches.get_one::<String>("PARTITION").unwrap());
+    let contexts = PathBuf::from(matches.get_one::<String>("contexts").unwrap());
+    let partition = match matches.get_one::<String>("partition-name") {
+        Some(name) => name.clone(),
+        None => normalize_partition_name(&extracted_dir.file_name().unwrap_or_default().to_string_lossy()),
+    };
+    if !extracted_dir.is_dir() {
+        return Err(anyhow::anyhow!("Partition directory does not exist: {:?}", extracted_dir));
+    }
+    let options = processor::PruneOptions {
+        aggressive: matches.get_flag("aggressive"),
+        all_entries: matches.get_flag("all-entries"),
+        apply: matches.get_flag("apply"),
+    };
+
+    let report = processor::prune_contexts(&extracted_dir, &partition, &contexts, options)?;
+
+    if matches.get_one::<String>("format").unwrap() == "json" {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        for stale in &report.stale {
+            println!("{}:{}: {}", contexts.display(), stale.line, stale.entry);
+        }
+        if report.applied {
+            println!("Removed {} of {} checked entries, which match nothing.", report.stale.len(), report.checked);
+        } else {
+            println!("{} of {} checked entries match nothing.", report.stale.len(), report.checked);
+        }
+        if report.runtime > 0 {
+            println!(
+                "{} recursive or wildcard entries were kept as they may match paths created at runtime, pass --aggressive to check them.",
+                report.runtime
+            );
+        }
+    }
+
+    if !report.applied && !report.stale.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+#[cfg(feature = "bench")]
+fn run_bench(matches: &ArgMatches) -> Result<()> {
+    use gen_file_contexts::bench::{self, TreeSpec};
+
+    let spec = TreeSpec {
+        files: *matches.get_one::<usize>("files").unwrap(),
+        depth: *matches.get_one::<usize>("depth").unwrap(),
+        name_len: *matches.get_one::<usize>("name-len").unwrap(),
+        seed: *matches.get_one::<u64>("seed").unwrap(),
+    };
+    let runs = *matches.get_one::<usize>("runs").unwrap();
+    let summary = bench::run_bench(&spec, runs, *matches.get_one::<usize>("threads").unwrap())?;
+
+    println!("{} paths, {} runs", summary.paths, summary.runs.len());
+    println!("min     {:>10.3}s", summary.min().as_secs_f64());
+    println!("median  {:>10.3}s", summary.median().as_secs_f64());
+    println!("max     {:>10.3}s", summary.max().as_secs_f64());
+    println!("{:.0} paths/s at the median", summary.throughput());
+    Ok(())
+}
+
+fn run_undo(matches: &ArgMatches) -> Result<()> {
+    let contexts = PathBuf::from(matches.get_one::<String>("FILE").unwrap());
+
+    if matches.get_flag("list") {
+        for run in contexts_file::Journal::load(&contexts)?.runs {
+            println!("{} partition={} timestamp={} lines={}", run.id, run.partition, run.timestamp, run.lines.len());
+        }
+        return Ok(());
+    }
+
+    let summary = contexts_file::undo_run(&contexts, matches.get_one::<u64>("run").copied())?;
+    for line in &summary.missing {
+        eprintln!("Warning: line from run {} no longer in {:?}: {}", summary.run.id, contexts, line);
+    }
+    println!(
+        "Undid run {} ({}): removed {} of {} lines.",
+        summary.run.id, summary.run.partition, summary.removed, summary.run.lines.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::error::ErrorKind;
+    use std::sync::{Mutex, PoisonError};
+    use tempfile::TempDir;
+
+    const ENV_VARS: [&str; 5] = ["FCGEN_PARTITION", "FCGEN_CONTEXTS", "FCGEN_FSTYPE", "FCGEN_THREADS", "FCGEN_MODE"];
+
+    /// Tests share the process environment, so parsing takes turns.
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    /// Parses `argv` with only the `env` FCGEN_* variables set; errors come
+    /// with the note naming the variable, if any.
+    fn parse<'a>(env: &[(&str, &str)], argv: impl IntoIterator<Item = &'a str>) -> Result<ArgMatches, (ErrorKind, Option<String>)> {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        // SAFETY: every test reading these variables holds the lock.
+        unsafe {
+            ENV_VARS.iter().for_each(|var| std::env::remove_var(var));
+            env.iter().for_each(|(var, value)| std::env::set_var(var, value));
+        }
+        let result = cli().try_get_matches_from(argv).map_err(|error| (error.kind(), env_hint(cli(), &error)));
+        unsafe {
+            ENV_VARS.iter().for_each(|var| std::env::remove_var(var));
+        }
+        result
+    }
+
+    /// Configs for `args` given after a temporary `-p vendor -c vendor_file_contexts`.
+    fn configs(args: &[&str]) -> gen_file_contexts::Result<Vec<Config>> {
+        let dir = TempDir::new().unwrap();
+        let partition = dir.path().join("vendor");
+        let contexts = dir.path().join("vendor_file_contexts");
+        std::fs::create_dir(&partition).unwrap();
+        std::fs::write(&contexts, "").unwrap();
+        let mut argv = vec!["gen_file-contexts", "-p", partition.to_str().unwrap(), "-c", contexts.to_str().unwrap()];
+        argv.extend(args);
+        Config::from_matches(&parse(&[], argv).unwrap())
+    }
+
+    fn parse_error(args: &[&str]) -> ErrorKind {
+        let argv = ["gen_file-contexts", "-p", "vendor", "-c", "vendor_file_contexts"].into_iter().chain(args.iter().copied());
+        parse(&[], argv).unwrap_err().0
+    }
+
+    #[test]
+    fn fstype_values_are_checked_while_parsing() {
+        assert_eq!(parse_error(&["-f", "ext5", "-m", "all"]), ErrorKind::InvalidValue);
+        let config = &configs(&["-f", "SquashFS", "-m", "all"]).unwrap()[0];
+        assert_eq!(config.fstype, FilesystemType::Squashfs);
+        assert_eq!(config.folder_pattern(), "");
+    }
+
+    #[test]
+    fn modes_are_repeatable_or_comma_separated() {
+        assert_eq!(configs(&["-f", "ext4", "-m", "bin,lib"]).unwrap()[0].modes, [Mode::Bin, Mode::Lib]);
+        assert_eq!(configs(&["-f", "ext4", "-m", "bin", "-m", "etc"]).unwrap()[0].modes, [Mode::Bin, Mode::Etc]);
+        assert_eq!(parse_error(&["-f", "ext4", "-m", "sbin"]), ErrorKind::InvalidValue);
+    }
+
+    #[test]
+    fn old_mode_flags_still_work() {
+        assert_eq!(configs(&["-f", "ext4", "-b"]).unwrap()[0].modes, [Mode::Bin]);
+        assert_eq!(configs(&["-f", "ext4", "-a"]).unwrap()[0].modes, [Mode::All]);
+        assert_eq!(parse_error(&["-f", "ext4", "-a", "-m", "bin"]), ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn prefix_conflicts_with_no_partition_prefix() {
+        assert_eq!(parse_error(&["-f", "ext4", "-m", "all", "--prefix", "/odm", "--no-partition-prefix"]), ErrorKind::ArgumentConflict);
+        let config = &configs(&["-f", "ext4", "-m", "all", "--no-partition-prefix"]).unwrap()[0];
+        assert_eq!(config.mount_point("vendor"), "");
+        let config = &configs(&["-f", "ext4", "-m", "all", "--prefix", "/odm/", "--partition-name", "product"]).unwrap()[0];
+        assert_eq!(config.prefix.as_deref(), Some("/odm"));
+    }
+
+    /// Configs for `--config` pointing at `content`, written next to a
+    /// `vendor` folder, followed by `args`, with the `env` variables set.
+    fn file_configs(content: &str, env: &[(&str, &str)], args: &[&str]) -> Vec<Config> {
+        let dir = TempDir::new().unwrap();
+        std::fs::create_dir(dir.path().join("vendor")).unwrap();
+        let path = dir.path().join("fc-gen.toml");
+        std::fs::write(&path, content).unwrap();
+        let mut argv = vec!["gen_file-contexts", "--config", path.to_str().unwrap()];
+        argv.extend(args);
+        Config::from_matches(&parse(env, argv).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn flags_override_config_file_values() {
+        let content = "mode = [\"bin\"]\nfstype = \"erofs\"\nthreads = 8\npartition = \"vendor\"\ncontexts = [\"vendor_file_contexts\"]\n";
+        let config = &file_configs(content, &[], &[])[0];
+        assert_eq!((config.modes.as_slice(), config.fstype, config.cores), (&[Mode::Bin][..], FilesystemType::Erofs, 8));
+
+        let config = &file_configs(content, &[], &["-t", "2", "-m", "lib", "-f", "ext4"])[0];
+        assert_eq!((config.modes.as_slice(), config.fstype, config.cores), (&[Mode::Lib][..], FilesystemType::Ext4, 2));
+    }
+
+    /// Threads, modes and type of the config, for comparing precedence.
+    fn settings(config: &Config) -> (usize, Vec<Mode>, FilesystemType) {
+        (config.cores, config.modes.clone(), config.fstype)
+    }
+
+    #[test]
+    fn environment_is_used_when_flags_are_absent() {
+        let dir = TempDir::new().unwrap();
+        let partition = dir.path().join("vendor");
+        let contexts = dir.path().join("vendor_file_contexts");
+        std::fs::create_dir(&partition).unwrap();
+        let env = [
+            ("FCGEN_PARTITION", partition.to_str().unwrap()),
+            ("FCGEN_CONTEXTS", contexts.to_str().unwrap()),
+            ("FCGEN_FSTYPE", "erofs"),
+            ("FCGEN_THREADS", "6"),
+            ("FCGEN_MODE", "lib,etc"),
+        ];
+
+        let config = &Config::from_matches(&parse(&env, ["gen_file-contexts"]).unwrap()).unwrap()[0];
+        assert_eq!(settings(config), (6, vec![Mode::Lib, Mode::Etc], FilesystemType::Erofs));
+        assert_eq!((config.extracted_dir.as_path(), config.file_contexts.as_path()), (partition.as_path(), contexts.as_path()));
+
+        let argv = ["gen_file-contexts", "-t", "2", "-m", "bin", "-f", "ext4"];
+        let config = &Config::from_matches(&parse(&env, argv).unwrap()).unwrap()[0];
+        assert_eq!(settings(config), (2, vec![Mode::Bin], FilesystemType::Ext4));
+
+        // Built-in defaults come last.
+        let env = [("FCGEN_PARTITION", env[0].1), ("FCGEN_CONTEXTS", env[1].1), ("FCGEN_FSTYPE", "ext4"), ("FCGEN_MODE", "all")];
+        let config = &Config::from_matches(&parse(&env, ["gen_file-contexts"]).unwrap()).unwrap()[0];
+        assert_eq!(config.cores, 4);
+    }
+
+    #[test]
+    fn precedence_is_flag_then_environment_then_config_file() {
+        let content = "mode = [\"bin\"]\nfstype = \"erofs\"\nthreads = 8\npartition = \"vendor\"\ncontexts = [\"vendor_file_contexts\"]\n";
+        let env = [("FCGEN_THREADS", "6"), ("FCGEN_MODE", "lib"), ("FCGEN_FSTYPE", "f2fs")];
+
+        assert_eq!(settings(&file_configs(content, &[], &[])[0]), (8, vec![Mode::Bin], FilesystemType::Erofs));
+        assert_eq!(settings(&file_configs(content, &env, &[])[0]), (6, vec![Mode::Lib], FilesystemType::F2fs));
+        let flags = ["-t", "2", "-m", "etc", "-f", "squashfs"];
+        assert_eq!(settings(&file_configs(content, &env, &flags)[0]), (2, vec![Mode::Etc], FilesystemType::Squashfs));
+    }
+
+    #[test]
+    fn invalid_environment_values_name_the_variable() {
+        let argv = ["gen_file-contexts", "-p", "vendor", "-c", "vendor_file_contexts", "-f", "ext4", "-m", "all"];
+        let (kind, note) = parse(&[("FCGEN_THREADS", "many")], argv).unwrap_err();
+        assert_eq!(kind, ErrorKind::ValueValidation);
+        assert_eq!(note.as_deref(), Some("note: the value comes from the FCGEN_THREADS environment variable"));
+
+        let (kind, note) = parse(&[("FCGEN_MODE", "bin,sbin")], argv[..7].iter().copied()).unwrap_err();
+        assert_eq!(kind, ErrorKind::InvalidValue);
+        assert!(note.is_some_and(|note| note.contains("FCGEN_MODE")));
 
-    let config = Config::from_matches(&matches)?;
-    processor::process_file_contexts(&config)
-}
\ No newline at end of file
+        // Values from the command line need no note.
+        let argv = ["gen_file-contexts", "-p", "vendor", "-c", "vendor_file_contexts", "-f", "ext4", "-m", "sbin"];
+        assert_eq!(parse(&[], argv).unwrap_err(), (ErrorKind::InvalidValue, None));
+    }
+}
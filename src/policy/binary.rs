@@ -0,0 +1,297 @@
+const POLICYDB_MAGIC: u32 = 0xF97C_FF8C;
+const POLICYDB_MODULE_MAGIC: u32 = 0xF97C_FF8D;
+const POLICYDB_STRING: &[u8] = b"SE Linux";
+
+const VERSION_MIN: u32 = 15;
+const VERSION_MAX: u32 = 33;
+const VERSION_VALIDATETRANS: u32 = 19;
+const VERSION_POLCAP: u32 = 22;
+const VERSION_PERMISSIVE: u32 = 23;
+const VERSION_BOUNDARY: u32 = 24;
+const VERSION_NEW_OBJECT_DEFAULTS: u32 = 27;
+const VERSION_DEFAULT_TYPE: u32 = 28;
+const VERSION_CONSTRAINT_NAMES: u32 = 29;
+
+/// Commons, classes and roles come before types.
+const SYM_TYPES: u32 = 3;
+const CEXPR_NAMES: u32 = 5;
+const TYPE_PROPERTY_ATTRIBUTE: u32 = 0x2;
+
+/// Whether `content` starts like a kernel or module policy.
+pub(crate) fn is_binary_policy(content: &[u8]) -> bool {
+    content.get(..4).is_some_and(|magic| {
+        let magic = u32::from_le_bytes([magic[0], magic[1], magic[2], magic[3]]);
+        magic == POLICYDB_MAGIC || magic == POLICYDB_MODULE_MAGIC
+    })
+}
+
+/// Names of the types and type aliases declared by a binary kernel policy
+/// such as `precompiled_sepolicy`, reading only as far as the types table.
+/// Attributes are left out since files cannot be labeled with them.
+pub(crate) fn read_policy_types(content: &[u8]) -> Result<Vec<String>, String> {
+    let mut reader = PolicyReader { content, offset: 0, version: 0 };
+    match reader.u32()? {
+        POLICYDB_MAGIC => {}
+        POLICYDB_MODULE_MAGIC => {
+            return Err("this is a policy module (.pp), pass the linked kernel policy instead".to_string());
+        }
+        _ => return Err("not a binary SELinux policy".to_string()),
+    }
+    let len = reader.u32()? as usize;
+    if reader.bytes(len)? != POLICYDB_STRING {
+        return Err("not a kernel policy".to_string());
+    }
+    let version = reader.u32()?;
+    if !(VERSION_MIN..=VERSION_MAX).contains(&version) {
+        return Err(format!(
+            "policy version {} is not supported, only versions {} to {}",
+            version, VERSION_MIN, VERSION_MAX
+        ));
+    }
+    reader.version = version;
+    // Config flags, then the number of symbol and object context tables.
+    reader.u32()?;
+    let sym_num = reader.u32()?;
+    reader.u32()?;
+    if sym_num <= SYM_TYPES {
+        return Err(format!("policy has only {} symbol tables", sym_num));
+    }
+    if version >= VERSION_POLCAP {
+        reader.ebitmap()?;
+    }
+    if version >= VERSION_PERMISSIVE {
+        reader.ebitmap()?;
+    }
+
+    for table in 0..SYM_TYPES {
+        let _primary_names = reader.u32()?;
+        let count = reader.u32()?;
+        for _ in 0..count {
+            match table {
+                0 => reader.common()?,
+                1 => reader.class()?,
+                _ => reader.role()?,
+            }
+        }
+    }
+
+    let _primary_names = reader.u32()?;
+    let count = reader.u32()?;
+    let mut types = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let (name, is_attribute) = reader.type_datum()?;
+        if !is_attribute {
+            types.push(name);
+        }
+    }
+    Ok(types)
+}
+
+struct PolicyReader<'a> {
+    content: &'a [u8],
+    offset: usize,
+    version: u32,
+}
+
+impl<'a> PolicyReader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self.offset.checked_add(len).filter(|end| *end <= self.content.len());
+        let Some(end) = end else {
+            return Err(format!("truncated at byte {}", self.offset));
+        };
+        let bytes = &self.content[self.offset..end];
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn name(&mut self, len: u32) -> Result<String, String> {
+        let offset = self.offset;
+        let bytes = self.bytes(len as usize)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| format!("invalid UTF-8 name at byte {}", offset))
+    }
+
+    /// Map unit, highest bit and node count, then a start bit and a 64-bit
+    /// map per node.
+    fn ebitmap(&mut self) -> Result<(), String> {
+        self.u32()?;
+        self.u32()?;
+        let nodes = self.u32()? as usize;
+        self.bytes(nodes.checked_mul(12).ok_or("corrupt bitmap")?)?;
+        Ok(())
+    }
+
+    fn permission(&mut self) -> Result<(), String> {
+        let len = self.u32()?;
+        self.u32()?;
+        self.bytes(len as usize)?;
+        Ok(())
+    }
+
+    fn common(&mut self) -> Result<(), String> {
+        let len = self.u32()?;
+        self.bytes(8)?;
+        let permissions = self.u32()?;
+        self.bytes(len as usize)?;
+        for _ in 0..permissions {
+            self.permission()?;
+        }
+        Ok(())
+    }
+
+    fn class(&mut self) -> Result<(), String> {
+        let len = self.u32()?;
+        let common_len = self.u32()?;
+        self.bytes(8)?;
+        let permissions = self.u32()?;
+        let constraints = self.u32()?;
+        self.bytes(len as usize)?;
+        self.bytes(common_len as usize)?;
+        for _ in 0..permissions {
+            self.permission()?;
+        }
+        self.constraints(constraints)?;
+        if self.version >= VERSION_VALIDATETRANS {
+            let validatetrans = self.u32()?;
+            self.constraints(validatetrans)?;
+        }
+        if self.version >= VERSION_NEW_OBJECT_DEFAULTS {
+            self.bytes(12)?;
+        }
+        if self.version >= VERSION_DEFAULT_TYPE {
+            self.u32()?;
+        }
+        Ok(())
+    }
+
+    fn constraints(&mut self, count: u32) -> Result<(), String> {
+        for _ in 0..count {
+            self.u32()?;
+            let expressions = self.u32()?;
+            for _ in 0..expressions {
+                let expression_type = self.u32()?;
+                self.bytes(8)?;
+                if expression_type == CEXPR_NAMES {
+                    self.ebitmap()?;
+                    if self.version >= VERSION_CONSTRAINT_NAMES {
+                        self.ebitmap()?;
+                        self.ebitmap()?;
+                        self.u32()?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn role(&mut self) -> Result<(), String> {
+        let len = self.u32()?;
+        self.bytes(if self.version >= VERSION_BOUNDARY { 8 } else { 4 })?;
+        self.bytes(len as usize)?;
+        self.ebitmap()?;
+        self.ebitmap()
+    }
+
+    /// The type's name and whether it is an attribute.
+    fn type_datum(&mut self) -> Result<(String, bool), String> {
+        let len = self.u32()?;
+        self.u32()?;
+        let is_attribute = if self.version >= VERSION_BOUNDARY {
+            let properties = self.u32()?;
+            self.u32()?;
+            properties & TYPE_PROPERTY_ATTRIBUTE != 0
+        } else {
+            self.u32()?;
+            false
+        };
+        Ok((self.name(len)?, is_attribute))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_u32(out: &mut Vec<u8>, value: u32) {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// An empty map: unit size, highest bit and no nodes.
+    fn push_ebitmap(out: &mut Vec<u8>) {
+        for value in [64, 0, 0] {
+            push_u32(out, value);
+        }
+    }
+
+    /// A kernel policy of `version` holding only `types`, each a name and
+    /// whether it is an attribute.
+    fn policy(version: u32, types: &[(&str, bool)]) -> Vec<u8> {
+        let mut out = Vec::new();
+        push_u32(&mut out, POLICYDB_MAGIC);
+        push_u32(&mut out, POLICYDB_STRING.len() as u32);
+        out.extend_from_slice(POLICYDB_STRING);
+        push_u32(&mut out, version);
+        // Config flags, symbol tables and object context tables.
+        for value in [0, 8, 9] {
+            push_u32(&mut out, value);
+        }
+        if version >= VERSION_POLCAP {
+            push_ebitmap(&mut out);
+        }
+        if version >= VERSION_PERMISSIVE {
+            push_ebitmap(&mut out);
+        }
+        // No commons, classes or roles.
+        for _ in 0..SYM_TYPES {
+            push_u32(&mut out, 0);
+            push_u32(&mut out, 0);
+        }
+        push_u32(&mut out, types.len() as u32);
+        push_u32(&mut out, types.len() as u32);
+        for (value, (name, is_attribute)) in types.iter().enumerate() {
+            push_u32(&mut out, name.len() as u32);
+            push_u32(&mut out, value as u32 + 1);
+            if version >= VERSION_BOUNDARY {
+                push_u32(&mut out, if *is_attribute { TYPE_PROPERTY_ATTRIBUTE } else { 1 });
+                push_u32(&mut out, 0);
+            } else {
+                push_u32(&mut out, 1);
+            }
+            out.extend_from_slice(name.as_bytes());
+        }
+        out
+    }
+
+    #[test]
+    fn types_are_read_and_attributes_left_out() {
+        let types = [("vendor_file", false), ("file_type", true), ("vendor_shell_exec", false)];
+        let content = policy(30, &types);
+        assert!(is_binary_policy(&content));
+        assert_eq!(read_policy_types(&content).unwrap(), ["vendor_file", "vendor_shell_exec"]);
+
+        // Before boundaries there are no attribute flags to read.
+        let content = policy(VERSION_MIN, &[("vendor_file", false)]);
+        assert_eq!(read_policy_types(&content).unwrap(), ["vendor_file"]);
+    }
+
+    #[test]
+    fn modules_and_unknown_versions_are_rejected() {
+        let mut content = policy(30, &[("vendor_file", false)]);
+        content[16..20].copy_from_slice(&(VERSION_MAX + 1).to_le_bytes());
+        let error = read_policy_types(&content).unwrap_err();
+        assert!(error.contains(&format!("version {}", VERSION_MAX + 1)), "{}", error);
+
+        content[..4].copy_from_slice(&POLICYDB_MODULE_MAGIC.to_le_bytes());
+        assert!(is_binary_policy(&content));
+        assert!(read_policy_types(&content).unwrap_err().contains("policy module"));
+
+        let content = policy(30, &[("vendor_file", false)]);
+        let error = read_policy_types(&content[..content.len() - 2]).unwrap_err();
+        assert!(error.starts_with("truncated at byte"), "{}", error);
+        assert!(!is_binary_policy(b"(type vendor_file)"));
+    }
+}
@@ -0,0 +1,121 @@
+use super::binary::{is_binary_policy, read_policy_types};
+use crate::error::{Error, Result};
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+/// Type names a device's policy declares, for `--valid-types`.
+#[derive(Debug, Clone, Default)]
+pub struct KnownTypes {
+    types: HashSet<String>,
+}
+
+impl KnownTypes {
+    /// Reads each file as a binary policy, CIL such as `plat_sepolicy.cil`
+    /// or a list of type names, and merges the types they declare.
+    pub fn load(paths: &[impl AsRef<Path>]) -> Result<Self> {
+        let mut types = HashSet::new();
+        for path in paths {
+            let path = path.as_ref();
+            let content = fs::read(path).map_err(|e| Error::read(path, e))?;
+            let declared = Self::parse(&content).map_err(|message| Error::invalid_file("types file", path, message))?;
+            if declared.is_empty() {
+                return Err(Error::invalid_file("types file", path, "declares no types"));
+            }
+            types.extend(declared);
+        }
+        Ok(Self { types })
+    }
+
+    fn parse(content: &[u8]) -> Result<Vec<String>, String> {
+        if is_binary_policy(content) {
+            return read_policy_types(content);
+        }
+        let content = std::str::from_utf8(content).map_err(|_| "neither a binary policy nor text".to_string())?;
+        let is_cil = content.lines().any(|line| line.trim_start().starts_with('('));
+        Ok(if is_cil { cil_types(content) } else { listed_types(content) })
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.types.contains(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.types.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.types.is_empty()
+    }
+}
+
+/// Names in `(type foo)` and `(typealias foo)` statements, one per line as
+/// the policy build writes them.
+fn cil_types(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let statement = line.trim().strip_prefix('(')?.strip_suffix(')')?;
+            let mut words = statement.split_whitespace();
+            let keyword = words.next()?;
+            let name = words.next()?;
+            (matches!(keyword, "type" | "typealias") && words.next().is_none()).then(|| name.to_string())
+        })
+        .collect()
+}
+
+/// Whitespace-separated names, with `#` starting a comment.
+fn listed_types(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .flat_map(|line| line.split('#').next().unwrap_or_default().split_whitespace())
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const CIL: &str = "\
+(type vendor_file)
+(roletype object_r vendor_file)
+(typeattribute file_type)
+(typeattributeset file_type (vendor_file))
+  (typealias hal_foo_exec)
+(typealiasactual hal_foo_exec vendor_file)
+(type)
+";
+
+    #[test]
+    fn cil_declarations_are_extracted() {
+        assert_eq!(KnownTypes::parse(CIL.as_bytes()).unwrap(), ["vendor_file", "hal_foo_exec"]);
+    }
+
+    #[test]
+    fn plain_lists_allow_comments_and_several_names_per_line() {
+        let list = "# from the device\nvendor_file vendor_configs_file\n\nvendor_shell_exec # used by init\n";
+        assert_eq!(KnownTypes::parse(list.as_bytes()).unwrap(), ["vendor_file", "vendor_configs_file", "vendor_shell_exec"]);
+        assert!(KnownTypes::parse(&[0xff, 0xfe, 0x00]).is_err());
+    }
+
+    #[test]
+    fn files_are_merged_and_must_declare_types() {
+        let dir = TempDir::new().unwrap();
+        let cil = dir.path().join("plat_sepolicy.cil");
+        let list = dir.path().join("types.txt");
+        let empty = dir.path().join("empty.cil");
+        fs::write(&cil, CIL).unwrap();
+        fs::write(&list, "vendor_configs_file\n").unwrap();
+        fs::write(&empty, "# no types yet\n").unwrap();
+
+        let known = KnownTypes::load(&[&cil, &list]).unwrap();
+        assert_eq!(known.len(), 3);
+        assert!(known.contains("vendor_configs_file") && known.contains("hal_foo_exec"));
+        assert!(!known.contains("file_type"));
+
+        let error = KnownTypes::load(&[&empty]).unwrap_err().to_string();
+        assert!(error.contains("declares no types"), "{}", error);
+    }
+}
@@ -0,0 +1,3 @@
+mod binary;
+mod known_types;
+pub use known_types::KnownTypes;
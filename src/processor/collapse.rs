@@ -0,0 +1,164 @@
+use super::context_processor::RECURSIVE_SUFFIX;
+use super::entry::ContextEntry;
+use crate::contexts_file::SecurityContext;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// Replaces the entries of folders whose whole contents share the folder's
+/// context with one recursive entry per folder.
+///
+/// `children` gives the number of entries a folder holds in the partition,
+/// or `None` when that is unknown. A folder with contents that got no
+/// entry here, such as paths already covered, excluded or unrepresentable,
+/// is never collapsed: the recursive entry is written after the existing
+/// ones and would relabel those paths too.
+pub fn collapse_entries(mut entries: Vec<ContextEntry>, children: impl Fn(&Path) -> Option<usize>) -> Vec<ContextEntry> {
+    entries.sort_by(|a, b| a.source.cmp(&b.source));
+
+    let dir_contexts: HashMap<&Path, &SecurityContext> = entries
+        .iter()
+        .filter(|entry| entry.is_dir)
+        .map(|entry| (entry.source.as_path(), &entry.context))
+        .collect();
+
+    let mut subtree_contexts: HashMap<&Path, Option<&SecurityContext>> = HashMap::new();
+    for entry in &entries {
+        for ancestor in entry.source.ancestors().skip(1) {
+            if ancestor.as_os_str().is_empty() {
+                break;
+            }
+            subtree_contexts
+                .entry(ancestor)
+                .and_modify(|context| {
+                    if *context != Some(&entry.context) {
+                        *context = None;
+                    }
+                })
+                .or_insert(Some(&entry.context));
+        }
+    }
+
+    // Folders are only complete when every path in them got an entry.
+    let sources: HashSet<&Path> = entries.iter().map(|entry| entry.source.as_path()).collect();
+    let mut generated_children: HashMap<&Path, usize> = HashMap::new();
+    for source in &sources {
+        if let Some(parent) = source.parent() {
+            *generated_children.entry(parent).or_default() += 1;
+        }
+    }
+    let mut incomplete: HashSet<&Path> = HashSet::new();
+    for dir in dir_contexts.keys() {
+        if children(dir) != Some(generated_children.get(dir).copied().unwrap_or(0)) {
+            incomplete.extend(dir.ancestors().take_while(|ancestor| !ancestor.as_os_str().is_empty()));
+        }
+    }
+
+    let collapsible: HashSet<PathBuf> = dir_contexts
+        .iter()
+        .filter(|(dir, context)| subtree_contexts.get(*dir) == Some(&Some(**context)) && !incomplete.contains(*dir))
+        .map(|(dir, _)| dir.to_path_buf())
+        .collect();
+
+    let mut collapsed: HashSet<PathBuf> = HashSet::new();
+    let mut results = Vec::with_capacity(entries.len());
+
+    for entry in &entries {
+        if entry.source.ancestors().skip(1).any(|ancestor| collapsed.contains(ancestor)) {
+            continue;
+        }
+        if collapsed.contains(&entry.source) {
+            continue;
+        }
+        if entry.is_dir && collapsible.contains(&entry.source) {
+            let base = entry.pattern.strip_suffix(RECURSIVE_SUFFIX).unwrap_or(&entry.pattern);
+            results.push(ContextEntry {
+                pattern: format!("{}{}", base, RECURSIVE_SUFFIX),
+                file_type: None,
+                ..entry.clone()
+            });
+            collapsed.insert(entry.source.clone());
+            continue;
+        }
+        results.push(entry.clone());
+    }
+
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::LabelReason;
+
+    fn entry(source: &str, is_dir: bool, label: &str) -> ContextEntry {
+        ContextEntry {
+            pattern: format!("/vendor/{}", source),
+            file_type: None,
+            context: format!("u:object_r:{}:s0", label).parse().unwrap(),
+            is_dir,
+            source: PathBuf::from(source),
+            reason: LabelReason::Heuristic("test"),
+        }
+    }
+
+    /// Child counts of a synthetic tree given as `(folder, entries)` pairs.
+    fn tree(counts: &[(&str, usize)]) -> impl Fn(&Path) -> Option<usize> {
+        let counts: HashMap<PathBuf, usize> = counts.iter().map(|(dir, count)| (PathBuf::from(dir), *count)).collect();
+        move |dir| counts.get(dir).copied()
+    }
+
+    fn patterns(entries: &[ContextEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.pattern.as_str()).collect()
+    }
+
+    #[test]
+    fn folder_with_one_label_collapses() {
+        let entries = vec![
+            entry("firmware", true, "vendor_firmware_file"),
+            entry("firmware/a.bin", false, "vendor_firmware_file"),
+            entry("firmware/b.bin", false, "vendor_firmware_file"),
+            entry("firmware/sub", true, "vendor_firmware_file"),
+            entry("firmware/sub/c.bin", false, "vendor_firmware_file"),
+        ];
+        let collapsed = collapse_entries(entries, tree(&[("firmware", 3), ("firmware/sub", 1)]));
+        assert_eq!(patterns(&collapsed), ["/vendor/firmware(/.*)?"]);
+    }
+
+    #[test]
+    fn differing_label_keeps_the_folder_apart() {
+        let entries = vec![
+            entry("etc", true, "vendor_configs_file"),
+            entry("etc/a.xml", false, "vendor_configs_file"),
+            entry("etc/b.sh", false, "vendor_file"),
+        ];
+        let collapsed = collapse_entries(entries, tree(&[("etc", 2)]));
+        assert_eq!(patterns(&collapsed), ["/vendor/etc", "/vendor/etc/a.xml", "/vendor/etc/b.sh"]);
+    }
+
+    #[test]
+    fn paths_without_entries_keep_the_folder_apart() {
+        // firmware/stock.bin is already covered by an existing entry.
+        let entries = vec![
+            entry("firmware", true, "vendor_firmware_file"),
+            entry("firmware/a.bin", false, "vendor_firmware_file"),
+        ];
+        let collapsed = collapse_entries(entries.clone(), tree(&[("firmware", 2)]));
+        assert_eq!(patterns(&collapsed), ["/vendor/firmware", "/vendor/firmware/a.bin"]);
+
+        let collapsed = collapse_entries(entries, tree(&[]));
+        assert_eq!(collapsed.len(), 2);
+    }
+
+    #[test]
+    fn incomplete_subfolder_blocks_its_parents_only() {
+        let entries = vec![
+            entry("app", true, "vendor_app_file"),
+            entry("app/Foo", true, "vendor_app_file"),
+            entry("app/Foo/Foo.apk", false, "vendor_app_file"),
+            entry("app/Bar", true, "vendor_app_file"),
+            entry("app/Bar/Bar.apk", false, "vendor_app_file"),
+        ];
+        let collapsed = collapse_entries(entries, tree(&[("app", 2), ("app/Foo", 1), ("app/Bar", 2)]));
+        assert_eq!(patterns(&collapsed), ["/vendor/app", "/vendor/app/Bar", "/vendor/app/Bar/Bar.apk", "/vendor/app/Foo(/.*)?"]);
+    }
+}
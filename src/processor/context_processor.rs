@@ -1,117 +1,1142 @@
-use crate::config::Config;
-use crate::progress::bar::ProgressTracker;
-use crate::utils::regex_utils::escape_regex;
-use anyhow::Result;
-use std::collections::HashSet;
-use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader, Write};
-use std::path::PathBuf;
+use crate::config::{normalize_partition_name, Config, LabelPolicy, Mode, PartitionKind};
+use crate::contexts_file::{
+    compile_contexts_files, find_conflicts, find_marked_block, is_compiled, lock_path, parse_compiled, parse_line,
+    record_run, replace_marked_block, report_conflicts, split_bom, strip_bom, Align, Alignment, ContextLine, ContextsLock,
+    FileStamp, FileType, LineEnding, SecurityContext,
+};
+use super::collapse::collapse_entries;
+use super::entry::{apply_hook, ContextEntry, EntryHook, HookAction, LabelReason};
+use super::filter::PathFilter;
+use super::heuristics::{EntryKind, Heuristics, SYSTEM_FIRMWARE};
+use super::interrupt::OnInterrupt;
+use super::matcher::ContextMatcher;
+use super::overrides::{Overrides, OVERRIDE_FILE_NAME};
+use super::path_list::PathList;
+use super::report::{Report, SkippedPath, ThreadTiming};
+use super::rules::RuleSet;
+use super::shadowing::{check_ordering, insert_sorted, report_ordering};
+use super::source_contexts::SourceContexts;
+use super::validate::validate_entries;
+use super::walk::{walk_partition, Excluded, WalkedPath};
+use crate::policy::KnownTypes;
+use crate::progress::bar::BarSink;
+use crate::progress::reporter::{errln, outln};
+use crate::progress::sink::{Phase, ProgressSink, ProgressTracker};
+use crate::utils::elf::{detect_elf, ElfKind};
+use crate::utils::fs_utils::{ends_with_newline, entry_path, pattern_path, slash_separated, write_atomic};
+use crate::utils::regex_utils::{escape_path, unescape_literal};
+use crate::utils::selinux_xattr::read_selinux_label;
+use crate::error::{Error, Result};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
-use walkdir::WalkDir;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
 
-pub fn process_file_contexts(config: &Config) -> Result<()> {
-    let partition_name = get_partition_name(&config.extracted_dir);
-    let existing_contexts = read_existing_contexts(&config.file_contexts)?;
-    let files_to_process = collect_files_to_process(config)?;
-    let total_files = files_to_process.len();
-    let missing_count = count_missing_entries(&files_to_process, config, &partition_name, &existing_contexts)?;
+const STREAM_FLUSH_INTERVAL: usize = 256;
+const WALK_QUEUE_LEN: usize = 1024;
+pub(super) const RECURSIVE_SUFFIX: &str = "(/.*)?";
 
-    if !config.silent {
-        let mode_str = match config.mode {
-            crate::config::Mode::All => "file_contexts",
-            crate::config::Mode::Bin => "/bin/ file_contexts",
-        };
-        
-        if missing_count == 0 {
-            println!("No missing entries found in {}.", mode_str);
-            println!();
-            return Ok(());
+pub fn check_file_contexts(config: &Config) -> Result<usize> {
+    let partition_name = resolve_partition_name(config);
+    report_partition_name(config, &partition_name);
+    let mount_point = config.mount_point(&partition_name);
+    let sink = progress_sink(config);
+    sink.start(&partition_name);
+    let existing_contexts = read_existing_contexts(&config.existing_contexts, config.ci_match)?;
+    let filter = PathFilter::new(config)?;
+    let listed = PathList::load(config)?;
+    let mut missing_entries = Vec::new();
+    let mut unrepresentable = Vec::new();
+    let mut non_utf8 = Vec::new();
+    let scanning = ProgressTracker::new(Arc::clone(&sink), Phase::Scanning, None);
+    let excluded = walk_partition(config, &filter, listed.as_ref(), |relative_path, file_type| {
+        scanning.increment();
+        if !config.lossy_paths && relative_path.to_str().is_none() {
+            non_utf8.push(relative_path.to_path_buf());
+        } else if is_missing(relative_path, Some(file_type), &mount_point, &existing_contexts, config.lossy_paths) {
+            // Generation skips these too, so they are not counted as missing.
+            if is_representable(relative_path) {
+                missing_entries.push(relative_path.to_path_buf());
+            } else {
+                unrepresentable.push(relative_path.to_path_buf());
+            }
+        }
+        ControlFlow::Continue(())
+    });
+    scanning.finish();
+    report_excluded(config, &excluded);
+    let skipped = walk_errors(config, excluded.errors)?;
+    fail_on_non_utf8(config, &non_utf8)?;
+    missing_entries.sort();
+    let root_missing = config.modes.contains(&Mode::All)
+        && !existing_contexts.is_match(&entry_path(&mount_point, ""), Some(FileType::Directory));
+    let missing_count = missing_entries.len() + usize::from(root_missing);
+    sink.finish(&partition_name);
+
+    if config.silent {
+        errln!("{}", missing_count);
+        report_whitespace(&mount_point, &unrepresentable);
+        report_non_utf8(&mount_point, &non_utf8);
+        report_skipped(&skipped, config.verbose);
+        return Ok(missing_count);
+    }
+
+    if root_missing {
+        outln!("{}", entry_path(&mount_point, ""));
+    }
+    for relative_path in &missing_entries {
+        outln!("{}", entry_path(&mount_point, &slash_separated(&relative_path.to_string_lossy())));
+    }
+    report_whitespace(&mount_point, &unrepresentable);
+    report_non_utf8(&mount_point, &non_utf8);
+    report_skipped(&skipped, config.verbose);
+
+    if missing_count == 0 {
+        outln!("No missing entries found in {}.", mode_description(config));
+    } else {
+        outln!("{} missing entries detected in {}.", missing_count, mode_description(config));
+    }
+
+    Ok(missing_count)
+}
+
+pub fn process_file_contexts(config: &Config) -> Result<Report> {
+    process_with_hook(config, |_| HookAction::Keep)
+}
+
+/// Like [`process_file_contexts`], with `hook` deciding whether each
+/// generated entry is kept, dropped or replaced before it is written.
+pub fn process_with_hook(config: &Config, hook: impl Fn(&ContextEntry) -> HookAction + Sync) -> Result<Report> {
+    let hook: EntryHook = &hook;
+    let _lock = config.lock.then(|| lock_contexts(config)).transpose()?;
+    // Taken before the existing entries are read, to notice other runs
+    // writing to the file meanwhile.
+    let stamp = FileStamp::of(&config.file_contexts).map_err(|e| Error::read(&config.file_contexts, e))?;
+    let report = if config.stream && !config.dry_run {
+        stream_missing_entries(config, hook, stamp)?
+    } else {
+        let (mut entries, mut report) = generate_entries(config, hook)?;
+
+        if report.interrupted && config.on_interrupt == OnInterrupt::Discard {
+            info!(entries = entries.len(), "discarding entries after interruption");
+        } else if config.dry_run {
+            let alignment = config.alignment(entries.iter().map(|entry| (entry.pattern.as_str(), entry.file_type)));
+            for entry in &entries {
+                if config.explain {
+                    outln!("{}", entry.explain());
+                } else {
+                    outln!("{}", alignment.line(&entry.pattern, entry.file_type, &entry.context.to_string()));
+                }
+            }
         } else {
-            println!("{} missing entries detected in {}, autogenerating...", missing_count, mode_str);
+            let partition = resolve_partition_name(config);
+            let _lock = lock_for_write(config)?;
+            if contexts_changed(config, stamp)? {
+                entries = drop_covered_entries(config, &partition, entries)?;
+                report.record_entries(&entries);
+            }
+            info!(path = %config.file_contexts.display(), entries = entries.len(), "writing entries");
+            if config.insert_sorted {
+                write_inserted_entries(config, &partition, &entries)?;
+            } else if config.markers {
+                write_marked_entries(config, &partition, &entries)?;
+            } else if !entries.is_empty() {
+                let alignment = config.alignment(entries.iter().map(|entry| (entry.pattern.as_str(), entry.file_type)));
+                let lines: Vec<String> = entries
+                    .iter()
+                    .map(|entry| alignment.line(&entry.pattern, entry.file_type, &entry.context.to_string()))
+                    .collect();
+                append_lines(&config.file_contexts, &lines)?;
+            }
+            record_journal(config, &partition, entries.iter().map(|entry| entry.to_string()).collect());
+            check_written_order(config, &partition, &entries)?;
+        }
+        report
+    };
+    if let Some(output) = &config.compile_output
+        && !report.interrupted
+    {
+        compile_contexts(config, output)?;
+    }
+    if config.explain && !config.dry_run {
+        for entry in &report.entries {
+            outln!("{}", entry.explain());
         }
     }
+    if !config.silent {
+        outln!();
+    }
+    progress_sink(config).finish(&report.partition);
 
-    let progress_tracker = ProgressTracker::new(total_files, !config.silent && missing_count > 0);
-    
-    let chunk_size = (total_files + config.cores - 1) / config.cores;
-    let chunks: Vec<Vec<PathBuf>> = files_to_process
-        .chunks(chunk_size)
-        .map(|chunk| chunk.to_vec())
+    Ok(report)
+}
+
+fn progress_sink(config: &Config) -> Arc<dyn ProgressSink> {
+    config.progress_sink
+        .clone()
+        .unwrap_or_else(|| Arc::new(BarSink::new(config.progress_mode())))
+}
+
+fn lock_contexts(config: &Config) -> Result<ContextsLock> {
+    let path = &config.file_contexts;
+    let timeout = config.wait_lock.map(Duration::from_secs);
+    ContextsLock::acquire(path, timeout, || {
+        if !config.silent {
+            outln!("Waiting for another run to release {:?}...", lock_path(path));
+        }
+    })
+    .map_err(|error| match (error.kind(), config.wait_lock) {
+        (ErrorKind::TimedOut, Some(seconds)) => Error::LockTimeout { path: lock_path(path), seconds },
+        _ => Error::Lock { path: lock_path(path), error },
+    })
+}
+
+/// Locks the contexts file for writing, unless `--lock` already holds it
+/// for the whole run. A lock file that cannot be created, e.g. in a
+/// read-only folder, only gets a warning.
+fn lock_for_write(config: &Config) -> Result<Option<ContextsLock>> {
+    if config.lock {
+        return Ok(None);
+    }
+    match lock_contexts(config) {
+        Ok(lock) => Ok(Some(lock)),
+        Err(Error::Lock { path, error }) => {
+            warn!(path = %path.display(), error = %error, "writing without a lock");
+            errln!("Warning: could not lock {:?}, writing without a lock: {}", path, error);
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether the contexts file changed since `stamp` was taken, warning when
+/// it did.
+fn contexts_changed(config: &Config, stamp: Option<FileStamp>) -> Result<bool> {
+    let path = &config.file_contexts;
+    let changed = FileStamp::of(path).map_err(|e| Error::read(path, e))? != stamp;
+    if changed {
+        warn!(path = %path.display(), "contexts file changed since it was read");
+        errln!("Warning: {:?} changed while entries were generated, probably by another run; re-reading it", path);
+    }
+    Ok(changed)
+}
+
+/// Re-reads the existing contexts and drops the entries for paths they now
+/// cover.
+fn drop_covered_entries(config: &Config, partition: &str, entries: Vec<ContextEntry>) -> Result<Vec<ContextEntry>> {
+    let existing_contexts = read_existing_contexts(&config.existing_contexts, config.ci_match)?;
+    let mount_point = config.mount_point(partition);
+    let before = entries.len();
+    let entries: Vec<ContextEntry> = entries
+        .into_iter()
+        .filter(|entry| match entry.source.as_os_str().is_empty() {
+            true => !existing_contexts.is_match(&entry_path(&mount_point, ""), Some(FileType::Directory)),
+            false => {
+                let file_type = entry.file_type.or(entry.is_dir.then_some(FileType::Directory));
+                is_missing(&entry.source, file_type, &mount_point, &existing_contexts, config.lossy_paths)
+            }
+        })
         .collect();
+    if entries.len() < before && !config.silent {
+        outln!("Dropped {} generated entries the other run already added.", before - entries.len());
+    }
+    Ok(entries)
+}
 
-    let (tx, rx) = std::sync::mpsc::channel();
-    
-    let handles: Vec<_> = chunks.into_iter().map(|chunk| {
-        let config = config.clone();
-        let partition = partition_name.clone();
-        let existing = existing_contexts.clone();
-        let progress = progress_tracker.clone();
-        let tx = tx.clone();
-
-        thread::spawn(move || -> Result<()> { 
-            let mut results = Vec::new();
-            process_chunk(chunk, &config, &partition, &existing, progress, &mut results)?;
-            tx.send(results).map_err(|e| anyhow::anyhow!("Channel send error: {}", e))?;
-            Ok(())
+pub fn write_entries(path: &Path, entries: &[ContextEntry]) -> Result<()> {
+    append_lines(path, &entries.iter().map(ToString::to_string).collect::<Vec<_>>())
+}
+
+fn append_lines(path: &Path, lines: &[String]) -> Result<()> {
+    let mut content = match fs::read(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(Error::read(path, e)),
+    };
+    let newline = LineEnding::detect(&content).as_str();
+    let needs_newline = !content.is_empty() && !content.ends_with(b"\n");
+    let original_len = content.len();
+
+    if needs_newline {
+        content.extend_from_slice(newline.as_bytes());
+    }
+    for line in lines {
+        content.extend_from_slice(line.as_bytes());
+        content.extend_from_slice(newline.as_bytes());
+    }
+
+    if let Err(e) = write_atomic(path, &content) {
+        errln!("Warning: {:#}, appending in place instead", e);
+        OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(&content[original_len..]))
+            .map_err(|e| Error::write(path, e))?;
+    }
+    Ok(())
+}
+
+/// Compiles the existing contexts files, then the one written to when it is
+/// not among them, so generated entries take precedence like in the text file.
+fn compile_contexts(config: &Config, output: &Path) -> Result<()> {
+    let mut inputs = config.existing_contexts.clone();
+    if !inputs.contains(&config.file_contexts) && config.file_contexts.exists() {
+        inputs.push(config.file_contexts.clone());
+    }
+    let summary = compile_contexts_files(&inputs, output, None)?;
+    info!(path = %output.display(), entries = summary.entries, stems = summary.stems, "compiled contexts");
+    Ok(())
+}
+
+fn record_journal(config: &Config, partition: &str, lines: Vec<String>) {
+    if lines.is_empty() {
+        return;
+    }
+    if let Err(e) = record_run(&config.file_contexts, partition, lines) {
+        errln!("Warning: could not record this run for undo: {:#}", e);
+    }
+}
+
+/// Merges `entries` into the partition's marked block, dropping previously
+/// generated entries whose source paths no longer exist.
+fn write_marked_entries(config: &Config, partition: &str, entries: &[ContextEntry]) -> Result<()> {
+    let path = &config.file_contexts;
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(Error::read(path, e)),
+    };
+    let lines: Vec<&str> = strip_bom(&content).lines().collect();
+    let block = find_marked_block(&lines, partition).map_err(|e| Error::invalid_file("markers in", path, e))?;
+
+    let mut kept = 0;
+    let mut stale = 0;
+    let mut merged: Vec<(String, Option<FileType>, String)> = Vec::new();
+    for (index, line) in lines[block.clone().unwrap_or_default()].iter().enumerate() {
+        if let ContextLine::Entry(entry) = parse_line(line, index + 1) {
+            if generated_source_exists(&entry.pattern, partition, config) {
+                kept += 1;
+                merged.push((entry.pattern, entry.file_type, entry.context));
+            } else {
+                stale += 1;
+            }
+        }
+    }
+    if block.is_none() && entries.is_empty() {
+        return Ok(());
+    }
+
+    merged.extend(entries.iter().map(|entry| (entry.pattern.clone(), entry.file_type, entry.context.to_string())));
+    if !config.no_sort {
+        let plain = Alignment::new(Align::None, None, []);
+        merged.sort_by_cached_key(|(pattern, file_type, context)| {
+            (pattern.clone(), plain.line(pattern, *file_type, context))
+        });
+    }
+    let alignment = config.alignment(merged.iter().map(|(pattern, file_type, _)| (pattern.as_str(), *file_type)));
+    let merged: Vec<String> = merged
+        .iter()
+        .map(|(pattern, file_type, context)| alignment.line(pattern, *file_type, context))
+        .collect();
+
+    let updated = replace_marked_block(&content, partition, &merged)?;
+    if let Err(e) = write_atomic(path, updated.as_bytes()) {
+        errln!("Warning: {:#}, rewriting in place instead", e);
+        fs::write(path, updated).map_err(|e| Error::write(path, e))?;
+    }
+
+    if stale > 0 && !config.silent {
+        outln!("Removed {} generated entries for paths that no longer exist ({} kept).", stale, kept);
+    }
+    Ok(())
+}
+
+/// Splices `entries` in after the existing entries matching their paths,
+/// without a marked block.
+fn write_inserted_entries(config: &Config, partition: &str, entries: &[ContextEntry]) -> Result<()> {
+    if entries.is_empty() {
+        return Ok(());
+    }
+    let path = &config.file_contexts;
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(Error::read(path, e)),
+    };
+    let newline = LineEnding::detect(content.as_bytes()).as_str();
+    let (bom, body) = split_bom(&content);
+    let lines: Vec<&str> = body.lines().collect();
+    let alignment = config.alignment(entries.iter().map(|entry| (entry.pattern.as_str(), entry.file_type)));
+    let output = insert_sorted(&lines, entries, &config.mount_point(partition), |entry| {
+        alignment.line(&entry.pattern, entry.file_type, &entry.context.to_string())
+    })?;
+
+    let mut updated = String::with_capacity(content.len() + entries.len() * 64);
+    updated.push_str(bom);
+    for line in output {
+        updated.push_str(&line);
+        updated.push_str(newline);
+    }
+    if let Err(e) = write_atomic(path, updated.as_bytes()) {
+        errln!("Warning: {:#}, rewriting in place instead", e);
+        fs::write(path, updated).map_err(|e| Error::write(path, e))?;
+    }
+    Ok(())
+}
+
+/// Warns about written entries whose order against the existing entries
+/// overlapping them changes labels unexpectedly where the last match
+/// applies.
+fn check_written_order(config: &Config, partition: &str, entries: &[ContextEntry]) -> Result<()> {
+    if entries.is_empty() {
+        return Ok(());
+    }
+    let path = &config.file_contexts;
+    let content = fs::read_to_string(path).map_err(|e| Error::read(path, e))?;
+    let lines: Vec<&str> = strip_bom(&content).lines().collect();
+    let ordering = check_ordering(&lines, entries, &config.mount_point(partition))?;
+    report_ordering(&ordering);
+    if ordering.overriding > 0 && !config.silent {
+        outln!(
+            "{} generated entries follow existing entries matching their paths with another label, and replace it where the last match applies.",
+            ordering.overriding
+        );
+    }
+    Ok(())
+}
+
+/// Generated patterns are escaped literals, optionally with the recursive
+/// folder suffix; anything else is kept since its source cannot be told.
+/// Entries are always kept when reading an image or archive.
+fn generated_source_exists(pattern: &str, partition: &str, config: &Config) -> bool {
+    if config.partition_is_file() {
+        return true;
+    }
+    let Some(rest) = pattern.strip_prefix(&config.mount_point(partition)) else {
+        return true;
+    };
+    let rest = rest.strip_suffix(RECURSIVE_SUFFIX).unwrap_or(rest);
+    if rest.is_empty() {
+        return true;
+    }
+    match unescape_literal(rest) {
+        Some(relative_path) => config.extracted_dir
+            .join(relative_path.trim_start_matches('/'))
+            .symlink_metadata()
+            .is_ok(),
+        None => true,
+    }
+}
+
+pub fn generate_missing_entries(config: &Config) -> Result<Vec<ContextEntry>> {
+    generate_entries(config, &|_| HookAction::Keep).map(|(entries, _)| entries)
+}
+
+/// The number of paths directly in a folder of the partition, for
+/// `--collapse`. Images and archives are counted from their listing;
+/// override files never get entries and are not counted.
+fn folder_children<'a>(config: &'a Config, scan: &PartitionScan) -> impl Fn(&Path) -> Option<usize> + 'a {
+    let listed: Option<HashMap<PathBuf, usize>> = scan.listed.as_ref().filter(|_| config.partition_is_file()).map(|listed| {
+        let mut counts = HashMap::new();
+        for path in listed.paths() {
+            if let Some(parent) = path.relative_path.parent() {
+                *counts.entry(parent.to_path_buf()).or_default() += 1;
+            }
+        }
+        counts
+    });
+    move |dir| match &listed {
+        Some(counts) => Some(counts.get(dir).copied().unwrap_or(0)),
+        None => fs::read_dir(config.extracted_dir.join(dir)).ok().map(|entries| {
+            entries.filter_map(|entry| entry.ok()).filter(|entry| entry.file_name() != OVERRIDE_FILE_NAME).count()
+        }),
+    }
+}
+
+fn generate_entries(config: &Config, hook: EntryHook) -> Result<(Vec<ContextEntry>, Report)> {
+    let started = Instant::now();
+    let (scan, mut report) = scan_partition(config)?;
+    if scan.missing_count() == 0 || config.interrupt.is_triggered() {
+        report_skipped(&report.skipped, config.verbose);
+        report.interrupted = config.interrupt.is_triggered();
+        report.record_elapsed(started.elapsed());
+        return Ok((Vec::new(), report));
+    }
+
+    let progress_tracker = ProgressTracker::new(progress_sink(config), Phase::Generating, Some(scan.missing));
+    let (tx, rx) = mpsc::channel();
+    let mut outcome = label_missing_entries(config, &scan, &progress_tracker, hook, tx)?;
+
+    let mut results: Vec<ContextEntry> = rx.into_iter().flatten().collect();
+    if config.collapse {
+        results = collapse_entries(results, folder_children(config, &scan));
+    }
+    if !config.no_sort {
+        results.sort_by(|a, b| a.pattern.cmp(&b.pattern));
+    }
+    if scan.root_missing {
+        let root = check_types(process_root(config, &scan)?, config, scan.types.as_ref())?;
+        let root = apply_hook(root, hook);
+        outcome.labeled.record(FileType::Directory, &root);
+        results.splice(0..0, root);
+    }
+    progress_tracker.finish();
+    report_unrepresentable(&scan);
+    report_skipped(&outcome.skipped, config.verbose);
+
+    // Entries about to be discarded are not worth validating.
+    let discarding = config.interrupt.is_triggered() && config.on_interrupt == OnInterrupt::Discard;
+    if config.validate && !discarding {
+        let failures = validate_entries(&results, &scan.mount_point);
+        for failure in &failures {
+            errln!("Invalid entry for {}: {} ({})", failure.path, failure.entry, failure.reason);
+        }
+        if !failures.is_empty() && !config.force {
+            return Err(Error::Validation(failures.len()));
+        }
+    }
+
+    report.record_entries(&results);
+    outcome.record(&mut report, config, started);
+    Ok((results, report))
+}
+
+fn stream_missing_entries(config: &Config, hook: EntryHook, stamp: Option<FileStamp>) -> Result<Report> {
+    let started = Instant::now();
+    let (scan, mut report) = scan_partition(config)?;
+    if scan.missing_count() == 0 || config.interrupt.is_triggered() {
+        report_skipped(&report.skipped, config.verbose);
+        report.interrupted = config.interrupt.is_triggered();
+        report.record_elapsed(started.elapsed());
+        return Ok(report);
+    }
+
+    let progress_tracker = ProgressTracker::new(progress_sink(config), Phase::Generating, Some(scan.missing));
+    let (tx, rx) = mpsc::channel::<Vec<ContextEntry>>();
+
+    info!(path = %config.file_contexts.display(), "streaming entries");
+    let _lock = lock_for_write(config)?;
+    // Streamed entries cannot be checked again, only warned about.
+    if contexts_changed(config, stamp)? && !config.lock {
+        errln!("Warning: streamed entries may repeat ones added meanwhile, pass --lock to make runs take turns");
+    }
+    let original_len = match fs::metadata(&config.file_contexts) {
+        Ok(metadata) => Some(metadata.len()),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => return Err(Error::read(&config.file_contexts, e)),
+    };
+    let writer = {
+        let path = config.file_contexts.clone();
+        let mount_point = scan.mount_point.clone();
+        let validate = config.validate;
+        let force = config.force;
+        let alignment = config.alignment([]);
+        thread::spawn(move || {
+            let mut written = Vec::new();
+            let result = write_stream(&path, rx, &mount_point, validate, force, alignment, &mut written);
+            (written, result)
         })
-    }).collect();
+    };
+
+    let mut root_stats = WorkerStats::default();
+    if scan.root_missing {
+        let root = check_types(process_root(config, &scan)?, config, scan.types.as_ref())?;
+        let root = apply_hook(root, hook);
+        root_stats.record(FileType::Directory, &root);
+        tx.send(root).map_err(|e| Error::Thread(format!("Channel send error: {}", e)))?;
+    }
+    let labeled = label_missing_entries(config, &scan, &progress_tracker, hook, tx);
+    let (written, result) = writer.join().map_err(|_| Error::Thread("Writer thread panicked".to_string()))?;
 
-    for handle in handles {
-        handle.join().unwrap()?;
+    progress_tracker.finish();
+    report_unrepresentable(&scan);
+    if config.interrupt.is_triggered() && config.on_interrupt == OnInterrupt::Discard {
+        discard_streamed(&config.file_contexts, original_len)?;
+    } else {
+        record_journal(config, &scan.partition_name, written.iter().map(|entry| entry.to_string()).collect());
     }
 
-    drop(tx);
+    result?;
+    let mut outcome = labeled?;
+    report_skipped(&outcome.skipped, config.verbose);
 
-    let mut file = OpenOptions::new()
+    report.record_entries(&written);
+    outcome.labeled = outcome.labeled.merge(root_stats);
+    outcome.record(&mut report, config, started);
+    Ok(report)
+}
+
+/// Takes back the entries streamed to `path` by cutting it to the length it
+/// had before, or removing it when it did not exist.
+fn discard_streamed(path: &Path, original_len: Option<u64>) -> Result<()> {
+    info!(path = %path.display(), "discarding streamed entries after interruption");
+    match original_len {
+        Some(len) => OpenOptions::new().write(true).open(path).and_then(|file| file.set_len(len)),
+        None => fs::remove_file(path),
+    }
+    .map_err(|e| Error::write(path, e))
+}
+
+fn write_stream(
+    path: &Path,
+    rx: mpsc::Receiver<Vec<ContextEntry>>,
+    mount_point: &str,
+    validate: bool,
+    force: bool,
+    alignment: Alignment,
+    written: &mut Vec<ContextEntry>,
+) -> Result<()> {
+    let write_error = |e| Error::write(path, e);
+    let needs_newline = !ends_with_newline(path).map_err(|e| Error::read(path, e))?;
+    let newline = LineEnding::of_file(path).map_err(|e| Error::read(path, e))?.as_str();
+    let file = OpenOptions::new()
         .append(true)
         .create(true)
-        .open(&config.file_contexts)?;
+        .open(path)
+        .map_err(write_error)?;
+    let mut writer = BufWriter::new(file);
+    let mut invalid = 0;
 
-    for result_batch in rx {
-        for line in result_batch {
-            writeln!(file, "{}", line)?;
-        }
+    if needs_newline {
+        writer.write_all(newline.as_bytes()).map_err(write_error)?;
     }
-    progress_tracker.finish();
-    if !config.silent && missing_count > 0 {
-        println!();
+
+    for (index, batch) in rx.into_iter().enumerate() {
+        if validate {
+            let failures = validate_entries(&batch, mount_point);
+            for failure in &failures {
+                errln!("Invalid entry for {}: {} ({})", failure.path, failure.entry, failure.reason);
+            }
+            invalid += failures.len();
+            if !failures.is_empty() && !force {
+                continue;
+            }
+        }
+        for entry in &batch {
+            let line = alignment.line(&entry.pattern, entry.file_type, &entry.context.to_string());
+            write!(writer, "{}{}", line, newline).map_err(write_error)?;
+        }
+        written.extend(batch);
+        if index % STREAM_FLUSH_INTERVAL == 0 {
+            writer.flush().map_err(write_error)?;
+        }
     }
+    writer.flush().map_err(write_error)?;
 
+    if invalid > 0 && !force {
+        return Err(Error::Validation(invalid));
+    }
     Ok(())
 }
 
-fn count_missing_entries(
-    files_to_process: &[PathBuf],
-    config: &Config,
-    partition: &str,
-    existing_contexts: &HashSet<String>,
-) -> Result<usize> {
-    let mut missing_count = 0;
-
-    for relative_path in files_to_process {
-        if let Some(path_str) = relative_path.to_str() {
-            if path_str.is_empty() {
-                continue;
+/// Counts from a first pass over the partition. Missing paths are not kept;
+/// labeling walks the partition again and streams them to the workers.
+struct PartitionScan {
+    partition_name: String,
+    /// Start of the entry paths, see [`Config::mount_point`].
+    mount_point: String,
+    rules: RuleSet,
+    source: SourceContexts,
+    overrides: Overrides,
+    filter: PathFilter,
+    listed: Option<PathList>,
+    types: Option<TypeCheck>,
+    existing_contexts: ContextMatcher,
+    missing: usize,
+    /// Paths the walk could not read, counted with the ones labeling fails on.
+    skipped: Vec<SkippedPath>,
+    unrepresentable: Vec<PathBuf>,
+    non_utf8: Vec<PathBuf>,
+    root_missing: bool,
+}
+
+/// Types declared by `--valid-types` and the context used instead of
+/// undeclared ones.
+struct TypeCheck {
+    known: KnownTypes,
+    fallback: SecurityContext,
+}
+
+impl TypeCheck {
+    fn load(config: &Config, partition: &str) -> Result<Option<Self>> {
+        if config.valid_types.is_empty() {
+            return Ok(None);
+        }
+        let known = KnownTypes::load(&config.valid_types)?;
+        let label = match &config.type_fallback {
+            Some(label) => label.as_str(),
+            None if config.partition_kind(partition).is_vendor_like() => config.labels.vendor_file.as_str(),
+            None => config.labels.system_file.as_str(),
+        };
+        let fallback = config.security_context(label)?;
+        if !known.contains(&fallback.r#type) {
+            return Err(Error::InvalidArgument(format!(
+                "Fallback type {:?} is not declared by --valid-types either, pass --type-fallback",
+                fallback.r#type
+            )));
+        }
+        info!(types = known.len(), fallback = %fallback, "loaded valid types");
+        Ok(Some(Self { known, fallback }))
+    }
+}
+
+/// Relabels entries whose type the policy does not declare with the
+/// fallback context, or fails with `--strict`.
+fn check_types(entries: Vec<ContextEntry>, config: &Config, types: Option<&TypeCheck>) -> Result<Vec<ContextEntry>> {
+    let Some(types) = types else {
+        return Ok(entries);
+    };
+    entries
+        .into_iter()
+        .map(|mut entry| {
+            if types.known.contains(&entry.context.r#type) {
+                return Ok(entry);
+            }
+            if config.strict {
+                return Err(Error::UndeclaredType(entry.context.r#type));
             }
-            
-            let escaped_path = escape_regex(path_str);
-            let full_context_path = format!("/{}/{} ", partition, escaped_path);
-            let folder_context_path = format!("/{}/{}{} ", partition, escaped_path, config.fstype.folder_pattern());
-            
-            let full_context_trimmed = full_context_path.trim().to_string();
-            let folder_context_trimmed = folder_context_path.trim().to_string();
-            
-            if !existing_contexts.contains(&full_context_trimmed) &&
-               !existing_contexts.contains(&folder_context_trimmed) {
-                missing_count += 1;
+            errln!(
+                "Warning: type {} is not declared by --valid-types, labeling {} as {}",
+                entry.context.r#type, entry.pattern, types.fallback
+            );
+            entry.context = types.fallback.clone();
+            entry.reason = LabelReason::Heuristic("fallback for undeclared type");
+            Ok(entry)
+        })
+        .collect()
+}
+
+impl PartitionScan {
+    fn missing_count(&self) -> usize {
+        self.missing + usize::from(self.root_missing)
+    }
+}
+
+fn scan_partition(config: &Config) -> Result<(PartitionScan, Report)> {
+    let partition_name = resolve_partition_name(config);
+    report_partition_name(config, &partition_name);
+    warn_unknown_partition_kind(config, &partition_name);
+    info!(partition = %partition_name, dir = %config.extracted_dir.display(), "scanning partition");
+    progress_sink(config).start(&partition_name);
+    let existing_contexts = read_existing_contexts(&config.existing_contexts, config.ci_match)?;
+    let rules = match &config.rules_file {
+        Some(path) => RuleSet::load(path)?,
+        None => RuleSet::default(),
+    };
+    let source = match &config.source_contexts {
+        Some(path) => SourceContexts::load(path)?,
+        None => SourceContexts::default(),
+    };
+    let filter = PathFilter::new(config)?;
+    let listed = PathList::load(config)?;
+    let types = TypeCheck::load(config, &partition_name)?;
+    let mount_point = config.mount_point(&partition_name);
+    let mut scanned = 0;
+    let mut missing = 0;
+    let mut unrepresentable = Vec::new();
+    let mut non_utf8 = Vec::new();
+    let scanning = ProgressTracker::new(progress_sink(config), Phase::Scanning, None);
+    let excluded = walk_partition(config, &filter, listed.as_ref(), |relative_path, file_type| {
+        if config.interrupt.is_triggered() {
+            return ControlFlow::Break(());
+        }
+        scanned += 1;
+        scanning.increment();
+        if !config.lossy_paths && relative_path.to_str().is_none() {
+            non_utf8.push(relative_path.to_path_buf());
+        } else if is_missing(relative_path, Some(file_type), &mount_point, &existing_contexts, config.lossy_paths) {
+            if is_representable(relative_path) {
+                missing += 1;
+            } else {
+                unrepresentable.push(relative_path.to_path_buf());
             }
         }
+        ControlFlow::Continue(())
+    });
+    scanning.finish();
+    report_excluded(config, &excluded);
+    let skipped = walk_errors(config, excluded.errors)?;
+    fail_on_non_utf8(config, &non_utf8)?;
+    let overrides = Overrides::load(config, &excluded.override_files)?;
+    if !overrides.is_empty() {
+        info!(files = overrides.len(), "read override files");
     }
-    
-    Ok(missing_count)
+    info!(paths = scanned, excluded = excluded.filtered, default_excluded = excluded.by_default, depth_limited = excluded.depth_limited, "collected paths");
+    let root_missing = config.modes.contains(&Mode::All)
+        && !existing_contexts.is_match(&entry_path(&mount_point, ""), Some(FileType::Directory));
+    let report = Report {
+        partition: partition_name.clone(),
+        contexts_file: config.file_contexts.clone(),
+        scanned,
+        covered: scanned - missing - unrepresentable.len() - non_utf8.len(),
+        excluded: excluded.filtered,
+        default_excluded: excluded.by_default,
+        depth_limited: excluded.depth_limited,
+        skipped: skipped.clone(),
+        unrepresentable: unrepresentable.clone(),
+        non_utf8: non_utf8.clone(),
+        ..Default::default()
+    };
+    let scan = PartitionScan {
+        partition_name,
+        mount_point,
+        rules,
+        source,
+        overrides,
+        filter,
+        listed,
+        types,
+        existing_contexts,
+        missing,
+        skipped,
+        unrepresentable,
+        non_utf8,
+        root_missing,
+    };
+
+    let mode_str = mode_description(config);
+    info!(missing = scan.missing_count(), covered = report.covered, "counted missing entries");
+
+    if config.interrupt.is_triggered() {
+        info!("interrupted while scanning");
+    } else if scan.missing_count() == 0 {
+        if !config.silent {
+            outln!("No missing entries found in {}.", mode_str);
+        }
+        report_unrepresentable(&scan);
+    } else if !config.silent {
+        outln!("{} missing entries detected in {}, autogenerating...", scan.missing_count(), mode_str);
+    }
+
+    Ok((scan, report))
 }
 
-fn get_partition_name(extracted_dir: &PathBuf) -> String {
+fn label_missing_entries(
+    config: &Config,
+    scan: &PartitionScan,
+    progress_tracker: &ProgressTracker,
+    hook: EntryHook,
+    tx: mpsc::Sender<Vec<ContextEntry>>,
+) -> Result<LabelOutcome> {
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(config.cores)
+        .build()
+        .map_err(|e| Error::Thread(format!("Failed to start worker threads: {}", e)))?;
+    let types = scan.types.as_ref();
+    let skipped = Mutex::new(scan.skipped.clone());
+    let workers = Mutex::new((0..config.cores).map(|_| WorkerStats::default()).collect::<Vec<_>>());
+    info!(paths = scan.missing, threads = config.cores, "generating entries");
+
+    // The walk feeds a bounded queue, so the workers start right away and
+    // only a bounded number of paths is held in memory at any time.
+    let (path_tx, path_rx) = mpsc::sync_channel::<WalkedPath>(WALK_QUEUE_LEN);
+    thread::scope(|scope| {
+        let producer = scope.spawn(move || {
+            walk_partition(config, &scan.filter, scan.listed.as_ref(), |relative_path, file_type| {
+                if config.interrupt.is_triggered() {
+                    return ControlFlow::Break(());
+                }
+                if !is_missing(relative_path, Some(file_type), &scan.mount_point, &scan.existing_contexts, config.lossy_paths)
+                    || !is_representable(relative_path)
+                {
+                    return ControlFlow::Continue(());
+                }
+                // A closed queue means labeling stopped early on an error.
+                match path_tx.send(WalkedPath::new(relative_path, file_type)) {
+                    Ok(()) => ControlFlow::Continue(()),
+                    Err(_) => ControlFlow::Break(()),
+                }
+            });
+        });
+
+        let labeled = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.install(|| {
+                path_rx
+                    .into_iter()
+                    .par_bridge()
+                    .try_for_each_with(tx, |tx, walked| -> Result<()> {
+                        // Paths already queued are dropped once interrupted.
+                        if config.interrupt.is_triggered() {
+                            return Ok(());
+                        }
+                        let started = Instant::now();
+                        let result = process_entry(&walked, config, scan)
+                            .and_then(|entries| check_types(entries, config, types))
+                            .map(|entries| apply_hook(entries, hook));
+                        if let Some(thread) = rayon::current_thread_index() {
+                            let stats = &mut workers.lock().unwrap()[thread];
+                            stats.paths += 1;
+                            stats.busy += started.elapsed();
+                            if let Ok(entries) = &result {
+                                stats.record(walked.file_type, entries);
+                            }
+                        }
+                        progress_tracker.increment();
+                        match result {
+                            Ok(entries) => {
+                                tx.send(entries).map_err(|e| Error::Thread(format!("Channel send error: {}", e)))
+                            }
+                            Err(e) if config.strict => {
+                                Err(Error::Label { path: walked.relative_path, error: Box::new(e) })
+                            }
+                            Err(e) => {
+                                warn!(path = %walked.relative_path.display(), error = format!("{:#}", e), "skipped path");
+                                skipped.lock().unwrap().push(SkippedPath {
+                                    path: walked.relative_path,
+                                    reason: format!("{:#}", e),
+                                });
+                                Ok(())
+                            }
+                        }
+                    })
+            })
+        }))
+        .map_err(|_| Error::Thread("A worker thread panicked while labeling entries".to_string()));
+
+        producer.join().map_err(|_| Error::Thread("The directory walk panicked".to_string()))?;
+        labeled?
+    })?;
+
+    let workers = workers.into_inner().unwrap();
+    let threads = workers
+        .iter()
+        .enumerate()
+        .map(|(thread, stats)| ThreadTiming {
+            thread,
+            paths: stats.paths,
+            busy_ms: stats.busy.as_millis() as u64,
+        })
+        .collect();
+    let labeled = workers.into_iter().fold(WorkerStats::default(), WorkerStats::merge);
+
+    Ok(LabelOutcome {
+        skipped: skipped.into_inner().unwrap(),
+        threads,
+        labeled,
+    })
+}
+
+struct LabelOutcome {
+    skipped: Vec<SkippedPath>,
+    threads: Vec<ThreadTiming>,
+    labeled: WorkerStats,
+}
+
+impl LabelOutcome {
+    fn record(self, report: &mut Report, config: &Config, started: Instant) {
+        let labeled = self.labeled;
+        warn_system_firmware(config, &report.partition, labeled.system_firmware);
+        report.interrupted = config.interrupt.is_triggered();
+        report.skipped = self.skipped;
+        report.threads = self.threads;
+        report.files = labeled.files;
+        report.directories = labeled.directories;
+        report.special_files = labeled.special_files;
+        report.types = labeled.types.into_iter().collect();
+        report.record_elapsed(started.elapsed());
+    }
+}
+
+/// What one labeling thread did, kept apart from the other threads until
+/// labeling ends.
+#[derive(Debug, Default)]
+struct WorkerStats {
+    paths: usize,
+    busy: Duration,
+    files: usize,
+    directories: usize,
+    special_files: usize,
+    /// Paths below etc/firmware/ on system-like partitions labeled by the
+    /// heuristics.
+    system_firmware: usize,
+    /// Entries per SELinux type.
+    types: HashMap<String, usize>,
+}
+
+impl WorkerStats {
+    /// Counts a labeled path; paths whose entries were all dropped by the
+    /// hook are not counted.
+    fn record(&mut self, file_type: FileType, entries: &[ContextEntry]) {
+        if entries.is_empty() {
+            return;
+        }
+        match file_type {
+            FileType::Regular | FileType::Symlink => self.files += 1,
+            FileType::Directory => self.directories += 1,
+            FileType::CharDevice | FileType::BlockDevice | FileType::Socket | FileType::Pipe => self.special_files += 1,
+        }
+        if entries[0].reason == LabelReason::Heuristic(SYSTEM_FIRMWARE) {
+            self.system_firmware += 1;
+        }
+        for entry in entries {
+            *self.types.entry(entry.context.r#type.clone()).or_default() += 1;
+        }
+    }
+
+    fn merge(mut self, other: Self) -> Self {
+        self.paths += other.paths;
+        self.busy += other.busy;
+        self.files += other.files;
+        self.directories += other.directories;
+        self.special_files += other.special_files;
+        self.system_firmware += other.system_firmware;
+        for (label, count) in other.types {
+            *self.types.entry(label).or_default() += count;
+        }
+        self
+    }
+}
+
+/// Paths the walk could not read, to skip and report, or the first of them
+/// with `--strict`.
+fn walk_errors(config: &Config, errors: Vec<(PathBuf, io::Error)>) -> Result<Vec<SkippedPath>> {
+    let mut skipped = Vec::new();
+    for (path, error) in errors {
+        if config.strict {
+            return Err(Error::read(config.extracted_dir.join(path), error));
+        }
+        warn!(path = %path.display(), error = %error, "skipped path");
+        skipped.push(SkippedPath { path, reason: error.to_string() });
+    }
+    Ok(skipped)
+}
+
+fn report_skipped(skipped: &[SkippedPath], verbose: u8) {
+    if skipped.is_empty() {
+        return;
+    }
+    if verbose > 0 {
+        errln!("{} paths skipped due to errors", skipped.len());
+    } else {
+        errln!("{} paths skipped due to errors, run with -v for details", skipped.len());
+    }
+}
+
+/// Firmware on system-like partitions is a device-specific choice, so the
+/// default label is pointed out once per partition until one is set.
+fn warn_system_firmware(config: &Config, partition: &str, paths: usize) {
+    if paths == 0 || config.labels.system_firmware != LabelPolicy::default().system_firmware {
+        return;
+    }
+    errln!(
+        "Warning: labeled {} paths below {}/etc/firmware/ as {}; review whether they need vendor_firmware_file, \
+         and set system-firmware in --labels or add a --rules entry to choose.",
+        paths,
+        config.mount_point(partition),
+        config.labels.system_firmware
+    );
+}
+
+fn report_unrepresentable(scan: &PartitionScan) {
+    report_non_utf8(&scan.mount_point, &scan.non_utf8);
+    report_whitespace(&scan.mount_point, &scan.unrepresentable);
+}
+
+fn report_whitespace(mount_point: &str, paths: &[PathBuf]) {
+    if paths.is_empty() {
+        return;
+    }
+    errln!(
+        "Warning: skipped {} paths containing whitespace, which file_contexts cannot represent:",
+        paths.len()
+    );
+    for path in paths {
+        errln!("  {}", entry_path(mount_point, &path.display().to_string()));
+    }
+}
+
+fn report_non_utf8(mount_point: &str, paths: &[PathBuf]) {
+    if paths.is_empty() {
+        return;
+    }
+    errln!(
+        "Warning: skipped {} paths whose names are not valid UTF-8, pass --lossy-paths to label them:",
+        paths.len()
+    );
+    for path in paths {
+        errln!("  {}", entry_path(mount_point, &slash_separated(&path.to_string_lossy())));
+    }
+}
+
+/// With `--fail-on-invalid-path`, names that are not valid UTF-8 abort
+/// the run instead of being skipped.
+fn fail_on_non_utf8(config: &Config, paths: &[PathBuf]) -> Result<()> {
+    match paths.first() {
+        Some(example) if config.fail_on_invalid_path => {
+            Err(Error::NonUtf8Paths { count: paths.len(), example: example.clone() })
+        }
+        _ => Ok(()),
+    }
+}
+
+fn mode_description(config: &Config) -> String {
+    if config.modes.contains(&Mode::All) {
+        return "file_contexts".to_string();
+    }
+    let dirs: Vec<String> = config.modes
+        .iter()
+        .map(|mode| format!("/{}/", mode.top_level_dirs()[0]))
+        .collect();
+    format!("{} file_contexts", dirs.join(", "))
+}
+
+fn resolve_partition_name(config: &Config) -> String {
+    match (&config.prefix, &config.partition_name) {
+        (Some(prefix), _) => prefix.trim_start_matches('/').to_string(),
+        (None, Some(name)) => name.clone(),
+        (None, None) => normalize_partition_name(&get_partition_name(&config.extracted_dir)),
+    }
+}
+
+fn report_partition_name(config: &Config, partition_name: &str) {
+    let folder_name = get_partition_name(&config.extracted_dir);
+    if config.prefix.is_none() && config.partition_name.is_none() && partition_name != folder_name && !config.silent {
+        outln!(
+            "Using partition name \"{}\" for folder \"{}\", pass --partition-name to override.",
+            partition_name, folder_name
+        );
+    }
+}
+
+/// OEM partitions such as `mi_ext` or `my_engineering` fall back to system
+/// labels, which is wrong for the vendor-like ones.
+fn warn_unknown_partition_kind(config: &Config, partition_name: &str) {
+    if config.treat_as.is_none() && PartitionKind::recognize(partition_name).is_none() {
+        errln!(
+            "Warning: \"{}\" is not a known partition name, labeling it as a system partition; pass --treat-as to choose.",
+            partition_name
+        );
+    }
+}
+
+/// Whether no existing entry labels the path, honoring the file-type field
+/// of typed entries such as `-d` or `--`.
+fn is_missing(
+    relative_path: &Path,
+    file_type: Option<FileType>,
+    mount_point: &str,
+    existing_contexts: &ContextMatcher,
+    lossy: bool,
+) -> bool {
+    pattern_path(relative_path, lossy).is_some_and(|path_str| {
+        !path_str.is_empty() && !existing_contexts.is_match(&entry_path(mount_point, &path_str), file_type)
+    })
+}
+
+fn is_representable(relative_path: &Path) -> bool {
+    !relative_path.to_string_lossy().contains(char::is_whitespace)
+}
+
+fn get_partition_name(extracted_dir: &Path) -> String {
     extracted_dir
         .file_name()
         .and_then(|n| n.to_str())
@@ -119,156 +1144,813 @@ fn get_partition_name(extracted_dir: &PathBuf) -> String {
         .to_string()
 }
 
-fn read_existing_contexts(path: &PathBuf) -> Result<HashSet<String>> {
-    let mut contexts = HashSet::new();
+fn read_existing_contexts(paths: &[PathBuf], case_insensitive: bool) -> Result<ContextMatcher> {
+    let mut entries = Vec::new();
     
-    if let Ok(file) = File::open(path) {
-        for line in BufReader::new(file).lines() {
-            if let Ok(line) = line {
-                if let Some(path_part) = line.split_whitespace().next() {
-                    contexts.insert(path_part.to_string());
+    for (index, path) in paths.iter().enumerate() {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) => {
+                if index > 0 {
+                    errln!("Warning: could not read contexts file {:?}: {}", path, e);
                 }
+                continue;
             }
+        };
+        let mut reader = BufReader::new(file);
+        if reader.fill_buf().is_ok_and(is_compiled) {
+            let mut content = Vec::new();
+            reader.read_to_end(&mut content).map_err(|e| Error::read(path, e))?;
+            let compiled = parse_compiled(&content)
+                .map_err(|message| Error::invalid_file("compiled contexts file", path, message))?;
+            entries.extend(compiled.into_iter().map(|entry| (path.as_path(), entry)));
+            continue;
         }
+        for (index, line) in reader.lines().map_while(Result::ok).enumerate() {
+            // Windows editors leave a byte order mark and CRLF line endings.
+            let line = if index == 0 { strip_bom(&line) } else { &line };
+            if let ContextLine::Entry(entry) = parse_line(line.trim_end_matches('\r'), index + 1) {
+                entries.push((path.as_path(), entry));
+            }
+        }
+    }
+
+    report_conflicts(&find_conflicts(entries.iter().map(|(path, entry)| (*path, entry))));
+    let entries = entries.iter().map(|(_, entry)| (entry.pattern.as_str(), entry.file_type));
+    if !case_insensitive {
+        return ContextMatcher::new(entries);
     }
-    Ok(contexts)
+    let matcher = ContextMatcher::new_case_insensitive(entries)?;
+    report_case_collisions(matcher.case_collisions());
+    Ok(matcher)
 }
 
-fn collect_files_to_process(config: &Config) -> Result<Vec<PathBuf>> {
-    let mut files = Vec::new();
-    
-    for entry in WalkDir::new(&config.extracted_dir)
-        .min_depth(1)
-        .into_iter()
-        .filter_map(|e| e.ok())
-    {
-        let path = entry.path();
-        
-        if let Ok(relative_path) = path.strip_prefix(&config.extracted_dir) {
-            if relative_path.as_os_str().is_empty() {
-                continue;
-            }
-            match config.mode {
-                crate::config::Mode::Bin => {
-                    if let Some(path_str) = relative_path.to_str() {
-                        if path_str.contains("/bin/") {
-                            files.push(relative_path.to_path_buf());
-                        }
-                    }
-                }
-                crate::config::Mode::All => {
-                    files.push(relative_path.to_path_buf());
-                }
-            }
+fn report_case_collisions(collisions: &[Vec<String>]) {
+    if collisions.is_empty() {
+        return;
+    }
+    errln!(
+        "Warning: {} groups of paths in the existing contexts differ only in case; a case-insensitive extraction kept one of each:",
+        collisions.len()
+    );
+    for spellings in collisions {
+        errln!("  {}", spellings.join(", "));
+    }
+}
+
+fn report_excluded(config: &Config, excluded: &Excluded) {
+    for (link, parent) in &excluded.symlink_loops {
+        let parent = match parent.as_os_str().is_empty() {
+            true => "the partition root".to_string(),
+            false => format!("{:?}", parent),
+        };
+        errln!("Warning: not following {:?}, a symlink loop back to {}; labeling it as a symlink.", link, parent);
+    }
+    if config.silent {
+        return;
+    }
+    if excluded.filtered > 0 {
+        outln!("Excluded {} paths matching exclude or ignore patterns.", excluded.filtered);
+    }
+    if excluded.by_default > 0 {
+        outln!(
+            "Skipped {} extraction leftovers such as lost+found, pass --no-default-excludes to process them.",
+            excluded.by_default
+        );
+    }
+    if excluded.depth_limited > 0 {
+        outln!(
+            "Did not walk the contents of {} directories at --max-depth {}.",
+            excluded.depth_limited,
+            config.max_depth.unwrap_or_default()
+        );
+    }
+}
+
+fn process_entry(walked: &WalkedPath, config: &Config, scan: &PartitionScan) -> Result<Vec<ContextEntry>> {
+    let relative_path = walked.relative_path.as_path();
+    let Some(path_str) = pattern_path(relative_path, config.lossy_paths) else {
+        return Ok(Vec::new());
+    };
+    let escaped_path = escape_path(&path_str);
+    let entry = EntryKind::from_file_type(walked.file_type);
+    let (context, reason) = choose_label(relative_path, &escaped_path, entry, config, scan)?;
+    let pattern = entry_path(&scan.mount_point, &escaped_path);
+
+    if walked.is_file_like() {
+        Ok(vec![ContextEntry {
+            pattern,
+            file_type: (config.emit_type_field && walked.file_type != FileType::Regular).then_some(walked.file_type),
+            context,
+            is_dir: false,
+            source: relative_path.to_path_buf(),
+            reason,
+        }])
+    } else {
+        // Existing entries for paths below stay in force only if the folder
+        // pattern written after them does not cover them too.
+        let recursive = !scan.existing_contexts.has_entries_below(&entry_path(&scan.mount_point, &path_str));
+        Ok(directory_entries(pattern, context, relative_path, reason, config, recursive))
+    }
+}
+
+/// The context of one path: the first matching rule, then the source
+/// contexts, the stored attribute, the nearest override file and finally
+/// the heuristics. Rules never apply to the root itself.
+fn choose_label(
+    relative_path: &Path,
+    escaped_path: &str,
+    entry: EntryKind,
+    config: &Config,
+    scan: &PartitionScan,
+) -> Result<(SecurityContext, LabelReason)> {
+    let partition = scan.partition_name.as_str();
+    let is_root = relative_path.as_os_str().is_empty();
+    let stored = stored_label(config, relative_path);
+    let heuristic;
+
+    let (context, reason) = match () {
+        _ if !is_root && let Some((context, line)) = scan.rules.lookup(relative_path, entry == EntryKind::Dir) => {
+            (context, LabelReason::Rule(line))
+        }
+        _ if let Some((context, line)) = scan.source.lookup(&scan.mount_point, relative_path) => (context, LabelReason::Source(line)),
+        _ if let Some(label) = &stored => (label.as_str(), LabelReason::Xattr),
+        _ if let Some(context) = scan.overrides.lookup(relative_path) => (context, LabelReason::Override),
+        _ => {
+            let heuristics = Heuristics {
+                labels: &config.labels,
+                extensions: scan.rules.extensions(),
+                kind: config.partition_kind(partition),
+                legacy: config.legacy_matching,
+                nested_bin: config.nested_bin,
+            };
+            heuristic = heuristics.label(relative_path, escaped_path, entry, || {
+                config.inspect_content.then(|| inspect_elf(&config.extracted_dir.join(relative_path))).flatten()
+            });
+            (heuristic.0.as_ref(), LabelReason::Heuristic(heuristic.1))
+        }
+    };
+    debug!(path = %relative_path.display(), ?entry, %reason, context, "labeled path");
+    Ok((config.security_context(context)?, reason))
+}
+
+fn inspect_elf(full_path: &Path) -> Option<ElfKind> {
+    match detect_elf(full_path) {
+        Ok(kind) => kind,
+        Err(e) => {
+            errln!("Warning: could not inspect {:?}, using path heuristics: {}", full_path, e);
+            None
+        }
+    }
+}
+
+/// The `security.selinux` label of the extracted path with `--from-xattr`.
+/// Missing, unreadable and malformed attributes leave the path to the
+/// heuristics.
+fn stored_label(config: &Config, relative_path: &Path) -> Option<String> {
+    if !config.from_xattr {
+        return None;
+    }
+    let full_path = config.extracted_dir.join(relative_path);
+    match read_selinux_label(&full_path) {
+        Ok(Some(label)) if label.parse::<SecurityContext>().is_ok() => Some(label),
+        Ok(Some(label)) => {
+            debug!(path = %full_path.display(), label, "ignoring malformed security.selinux attribute");
+            None
+        }
+        Ok(None) => None,
+        Err(e) => {
+            debug!(path = %full_path.display(), error = %e, "could not read security.selinux attribute");
+            None
         }
     }
-    Ok(files)
 }
 
-fn process_chunk(
-    chunk: Vec<PathBuf>,
+/// The root only gets its exact pattern: generated entries are written
+/// after the existing ones, so a recursive root pattern would take every
+/// path below it from the entries before it where the last match applies.
+fn process_root(config: &Config, scan: &PartitionScan) -> Result<Vec<ContextEntry>> {
+    let (context, reason) = choose_label(Path::new(""), "", EntryKind::Dir, config, scan)?;
+    Ok(directory_entries(entry_path(&scan.mount_point, ""), context, Path::new(""), reason, config, false))
+}
+
+/// The entries of a directory whose own pattern is `pattern`: the exact
+/// pattern unless the folder pattern is empty, then the folder pattern.
+/// Without `recursive` only the exact pattern is written.
+fn directory_entries(
+    pattern: String,
+    context: SecurityContext,
+    relative_path: &Path,
+    reason: LabelReason,
     config: &Config,
-    partition: &str,
-    existing_contexts: &HashSet<String>,
-    progress: ProgressTracker,
-    results: &mut Vec<String>,
-) -> Result<()> {
-    for relative_path in chunk {
-        let full_path = config.extracted_dir.join(&relative_path);
-        
-        if let Some(path_str) = relative_path.to_str() {
-            if path_str.is_empty() {
-                progress.increment();
-                continue;
+    recursive: bool,
+) -> Vec<ContextEntry> {
+    let folder_pattern = if recursive { config.folder_pattern() } else { "" };
+    let mut patterns = Vec::new();
+
+    if !folder_pattern.is_empty() {
+        patterns.push(pattern.clone());
+    }
+    patterns.push(format!("{}{}", pattern, folder_pattern));
+
+    patterns
+        .into_iter()
+        .map(|pattern| ContextEntry {
+            file_type: directory_type_field(&pattern, config),
+            pattern,
+            context: context.clone(),
+            is_dir: true,
+            source: relative_path.to_path_buf(),
+            reason,
+        })
+        .collect()
+}
+
+/// Only exact directory patterns get `-d`; a recursive `(/.*)?` pattern also
+/// covers the files below the directory and must stay untyped.
+fn directory_type_field(pattern: &str, config: &Config) -> Option<FileType> {
+    let recursive = config.folder_pattern();
+    let is_recursive = !recursive.is_empty() && pattern.ends_with(recursive);
+    (config.emit_type_field && !is_recursive).then_some(FileType::Directory)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FilesystemType;
+    use crate::contexts_file::undo_run;
+    use crate::progress::bar::ProgressMode;
+    use regex::Regex;
+    use tempfile::TempDir;
+
+    /// A `vendor` folder holding `files`, and a contexts file with `stock`.
+    fn fixture(files: &[&str], stock: &str) -> (TempDir, PathBuf, PathBuf) {
+        partition_fixture("vendor", files, stock)
+    }
+
+    fn partition_fixture(name: &str, files: &[&str], stock: &str) -> (TempDir, PathBuf, PathBuf) {
+        let dir = TempDir::new().unwrap();
+        let partition = dir.path().join(name);
+        fs::create_dir(&partition).unwrap();
+        for file in files {
+            let path = partition.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        let contexts = dir.path().join(format!("{}_file_contexts", name));
+        fs::write(&contexts, stock).unwrap();
+        (dir, partition, contexts)
+    }
+
+    fn config(partition: &Path, contexts: &Path) -> crate::config::ConfigBuilder {
+        Config::builder()
+            .extracted_dir(partition)
+            .file_contexts(contexts)
+            .fstype(FilesystemType::Ext4)
+            .mode(Mode::All)
+            .threads(1)
+            .quiet(true)
+            .progress(ProgressMode::Never)
+    }
+
+    /// The context of `path` where the last matching entry applies.
+    fn last_match(contexts: &Path, path: &str) -> Option<String> {
+        let content = fs::read_to_string(contexts).unwrap();
+        content
+            .lines()
+            .enumerate()
+            .filter_map(|(index, line)| match parse_line(line, index + 1) {
+                ContextLine::Entry(entry) => Some(entry),
+                _ => None,
+            })
+            .filter(|entry| Regex::new(&format!("^(?:{})$", entry.pattern)).unwrap().is_match(path))
+            .last()
+            .map(|entry| entry.context)
+    }
+
+    #[test]
+    fn appended_entries_keep_stock_regex_labels() {
+        let stock = "/vendor/lib64/hw(/.*)? u:object_r:same_process_hal_file:s0\n";
+        let (_dir, partition, contexts) = fixture(&["lib64/hw/gralloc.so", "lib64/libfoo.so"], stock);
+        process_file_contexts(&config(&partition, &contexts).build().unwrap()).unwrap();
+
+        let written = fs::read_to_string(&contexts).unwrap();
+        assert!(written.lines().any(|line| line == "/vendor u:object_r:vendor_file:s0"));
+        assert!(!written.contains("/vendor(/.*)?"));
+        assert!(!written.contains("/vendor/lib64(/.*)?"));
+        assert_eq!(
+            last_match(&contexts, "/vendor/lib64/hw/gralloc.so").as_deref(),
+            Some("u:object_r:same_process_hal_file:s0")
+        );
+        assert_eq!(last_match(&contexts, "/vendor/lib64/libfoo.so").as_deref(), Some("u:object_r:vendor_file:s0"));
+    }
+
+    #[test]
+    fn appended_entries_keep_stock_regexes_starting_above_them() {
+        let stock = "/vendor(/.*)? u:object_r:vendor_file:s0\n/vendor/lib(64)?/.*\\.so u:object_r:same_process_hal_file:s0\n";
+        let (_dir, partition, contexts) = fixture(&["lib64/libfoo.so", "lib64/foo.txt"], stock);
+        process_file_contexts(&config(&partition, &contexts).build().unwrap()).unwrap();
+
+        assert!(!fs::read_to_string(&contexts).unwrap().contains("/vendor/lib64(/.*)?"));
+        assert_eq!(
+            last_match(&contexts, "/vendor/lib64/libfoo.so").as_deref(),
+            Some("u:object_r:same_process_hal_file:s0")
+        );
+    }
+
+    #[test]
+    fn paths_covered_by_stock_patterns_get_no_entries() {
+        let stock = "\
+/vendor/bin/hw/android\\.hardware\\.light-service u:object_r:hal_light_default_exec:s0
+/vendor/lib(64)?/.*\\.so       u:object_r:vendor_file:s0
+/vendor/etc(/.*)?             u:object_r:vendor_configs_file:s0
+";
+        let files = ["bin/hw/android.hardware.light-service", "lib64/libfoo.so", "etc/foo.xml", "bin/hw/vendor.foo-service"];
+        let (_dir, partition, contexts) = fixture(&files, stock);
+        let config = config(&partition, &contexts).build().unwrap();
+
+        let mut patterns: Vec<String> = generate_missing_entries(&config).unwrap().into_iter().map(|entry| entry.pattern).collect();
+        patterns.sort();
+        assert_eq!(patterns, ["/vendor", "/vendor/bin", "/vendor/bin/hw", "/vendor/bin/hw/vendor\\.foo-service", "/vendor/lib64"]);
+    }
+
+    #[test]
+    fn check_skips_unrepresentable_paths_and_honors_entry_types() {
+        let stock = "/vendor(/.*)? -d u:object_r:vendor_file:s0\n";
+        let (_dir, partition, contexts) = fixture(&["etc/a.xml", "etc/b.xml", "my file"], stock);
+        let config = config(&partition, &contexts).check(true).build().unwrap();
+
+        // The `-d` entry only labels the folders, and "my file" is skipped
+        // like generation skips it.
+        assert_eq!(check_file_contexts(&config).unwrap(), 2);
+        assert_eq!(fs::read_to_string(&contexts).unwrap(), stock);
+    }
+
+    #[test]
+    fn output_does_not_depend_on_the_thread_count() {
+        let files = ["bin/foo", "bin/hw/vendor.foo-service", "etc/foo.xml", "lib64/libfoo.so", "firmware/a.bin", "app/Foo/Foo.apk"];
+        let (_dir, partition, contexts) = fixture(&files, "/vendor/etc(/.*)? u:object_r:vendor_configs_file:s0\n");
+        let generate = |threads| {
+            let config = config(&partition, &contexts).threads(threads).build().unwrap();
+            generate_missing_entries(&config).unwrap()
+        };
+
+        let single = generate(1);
+        // Folders get an exact and a recursive entry on ext4.
+        assert_eq!(single.len(), 18);
+        assert_eq!(generate(8), single);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_are_labeled_like_files() {
+        use std::os::unix::fs::symlink;
+
+        let (_dir, partition, contexts) = fixture(&["bin/foo", "etc/foo.xml"], "");
+        symlink("foo", partition.join("bin/relative")).unwrap();
+        symlink("/vendor/bin/foo", partition.join("bin/absolute")).unwrap();
+        symlink("missing", partition.join("bin/dangling")).unwrap();
+        symlink("../etc", partition.join("bin/etc")).unwrap();
+        let links = ["/vendor/bin/relative", "/vendor/bin/absolute", "/vendor/bin/dangling", "/vendor/bin/etc"];
+
+        for fstype in [FilesystemType::Ext4, FilesystemType::Erofs] {
+            let config = config(&partition, &contexts).fstype(fstype).build().unwrap();
+            let entries = generate_missing_entries(&config).unwrap();
+            let find = |path: &str| entries.iter().find(|entry| entry.pattern == path).unwrap();
+            for link in links {
+                assert!(!find(link).is_dir, "{}", link);
+                assert_eq!(find(link).context, find("/vendor/bin/foo").context);
             }
-            
-            let escaped_path = escape_regex(path_str);
-            let full_context_path = format!("/{}/{} ", partition, escaped_path);
-            let folder_context_path = format!("/{}/{}{} ", partition, escaped_path, config.fstype.folder_pattern());
-            
-            let full_context_trimmed = full_context_path.trim().to_string();
-            let folder_context_trimmed = folder_context_path.trim().to_string();
-            
-            if !existing_contexts.contains(&full_context_trimmed) &&
-               !existing_contexts.contains(&folder_context_trimmed) {
-                let is_file = full_path.is_file();
-
-                if is_file {
-                    let context_line = process_files(&escaped_path, partition, config)?;
-                    results.push(context_line);
-                } else {
-                    let context_lines = process_dirs(&escaped_path, partition, &config.fstype)?;
-                    results.extend(context_lines);
+            assert!(entries.iter().all(|entry| !entry.pattern.starts_with("/vendor/bin/etc(")));
+        }
+
+        let config = config(&partition, &contexts).skip_symlinks(true).build().unwrap();
+        let entries = generate_missing_entries(&config).unwrap();
+        assert!(entries.iter().all(|entry| !links.contains(&entry.pattern.as_str())));
+        assert!(entries.iter().any(|entry| entry.pattern == "/vendor/bin/foo"));
+    }
+
+    #[test]
+    fn empty_partition_generates_nothing() {
+        let stock = "/vendor u:object_r:vendor_file:s0\n";
+        let (_dir, partition, contexts) = fixture(&[], stock);
+        let report = process_file_contexts(&config(&partition, &contexts).threads(8).build().unwrap()).unwrap();
+        assert_eq!((report.scanned, report.generated), (0, 0));
+        assert_eq!(fs::read_to_string(&contexts).unwrap(), stock);
+    }
+
+    #[test]
+    fn more_threads_than_paths() {
+        let (_dir, partition, contexts) = fixture(&["foo"], "/vendor u:object_r:vendor_file:s0\n");
+        let report = process_file_contexts(&config(&partition, &contexts).threads(8).build().unwrap()).unwrap();
+        assert_eq!((report.scanned, report.generated), (1, 1));
+        assert_eq!(last_match(&contexts, "/vendor/foo").as_deref(), Some("u:object_r:vendor_file:s0"));
+    }
+
+    #[test]
+    fn dlkm_trees_get_kernel_module_labels() {
+        let files = ["lib/modules/foo.ko", "lib/modules/modules.dep", "etc/build.prop"];
+        let (_dir, partition, contexts) = partition_fixture("vendor_dlkm", &files, "");
+        let config = config(&partition, &contexts).kernel_module_context("u:object_r:my_modules:s0").build().unwrap();
+        process_file_contexts(&config).unwrap();
+
+        let label = |path| last_match(&contexts, path);
+        assert_eq!(label("/vendor_dlkm/lib/modules").as_deref(), Some("u:object_r:my_modules:s0"));
+        assert_eq!(label("/vendor_dlkm/lib/modules/foo.ko").as_deref(), Some("u:object_r:my_modules:s0"));
+        assert_eq!(label("/vendor_dlkm/lib/modules/modules.dep").as_deref(), Some("u:object_r:my_modules:s0"));
+        assert_eq!(label("/vendor_dlkm/etc/build.prop").as_deref(), Some("u:object_r:vendor_configs_file:s0"));
+    }
+
+    #[test]
+    fn unreadable_files_fall_back_to_path_heuristics() {
+        assert_eq!(inspect_elf(Path::new("/nonexistent/file")), None);
+    }
+
+    #[test]
+    fn names_with_whitespace_are_skipped_and_reported() {
+        let (_dir, partition, contexts) = fixture(&["etc/NOTICE FILE.txt", "etc/notice.txt"], "/vendor u:object_r:vendor_file:s0\n");
+        let report = process_file_contexts(&config(&partition, &contexts).build().unwrap()).unwrap();
+        assert_eq!(report.unrepresentable, [PathBuf::from("etc/NOTICE FILE.txt")]);
+        assert!(!fs::read_to_string(&contexts).unwrap().contains("NOTICE"));
+        assert!(last_match(&contexts, "/vendor/etc/notice.txt").is_some());
+    }
+
+    #[test]
+    fn appended_entries_start_on_their_own_line() {
+        for stream in [false, true] {
+            for stock in ["", "/vendor u:object_r:vendor_file:s0\n", "/vendor u:object_r:vendor_file:s0"] {
+                let (_dir, partition, contexts) = fixture(&["foo"], stock);
+                process_file_contexts(&config(&partition, &contexts).stream(stream).build().unwrap()).unwrap();
+
+                let written = fs::read_to_string(&contexts).unwrap();
+                assert!(written.ends_with('\n'));
+                for line in written.lines().filter(|line| !line.starts_with('#')) {
+                    assert_eq!(line.split_whitespace().count(), 2, "{:?} in {:?}", line, written);
                 }
+                assert_eq!(last_match(&contexts, "/vendor/foo").as_deref(), Some("u:object_r:vendor_file:s0"));
             }
         }
-        progress.increment();
     }
-    Ok(())
-}
 
-fn process_files(
-    escaped_path: &str,
-    partition: &str,
-    config: &Config,
-) -> Result<String> {
-    let processed_path = format!("/{}", escaped_path);
-    
-    let context = match () {
-        _ if processed_path.contains("/bin/hw/") => "u:object_r:hal_allocator_default_exec:s0",
-        _ if processed_path.contains("/bin/") => {
-            if !partition.contains("vendor") && !partition.contains("odm") {
-                config.system_bin_context.as_str()
-            } else {
-                config.vendor_bin_context.as_str()
-            }
+    #[test]
+    fn mediatek_vendor_bins_use_the_configured_label() {
+        let files = ["bin/mtk_agpsd", "bin/init.mtk.sh", "bin/toybox_vendor", "etc/init/mtk_agpsd.rc"];
+        let (_dir, partition, contexts) = fixture(&files, "/vendor u:object_r:vendor_file:s0\n");
+        let config = config(&partition, &contexts).vendor_bin_context("mtk_agpsd_exec").build().unwrap();
+        let entries = generate_missing_entries(&config).unwrap();
+
+        let explain = |pattern: &str| entries.iter().find(|entry| entry.pattern == pattern).unwrap().explain();
+        assert_eq!(explain("/vendor/bin/mtk_agpsd"), "/vendor/bin/mtk_agpsd -> mtk_agpsd_exec (heuristic: vendor /bin/ path)");
+        assert_eq!(explain("/vendor/bin/init\\.mtk\\.sh"), "/vendor/bin/init\\.mtk\\.sh -> vendor_shell_exec (heuristic: vendor /bin/ shell script)");
+        assert_eq!(explain("/vendor/bin/toybox_vendor"), "/vendor/bin/toybox_vendor -> vendor_toolbox_exec (heuristic: vendor toolbox)");
+        assert_eq!(
+            explain("/vendor/etc/init/mtk_agpsd\\.rc"),
+            "/vendor/etc/init/mtk_agpsd\\.rc -> vendor_configs_file (heuristic: vendor init .rc script)"
+        );
+        assert!(entries.iter().all(|entry| entry.context.r#type != "vendor_qti_init_shell_exec"));
+    }
+
+    /// Setting `security.selinux` needs privileges most test runs lack, in
+    /// which case there is nothing to check.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn stored_labels_win_over_heuristics() {
+        let (_dir, partition, contexts) = fixture(&["etc/foo.xml", "etc/bad.xml", "etc/none.xml"], "/vendor u:object_r:vendor_file:s0\n");
+        let set = |path: &str, value: &[u8]| xattr::set(partition.join(path), "security.selinux", value);
+        if set("etc/foo.xml", b"u:object_r:my_custom_file:s0\0").is_err() {
+            return;
+        }
+        set("etc/bad.xml", b"not a context\0").unwrap();
+
+        let config = config(&partition, &contexts).from_xattr(true).build().unwrap();
+        let entries = generate_missing_entries(&config).unwrap();
+        let find = |pattern: &str| entries.iter().find(|entry| entry.pattern == pattern).unwrap();
+        assert_eq!(find("/vendor/etc/foo\\.xml").context.to_string(), "u:object_r:my_custom_file:s0");
+        assert_eq!(find("/vendor/etc/foo\\.xml").reason, LabelReason::Xattr);
+        for pattern in ["/vendor/etc/bad\\.xml", "/vendor/etc/none\\.xml"] {
+            assert_eq!(find(pattern).reason, LabelReason::Heuristic("vendor /etc/ path"));
+        }
+    }
+
+    #[test]
+    fn compile_output_holds_stock_and_generated_entries() {
+        let (dir, partition, contexts) = fixture(&["etc/foo.xml"], "/vendor/lib(64)?(/.*)? u:object_r:vendor_file:s0\n");
+        let output = dir.path().join("file_contexts.bin");
+        let config = config(&partition, &contexts).compile_output(Some(output.clone())).build().unwrap();
+        process_file_contexts(&config).unwrap();
+
+        let compiled = crate::contexts_file::parse_compiled(&fs::read(&output).unwrap()).unwrap();
+        let patterns: Vec<&str> = compiled.iter().map(|entry| entry.pattern.as_str()).collect();
+        assert!(patterns.contains(&"/vendor/lib(64)?(/.*)?"), "{:?}", patterns);
+        assert!(patterns.contains(&"/vendor/etc/foo\\.xml"), "{:?}", patterns);
+    }
+
+    #[test]
+    fn compiled_stock_contexts_match_like_text() {
+        let stock = "/vendor/etc(/.*)? u:object_r:vendor_configs_file:s0\n/vendor/lib(64)?/libfoo\\.so u:object_r:same_process_hal_file:s0\n";
+        let files = ["etc/a.xml", "lib64/libfoo.so", "lib64/libbar.so", "bin/sh"];
+        let (dir, partition, contexts) = fixture(&files, stock);
+        let compiled = dir.path().join("vendor_file_contexts.bin");
+        compile_contexts_files(std::slice::from_ref(&contexts), &compiled, Some("10.42 2022-12-11")).unwrap();
+
+        let patterns = |contexts: &Path| -> Vec<String> {
+            let config = config(&partition, contexts).dry_run(true).build().unwrap();
+            generate_missing_entries(&config).unwrap().into_iter().map(|entry| entry.pattern).collect()
+        };
+        let from_text = patterns(&contexts);
+        assert!(from_text.contains(&"/vendor/lib64/libbar\\.so".to_string()), "{:?}", from_text);
+        assert!(!from_text.iter().any(|pattern| pattern.contains("libfoo") || pattern.starts_with("/vendor/etc")));
+        assert_eq!(patterns(&compiled), from_text);
+    }
+
+    #[test]
+    fn undeclared_types_fall_back_or_fail_when_strict() {
+        let (dir, partition, contexts) = fixture(&["bin/sh", "etc/a.xml"], "/vendor u:object_r:vendor_file:s0\n");
+        let types = dir.path().join("types.txt");
+        fs::write(&types, "vendor_file vendor_configs_file\n").unwrap();
+        let config = || config(&partition, &contexts).valid_types(vec![types.clone()]);
+
+        let entries = generate_missing_entries(&config().build().unwrap()).unwrap();
+        let context = |source: &str| entries.iter().find(|entry| entry.source == Path::new(source)).unwrap().context.to_string();
+        assert_eq!(context("bin/sh"), "u:object_r:vendor_file:s0");
+        assert_eq!(context("etc/a.xml"), "u:object_r:vendor_configs_file:s0");
+
+        let config_fallback = config().type_fallback(Some("vendor_configs_file".to_string())).build().unwrap();
+        let entries = generate_missing_entries(&config_fallback).unwrap();
+        let sh = entries.iter().find(|entry| entry.source == Path::new("bin/sh")).unwrap();
+        assert_eq!(sh.context.r#type, "vendor_configs_file");
+
+        let error = generate_missing_entries(&config().strict(true).build().unwrap()).unwrap_err();
+        let Error::Label { path, error } = error else { panic!("{}", error) };
+        assert_eq!(path, Path::new("bin/sh"));
+        assert!(matches!(*error, Error::UndeclaredType(ref name) if name == "vendor_qti_init_shell_exec"), "{}", error);
+
+        fs::write(&types, "vendor_configs_file\n").unwrap();
+        let error = generate_missing_entries(&config().build().unwrap()).unwrap_err().to_string();
+        assert!(error.contains("--type-fallback"), "{}", error);
+    }
+
+    #[test]
+    fn windows_line_endings_and_bom_are_kept() {
+        let stock = "\u{FEFF}/vendor u:object_r:vendor_file:s0\r\n/vendor/bin/sh u:object_r:vendor_shell_exec:s0\r\n";
+        for stream in [false, true] {
+            let (_dir, partition, contexts) = fixture(&["bin/sh", "bin/foo"], stock);
+            process_file_contexts(&config(&partition, &contexts).stream(stream).build().unwrap()).unwrap();
+            let content = fs::read_to_string(&contexts).unwrap();
+            assert!(content.starts_with(stock), "{:?}", content);
+            assert_eq!(content.matches('\n').count(), content.matches("\r\n").count(), "{:?}", content);
+            assert_eq!(content.matches("/vendor/bin/sh ").count(), 1, "{:?}", content);
+            assert_eq!(last_match(&contexts, "/vendor/bin/foo").as_deref(), Some("u:object_r:vendor_qti_init_shell_exec:s0"));
         }
-        _ if !partition.contains("vendor") && !partition.contains("odm") &&
-              (processed_path.contains("/lib/") || processed_path.contains("/lib64/")) => {
-            "u:object_r:system_lib_file:s0"
-        }
-        _ if partition.contains("vendor") || partition.contains("odm") => {
-            match () {
-                _ if processed_path.contains("/etc/") => "u:object_r:vendor_configs_file:s0",
-                _ if processed_path.contains("/firmware/") => "u:object_r:vendor_firmware_file:s0",
-                _ if processed_path.contains("/app/") || processed_path.contains("/priv-app/") => "u:object_r:vendor_app_file:s0",
-                _ if processed_path.contains("/framework/") => "u:object_r:vendor_framework_file:s0",
-                _ if processed_path.contains("/overlay/") => "u:object_r:vendor_overlay_file:s0",
-                _ => "u:object_r:vendor_file:s0",
+
+        let (_dir, partition, contexts) = fixture(&["bin/foo"], "/vendor u:object_r:vendor_file:s0\n");
+        process_file_contexts(&config(&partition, &contexts).build().unwrap()).unwrap();
+        assert!(!fs::read_to_string(&contexts).unwrap().contains('\r'));
+    }
+
+    #[test]
+    fn nested_paths_are_slash_separated() {
+        let (_dir, partition, contexts) = fixture(&["etc/wifi/config"], "/vendor u:object_r:vendor_file:s0\n");
+        let config = config(&partition, &contexts).build().unwrap();
+        let entries = generate_missing_entries(&config).unwrap();
+        let patterns: Vec<&str> = entries.iter().map(|entry| entry.pattern.as_str()).collect();
+        assert!(patterns.contains(&"/vendor/etc/wifi/config"), "{:?}", patterns);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn invalid_utf8_names_are_reported_converted_or_fatal() {
+        use std::ffi::OsString;
+        use std::os::unix::ffi::OsStringExt;
+
+        let (_dir, partition, contexts) = fixture(&["etc/a.xml"], "/vendor u:object_r:vendor_file:s0\n");
+        let name = PathBuf::from(OsString::from_vec(b"fo\xffo.xml".to_vec()));
+        fs::write(partition.join("etc").join(&name), "").unwrap();
+        let invalid = Path::new("etc").join(&name);
+
+        let report = process_file_contexts(&config(&partition, &contexts).dry_run(true).build().unwrap()).unwrap();
+        assert_eq!(report.non_utf8, std::slice::from_ref(&invalid));
+
+        let entries = generate_missing_entries(&config(&partition, &contexts).lossy_paths(true).build().unwrap()).unwrap();
+        let entry = entries.iter().find(|entry| entry.source == invalid).unwrap();
+        assert_eq!(entry.pattern, "/vendor/etc/fo[^/]+o\\.xml");
+
+        let error = generate_missing_entries(&config(&partition, &contexts).fail_on_invalid_path(true).build().unwrap()).unwrap_err();
+        assert!(matches!(error, Error::NonUtf8Paths { count: 1, ref example } if *example == invalid), "{}", error);
+        assert!(config(&partition, &contexts).lossy_paths(true).fail_on_invalid_path(true).build().is_err());
+    }
+
+    #[test]
+    fn writes_wait_for_the_contexts_lock() {
+        let (_dir, partition, contexts) = fixture(&["bin/foo"], "/vendor u:object_r:vendor_file:s0\n");
+        let held = ContextsLock::acquire(&contexts, None, || {}).unwrap();
+        let error = process_file_contexts(&config(&partition, &contexts).lock(true).wait_lock(Some(0)).build().unwrap()).unwrap_err();
+        assert!(matches!(error, Error::LockTimeout { seconds: 0, .. }), "{}", error);
+        drop(held);
+        process_file_contexts(&config(&partition, &contexts).lock(true).wait_lock(Some(0)).build().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn parallel_runs_on_one_contexts_file_keep_whole_blocks() {
+        let dir = TempDir::new().unwrap();
+        let contexts = dir.path().join("file_contexts");
+        fs::write(&contexts, "/vendor u:object_r:vendor_file:s0\n/odm u:object_r:vendor_file:s0\n").unwrap();
+        let files: Vec<String> = (0..50).map(|index| format!("bin/tool{}", index)).collect();
+        let runs: Vec<_> = ["vendor", "odm"]
+            .into_iter()
+            .map(|name| {
+                let partition = dir.path().join(name);
+                for file in &files {
+                    fs::create_dir_all(partition.join("bin")).unwrap();
+                    fs::write(partition.join(file), "").unwrap();
+                }
+                config(&partition, &contexts).lock(true).build().unwrap()
+            })
+            .collect();
+        thread::scope(|scope| {
+            for config in &runs {
+                scope.spawn(move || process_file_contexts(config).unwrap());
             }
+        });
+
+        let content = fs::read_to_string(&contexts).unwrap();
+        for name in ["vendor", "odm"] {
+            assert_eq!(content.matches(&format!("# BEGIN gen_file-context ({})\n", name)).count(), 1, "{}", content);
+            assert_eq!(content.matches(&format!("# END gen_file-context ({})\n", name)).count(), 1, "{}", content);
+            assert_eq!(last_match(&contexts, &format!("/{}/bin/tool7", name)).as_deref(), Some("u:object_r:vendor_qti_init_shell_exec:s0"));
         }
-        _ => "u:object_r:system_file:s0",
-    };
+        assert!(content.lines().all(|line| line.is_empty() || line.starts_with('#') || line.starts_with('/')), "{}", content);
+    }
 
-    Ok(format!("/{}/{} {}", partition, escaped_path, context))
-}
+    #[test]
+    fn apex_and_framework_trees() {
+        let files = ["apex/com.vendor.foo.apex", "apex/com.vendor.bar/com.vendor.bar.apex", "etc/a.xml"];
+        let (_dir, partition, contexts) = fixture(&files, "/vendor u:object_r:vendor_file:s0\n");
+        process_file_contexts(&config(&partition, &contexts).build().unwrap()).unwrap();
+        for path in ["/vendor/apex", "/vendor/apex/com.vendor.foo.apex", "/vendor/apex/com.vendor.bar/com.vendor.bar.apex"] {
+            assert_eq!(last_match(&contexts, path).as_deref(), Some("u:object_r:vendor_apex_file:s0"), "{}", path);
+        }
+        assert_eq!(last_match(&contexts, "/vendor/etc/a.xml").as_deref(), Some("u:object_r:vendor_configs_file:s0"));
 
-fn process_dirs(
-    escaped_path: &str,
-    partition: &str,
-    fstype: &crate::config::FilesystemType,
-) -> Result<Vec<String>> {
-    let processed_path = format!("/{}", escaped_path);
-    
-    let context = if partition.contains("vendor") || partition.contains("odm") {
-        match () {
-            _ if processed_path.contains("/etc") => "u:object_r:vendor_configs_file:s0",
-            _ if processed_path.contains("/firmware") => "u:object_r:vendor_firmware_file:s0",
-            _ if processed_path.contains("/app") || processed_path.contains("/priv-app") => "u:object_r:vendor_app_file:s0",
-            _ if processed_path.contains("/framework") => "u:object_r:vendor_framework_file:s0",
-            _ if processed_path.contains("/overlay") => "u:object_r:vendor_overlay_file:s0",
-            _ => "u:object_r:vendor_file:s0",
+        let files = ["framework/foo.jar", "framework/oat/arm64/foo.odex", "overlay/Foo.apk"];
+        let (dir, partition, contexts) = partition_fixture("product", &files, "/product u:object_r:system_file:s0\n");
+        let labels = dir.path().join("labels.toml");
+        fs::write(&labels, "product-framework = \"system_framework_file\"\n").unwrap();
+        let labels = LabelPolicy::load(&labels).unwrap();
+        process_file_contexts(&config(&partition, &contexts).labels(labels).build().unwrap()).unwrap();
+        for path in ["/product/framework", "/product/framework/foo.jar", "/product/framework/oat/arm64/foo.odex"] {
+            assert_eq!(last_match(&contexts, path).as_deref(), Some("u:object_r:system_framework_file:s0"), "{}", path);
         }
-    } else {
-        "u:object_r:system_file:s0"
-    };
+        assert_eq!(last_match(&contexts, "/product/overlay/Foo.apk").as_deref(), Some("u:object_r:vendor_overlay_file:s0"));
+    }
 
-    let mut results = Vec::new();
-    
-    if matches!(fstype, crate::config::FilesystemType::Ext4) {
-        results.push(format!("/{}/{} {}", partition, escaped_path, context));
+    #[test]
+    fn the_three_prefixing_modes() {
+        let (_dir, partition, contexts) = fixture(&["bin/foo"], "");
+        let patterns = |builder: crate::config::ConfigBuilder| -> Vec<String> {
+            let entries = generate_missing_entries(&builder.build().unwrap()).unwrap();
+            entries.into_iter().map(|entry| entry.pattern).collect()
+        };
+
+        // The folder or partition name.
+        assert!(patterns(config(&partition, &contexts)).contains(&"/vendor/bin/foo".to_string()));
+        let odm = patterns(config(&partition, &contexts).partition_name("odm"));
+        assert!(odm.contains(&"/odm/bin/foo".to_string()), "{:?}", odm);
+
+        // --prefix wins over the partition name.
+        let prefixed = patterns(config(&partition, &contexts).partition_name("odm").prefix("/odm/"));
+        assert_eq!(prefixed, odm);
+
+        // Entries start at the root of the tree.
+        let (_dir, partition, contexts) = partition_fixture("ramdisk", &["system/bin/recovery", "sbin/foo"], "");
+        let root = patterns(config(&partition, &contexts).partition_name("system").no_partition_prefix(true));
+        assert!(root.contains(&"/system/bin/recovery".to_string()), "{:?}", root);
+        assert!(root.contains(&"/sbin/foo".to_string()), "{:?}", root);
+        assert!(root.iter().all(|pattern| pattern.starts_with('/') && !pattern.starts_with("//")), "{:?}", root);
     }
-    results.push(format!("/{}/{}{} {}", partition, escaped_path, fstype.folder_pattern(), context));
-    
-    Ok(results)
-}
\ No newline at end of file
+
+    #[test]
+    fn prefix_options_are_validated() {
+        let (_dir, partition, contexts) = fixture(&[], "");
+        for prefix in ["odm", "/", "//"] {
+            assert!(config(&partition, &contexts).prefix(prefix).build().is_err(), "{}", prefix);
+        }
+        let error = config(&partition, &contexts).prefix("/odm").no_partition_prefix(true).build().unwrap_err();
+        assert!(error.to_string().contains("--no-partition-prefix"), "{}", error);
+    }
+
+    #[test]
+    fn reruns_replace_the_generated_block() {
+        let (_dir, partition, contexts) = fixture(&["bin/foo", "bin/bar"], "/vendor u:object_r:vendor_file:s0\n");
+        process_file_contexts(&config(&partition, &contexts).build().unwrap()).unwrap();
+        assert!(fs::read_to_string(&contexts).unwrap().contains("/vendor/bin/bar "));
+
+        fs::remove_file(partition.join("bin/bar")).unwrap();
+        process_file_contexts(&config(&partition, &contexts).build().unwrap()).unwrap();
+        let content = fs::read_to_string(&contexts).unwrap();
+        assert!(!content.contains("/vendor/bin/bar"), "{}", content);
+        assert!(content.contains("/vendor/bin/foo "), "{}", content);
+        assert_eq!(content.matches("# BEGIN gen_file-context (vendor)").count(), 1, "{}", content);
+    }
+
+    #[test]
+    fn undo_restores_the_contexts_file() {
+        let stock = "/vendor u:object_r:vendor_file:s0\n";
+        let (_dir, partition, contexts) = fixture(&["bin/foo", "etc/a.xml"], stock);
+        process_file_contexts(&config(&partition, &contexts).build().unwrap()).unwrap();
+        assert_ne!(fs::read_to_string(&contexts).unwrap(), stock);
+
+        let summary = undo_run(&contexts, None).unwrap();
+        assert_eq!(summary.run.partition, "vendor");
+        assert!(summary.missing.is_empty(), "{:?}", summary.missing);
+        assert_eq!(fs::read_to_string(&contexts).unwrap(), stock);
+    }
+
+    #[test]
+    fn hooks_skip_and_replace_entries() {
+        let (_dir, partition, contexts) = fixture(&["firmware/wifi/a.bin", "bin/foo", "bin/skipme"], "/vendor u:object_r:vendor_file:s0\n");
+        let wifi: SecurityContext = "u:object_r:vendor_wifi_firmware_file:s0".parse().unwrap();
+        for stream in [false, true] {
+            let config = config(&partition, &contexts).stream(stream).build().unwrap();
+            process_with_hook(&config, |entry| match () {
+                _ if entry.source.starts_with("bin/skipme") => HookAction::Skip,
+                _ if entry.source.starts_with("firmware/wifi") => HookAction::Replace(ContextEntry { context: wifi.clone(), ..entry.clone() }),
+                _ => HookAction::Keep,
+            })
+            .unwrap();
+            assert_eq!(last_match(&contexts, "/vendor/firmware/wifi/a.bin").as_deref(), Some("u:object_r:vendor_wifi_firmware_file:s0"));
+            assert_eq!(last_match(&contexts, "/vendor/bin/foo").as_deref(), Some("u:object_r:vendor_qti_init_shell_exec:s0"));
+            assert_eq!(last_match(&contexts, "/vendor/bin/skipme").as_deref(), Some("u:object_r:vendor_file:s0"));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn ext4_images_report_invalid_names_like_folders() {
+        use flate2::read::GzDecoder;
+        use std::os::unix::ffi::OsStrExt;
+
+        let image = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/vendor.ext4.img.gz"));
+        let (dir, _partition, contexts) = fixture(&[], "/vendor u:object_r:vendor_file:s0\n");
+        let mut content = Vec::new();
+        GzDecoder::new(&image[..]).read_to_end(&mut content).unwrap();
+        let path = dir.path().join("vendor.img");
+        fs::write(&path, content).unwrap();
+
+        let report = process_file_contexts(&config(&path, &contexts).dry_run(true).build().unwrap()).unwrap();
+        assert_eq!(report.non_utf8.len(), 1);
+        assert_eq!(report.non_utf8[0].as_os_str().as_bytes(), b"etc/bad\xffname");
+
+        let entries = generate_missing_entries(&config(&path, &contexts).lossy_paths(true).build().unwrap()).unwrap();
+        assert!(entries.iter().any(|entry| entry.pattern == "/vendor/etc/bad[^/]+name"));
+        assert!(entries.iter().any(|entry| entry.pattern == "/vendor/bin/sh" && entry.context.r#type == "vendor_qti_init_shell_exec"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unreadable_folders_are_skipped_or_fail_strict_runs() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (_dir, partition, contexts) = fixture(&["bin/foo", "etc/private/key"], "");
+        let private = partition.join("etc/private");
+        fs::set_permissions(&private, fs::Permissions::from_mode(0o000)).unwrap();
+        // Permissions do not apply to root.
+        if fs::read_dir(&private).is_err() {
+            let report = process_file_contexts(&config(&partition, &contexts).dry_run(true).build().unwrap()).unwrap();
+            let skipped: Vec<_> = report.skipped.iter().map(|skipped| skipped.path.as_path()).collect();
+            assert_eq!(skipped, [Path::new("etc/private")]);
+            // The folder is labeled even though its contents are not.
+            assert_eq!(report.directories, 4);
+
+            let config = config(&partition, &contexts).dry_run(true).strict(true).build().unwrap();
+            assert!(matches!(process_file_contexts(&config), Err(Error::Read { path, .. }) if path == private));
+        }
+        fs::set_permissions(&private, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn archives_are_named_after_their_stem() {
+        use flate2::write::GzEncoder;
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("vendor.tar.gz");
+        let mut builder = tar::Builder::new(GzEncoder::new(File::create(&path).unwrap(), Default::default()));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(0);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, "vendor/bin/hw/vendor.foo-service", std::io::empty()).unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+        let contexts = dir.path().join("vendor_file_contexts");
+        fs::write(&contexts, "").unwrap();
+
+        let report = process_file_contexts(&config(&path, &contexts).dry_run(true).build().unwrap()).unwrap();
+        assert_eq!(report.partition, "vendor");
+        let entries = generate_missing_entries(&config(&path, &contexts).build().unwrap()).unwrap();
+        assert!(entries.iter().any(|entry| entry.pattern == "/vendor/bin/hw/vendor\\.foo-service"), "{entries:?}");
+
+        // `--partition-name` replaces the name; the wrapper still matches the stem.
+        let config = config(&path, &contexts).partition_name("odm").build().unwrap();
+        let entries = generate_missing_entries(&config).unwrap();
+        assert!(entries.iter().any(|entry| entry.pattern == "/odm/bin/hw/vendor\\.foo-service"), "{entries:?}");
+    }
+}
@@ -0,0 +1,90 @@
+use crate::contexts_file::{FileType, SecurityContext};
+use super::report::serialize_lossy_path;
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ContextEntry {
+    pub pattern: String,
+    pub file_type: Option<FileType>,
+    pub context: SecurityContext,
+    pub is_dir: bool,
+    #[serde(serialize_with = "serialize_lossy_path")]
+    pub source: PathBuf,
+    pub reason: LabelReason,
+}
+
+/// What a [`process_with_hook`](super::process_with_hook) callback does
+/// with a generated entry.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HookAction {
+    Keep,
+    Skip,
+    Replace(ContextEntry),
+}
+
+/// Callback run on every generated entry, possibly from several worker
+/// threads at once.
+pub type EntryHook<'a> = &'a (dyn Fn(&ContextEntry) -> HookAction + Sync);
+
+pub(crate) fn apply_hook(entries: Vec<ContextEntry>, hook: EntryHook) -> Vec<ContextEntry> {
+    entries
+        .into_iter()
+        .filter_map(|entry| match hook(&entry) {
+            HookAction::Keep => Some(entry),
+            HookAction::Skip => None,
+            HookAction::Replace(replacement) => Some(replacement),
+        })
+        .collect()
+}
+
+/// Why an entry received its context, for `--explain` and reports.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LabelReason {
+    /// A `--rules` entry, identified by its line in the rules file
+    Rule(usize),
+    /// A `.file_contexts_override` file in the path's folder or above it
+    Override,
+    /// One of the built-in path heuristics
+    Heuristic(&'static str),
+    /// A path listed in `--source-contexts`, identified by its line there
+    Source(usize),
+    /// The `security.selinux` attribute of the extracted path, with
+    /// `--from-xattr`
+    Xattr,
+}
+
+impl fmt::Display for LabelReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rule(line) => write!(f, "rule: --rules line {}", line),
+            Self::Override => write!(f, "override: nearest {}", super::OVERRIDE_FILE_NAME),
+            Self::Heuristic(branch) => write!(f, "heuristic: {}", branch),
+            Self::Source(line) => write!(f, "source: --source-contexts line {}", line),
+            Self::Xattr => write!(f, "xattr: security.selinux"),
+        }
+    }
+}
+
+impl Serialize for LabelReason {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl fmt::Display for ContextEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.file_type {
+            Some(file_type) => write!(f, "{} {} {}", self.pattern, file_type, self.context),
+            None => write!(f, "{} {}", self.pattern, self.context),
+        }
+    }
+}
+
+impl ContextEntry {
+    /// `<pattern> -> <type> (<reason>)`, naming only the type of the context.
+    pub fn explain(&self) -> String {
+        format!("{} -> {} ({})", self.pattern, self.context.r#type, self.reason)
+    }
+}
@@ -0,0 +1,143 @@
+use crate::config::Config;
+use crate::error::{Error, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::path::{Path, PathBuf};
+
+pub const IGNORE_FILE_NAME: &str = ".fcignore";
+
+/// Paths no partition ships but extractions commonly leave behind: the ext4
+/// `lost+found` folder, a `.journal` created by tune2fs, 7-Zip's `[SYS]`
+/// folder, GNU and pax tar header entries extracted as files, and desktop
+/// metadata. Only the tar entries at the partition root are skipped, since
+/// `@` is common in real names such as APEX versions.
+pub const DEFAULT_EXCLUDES: &[&str] = &[
+    "lost+found",
+    ".journal",
+    "\\[SYS\\]",
+    "@LongLink",
+    "@PaxHeader",
+    "**/.DS_Store",
+    "**/Thumbs.db",
+];
+
+/// Why the walk skips a path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pruned {
+    /// Matched `--exclude` or the ignore file
+    Filtered,
+    /// Matched [`DEFAULT_EXCLUDES`]
+    Default,
+}
+
+/// Filters applied to paths relative to the partition root while walking it.
+#[derive(Debug, Clone)]
+pub struct PathFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    default_exclude: Option<GlobSet>,
+    ignore: Option<Gitignore>,
+    ignore_file: Option<PathBuf>,
+}
+
+impl PathFilter {
+    pub fn new(config: &Config) -> Result<Self> {
+        let include = match config.include.as_slice() {
+            [] => None,
+            patterns => Some(build_glob_set("--include", patterns)?),
+        };
+        let ignore_file = match &config.ignore_file {
+            Some(path) if !path.is_file() => {
+                return Err(Error::InvalidArgument(format!("Ignore file does not exist: {:?}", path)));
+            }
+            Some(path) => Some(path.clone()),
+            None => Some(config.extracted_dir.join(IGNORE_FILE_NAME)).filter(|path| path.is_file()),
+        };
+        let ignore = match &ignore_file {
+            Some(path) => Some(load_ignore_file(&config.extracted_dir, path)?),
+            None => None,
+        };
+
+        Ok(Self {
+            include,
+            exclude: build_glob_set("--exclude", &config.exclude)?,
+            default_exclude: match config.default_excludes {
+                true => Some(build_glob_set("default exclude", DEFAULT_EXCLUDES)?),
+                false => None,
+            },
+            ignore,
+            ignore_file,
+        })
+    }
+
+    pub fn ignore_file(&self) -> Option<&Path> {
+        self.ignore_file.as_deref()
+    }
+
+    /// Whether the walk should skip this path along with everything below
+    /// it, and why.
+    ///
+    /// Explicit `--include` globs win over the ignore file, so ignored
+    /// directories are only pruned when no includes were given.
+    pub fn prunes(&self, relative_path: &Path, is_dir: bool) -> Option<Pruned> {
+        if self.exclude.is_match(relative_path) || (self.include.is_none() && self.is_ignored(relative_path, is_dir)) {
+            Some(Pruned::Filtered)
+        } else if self.default_exclude.as_ref().is_some_and(|set| set.is_match(relative_path)) {
+            Some(Pruned::Default)
+        } else {
+            None
+        }
+    }
+
+    /// Includes never prune the walk: a directory that does not match may
+    /// still contain paths that do.
+    pub fn is_included(&self, relative_path: &Path) -> bool {
+        self.include.as_ref().is_none_or(|include| include.is_match(relative_path))
+    }
+
+    fn is_ignored(&self, relative_path: &Path, is_dir: bool) -> bool {
+        self.ignore.as_ref().is_some_and(|ignore| {
+            ignore.matched_path_or_any_parents(relative_path, is_dir).is_ignore()
+        })
+    }
+}
+
+fn load_ignore_file(root: &Path, path: &Path) -> Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(root);
+    if let Some(e) = builder.add(path) {
+        return Err(Error::invalid_file("ignore file", path, e));
+    }
+    builder.build().map_err(|e| Error::invalid_file("ignore file", path, e))
+}
+
+fn build_glob_set(option: &'static str, patterns: &[impl AsRef<str>]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        // `.repack_meta/` reads naturally for a directory but would never
+        // match, since walked paths carry no trailing separator.
+        let pattern = pattern.as_ref().trim_end_matches('/');
+        let glob = Glob::new(pattern)
+            .map_err(|error| Error::InvalidGlob { option, pattern: pattern.to_string(), error })?;
+        builder.add(glob);
+    }
+    builder.build().map_err(|error| {
+        let pattern = patterns.iter().map(AsRef::as_ref).collect::<Vec<_>>().join(" ");
+        Error::InvalidGlob { option, pattern, error }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_excludes_keep_at_signs_in_real_names() {
+        let set = build_glob_set("default exclude", DEFAULT_EXCLUDES).unwrap();
+        assert!(set.is_match("lost+found"));
+        assert!(set.is_match("@LongLink"));
+        assert!(set.is_match("etc/.DS_Store"));
+        assert!(!set.is_match("apex/com.android.runtime@1"));
+        assert!(!set.is_match("etc/@LongLink"));
+        assert!(!set.is_match("app/lost+found"));
+    }
+}
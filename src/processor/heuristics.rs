@@ -0,0 +1,398 @@
+use crate::config::{LabelPolicy, PartitionKind};
+use crate::contexts_file::FileType;
+use crate::utils::elf::ElfKind;
+use crate::utils::fs_utils::slash_separated;
+use crate::utils::regex_utils::escape_path;
+use super::path_match::PathMatch;
+use super::rules::ExtensionLabel;
+use std::borrow::Cow;
+use std::path::Path;
+
+/// Why paths below etc/firmware/ on system-like partitions got their label;
+/// runs count these to suggest a review.
+pub(crate) const SYSTEM_FIRMWARE: &str = "system /etc/firmware/ path";
+
+/// Toolbox binaries on vendor partitions, which run in their own domain.
+const VENDOR_TOOLBOX_NAMES: &[&str] = &["toolbox", "toybox_vendor"];
+
+/// What the heuristics are labeling. Symlinks are labeled like files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+    /// Device nodes, pipes and sockets, which get the partition's default
+    /// label.
+    Special,
+}
+
+impl EntryKind {
+    pub fn from_file_type(file_type: FileType) -> Self {
+        match file_type {
+            FileType::Regular => Self::File,
+            FileType::Symlink => Self::Symlink,
+            FileType::Directory => Self::Dir,
+            FileType::CharDevice | FileType::BlockDevice | FileType::Socket | FileType::Pipe => Self::Special,
+        }
+    }
+
+    fn is_file_like(self) -> bool {
+        matches!(self, Self::File | Self::Symlink)
+    }
+}
+
+/// The label the built-in heuristics give `path`, relative to the root of
+/// `partition` (an empty path being the root itself). Only the path is
+/// looked at: rules, source contexts, attributes and ELF headers are not
+/// consulted, and the partition kind comes from its name.
+///
+/// Most labels borrow from `policy`; HAL services below `bin/hw/` get a
+/// `hal_<name>_default_exec` type built from their file name.
+///
+/// ```
+/// use gen_file_contexts::processor::{label_for, EntryKind};
+/// use gen_file_contexts::LabelPolicy;
+///
+/// let policy = LabelPolicy::default();
+/// assert_eq!(label_for("etc/init/foo.rc", "vendor", EntryKind::File, &policy), "vendor_configs_file");
+/// assert_eq!(label_for("bin/hw/android.hardware.light-service", "odm", EntryKind::File, &policy), "hal_light_default_exec");
+/// assert_eq!(label_for("lib64", "system", EntryKind::Dir, &policy), "system_file");
+/// ```
+pub fn label_for<'a>(path: &str, partition: &str, kind: EntryKind, policy: &'a LabelPolicy) -> Cow<'a, str> {
+    let path = slash_separated(path.trim_matches('/'));
+    let heuristics = Heuristics {
+        labels: policy,
+        extensions: &[],
+        kind: PartitionKind::from_name(partition),
+        legacy: false,
+        nested_bin: false,
+    };
+    heuristics.label(Path::new(path.as_ref()), &escape_path(&path), kind, || None).0
+}
+
+/// The heuristics for one partition, shared by [`label_for`] and the
+/// labeling pipeline.
+pub(crate) struct Heuristics<'a> {
+    pub labels: &'a LabelPolicy,
+    /// Extension mappings from the rules file, ahead of the built-in `.apk`
+    /// one.
+    pub extensions: &'a [ExtensionLabel],
+    pub kind: PartitionKind,
+    /// Substring checks on the escaped path, like `--legacy-matching`.
+    pub legacy: bool,
+    /// `bin` folders below the partition root count, like `--nested-bin`.
+    pub nested_bin: bool,
+}
+
+impl<'a> Heuristics<'a> {
+    /// The label of `relative_path` and why it was chosen. `inspect` gives
+    /// the ELF type of files and is only called when no path heuristic
+    /// before it applies.
+    pub(crate) fn label(
+        &self,
+        relative_path: &Path,
+        escaped_path: &str,
+        entry: EntryKind,
+        inspect: impl FnOnce() -> Option<ElfKind>,
+    ) -> (Cow<'a, str>, &'static str) {
+        let labels = self.labels;
+        let default = match () {
+            _ if self.kind == PartitionKind::SystemDlkm => &labels.system_dlkm,
+            _ if self.kind.is_vendor_like() => &labels.vendor_file,
+            _ => &labels.system_file,
+        };
+        let (label, reason) = match () {
+            _ if relative_path.as_os_str().is_empty() => (default, "partition root"),
+            _ if entry == EntryKind::Special => (default, "special file"),
+            _ if entry.is_file_like() => return self.file_label(relative_path, escaped_path, inspect),
+            _ => self.dir_label(relative_path, escaped_path),
+        };
+        (Cow::Borrowed(label.as_str()), reason)
+    }
+
+    fn file_label(
+        &self,
+        relative_path: &Path,
+        escaped_path: &str,
+        inspect: impl FnOnce() -> Option<ElfKind>,
+    ) -> (Cow<'a, str>, &'static str) {
+        let path = PathMatch::new(relative_path, escaped_path, self.legacy, self.nested_bin);
+        let kind = self.kind;
+        let labels = self.labels;
+        let has_extension = |ext: &str| relative_path.extension().is_some_and(|extension| extension == ext);
+
+        let (label, reason) = match () {
+            _ if kind == PartitionKind::SystemDlkm => (&labels.system_dlkm, "system_dlkm partition"),
+            _ if kind.is_vendor_like() && (has_extension("ko") || path.below(&["lib", "modules"])) => {
+                (&labels.kernel_module, "vendor kernel module")
+            }
+            _ if path.below(&["bin", "hw"]) => match hal_service_name(relative_path) {
+                Some(name) => return (Cow::Owned(format!("hal_{}_default_exec", name)), "HAL service name"),
+                None => (&labels.hal_fallback, "HAL fallback"),
+            },
+            _ if path.below(&["bin"]) => {
+                let file_name = relative_path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+                match () {
+                    _ if !kind.is_vendor_like() => (&labels.system_bin, "system /bin/ path"),
+                    _ if VENDOR_TOOLBOX_NAMES.contains(&file_name) => (&labels.vendor_toolbox, "vendor toolbox"),
+                    _ if has_extension("sh") => (&labels.vendor_shell, "vendor /bin/ shell script"),
+                    _ => (&labels.vendor_bin, "vendor /bin/ path"),
+                }
+            }
+            // Ahead of ELF inspection, which would take compiled odex files for libraries.
+            _ if is_product_like(kind) && path.below(&["framework"]) => {
+                (&labels.product_framework, "product /framework/ path")
+            }
+            _ if let Some(elf) = inspect() => match (elf, kind.is_vendor_like()) {
+                (ElfKind::Executable, true) => (&labels.vendor_bin, "vendor ELF executable"),
+                (ElfKind::Executable, false) => (&labels.system_bin, "system ELF executable"),
+                (ElfKind::SharedObject, true) => (&labels.vendor_file, "vendor ELF shared object"),
+                (ElfKind::SharedObject, false) => (&labels.system_lib, "system ELF shared object"),
+            },
+            _ if !kind.is_vendor_like() && (path.below(&["lib"]) || path.below(&["lib64"])) => {
+                (&labels.system_lib, "system /lib/ path")
+            }
+            _ if is_product_like(kind) && path.below(&["overlay"]) => (&labels.vendor_overlay, "product overlay"),
+            _ if kind.is_vendor_like() => match () {
+                _ if path.below(&["apex"]) => (&labels.vendor_apex, "vendor apex"),
+                // Overlays are packaged as APKs too and keep their own label.
+                _ if !path.below(&["overlay"]) && let Some(extension) = self.extension_label(relative_path) => {
+                    return (Cow::Borrowed(extension.context.as_str()), "vendor extension mapping");
+                }
+                _ if !path.below(&["overlay"]) && has_extension("apk") => (&labels.vendor_app, "vendor .apk file"),
+                _ if path.below(&["etc", "init"]) && has_extension("rc") => (&labels.vendor_etc, "vendor init .rc script"),
+                _ if path.below(&["etc"]) => (&labels.vendor_etc, "vendor /etc/ path"),
+                _ if path.below(&["firmware"]) => (&labels.vendor_firmware, "vendor /firmware/ path"),
+                _ if path.below(&["app"]) || path.below(&["priv-app"]) => (&labels.vendor_app, "vendor app"),
+                _ if path.below(&["framework"]) => (&labels.vendor_framework, "vendor /framework/ path"),
+                _ if path.below(&["overlay"]) => (&labels.vendor_overlay, "vendor overlay"),
+                _ => (&labels.vendor_file, "vendor partition default"),
+            },
+            _ => match () {
+                _ if path.below(&["media"]) && relative_path.file_name().is_some_and(|name| name == "bootanimation.zip") => {
+                    (&labels.bootanimation, "boot animation")
+                }
+                _ if path.below(&["etc", "firmware"]) => (&labels.system_firmware, SYSTEM_FIRMWARE),
+                _ if path.below(&["media", "audio"]) => (&labels.media_audio, "system /media/audio/ path"),
+                _ if path.below(&["fonts"]) => (&labels.fonts, "system /fonts/ path"),
+                _ if path.below(&["usr", "share"]) => (&labels.usr_share, "system /usr/share/ path"),
+                _ => (&labels.system_file, "system partition default"),
+            },
+        };
+        (Cow::Borrowed(label.as_str()), reason)
+    }
+
+    fn extension_label(&self, relative_path: &Path) -> Option<&'a ExtensionLabel> {
+        let extension = relative_path.extension()?;
+        self.extensions.iter().find(|mapping| extension == mapping.extension.as_str())
+    }
+
+    fn dir_label(&self, relative_path: &Path, escaped_path: &str) -> (&'a String, &'static str) {
+        let path = PathMatch::new(relative_path, escaped_path, self.legacy, self.nested_bin);
+        let kind = self.kind;
+        let labels = self.labels;
+
+        match () {
+            _ if kind == PartitionKind::SystemDlkm => (&labels.system_dlkm, "system_dlkm partition"),
+            _ if kind.is_vendor_like() && path.within(&["lib", "modules"]) => {
+                (&labels.kernel_module, "vendor kernel module directory")
+            }
+            _ if kind.is_vendor_like() => match () {
+                _ if path.within(&["apex"]) => (&labels.vendor_apex, "vendor apex"),
+                _ if path.within(&["etc"]) => (&labels.vendor_etc, "vendor /etc/ path"),
+                _ if path.within(&["firmware"]) => (&labels.vendor_firmware, "vendor /firmware/ path"),
+                _ if path.within(&["app"]) || path.within(&["priv-app"]) => (&labels.vendor_app, "vendor app"),
+                _ if path.within(&["framework"]) => (&labels.vendor_framework, "vendor /framework/ path"),
+                _ if path.within(&["overlay"]) => (&labels.vendor_overlay, "vendor overlay"),
+                _ => (&labels.vendor_file, "vendor partition default"),
+            },
+            _ if is_product_like(kind) && path.within(&["overlay"]) => (&labels.vendor_overlay, "product overlay"),
+            _ if is_product_like(kind) && path.within(&["framework"]) => {
+                (&labels.product_framework, "product /framework/ path")
+            }
+            _ if path.within(&["etc", "firmware"]) => (&labels.system_firmware, SYSTEM_FIRMWARE),
+            _ if path.within(&["media", "audio"]) => (&labels.media_audio, "system /media/audio/ path"),
+            _ if path.within(&["fonts"]) => (&labels.fonts, "system /fonts/ path"),
+            _ if path.within(&["usr", "share"]) => (&labels.usr_share, "system /usr/share/ path"),
+            _ => (&labels.system_file, "system partition default"),
+        }
+    }
+}
+
+fn is_product_like(kind: PartitionKind) -> bool {
+    matches!(kind, PartitionKind::Product | PartitionKind::SystemExt)
+}
+
+fn hal_service_name(relative_path: &Path) -> Option<String> {
+    let file_name = relative_path.file_name()?.to_str()?;
+    let rest = file_name.strip_prefix("android.hardware.")?;
+    let end = rest.find(['@', '-']).unwrap_or(rest.len());
+    let name = &rest[..end];
+
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_') {
+        return None;
+    }
+    Some(name.replace('.', "_"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str, partition: &str) -> String {
+        label_for(path, partition, EntryKind::File, &LabelPolicy::default()).into_owned()
+    }
+
+    fn dir(path: &str, partition: &str) -> String {
+        label_for(path, partition, EntryKind::Dir, &LabelPolicy::default()).into_owned()
+    }
+
+    #[test]
+    fn kernel_modules_on_dlkm_partitions() {
+        for partition in ["vendor_dlkm", "odm_dlkm"] {
+            assert_eq!(file("lib/modules/foo.ko", partition), "vendor_kernel_modules");
+            assert_eq!(file("lib/modules/modules.load", partition), "vendor_kernel_modules");
+            assert_eq!(file("etc/foo.ko", partition), "vendor_kernel_modules");
+            assert_eq!(dir("lib/modules", partition), "vendor_kernel_modules");
+            assert_eq!(dir("lib/modules/5.10", partition), "vendor_kernel_modules");
+            assert_eq!(dir("lib", partition), "vendor_file");
+        }
+        assert_eq!(file("lib/modules/foo.ko", "system_dlkm"), "system_dlkm_file");
+        assert_eq!(dir("lib/modules", "system_dlkm"), "system_dlkm_file");
+    }
+
+    #[test]
+    fn inspected_elf_files_outside_bin() {
+        let labels = LabelPolicy::default();
+        let label = |path: &str, partition: &str, elf: Option<ElfKind>| {
+            let heuristics = Heuristics {
+                labels: &labels,
+                extensions: &[],
+                kind: PartitionKind::from_name(partition),
+                legacy: false,
+                nested_bin: false,
+            };
+            heuristics.label(Path::new(path), &escape_path(path), EntryKind::File, || elf).0.into_owned()
+        };
+        assert_eq!(label("xbin/daemon", "vendor", Some(ElfKind::Executable)), labels.vendor_bin);
+        assert_eq!(label("xbin/daemon", "system", Some(ElfKind::Executable)), labels.system_bin);
+        assert_eq!(label("xbin/libfoo.so", "system", Some(ElfKind::SharedObject)), labels.system_lib);
+        assert_eq!(label("xbin/notes.txt", "system", None), labels.system_file);
+        // Path heuristics ahead of inspection win.
+        assert_eq!(label("lib/modules/foo.ko", "vendor", Some(ElfKind::SharedObject)), labels.kernel_module);
+    }
+
+    #[test]
+    fn hal_services_are_named_after_their_interface() {
+        assert_eq!(file("bin/hw/android.hardware.light-service", "vendor"), "hal_light_default_exec");
+        assert_eq!(file("bin/hw/android.hardware.light-service.example", "vendor"), "hal_light_default_exec");
+        assert_eq!(file("bin/hw/android.hardware.light@2.0-service", "vendor"), "hal_light_default_exec");
+        assert_eq!(file("bin/hw/android.hardware.power.stats-service", "odm"), "hal_power_stats_default_exec");
+        assert_eq!(file("bin/hw/android.hardware.graphics.composer@2.4-service", "vendor"), "hal_graphics_composer_default_exec");
+    }
+
+    #[test]
+    fn unknown_hal_names_use_the_fallback() {
+        let fallback = LabelPolicy::default().hal_fallback;
+        assert_eq!(file("bin/hw/vendor.qti.hardware.perf@2.0-service", "vendor"), fallback);
+        assert_eq!(file("bin/hw/android.hardware.-service", "vendor"), fallback);
+    }
+
+    #[test]
+    fn overlays_are_matched_by_whole_folder_name() {
+        let labels = LabelPolicy::default();
+        assert_eq!(file("overlay/y.apk", "product"), labels.vendor_overlay);
+        assert_eq!(file("overlay/Sub/z.apk", "product"), labels.vendor_overlay);
+        assert_eq!(file("overlay/x.apk", "vendor"), labels.vendor_overlay);
+        assert_eq!(dir("overlay/Sub", "system_ext"), labels.vendor_overlay);
+        assert_eq!(file("overlaysold/x.apk", "vendor"), labels.vendor_app);
+        assert_eq!(file("overlays_backup/x.apk", "product"), labels.system_file);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn backslash_separated_paths_are_labeled_like_slashes() {
+        assert_eq!(file(r"bin\hw\android.hardware.light-service", "vendor"), file("bin/hw/android.hardware.light-service", "vendor"));
+        assert_eq!(file(r"\etc\init\foo.rc", "vendor"), "vendor_configs_file");
+    }
+
+    /// A policy labeling each category with its own field name, so the
+    /// label tells which branch chose it.
+    fn named_policy() -> LabelPolicy {
+        let keys: String = LabelPolicy::default()
+            .entries()
+            .into_iter()
+            .map(|(key, _)| format!("{} = \"{}\"\n", key, key.replace('-', "_")))
+            .collect();
+        toml::from_str(&keys).unwrap()
+    }
+
+    #[test]
+    fn every_branch_by_table() {
+        use EntryKind::{Dir, File, Special, Symlink};
+
+        let policy = named_policy();
+        let cases = [
+            ("", "vendor", Dir, "vendor_file"),
+            ("", "system_dlkm", Dir, "system_dlkm"),
+            ("", "product", Dir, "system_file"),
+            ("dev/null", "odm", Special, "vendor_file"),
+            ("bin/sh", "vendor", Symlink, "vendor_bin"),
+            // Files.
+            ("lib/modules/foo.ko", "system_dlkm", File, "system_dlkm"),
+            ("foo.ko", "vendor", File, "kernel_module"),
+            ("lib/modules/modules.dep", "vendor", File, "kernel_module"),
+            ("bin/hw/android.hardware.light-service", "vendor", File, "hal_light_default_exec"),
+            ("bin/hw/vendor.foo-service", "vendor", File, "hal_fallback"),
+            ("bin/ls", "system", File, "system_bin"),
+            ("bin/toybox_vendor", "vendor", File, "vendor_toolbox"),
+            ("bin/init.foo.sh", "vendor", File, "vendor_shell"),
+            ("bin/thermal-engine", "odm", File, "vendor_bin"),
+            ("framework/foo.jar", "product", File, "product_framework"),
+            ("lib64/libfoo.so", "system_ext", File, "system_lib"),
+            ("overlay/Foo.apk", "product", File, "vendor_overlay"),
+            ("apex/com.foo.apex", "vendor", File, "vendor_apex"),
+            ("app/Foo/Foo.apk", "vendor", File, "vendor_app"),
+            ("etc/init/foo.rc", "vendor", File, "vendor_etc"),
+            ("etc/wifi.conf", "vendor", File, "vendor_etc"),
+            ("firmware/a.bin", "vendor", File, "vendor_firmware"),
+            ("priv-app/Foo/oat/arm64/Foo.odex", "vendor", File, "vendor_app"),
+            ("framework/foo.jar", "vendor", File, "vendor_framework"),
+            ("overlay/Foo.apk", "vendor", File, "vendor_overlay"),
+            ("lib64/libfoo.so", "vendor", File, "vendor_file"),
+            ("media/bootanimation.zip", "system", File, "bootanimation"),
+            ("etc/firmware/a.bin", "system", File, "system_firmware"),
+            ("media/audio/ringtones/a.ogg", "system", File, "media_audio"),
+            ("fonts/Roboto.ttf", "system", File, "fonts"),
+            ("usr/share/zoneinfo/tzdata", "system", File, "usr_share"),
+            ("etc/hosts", "system", File, "system_file"),
+            // Directories.
+            ("lib/modules", "system_dlkm", Dir, "system_dlkm"),
+            ("lib/modules/5.10", "vendor", Dir, "kernel_module"),
+            ("apex", "vendor", Dir, "vendor_apex"),
+            ("etc/init", "vendor", Dir, "vendor_etc"),
+            ("firmware", "odm", Dir, "vendor_firmware"),
+            ("priv-app/Foo", "vendor", Dir, "vendor_app"),
+            ("framework", "vendor", Dir, "vendor_framework"),
+            ("overlay", "vendor", Dir, "vendor_overlay"),
+            ("bin", "vendor", Dir, "vendor_file"),
+            ("overlay", "product", Dir, "vendor_overlay"),
+            ("framework/oat", "system_ext", Dir, "product_framework"),
+            ("etc/firmware", "system", Dir, "system_firmware"),
+            ("media/audio", "system", Dir, "media_audio"),
+            ("fonts", "system", Dir, "fonts"),
+            ("usr/share", "system", Dir, "usr_share"),
+            ("etc", "system", Dir, "system_file"),
+        ];
+        for (path, partition, kind, expected) in cases {
+            assert_eq!(label_for(path, partition, kind, &policy), expected, "{} on {} as {:?}", path, partition, kind);
+        }
+    }
+
+    #[test]
+    fn surrounding_slashes_are_ignored() {
+        let policy = LabelPolicy::default();
+        assert_eq!(label_for("/etc/init/foo.rc/", "vendor", EntryKind::File, &policy), policy.vendor_etc);
+        assert_eq!(label_for("/", "vendor", EntryKind::Dir, &policy), policy.vendor_file);
+    }
+}
@@ -0,0 +1,39 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Asks a run to stop early, typically from a Ctrl-C handler. The walk and
+/// the labeling threads stop taking new paths, and the entries generated
+/// so far are handled as [`Config::on_interrupt`](crate::Config::on_interrupt)
+/// says.
+///
+/// Clones share the flag.
+#[derive(Debug, Clone, Default)]
+pub struct Interrupt(Arc<AtomicBool>);
+
+impl Interrupt {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the flag, returning whether it was already set.
+    pub fn trigger(&self) -> bool {
+        self.0.swap(true, Ordering::AcqRel)
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+/// What happens to the entries generated before an interruption.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnInterrupt {
+    /// Write them as a normal run would
+    Write,
+    /// Leave the contexts file as it was
+    #[default]
+    Discard,
+}
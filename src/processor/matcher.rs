@@ -0,0 +1,208 @@
+use crate::contexts_file::FileType;
+use crate::progress::reporter::errln;
+use crate::utils::regex_utils::{literal_prefix, unescape_literal};
+use crate::error::Result;
+use super::context_processor::RECURSIVE_SUFFIX;
+use regex::{Regex, RegexSet, RegexSetBuilder};
+use std::collections::{BTreeSet, HashMap};
+
+/// Matches paths against file_contexts patterns the way libselinux does:
+/// anchored regexes, restricted by the optional type field.
+#[derive(Debug, Clone)]
+pub struct ContextMatcher {
+    literals: HashMap<String, Vec<Option<FileType>>>,
+    patterns: RegexSet,
+    pattern_types: Vec<Option<FileType>>,
+    /// The literal start of every pattern, to find entries below a folder.
+    prefixes: BTreeSet<String>,
+    /// The literal start of each regex pattern other than `<folder>(/.*)?`,
+    /// which may go on to match paths below any folder it starts.
+    open_prefixes: Vec<String>,
+    case_insensitive: bool,
+    case_collisions: Vec<Vec<String>>,
+}
+
+impl ContextMatcher {
+    pub fn new<'a, I>(entries: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (&'a str, Option<FileType>)>,
+    {
+        Self::build(entries, false)
+    }
+
+    /// Like [`ContextMatcher::new`], ignoring case on both the patterns and
+    /// the matched paths, for partitions extracted on case-insensitive
+    /// filesystems.
+    pub fn new_case_insensitive<'a, I>(entries: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (&'a str, Option<FileType>)>,
+    {
+        Self::build(entries, true)
+    }
+
+    fn build<'a, I>(entries: I, case_insensitive: bool) -> Result<Self>
+    where
+        I: IntoIterator<Item = (&'a str, Option<FileType>)>,
+    {
+        let mut literals: HashMap<String, Vec<Option<FileType>>> = HashMap::new();
+        let mut spellings: HashMap<String, BTreeSet<String>> = HashMap::new();
+        let mut anchored = Vec::new();
+        let mut pattern_types = Vec::new();
+        let mut prefixes = BTreeSet::new();
+        let mut open_prefixes = Vec::new();
+
+        for (pattern, file_type) in entries {
+            let mut prefix = literal_prefix(pattern);
+            if case_insensitive {
+                prefix = prefix.to_lowercase();
+            }
+            prefixes.insert(prefix.clone());
+            if let Some(literal) = unescape_literal(pattern) {
+                if case_insensitive {
+                    let folded = literal.to_lowercase();
+                    spellings.entry(folded.clone()).or_default().insert(literal);
+                    literals.entry(folded).or_default().push(file_type);
+                } else {
+                    literals.entry(literal).or_default().push(file_type);
+                }
+                continue;
+            }
+
+            let candidate = format!("^(?:{})$", pattern);
+            match Regex::new(&candidate) {
+                Ok(_) => {
+                    if unescape_literal(pattern.strip_suffix(RECURSIVE_SUFFIX).unwrap_or(pattern)).is_none() {
+                        open_prefixes.push(prefix);
+                    }
+                    anchored.push(candidate);
+                    pattern_types.push(file_type);
+                }
+                Err(e) => errln!("Warning: skipping invalid pattern {}: {}", pattern, e),
+            }
+        }
+
+        let mut case_collisions: Vec<Vec<String>> = spellings
+            .into_values()
+            .filter(|spellings| spellings.len() > 1)
+            .map(|spellings| spellings.into_iter().collect())
+            .collect();
+        case_collisions.sort();
+
+        Ok(Self {
+            literals,
+            patterns: RegexSetBuilder::new(anchored)
+                .size_limit(1 << 28)
+                .case_insensitive(case_insensitive)
+                .build()?,
+            pattern_types,
+            prefixes,
+            open_prefixes,
+            case_insensitive,
+            case_collisions,
+        })
+    }
+
+    /// Literal paths that only differ in case, when matching ignores case.
+    /// A case-insensitive extraction kept only one of each group.
+    pub fn case_collisions(&self) -> &[Vec<String>] {
+        &self.case_collisions
+    }
+
+    /// Whether some entry may match paths strictly below the folder `dir`,
+    /// judged by the literal start of its pattern: either it starts below
+    /// `dir`, or it is a regex starting above it, such as
+    /// `/vendor/lib(64)?/.*\.so` for `/vendor/lib`. Entries labeling a whole
+    /// folder, `<folder>(/.*)?`, only count when they start below `dir`.
+    pub fn has_entries_below(&self, dir: &str) -> bool {
+        let mut below = format!("{}/", dir.trim_end_matches('/'));
+        if self.case_insensitive {
+            below = below.to_lowercase();
+        }
+        self.prefixes.range(below.clone()..).next().is_some_and(|prefix| prefix.starts_with(&below))
+            || self.open_prefixes.iter().any(|prefix| below.starts_with(prefix.as_str()))
+    }
+
+    /// An unknown `file_type` is matched by entries of any type.
+    pub fn is_match(&self, path: &str, file_type: Option<FileType>) -> bool {
+        let applies = |entry_type: &Option<FileType>| match (entry_type, file_type) {
+            (Some(entry_type), Some(file_type)) => *entry_type == file_type,
+            _ => true,
+        };
+
+        let literal = match self.case_insensitive {
+            true => self.literals.get(&path.to_lowercase()),
+            false => self.literals.get(path),
+        };
+        if literal.is_some_and(|types| types.iter().any(applies)) {
+            return true;
+        }
+        if self.pattern_types.iter().all(Option::is_none) {
+            return self.patterns.is_match(path);
+        }
+        self.patterns
+            .matches(path)
+            .iter()
+            .any(|index| applies(&self.pattern_types[index]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns_match_anchored_like_libselinux() {
+        let matcher = ContextMatcher::new([("/vendor/lib(64)?/.*\\.so", None)]).unwrap();
+        assert!(matcher.is_match("/vendor/lib64/libfoo.so", None));
+        assert!(matcher.is_match("/vendor/lib/hw/libbar.so", None));
+        assert!(!matcher.is_match("/vendor/lib64/libfoo.so.1", None));
+        assert!(!matcher.is_match("/system/vendor/lib64/libfoo.so", None));
+    }
+
+    #[test]
+    fn escaped_literals_match_only_themselves() {
+        let matcher = ContextMatcher::new([("/vendor/bin/hw/android\\.hardware\\.light-service", None)]).unwrap();
+        assert!(matcher.is_match("/vendor/bin/hw/android.hardware.light-service", None));
+        assert!(!matcher.is_match("/vendor/bin/hw/androidXhardware.light-service", None));
+    }
+
+    #[test]
+    fn type_field_restricts_known_types() {
+        let matcher = ContextMatcher::new([("/vendor/etc", Some(FileType::Directory))]).unwrap();
+        assert!(matcher.is_match("/vendor/etc", Some(FileType::Directory)));
+        assert!(matcher.is_match("/vendor/etc", None));
+        assert!(!matcher.is_match("/vendor/etc", Some(FileType::Regular)));
+    }
+
+    #[test]
+    fn invalid_patterns_are_skipped() {
+        let matcher = ContextMatcher::new([("/vendor/app/[invalid", None), ("/vendor/app(/.*)?", None)]).unwrap();
+        assert!(matcher.is_match("/vendor/app/Foo.apk", None));
+        assert_eq!(matcher.pattern_types.len(), 1);
+    }
+
+    #[test]
+    fn case_insensitive_matching_reports_collisions() {
+        let matcher = ContextMatcher::new_case_insensitive([("/vendor/etc/Foo", None), ("/vendor/etc/foo", None)]).unwrap();
+        assert!(matcher.is_match("/vendor/etc/FOO", None));
+        assert_eq!(matcher.case_collisions(), [vec!["/vendor/etc/Foo".to_string(), "/vendor/etc/foo".to_string()]]);
+    }
+
+    #[test]
+    fn entries_below_a_folder_are_found_by_prefix() {
+        let matcher = ContextMatcher::new([
+            ("/vendor(/.*)?", None),
+            ("/vendor/bin/hw/android\\.hardware\\.light-service", None),
+            ("/vendor/lib(64)?/.*\\.so", None),
+            ("/vendor/etc(/.*)?", None),
+        ])
+        .unwrap();
+        assert!(matcher.has_entries_below("/vendor"));
+        assert!(matcher.has_entries_below("/vendor/bin"));
+        assert!(matcher.has_entries_below("/vendor/lib"));
+        assert!(matcher.has_entries_below("/vendor/lib64"));
+        assert!(!matcher.has_entries_below("/vendor/bin/hw/android.hardware.light-service"));
+        assert!(!matcher.has_entries_below("/vendor/etc"));
+        assert!(!matcher.has_entries_below("/vendor/firmware"));
+    }
+}
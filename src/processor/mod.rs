@@ -1,2 +1,30 @@
+mod collapse;
 mod context_processor;
-pub use context_processor::process_file_contexts;
\ No newline at end of file
+mod entry;
+mod filter;
+mod heuristics;
+mod interrupt;
+mod matcher;
+mod overrides;
+mod path_list;
+mod path_match;
+mod prune;
+mod report;
+pub mod rules;
+mod scan_cache;
+mod shadowing;
+mod source_contexts;
+mod validate;
+mod verify;
+mod walk;
+pub use context_processor::{
+    check_file_contexts, generate_missing_entries, process_file_contexts, process_with_hook, write_entries,
+};
+pub use filter::DEFAULT_EXCLUDES;
+pub use heuristics::{label_for, EntryKind};
+pub use interrupt::{Interrupt, OnInterrupt};
+pub use overrides::OVERRIDE_FILE_NAME;
+pub use entry::{ContextEntry, EntryHook, HookAction, LabelReason};
+pub use prune::{prune_contexts, PruneOptions, PruneReport, StaleEntry};
+pub use report::{write_report, Report, ReportFormat, SkippedPath, ThreadTiming};
+pub use verify::{verify_partition, VerifyReport};
@@ -0,0 +1,58 @@
+use crate::config::Config;
+use crate::error::{Error, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Name of the files that set the context of the folder holding them and
+/// everything below it.
+pub const OVERRIDE_FILE_NAME: &str = ".file_contexts_override";
+
+/// Contexts set by `.file_contexts_override` files inside the partition,
+/// letting device maintainers label a subtree without a rules file.
+///
+/// The file holds the context on its first line that is neither blank nor
+/// a `#` comment, either a bare type or a full context. The deepest
+/// override above a path wins.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Overrides {
+    /// Contexts keyed by the folder holding the file, relative to the
+    /// partition root.
+    contexts: HashMap<PathBuf, String>,
+}
+
+impl Overrides {
+    /// Reads the override files at `files`, relative to the partition root.
+    pub fn load(config: &Config, files: &[PathBuf]) -> Result<Self> {
+        let mut contexts = HashMap::new();
+        for relative_path in files {
+            let path = config.extracted_dir.join(relative_path);
+            let content = fs::read_to_string(&path).map_err(|e| Error::read(&path, e))?;
+            let context = content
+                .lines()
+                .map(str::trim)
+                .find(|line| !line.is_empty() && !line.starts_with('#'))
+                .ok_or_else(|| Error::invalid_file("override file", &path, "no context given"))?;
+            config.security_context(context).map_err(|e| Error::invalid_file("override file", &path, e))?;
+            let dir = relative_path.parent().unwrap_or(Path::new("")).to_path_buf();
+            contexts.insert(dir, context.to_string());
+        }
+        Ok(Self { contexts })
+    }
+
+    /// The context of the deepest override at or above `relative_path`.
+    pub fn lookup(&self, relative_path: &Path) -> Option<&str> {
+        if self.contexts.is_empty() {
+            return None;
+        }
+        relative_path.ancestors().find_map(|dir| self.contexts.get(dir)).map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.contexts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.contexts.is_empty()
+    }
+}
@@ -0,0 +1,250 @@
+use crate::config::{normalize_partition_name, Config, FilesystemType};
+use crate::contexts_file::FileType;
+use crate::error::{Error, Result};
+use crate::utils::archive::{list_archive, ArchiveKind};
+use crate::utils::ext4::list_ext4_image;
+use super::walk::WalkedPath;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+
+/// Paths processed instead of walking the partition folder: the contents of
+/// a partition image or archive, or the list given with `--paths-from`. Listed
+/// directories are labeled themselves; their contents are only processed
+/// when listed too.
+#[derive(Debug, Clone)]
+pub struct PathList {
+    paths: Vec<WalkedPath>,
+}
+
+impl PathList {
+    /// Reads the list, `-` being stdin. File types come from the partition
+    /// folder, image or archive unless `--assume-files` is set.
+    pub fn load(config: &Config) -> Result<Option<Self>> {
+        let image = if config.partition_is_file() { Some(read_partition_file(config)?) } else { None };
+        let Some(source) = &config.paths_from else {
+            return Ok(image.map(|paths| Self { paths }));
+        };
+        let image_types: Option<HashMap<&Path, FileType>> = image
+            .as_ref()
+            .map(|paths| paths.iter().map(|walked| (walked.relative_path.as_path(), walked.file_type)).collect());
+        let content = read_list(source, io::stdin())?;
+
+        let mut seen = HashSet::new();
+        let mut paths = Vec::new();
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let relative_path = relative_to_root(Path::new(line), &config.extracted_dir).ok_or_else(|| {
+                Error::invalid_file("path list", source, format!("line {}: {:?} is outside the partition folder", index + 1, line))
+            })?;
+            if relative_path.as_os_str().is_empty() || !seen.insert(relative_path.clone()) {
+                continue;
+            }
+            let file_type = if config.assume_files {
+                FileType::Regular
+            } else if let Some(image_types) = &image_types {
+                *image_types.get(relative_path.as_path()).ok_or_else(|| {
+                    Error::invalid_file("path list", source, format!("line {}: {:?} is not in {:?}", index + 1, line, config.extracted_dir))
+                })?
+            } else {
+                let full_path = config.extracted_dir.join(&relative_path);
+                let metadata = match config.follow_symlinks {
+                    true => fs::metadata(&full_path).or_else(|_| fs::symlink_metadata(&full_path)),
+                    false => fs::symlink_metadata(&full_path),
+                }
+                .map_err(|e| Error::read(full_path, e))?;
+                FileType::from_fs(&metadata.file_type())
+            };
+            paths.push(WalkedPath { relative_path, file_type });
+        }
+        Ok(Some(Self { paths }))
+    }
+
+    pub fn paths(&self) -> &[WalkedPath] {
+        &self.paths
+    }
+}
+
+/// Every path in a partition archive or ext4 image, parents first. Images of
+/// other filesystems have to be extracted.
+fn read_partition_file(config: &Config) -> Result<Vec<WalkedPath>> {
+    let path = config.extracted_dir.as_path();
+    if let Some(kind) = ArchiveKind::from_path(path) {
+        let stem = normalize_partition_name(&path.file_name().unwrap_or_default().to_string_lossy());
+        let root_names: Vec<&str> = config.partition_name.as_deref().into_iter().chain([stem.as_str()]).collect();
+        let entries = list_archive(path, kind, &root_names).map_err(|e| Error::read(path, e))?;
+        return Ok(entries
+            .into_iter()
+            .map(|(relative_path, file_type)| WalkedPath { relative_path, file_type })
+            .collect());
+    }
+    match FilesystemType::detect_from_image(path).map_err(|e| Error::read(path, e))? {
+        Some(FilesystemType::Ext4) => {}
+        Some(fstype) => {
+            return Err(Error::InvalidArgument(format!(
+                "{:?} holds {}, not ext4; only ext4 images can be read without extracting them",
+                path,
+                fstype.name()
+            )));
+        }
+        None => {
+            return Err(Error::InvalidArgument(format!(
+                "{:?} is neither a folder, a tar or zip archive, nor an ext4 partition image",
+                path
+            )));
+        }
+    }
+    let entries = list_ext4_image(path).map_err(|e| Error::read(path, e))?;
+    Ok(entries
+        .into_iter()
+        .map(|entry| WalkedPath { relative_path: entry.path, file_type: entry.file_type })
+        .collect())
+}
+
+/// The list at `source`, or what `stdin` holds when it is `-`.
+fn read_list(source: &Path, mut stdin: impl Read) -> Result<String> {
+    if source == Path::new("-") {
+        let mut content = String::new();
+        stdin.read_to_string(&mut content).map_err(|e| Error::read(source, e))?;
+        Ok(content)
+    } else {
+        fs::read_to_string(source).map_err(|e| Error::read(source, e))
+    }
+}
+
+/// `path` relative to the partition root. Absolute paths must lie inside
+/// `root`, and `..` is never accepted.
+fn relative_to_root(path: &Path, root: &Path) -> Option<PathBuf> {
+    let path = if path.is_absolute() { strip_root(path, root)? } else { path.to_path_buf() };
+    let mut relative_path = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => relative_path.push(name),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(relative_path)
+}
+
+/// Strips `root` off the absolute `path`, also when `root` was given
+/// relative to the working directory or either goes through a symlink.
+fn strip_root(path: &Path, root: &Path) -> Option<PathBuf> {
+    if let Ok(root) = std::path::absolute(root)
+        && let Ok(relative_path) = path.strip_prefix(root)
+    {
+        return Some(relative_path.to_path_buf());
+    }
+    let root = fs::canonicalize(root).ok()?;
+    // The listed path itself may be a symlink or not exist yet, so only its
+    // closest existing parent is resolved.
+    let ancestor = path.parent()?.ancestors().find(|ancestor| ancestor.exists())?;
+    let rest = path.strip_prefix(ancestor).ok()?;
+    Some(fs::canonicalize(ancestor).ok()?.strip_prefix(root).ok()?.join(rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Mode;
+    use crate::progress::bar::ProgressMode;
+    use std::io::Cursor;
+    use tempfile::TempDir;
+
+    /// A `vendor` folder holding `bin/foo` and `etc/init/`.
+    fn partition() -> (TempDir, PathBuf) {
+        let dir = TempDir::new().unwrap();
+        let partition = dir.path().join("vendor");
+        fs::create_dir_all(partition.join("bin")).unwrap();
+        fs::create_dir_all(partition.join("etc/init")).unwrap();
+        fs::write(partition.join("bin/foo"), "").unwrap();
+        (dir, partition)
+    }
+
+    /// Loads `list` for `partition`, given as `root`.
+    fn load(partition: &Path, root: &Path, list: &str, assume_files: bool) -> Result<Vec<(PathBuf, FileType)>> {
+        let source = partition.with_file_name("paths.txt");
+        fs::write(&source, list).unwrap();
+        let config = Config::builder()
+            .extracted_dir(root)
+            .file_contexts(partition.with_file_name("vendor_file_contexts"))
+            .fstype(FilesystemType::Ext4)
+            .mode(Mode::All)
+            .progress(ProgressMode::Never)
+            .paths_from(Some(source))
+            .assume_files(assume_files)
+            .build()?;
+        let paths = PathList::load(&config)?.unwrap().paths;
+        Ok(paths.into_iter().map(|walked| (walked.relative_path, walked.file_type)).collect())
+    }
+
+    fn walked(path: &str, file_type: FileType) -> (PathBuf, FileType) {
+        (PathBuf::from(path), file_type)
+    }
+
+    #[test]
+    fn relative_and_absolute_paths_inside_the_root_are_listed() {
+        let (_dir, partition) = partition();
+        let list = format!("bin/foo\n./etc/init\n\n{}\n", partition.join("bin").display());
+        let paths = load(&partition, &partition, &list, false).unwrap();
+        assert_eq!(
+            paths,
+            [walked("bin/foo", FileType::Regular), walked("etc/init", FileType::Directory), walked("bin", FileType::Directory)]
+        );
+    }
+
+    #[test]
+    fn paths_outside_the_root_are_rejected() {
+        let (dir, partition) = partition();
+        for line in ["../system/bin/sh", "bin/../../system", "/system/bin/sh"] {
+            let error = load(&partition, &partition, line, false).unwrap_err();
+            assert!(error.to_string().contains("outside the partition folder"), "{line}: {error}");
+        }
+        let sibling = dir.path().join("vendor_dlkm/lib");
+        assert!(load(&partition, &partition, sibling.to_str().unwrap(), false).is_err());
+    }
+
+    #[test]
+    fn absolute_paths_match_a_root_given_another_way() {
+        let (dir, partition) = partition();
+        let absolute = partition.join("bin/foo");
+
+        // Through `..`, and through a symlink to the partition folder.
+        let roundabout = dir.path().join("vendor/bin/../../vendor");
+        assert_eq!(load(&partition, &roundabout, absolute.to_str().unwrap(), false).unwrap(), [walked("bin/foo", FileType::Regular)]);
+        #[cfg(unix)]
+        {
+            let link = dir.path().join("link");
+            std::os::unix::fs::symlink(&partition, &link).unwrap();
+            let through_link = link.join("bin/foo");
+            let paths = load(&partition, &partition, through_link.to_str().unwrap(), false).unwrap();
+            assert_eq!(paths, [walked("bin/foo", FileType::Regular)]);
+
+            // Symlinks inside the partition are listed as themselves.
+            std::os::unix::fs::symlink("foo", partition.join("bin/sh")).unwrap();
+            let paths = load(&partition, &roundabout, partition.join("bin/sh").to_str().unwrap(), false).unwrap();
+            assert_eq!(paths, [walked("bin/sh", FileType::Symlink)]);
+        }
+    }
+
+    #[test]
+    fn assume_files_skips_looking_paths_up() {
+        let (_dir, partition) = partition();
+        let paths = load(&partition, &partition, "etc/init\nbin/new-file\n", true).unwrap();
+        assert_eq!(paths, [walked("etc/init", FileType::Regular), walked("bin/new-file", FileType::Regular)]);
+        assert!(matches!(load(&partition, &partition, "bin/new-file", false), Err(Error::Read { .. })));
+    }
+
+    #[test]
+    fn dash_reads_stdin() {
+        let (_dir, partition) = partition();
+        assert_eq!(read_list(Path::new("-"), Cursor::new("bin/foo\n")).unwrap(), "bin/foo\n");
+        let source = partition.with_file_name("paths.txt");
+        fs::write(&source, "etc/init\n").unwrap();
+        assert_eq!(read_list(&source, Cursor::new("bin/foo\n")).unwrap(), "etc/init\n");
+    }
+}
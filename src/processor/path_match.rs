@@ -0,0 +1,109 @@
+use std::ffi::OsStr;
+use std::path::{Component, Path};
+
+/// Answers "is this path inside the partition's `<name>/` directory" for the
+/// labeling heuristics, comparing whole path components from the partition
+/// root so that lookalikes such as `overlays_backup` or `fetch`, and
+/// folders of the same name deeper in the tree, do not count.
+///
+/// `bin` folders are the exception with `--nested-bin`: like bin mode, the
+/// checks then find them at any depth.
+///
+/// With `legacy` set, the checks fall back to the substring tests used
+/// before, e.g. `contains("/etc/")` on the escaped path.
+pub(crate) struct PathMatch<'a> {
+    relative_path: &'a Path,
+    legacy_path: Option<String>,
+    nested_bin: bool,
+}
+
+impl<'a> PathMatch<'a> {
+    pub(crate) fn new(relative_path: &'a Path, escaped_path: &str, legacy: bool, nested_bin: bool) -> Self {
+        Self {
+            relative_path,
+            legacy_path: legacy.then(|| format!("/{}", escaped_path)),
+            nested_bin,
+        }
+    }
+
+    /// For files: the directories above the entry start with `names`, e.g.
+    /// `["bin", "hw"]` for anything below `bin/hw/`.
+    pub(crate) fn below(&self, names: &[&str]) -> bool {
+        match &self.legacy_path {
+            Some(path) => path.contains(&format!("/{}/", names.join("/"))),
+            None => self.relative_path.parent().is_some_and(|parent| self.starts_with(parent, names)),
+        }
+    }
+
+    /// For directories: the path itself or one of its parents is `names`.
+    pub(crate) fn within(&self, names: &[&str]) -> bool {
+        match &self.legacy_path {
+            Some(path) => path.contains(&format!("/{}", names.join("/"))),
+            None => self.starts_with(self.relative_path, names),
+        }
+    }
+
+    fn starts_with(&self, path: &Path, names: &[&str]) -> bool {
+        let components: Vec<&OsStr> = path
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(name),
+                _ => None,
+            })
+            .collect();
+        let matches_at = |start: usize| {
+            components
+                .get(start..start + names.len())
+                .is_some_and(|window| window.iter().zip(names).all(|(component, name)| *component == *name))
+        };
+        match names.first() {
+            Some(&"bin") if self.nested_bin => (0..components.len()).any(matches_at),
+            _ => matches_at(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn below(path: &str, names: &[&str], nested_bin: bool) -> bool {
+        PathMatch::new(Path::new(path), path, false, nested_bin).below(names)
+    }
+
+    fn within(path: &str, names: &[&str]) -> bool {
+        PathMatch::new(Path::new(path), path, false, false).within(names)
+    }
+
+    #[test]
+    fn top_level_folders_match() {
+        assert!(below("etc/foo.xml", &["etc"], false));
+        assert!(below("bin/hw/android.hardware.light-service", &["bin", "hw"], false));
+        assert!(within("etc", &["etc"]));
+        assert!(within("etc/firmware/sub", &["etc", "firmware"]));
+    }
+
+    #[test]
+    fn lookalike_names_do_not_match() {
+        assert!(!below("fetch/etc.d/foo", &["etc"], false));
+        assert!(!below("overlays_backup/foo.apk", &["overlay"], false));
+        assert!(!within("etcetera", &["etc"]));
+        assert!(!below("etc", &["etc"], false));
+    }
+
+    #[test]
+    fn nested_folders_only_match_for_bin_with_nested_bin() {
+        assert!(!below("apex/com.android.foo/etc/foo.xml", &["etc"], false));
+        assert!(!within("app/Foo/firmware", &["firmware"]));
+        assert!(!below("app/Foo/bin/tool", &["bin"], false));
+        assert!(below("app/Foo/bin/tool", &["bin"], true));
+        assert!(below("apex/foo/bin/hw/service", &["bin", "hw"], true));
+        assert!(!below("apex/foo/etc/foo.xml", &["etc"], true));
+    }
+
+    #[test]
+    fn legacy_matching_keeps_substring_checks() {
+        let path = PathMatch::new(Path::new("apex/foo/etc/foo.xml"), "apex/foo/etc/foo\\.xml", true, false);
+        assert!(path.below(&["etc"]));
+    }
+}
@@ -0,0 +1,168 @@
+use super::context_processor::RECURSIVE_SUFFIX;
+use crate::contexts_file::{
+    find_marked_block, lock_path, parse_line, split_bom, ContextLine, ContextsLock, FileType, LineEnding,
+};
+use crate::error::{Error, Result};
+use crate::progress::reporter::errln;
+use crate::utils::fs_utils::{slash_separated, write_atomic};
+use crate::utils::regex_utils::unescape_literal;
+use regex::{Regex, RegexSet};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PruneOptions {
+    /// Also check recursive and wildcard entries, which are otherwise kept
+    /// since they may be meant for paths created at runtime.
+    pub aggressive: bool,
+    /// Check every entry of the partition even when the file has a marked
+    /// block of generated entries.
+    pub all_entries: bool,
+    /// Remove the stale entries from the file instead of only listing them.
+    pub apply: bool,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct PruneReport {
+    pub partition: String,
+    pub contexts_file: PathBuf,
+    /// Entries looked up in the partition.
+    pub checked: usize,
+    /// Recursive and wildcard entries left alone.
+    pub runtime: usize,
+    pub stale: Vec<StaleEntry>,
+    /// The stale entries were removed from the file.
+    pub applied: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StaleEntry {
+    pub line: usize,
+    pub entry: String,
+}
+
+/// Finds the entries of `contexts` for `partition` that match no path in
+/// `extracted_dir`, removing them with [`PruneOptions::apply`].
+///
+/// Only the partition's marked block is looked at when there is one, and
+/// entries for other partitions are never touched.
+pub fn prune_contexts(extracted_dir: &Path, partition: &str, contexts: &Path, options: PruneOptions) -> Result<PruneReport> {
+    let _lock = options
+        .apply
+        .then(|| {
+            ContextsLock::acquire(contexts, None, || {
+                errln!("Waiting for another run to release {:?}...", lock_path(contexts));
+            })
+        })
+        .transpose()
+        .map_err(|error| Error::Lock { path: lock_path(contexts), error })?;
+    let content = fs::read_to_string(contexts).map_err(|e| Error::read(contexts, e))?;
+    let (bom, body) = split_bom(&content);
+    let lines: Vec<&str> = body.lines().collect();
+    let range = match find_marked_block(&lines, partition).map_err(|e| Error::invalid_file("markers in", contexts, e))? {
+        Some(block) if !options.all_entries => block,
+        _ => 0..lines.len(),
+    };
+
+    let mut report = PruneReport {
+        partition: partition.to_string(),
+        contexts_file: contexts.to_path_buf(),
+        ..Default::default()
+    };
+    // Line indexes of the checked entries, and what they match.
+    let mut candidates: Vec<(usize, Option<FileType>)> = Vec::new();
+    let mut literals: HashMap<String, Vec<usize>> = HashMap::new();
+    let mut patterns = Vec::new();
+    let mut pattern_candidates = Vec::new();
+    for index in range {
+        let ContextLine::Entry(entry) = parse_line(lines[index], index + 1) else {
+            continue;
+        };
+        if !is_for_partition(&entry.pattern, partition) {
+            continue;
+        }
+        let literal = unescape_literal(&entry.pattern);
+        if !options.aggressive && (literal.is_none() || entry.pattern.ends_with(RECURSIVE_SUFFIX)) {
+            report.runtime += 1;
+            continue;
+        }
+        let candidate = candidates.len();
+        match literal {
+            Some(path) => literals.entry(path).or_default().push(candidate),
+            None => {
+                let anchored = format!("^(?:{})$", entry.pattern);
+                if let Err(e) = Regex::new(&anchored) {
+                    errln!("Warning: not checking invalid pattern {} on line {}: {}", entry.pattern, index + 1, e);
+                    continue;
+                }
+                patterns.push(anchored);
+                pattern_candidates.push(candidate);
+            }
+        }
+        candidates.push((index, entry.file_type));
+    }
+    report.checked = candidates.len();
+    let patterns = RegexSet::new(&patterns)?;
+
+    let mut matched = vec![false; candidates.len()];
+    for entry in WalkDir::new(extracted_dir).into_iter().filter_map(|e| e.ok()) {
+        let Ok(relative_path) = entry.path().strip_prefix(extracted_dir) else {
+            continue;
+        };
+        let path = if relative_path.as_os_str().is_empty() {
+            format!("/{}", partition)
+        } else {
+            format!("/{}/{}", partition, slash_separated(&relative_path.to_string_lossy()))
+        };
+        let file_type = FileType::from_fs(&entry.file_type());
+        let hits = literals
+            .get(&path)
+            .into_iter()
+            .flatten()
+            .copied()
+            .chain(patterns.matches(&path).into_iter().map(|index| pattern_candidates[index]));
+        for candidate in hits {
+            if candidates[candidate].1.is_none_or(|wanted| wanted == file_type) {
+                matched[candidate] = true;
+            }
+        }
+    }
+
+    let stale: BTreeSet<usize> = candidates
+        .iter()
+        .zip(&matched)
+        .filter(|(_, matched)| !**matched)
+        .map(|((index, _), _)| *index)
+        .collect();
+    report.stale = stale
+        .iter()
+        .map(|&index| StaleEntry { line: index + 1, entry: lines[index].trim().to_string() })
+        .collect();
+
+    if options.apply && !stale.is_empty() {
+        let newline = LineEnding::detect(content.as_bytes()).as_str();
+        let mut output = String::with_capacity(content.len());
+        output.push_str(bom);
+        for (index, line) in lines.iter().enumerate() {
+            if !stale.contains(&index) {
+                output.push_str(line);
+                output.push_str(newline);
+            }
+        }
+        write_atomic(contexts, output.as_bytes())?;
+        report.applied = true;
+    }
+    Ok(report)
+}
+
+/// Whether `pattern` starts with the partition's mount point, so entries
+/// for other partitions sharing the file are left alone.
+fn is_for_partition(pattern: &str, partition: &str) -> bool {
+    pattern
+        .strip_prefix('/')
+        .and_then(|rest| rest.strip_prefix(partition))
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(['/', '(']))
+}
@@ -0,0 +1,158 @@
+use super::entry::ContextEntry;
+use crate::build_info::BuildInfo;
+use crate::utils::fs_utils::write_atomic;
+use crate::error::{Error, Result};
+use clap::ValueEnum;
+use serde::{Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Summary of one partition's run, returned by `process_file_contexts`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Report {
+    /// Build of the tool that produced the report.
+    pub generator: BuildInfo,
+    pub partition: String,
+    pub contexts_file: PathBuf,
+    pub scanned: usize,
+    pub covered: usize,
+    pub excluded: usize,
+    /// Paths skipped by the built-in list of extraction leftovers.
+    pub default_excluded: usize,
+    /// Directories at `--max-depth` whose contents were not walked.
+    pub depth_limited: usize,
+    pub generated: usize,
+    pub labels: BTreeMap<String, usize>,
+    /// Files and directories that got entries.
+    pub files: usize,
+    pub directories: usize,
+    /// Device nodes, pipes and sockets that got entries.
+    pub special_files: usize,
+    /// Entries per SELinux type, counted as they were labeled and so
+    /// before `--collapse` merges any.
+    pub types: BTreeMap<String, usize>,
+    pub entries: Vec<ContextEntry>,
+    pub skipped: Vec<SkippedPath>,
+    #[serde(serialize_with = "serialize_lossy_paths")]
+    pub unrepresentable: Vec<PathBuf>,
+    /// Paths skipped because their names are not valid UTF-8.
+    #[serde(serialize_with = "serialize_lossy_paths")]
+    pub non_utf8: Vec<PathBuf>,
+    pub threads: Vec<ThreadTiming>,
+    pub elapsed_ms: u64,
+    /// Scanned paths per second over the whole run.
+    pub paths_per_sec: f64,
+    /// The run was stopped early; the counts only cover what was done
+    /// until then.
+    pub interrupted: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SkippedPath {
+    #[serde(serialize_with = "serialize_lossy_path")]
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+/// Work done by one labeling thread.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ThreadTiming {
+    pub thread: usize,
+    pub paths: usize,
+    pub busy_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ReportFormat {
+    Json,
+    Yaml,
+}
+
+/// Paths that are not valid UTF-8 would fail to serialize, so invalid
+/// bytes are replaced by U+FFFD.
+pub(crate) fn serialize_lossy_path<S: Serializer>(path: &Path, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(&path.to_string_lossy())
+}
+
+fn serialize_lossy_paths<S: Serializer>(paths: &[PathBuf], serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_seq(paths.iter().map(|path| path.to_string_lossy()))
+}
+
+impl Report {
+    pub fn record_entries(&mut self, entries: &[ContextEntry]) {
+        self.generated = entries.len();
+        self.labels.clear();
+        for entry in entries {
+            *self.labels.entry(entry.context.to_string()).or_default() += 1;
+        }
+        self.entries = entries.to_vec();
+    }
+
+    pub(crate) fn record_elapsed(&mut self, elapsed: Duration) {
+        self.elapsed_ms = elapsed.as_millis() as u64;
+        self.paths_per_sec = match elapsed.as_secs_f64() {
+            0.0 => 0.0,
+            secs => self.scanned as f64 / secs,
+        };
+    }
+
+    /// The main counts of the run on one line, for `--summary`.
+    pub fn totals(&self) -> String {
+        let skipped = self.skipped.len() + self.unrepresentable.len() + self.non_utf8.len();
+        format!(
+            "{}: {} generated, {} scanned, {} already covered, {} skipped in {:.2}s{}",
+            self.partition,
+            self.generated,
+            self.scanned,
+            self.covered,
+            skipped,
+            self.elapsed_ms as f64 / 1000.0,
+            if self.interrupted { " (interrupted)" } else { "" }
+        )
+    }
+
+    /// The counts of the run as a small two-column table, one row per line.
+    pub fn summary(&self) -> String {
+        let mut rows = vec![
+            ("Scanned".to_string(), self.scanned.to_string()),
+            ("Already covered".to_string(), self.covered.to_string()),
+            ("Generated".to_string(), self.generated.to_string()),
+        ];
+        if self.depth_limited > 0 {
+            rows.insert(2, ("Beyond max depth".to_string(), format!("{} dirs", self.depth_limited)));
+        }
+        rows.extend(self.types.iter().map(|(label, count)| (format!("  {}", label), count.to_string())));
+        rows.extend([
+            ("Files".to_string(), self.files.to_string()),
+            ("Directories".to_string(), self.directories.to_string()),
+        ]);
+        if self.special_files > 0 {
+            rows.push(("Special files".to_string(), self.special_files.to_string()));
+        }
+        rows.extend([
+            ("Elapsed".to_string(), format!("{:.2}s", self.elapsed_ms as f64 / 1000.0)),
+            ("Throughput".to_string(), format!("{:.0} paths/s", self.paths_per_sec)),
+        ]);
+
+        let name_width = rows.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+        let value_width = rows.iter().map(|(_, value)| value.split(' ').next().unwrap_or("").len()).max().unwrap_or(0);
+        let mut table = String::new();
+        for (name, value) in rows {
+            let (number, unit) = value.split_once(' ').map_or((value.as_str(), ""), |(number, unit)| (number, unit));
+            let line = format!("{:<name_width$}  {:>value_width$} {}", name, number, unit);
+            table.push_str(line.trim_end());
+            table.push('\n');
+        }
+        table
+    }
+}
+
+pub fn write_report<T: Serialize>(path: &Path, report: &T, format: ReportFormat) -> Result<()> {
+    let content = match format {
+        ReportFormat::Json => serde_json::to_string_pretty(report).map(|json| json + "\n").map_err(|e| e.to_string()),
+        ReportFormat::Yaml => serde_yaml::to_string(report).map_err(|e| e.to_string()),
+    }
+    .map_err(|message| Error::Serialize { what: "report", message })?;
+    write_atomic(path, content.as_bytes())
+}
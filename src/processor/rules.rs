@@ -0,0 +1,153 @@
+use crate::contexts_file::SecurityContext;
+use crate::error::{Error, Result};
+use crate::utils::fs_utils::slash_separated;
+use globset::{Glob, GlobMatcher};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+use toml::Spanned;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleKind {
+    File,
+    Dir,
+    #[default]
+    Any,
+}
+
+#[derive(Debug, Clone)]
+enum RuleMatcher {
+    Glob(GlobMatcher),
+    Contains(String),
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    line: usize,
+    matcher: RuleMatcher,
+    kind: RuleKind,
+    context: String,
+}
+
+/// Label for files with an extension on vendor-like partitions, from an
+/// `[[extension]]` table. Unlike rules these are applied by the
+/// heuristics, so overlays keep their label.
+#[derive(Debug, Clone)]
+pub(crate) struct ExtensionLabel {
+    pub extension: String,
+    pub context: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RuleSet {
+    rules: Vec<Rule>,
+    extensions: Vec<ExtensionLabel>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    rule: Vec<Spanned<RuleDef>>,
+    #[serde(default)]
+    extension: Vec<Spanned<ExtensionDef>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ExtensionDef {
+    extension: String,
+    context: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleDef {
+    glob: Option<String>,
+    contains: Option<String>,
+    #[serde(default)]
+    kind: RuleKind,
+    context: String,
+}
+
+impl RuleSet {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path).map_err(|e| Error::read(path, e))?;
+        Self::parse(&content).map_err(|e| Error::invalid_file("rules file", path, e))
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        let file: RulesFile = toml::from_str(content)?;
+        let mut rules = Vec::with_capacity(file.rule.len());
+
+        for spanned in file.rule {
+            let line = content[..spanned.span().start].matches('\n').count() + 1;
+            let def = spanned.into_inner();
+
+            let matcher = match (def.glob, def.contains) {
+                (Some(glob), None) => RuleMatcher::Glob(
+                    Glob::new(&glob)
+                        .map_err(|e| Error::InvalidRule { line, message: format!("invalid glob {:?}: {}", glob, e) })?
+                        .compile_matcher(),
+                ),
+                (None, Some(substring)) => RuleMatcher::Contains(substring),
+                _ => {
+                    return Err(Error::InvalidRule {
+                        line,
+                        message: "exactly one of `glob` or `contains` is required".to_string(),
+                    });
+                }
+            };
+
+            SecurityContext::from_label(&def.context, &SecurityContext::default())
+                .map_err(|message| Error::InvalidRule { line, message })?;
+
+            rules.push(Rule {
+                line,
+                matcher,
+                kind: def.kind,
+                context: def.context,
+            });
+        }
+
+        let mut extensions = Vec::with_capacity(file.extension.len());
+        for spanned in file.extension {
+            let line = content[..spanned.span().start].matches('\n').count() + 1;
+            let def = spanned.into_inner();
+            let extension = def.extension.trim_start_matches('.');
+            if extension.is_empty() || extension.contains('/') {
+                return Err(Error::InvalidRule { line, message: format!("invalid extension {:?}", def.extension) });
+            }
+            SecurityContext::from_label(&def.context, &SecurityContext::default())
+                .map_err(|message| Error::InvalidRule { line, message })?;
+            extensions.push(ExtensionLabel { extension: extension.to_string(), context: def.context });
+        }
+
+        Ok(Self { rules, extensions })
+    }
+
+    pub(crate) fn extensions(&self) -> &[ExtensionLabel] {
+        &self.extensions
+    }
+
+    /// Returns the context of the first matching rule and the rule's line in the rules file.
+    pub fn lookup(&self, relative_path: &Path, is_dir: bool) -> Option<(&str, usize)> {
+        self.rules
+            .iter()
+            .find(|rule| {
+                let kind_matches = match rule.kind {
+                    RuleKind::File => !is_dir,
+                    RuleKind::Dir => is_dir,
+                    RuleKind::Any => true,
+                };
+                kind_matches && match &rule.matcher {
+                    RuleMatcher::Glob(glob) => glob.is_match(relative_path),
+                    RuleMatcher::Contains(substring) => relative_path
+                        .to_str()
+                        .is_some_and(|path| slash_separated(path).contains(substring.as_str())),
+                }
+            })
+            .map(|rule| (rule.context.as_str(), rule.line))
+    }
+}
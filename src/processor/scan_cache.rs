@@ -0,0 +1,284 @@
+use crate::config::{FilesystemType, Mode};
+use crate::contexts_file::FileType;
+use crate::utils::fs_utils::write_atomic;
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use crate::progress::reporter::errln;
+use tracing::{debug, info};
+
+const CACHE_VERSION: u32 = 2;
+
+/// A path found below the partition root, relative to it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedPath {
+    pub path: PathBuf,
+    pub mtime: Option<SystemTime>,
+    pub size: u64,
+    pub is_dir: bool,
+    pub file_type: FileType,
+}
+
+/// Children of one directory as listed when its mtime was `mtime`.
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedDir {
+    path: PathBuf,
+    mtime: Option<SystemTime>,
+    children: Vec<CachedPath>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ScanCache {
+    version: u32,
+    root: PathBuf,
+    fstype: String,
+    modes: Vec<String>,
+    dirs: Vec<CachedDir>,
+}
+
+impl ScanCache {
+    fn key_matches(&self, other: &Self) -> bool {
+        self.version == other.version
+            && self.root == other.root
+            && self.fstype == other.fstype
+            && self.modes == other.modes
+    }
+
+    fn root_mtime(&self) -> Option<SystemTime> {
+        self.dirs.first().filter(|dir| dir.path.as_os_str().is_empty()).and_then(|dir| dir.mtime)
+    }
+}
+
+/// Lists every path below `root` in walk order, parents before children.
+///
+/// Directories whose mtime is unchanged since the cached scan are not read
+/// again; their cached children are reused. A different root mtime, fstype
+/// or mode set discards the cache entirely, and an unreadable or corrupt
+/// cache is ignored. The updated scan is written back to `cache_path`.
+pub fn scan_with_cache(
+    root: &Path,
+    cache_path: &Path,
+    fstype: FilesystemType,
+    modes: &[Mode],
+) -> Vec<CachedPath> {
+    scan_counting_reads(root, cache_path, fstype, modes).0
+}
+
+/// [`scan_with_cache`], also returning how many directories were read.
+fn scan_counting_reads(root: &Path, cache_path: &Path, fstype: FilesystemType, modes: &[Mode]) -> (Vec<CachedPath>, usize) {
+    let mut scan = ScanCache {
+        version: CACHE_VERSION,
+        root: root.canonicalize().unwrap_or_else(|_| root.to_path_buf()),
+        fstype: format!("{:?}", fstype),
+        modes: modes.iter().map(|mode| format!("{:?}", mode)).collect(),
+        dirs: Vec::new(),
+    };
+
+    let root_mtime = modified(root);
+    let cached: HashMap<PathBuf, CachedDir> = match load_cache(cache_path) {
+        Some(cache) if !cache.key_matches(&scan) => {
+            info!(cache = ?cache_path, "scan cache was built with different settings, rescanning");
+            HashMap::new()
+        }
+        Some(cache) if root_mtime.is_none() || cache.root_mtime() != root_mtime => {
+            info!(cache = ?cache_path, "partition root changed since the cached scan, rescanning");
+            HashMap::new()
+        }
+        Some(cache) => cache.dirs.into_iter().map(|dir| (dir.path.clone(), dir)).collect(),
+        None => HashMap::new(),
+    };
+
+    let mut paths = Vec::new();
+    let mut reread = 0;
+    visit_dir(root, PathBuf::new(), &cached, &mut scan.dirs, &mut paths, &mut reread);
+    info!(paths = paths.len(), dirs = scan.dirs.len(), reread, "scanned partition with cache");
+
+    if let Err(e) = save_cache(cache_path, &scan) {
+        errln!("Warning: failed to write scan cache {:?}: {:#}", cache_path, e);
+    }
+    (paths, reread)
+}
+
+fn visit_dir(
+    root: &Path,
+    relative_dir: PathBuf,
+    cached: &HashMap<PathBuf, CachedDir>,
+    dirs: &mut Vec<CachedDir>,
+    paths: &mut Vec<CachedPath>,
+    reread: &mut usize,
+) {
+    let full_path = root.join(&relative_dir);
+    let mtime = modified(&full_path);
+
+    let children = match cached.get(&relative_dir) {
+        Some(dir) if mtime.is_some() && dir.mtime == mtime => dir.children.clone(),
+        _ => {
+            debug!(dir = ?relative_dir, "reading directory");
+            *reread += 1;
+            match read_children(&full_path, &relative_dir) {
+                Some(children) => children,
+                // Unreadable directories are skipped, as the uncached walk does.
+                None => return,
+            }
+        }
+    };
+
+    dirs.push(CachedDir { path: relative_dir, mtime, children: children.clone() });
+    for child in children {
+        let descend = child.is_dir.then(|| child.path.clone());
+        paths.push(child);
+        if let Some(child_dir) = descend {
+            visit_dir(root, child_dir, cached, dirs, paths, reread);
+        }
+    }
+}
+
+fn read_children(full_path: &Path, relative_dir: &Path) -> Option<Vec<CachedPath>> {
+    let mut children: Vec<CachedPath> = fs::read_dir(full_path)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let metadata = entry.path().symlink_metadata().ok()?;
+            Some(CachedPath {
+                path: relative_dir.join(entry.file_name()),
+                mtime: metadata.modified().ok(),
+                size: metadata.len(),
+                is_dir: metadata.is_dir(),
+                file_type: FileType::from_fs(&metadata.file_type()),
+            })
+        })
+        .collect();
+    children.sort_by(|a, b| a.path.cmp(&b.path));
+    Some(children)
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    path.symlink_metadata().and_then(|metadata| metadata.modified()).ok()
+}
+
+fn load_cache(path: &Path) -> Option<ScanCache> {
+    let content = fs::read(path).ok()?;
+    match serde_json::from_slice(&content) {
+        Ok(cache) => Some(cache),
+        Err(e) => {
+            errln!("Warning: ignoring corrupt scan cache {:?}: {}", path, e);
+            None
+        }
+    }
+}
+
+fn save_cache(path: &Path, cache: &ScanCache) -> Result<()> {
+    let content = serde_json::to_vec(cache)
+        .map_err(|e| Error::Serialize { what: "scan cache", message: e.to_string() })?;
+    write_atomic(path, &content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::time::{Duration, UNIX_EPOCH};
+    use tempfile::TempDir;
+
+    /// A `vendor` folder with four directories, all with the same mtime,
+    /// and the cache path next to it.
+    fn fixture() -> (TempDir, PathBuf, PathBuf) {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path().join("vendor");
+        for file in ["bin/foo", "etc/foo.xml", "etc/init/foo.rc"] {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        for folder in ["", "bin", "etc", "etc/init"] {
+            set_mtime(&root.join(folder), 1_000);
+        }
+        let cache_path = dir.path().join("scan-cache.json");
+        (dir, root, cache_path)
+    }
+
+    fn set_mtime(path: &Path, seconds: u64) {
+        File::open(path).unwrap().set_modified(UNIX_EPOCH + Duration::from_secs(seconds)).unwrap();
+    }
+
+    fn scan(root: &Path, cache_path: &Path, fstype: FilesystemType, modes: &[Mode]) -> (Vec<(PathBuf, FileType)>, usize) {
+        let (paths, reread) = scan_counting_reads(root, cache_path, fstype, modes);
+        (paths.into_iter().map(|cached| (cached.path, cached.file_type)).collect(), reread)
+    }
+
+    /// The paths below `root` as a scan without a cache lists them.
+    fn fresh_walk(root: &Path) -> Vec<(PathBuf, FileType)> {
+        let dir = TempDir::new().unwrap();
+        let (paths, reread) = scan(root, &dir.path().join("scan-cache.json"), FilesystemType::Ext4, &[Mode::All]);
+        assert_eq!(reread, paths.iter().filter(|(_, file_type)| *file_type == FileType::Directory).count() + 1);
+        paths
+    }
+
+    #[test]
+    fn unchanged_partitions_are_not_read_again() {
+        let (_dir, root, cache_path) = fixture();
+        let (paths, reread) = scan(&root, &cache_path, FilesystemType::Ext4, &[Mode::All]);
+        assert_eq!((paths.len(), reread), (6, 4));
+        assert_eq!(paths, fresh_walk(&root));
+
+        assert_eq!(scan(&root, &cache_path, FilesystemType::Ext4, &[Mode::All]), (paths, 0));
+    }
+
+    #[test]
+    fn only_changed_directories_are_read_again() {
+        let (_dir, root, cache_path) = fixture();
+        scan(&root, &cache_path, FilesystemType::Ext4, &[Mode::All]);
+
+        // Adding a file changes the mtime of its folder alone.
+        fs::write(root.join("etc/bar.xml"), "").unwrap();
+        set_mtime(&root.join("etc"), 2_000);
+        let (paths, reread) = scan(&root, &cache_path, FilesystemType::Ext4, &[Mode::All]);
+        assert_eq!(reread, 1);
+        assert!(paths.contains(&(PathBuf::from("etc/bar.xml"), FileType::Regular)));
+        assert_eq!(paths, fresh_walk(&root));
+
+        fs::remove_file(root.join("etc/init/foo.rc")).unwrap();
+        set_mtime(&root.join("etc/init"), 2_000);
+        let (paths, reread) = scan(&root, &cache_path, FilesystemType::Ext4, &[Mode::All]);
+        assert_eq!(reread, 1);
+        assert_eq!(paths, fresh_walk(&root));
+    }
+
+    #[test]
+    fn other_settings_or_a_changed_root_read_everything() {
+        let (_dir, root, cache_path) = fixture();
+        let full_scan = |fstype, modes: &[Mode]| {
+            let (paths, reread) = scan(&root, &cache_path, fstype, modes);
+            assert_eq!(paths, fresh_walk(&root));
+            reread
+        };
+        assert_eq!(full_scan(FilesystemType::Ext4, &[Mode::All]), 4);
+
+        assert_eq!(full_scan(FilesystemType::Erofs, &[Mode::All]), 4);
+        assert_eq!(full_scan(FilesystemType::Erofs, &[Mode::Bin, Mode::Etc]), 4);
+        assert_eq!(full_scan(FilesystemType::Erofs, &[Mode::Bin, Mode::Etc]), 0);
+
+        // A changed root discards the cache even for untouched folders.
+        fs::write(root.join("build.prop"), "").unwrap();
+        set_mtime(&root, 2_000);
+        assert_eq!(full_scan(FilesystemType::Erofs, &[Mode::Bin, Mode::Etc]), 4);
+    }
+
+    #[test]
+    fn corrupt_caches_are_ignored_and_replaced() {
+        let (_dir, root, cache_path) = fixture();
+        scan(&root, &cache_path, FilesystemType::Ext4, &[Mode::All]);
+        let content = fs::read(&cache_path).unwrap();
+
+        for corrupt in [&content[..content.len() / 2], b"not json", b""] {
+            fs::write(&cache_path, corrupt).unwrap();
+            let (paths, reread) = scan(&root, &cache_path, FilesystemType::Ext4, &[Mode::All]);
+            assert_eq!(reread, 4);
+            assert_eq!(paths, fresh_walk(&root));
+            assert_eq!(scan(&root, &cache_path, FilesystemType::Ext4, &[Mode::All]).1, 0);
+        }
+    }
+}
@@ -0,0 +1,391 @@
+use crate::contexts_file::{parse_line, ContextLine, FileType, ParsedEntry};
+use crate::error::Result;
+use crate::progress::reporter::errln;
+use crate::utils::fs_utils::{entry_path, slash_separated};
+use crate::utils::regex_utils::{literal_prefix, unescape_literal};
+use super::entry::ContextEntry;
+use regex::{Regex, RegexSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// A generated entry and an existing entry with another context whose
+/// order decides the label of a path where the last match applies.
+#[derive(Debug, Clone)]
+pub struct OrderConflict {
+    /// Line of the generated entry, 1-based.
+    pub line: usize,
+    pub entry: String,
+    pub stock_line: usize,
+    pub stock_entry: String,
+}
+
+/// How the generated entries of a written contexts file are ordered
+/// against the entries that were already there.
+#[derive(Debug, Clone, Default)]
+pub struct Ordering {
+    /// Existing entries after a generated one that also match its path, and
+    /// so keep their label for it.
+    pub shadowed: Vec<OrderConflict>,
+    /// Existing entries before a generated recursive or wildcard entry that
+    /// also matches their paths, and so lose their label.
+    pub overridden: Vec<OrderConflict>,
+    /// Generated entries after existing ones matching their path with
+    /// another context, which they replace as intended.
+    pub overriding: usize,
+}
+
+/// The existing entries of a contexts file, looked up by the paths they
+/// match.
+struct StockIndex {
+    entries: Vec<ParsedEntry>,
+    literals: HashMap<String, Vec<usize>>,
+    patterns: RegexSet,
+    /// Index into `entries` of each pattern of the set.
+    pattern_entries: Vec<usize>,
+    /// The patterns keyed by their literal start, see [`literal_prefix`].
+    pattern_prefixes: BTreeMap<String, Vec<usize>>,
+}
+
+impl StockIndex {
+    fn new(entries: Vec<ParsedEntry>) -> Result<Self> {
+        let mut literals: HashMap<String, Vec<usize>> = HashMap::new();
+        let mut patterns = Vec::new();
+        let mut pattern_entries = Vec::new();
+        let mut pattern_prefixes: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+        for (index, entry) in entries.iter().enumerate() {
+            match unescape_literal(&entry.pattern) {
+                Some(path) => literals.entry(path).or_default().push(index),
+                // Invalid patterns are already reported when the existing
+                // contexts are read.
+                None if let Some(anchored) = anchored(&entry.pattern) => {
+                    patterns.push(anchored);
+                    pattern_entries.push(index);
+                    pattern_prefixes.entry(literal_prefix(&entry.pattern)).or_default().push(index);
+                }
+                None => {}
+            }
+        }
+        Ok(Self { entries, literals, patterns: RegexSet::new(patterns)?, pattern_entries, pattern_prefixes })
+    }
+
+    /// The entries matching the path `entry` was generated for.
+    fn matching_source(&self, entry: &ContextEntry, mount_point: &str) -> Vec<&ParsedEntry> {
+        let path = source_path(entry, mount_point);
+        let file_type = source_file_type(entry);
+        self.literals
+            .get(&path)
+            .into_iter()
+            .flatten()
+            .copied()
+            .chain(self.patterns.matches(&path).into_iter().map(|index| self.pattern_entries[index]))
+            .map(|index| &self.entries[index])
+            .filter(|stock| stock.file_type.is_none_or(|wanted| wanted == file_type))
+            .collect()
+    }
+
+    /// For each of `entries`, the existing entries for paths its pattern
+    /// also matches, which only recursive and wildcard patterns do: single
+    /// paths it matches, and patterns whose literal start lies below its own.
+    fn covered_by(&self, entries: &[&ContextEntry]) -> Result<Vec<Vec<&ParsedEntry>>> {
+        let mut covered = vec![Vec::new(); entries.len()];
+        let mut patterns = Vec::new();
+        let mut pattern_entries = Vec::new();
+        for (index, entry) in entries.iter().enumerate() {
+            if unescape_literal(&entry.pattern).is_none()
+                && let Some(anchored) = anchored(&entry.pattern)
+            {
+                patterns.push(anchored);
+                pattern_entries.push(index);
+            }
+        }
+        if patterns.is_empty() {
+            return Ok(covered);
+        }
+        for &index in &pattern_entries {
+            let mut below = literal_prefix(&entries[index].pattern);
+            if below.is_empty() {
+                continue;
+            }
+            if !below.ends_with('/') {
+                below.push('/');
+            }
+            let entry_type = entries[index].file_type;
+            covered[index].extend(
+                self.pattern_prefixes
+                    .range(below.clone()..)
+                    .take_while(|(prefix, _)| prefix.starts_with(&below))
+                    .flat_map(|(_, stock)| stock.iter().map(|&stock| &self.entries[stock]))
+                    .filter(|stock| types_overlap(stock.file_type, entry_type)),
+            );
+        }
+        let patterns = RegexSet::new(patterns)?;
+        for (path, stock) in &self.literals {
+            for index in patterns.matches(path).into_iter().map(|index| pattern_entries[index]) {
+                let entry_type = entries[index].file_type;
+                covered[index].extend(
+                    stock
+                        .iter()
+                        .map(|&stock| &self.entries[stock])
+                        .filter(|stock| types_overlap(stock.file_type, entry_type)),
+                );
+            }
+        }
+        Ok(covered)
+    }
+}
+
+/// Finds the generated `entries` among `lines` of a written contexts file
+/// and checks how they are ordered against the existing entries that
+/// overlap them with another context.
+pub fn check_ordering(lines: &[&str], entries: &[ContextEntry], mount_point: &str) -> Result<Ordering> {
+    let generated: HashMap<String, &ContextEntry> = entries.iter().map(|entry| (entry.to_string(), entry)).collect();
+    let mut placed: Vec<(usize, &ContextEntry)> = Vec::new();
+    let mut stock = Vec::new();
+    let mut seen = HashSet::new();
+    // Backwards, so a generated entry repeating an existing one is its last
+    // copy.
+    for (index, line) in lines.iter().enumerate().rev() {
+        let ContextLine::Entry(parsed) = parse_line(line, index + 1) else {
+            continue;
+        };
+        // Fields are compared with single spaces, so aligned lines match.
+        let normalized = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        match generated.get(&normalized) {
+            Some(entry) if seen.insert(normalized.clone()) => placed.push((parsed.line, *entry)),
+            _ => stock.push(parsed),
+        }
+    }
+    placed.reverse();
+    stock.reverse();
+    let stock = StockIndex::new(stock)?;
+    let covered = stock.covered_by(&placed.iter().map(|(_, entry)| *entry).collect::<Vec<_>>())?;
+
+    let mut ordering = Ordering::default();
+    for ((line, entry), covered) in placed.iter().zip(covered) {
+        let context = entry.context.to_string();
+        let conflict = |stock: &ParsedEntry| OrderConflict {
+            line: *line,
+            entry: entry.to_string(),
+            stock_line: stock.line,
+            stock_entry: format!("{} {}", stock.pattern, stock.context),
+        };
+        let mut overriding = false;
+        for stock in stock.matching_source(entry, mount_point) {
+            if stock.context == context {
+                continue;
+            }
+            if stock.line < *line {
+                overriding = true;
+            } else {
+                ordering.shadowed.push(conflict(stock));
+            }
+        }
+        ordering.overriding += usize::from(overriding);
+        ordering.overridden.extend(
+            covered
+                .into_iter()
+                .filter(|stock| stock.line < *line && stock.context != context)
+                .map(conflict),
+        );
+    }
+    ordering.overridden.sort_by_key(|conflict| (conflict.line, conflict.stock_line));
+    Ok(ordering)
+}
+
+/// `lines` with each of `entries` placed after the last existing entry
+/// matching its path, so it takes precedence where the last match applies,
+/// and before the first existing entry for paths its pattern covers, so
+/// that one keeps its label. Entries that overlap nothing go at the end.
+/// `format` gives the line of each entry.
+pub fn insert_sorted(
+    lines: &[&str],
+    entries: &[ContextEntry],
+    mount_point: &str,
+    format: impl Fn(&ContextEntry) -> String,
+) -> Result<Vec<String>> {
+    let stock: Vec<ParsedEntry> = lines
+        .iter()
+        .enumerate()
+        .filter_map(|(index, line)| match parse_line(line, index + 1) {
+            ContextLine::Entry(entry) => Some(entry),
+            _ => None,
+        })
+        .collect();
+    let stock = StockIndex::new(stock)?;
+    let covered = stock.covered_by(&entries.iter().collect::<Vec<_>>())?;
+
+    let mut after_line: HashMap<usize, Vec<String>> = HashMap::new();
+    let mut before_line: HashMap<usize, Vec<String>> = HashMap::new();
+    let mut at_end = Vec::new();
+    for (entry, covered) in entries.iter().zip(covered) {
+        // Entries with the same context label alike in any order.
+        let context = entry.context.to_string();
+        let last_matching = stock
+            .matching_source(entry, mount_point)
+            .iter()
+            .filter(|stock| stock.context != context)
+            .map(|stock| stock.line)
+            .max();
+        let first_covered = covered.iter().filter(|stock| stock.context != context).map(|stock| stock.line).min();
+        match (last_matching, first_covered) {
+            // When both cannot hold, the entry's own path wins.
+            (Some(last), _) => after_line.entry(last).or_default().push(format(entry)),
+            (None, Some(first)) => before_line.entry(first).or_default().push(format(entry)),
+            (None, None) => at_end.push(format(entry)),
+        }
+    }
+
+    let mut output = Vec::with_capacity(lines.len() + entries.len());
+    for (index, line) in lines.iter().enumerate() {
+        output.extend(before_line.remove(&(index + 1)).unwrap_or_default());
+        output.push(line.to_string());
+        output.extend(after_line.remove(&(index + 1)).unwrap_or_default());
+    }
+    output.extend(at_end);
+    Ok(output)
+}
+
+pub fn report_ordering(ordering: &Ordering) {
+    if !ordering.shadowed.is_empty() {
+        errln!(
+            "Warning: {} existing entries after generated ones also match their paths with another label, and win \
+             where the last match applies:",
+            ordering.shadowed.len()
+        );
+        for conflict in &ordering.shadowed {
+            errln!("  line {}: {} is shadowed by line {}: {}", conflict.line, conflict.entry, conflict.stock_line, conflict.stock_entry);
+        }
+    }
+    if !ordering.overridden.is_empty() {
+        errln!(
+            "Warning: {} existing entries before generated ones are also matched by them with another label, and \
+             lose it where the last match applies:",
+            ordering.overridden.len()
+        );
+        for conflict in &ordering.overridden {
+            errln!("  line {}: {} overrides line {}: {}", conflict.line, conflict.entry, conflict.stock_line, conflict.stock_entry);
+        }
+    }
+    if !ordering.shadowed.is_empty() || !ordering.overridden.is_empty() {
+        errln!("Pass --insert-sorted to place generated entries next to the existing entries they overlap.");
+    }
+}
+
+fn types_overlap(stock: Option<FileType>, entry: Option<FileType>) -> bool {
+    match (stock, entry) {
+        (Some(stock), Some(entry)) => stock == entry,
+        _ => true,
+    }
+}
+
+fn anchored(pattern: &str) -> Option<String> {
+    let anchored = format!("^(?:{})$", pattern);
+    Regex::new(&anchored).is_ok().then_some(anchored)
+}
+
+fn source_path(entry: &ContextEntry, mount_point: &str) -> String {
+    entry_path(mount_point, &slash_separated(&entry.source.to_string_lossy()))
+}
+
+fn source_file_type(entry: &ContextEntry) -> FileType {
+    match entry.file_type {
+        Some(file_type) => file_type,
+        None if entry.is_dir => FileType::Directory,
+        None => FileType::Regular,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::LabelReason;
+    use std::path::PathBuf;
+
+    fn generated(pattern: &str, source: &str, label: &str) -> ContextEntry {
+        ContextEntry {
+            pattern: pattern.to_string(),
+            file_type: None,
+            context: format!("u:object_r:{}:s0", label).parse().unwrap(),
+            is_dir: pattern.ends_with("(/.*)?"),
+            source: PathBuf::from(source),
+            reason: LabelReason::Heuristic("test"),
+        }
+    }
+
+    #[test]
+    fn recursive_entry_after_stock_regex_is_reported() {
+        let lines = [
+            "/vendor/lib64/hw(/.*)? u:object_r:same_process_hal_file:s0",
+            "/vendor/lib64(/.*)? u:object_r:vendor_file:s0",
+        ];
+        let entries = [generated("/vendor/lib64(/.*)?", "lib64", "vendor_file")];
+        let ordering = check_ordering(&lines, &entries, "/vendor").unwrap();
+        assert!(ordering.shadowed.is_empty());
+        assert_eq!(ordering.overridden.len(), 1);
+        assert_eq!((ordering.overridden[0].line, ordering.overridden[0].stock_line), (2, 1));
+    }
+
+    #[test]
+    fn recursive_entry_after_stock_literal_is_reported() {
+        let lines = [
+            r"/vendor/etc/camera/x\.xml u:object_r:camera_file:s0",
+            "/vendor/etc/camera(/.*)? u:object_r:vendor_configs_file:s0",
+        ];
+        let entries = [generated("/vendor/etc/camera(/.*)?", "etc/camera", "vendor_configs_file")];
+        let ordering = check_ordering(&lines, &entries, "/vendor").unwrap();
+        assert_eq!(ordering.overridden.len(), 1);
+    }
+
+    #[test]
+    fn later_stock_entry_shadows_a_marked_block() {
+        let lines = [
+            "# BEGIN gen_file-context (vendor)",
+            "/vendor/foo u:object_r:foo_file:s0",
+            "# END gen_file-context (vendor)",
+            "/vendor(/.*)? u:object_r:vendor_file:s0",
+        ];
+        let entries = [generated("/vendor/foo", "foo", "foo_file")];
+        let ordering = check_ordering(&lines, &entries, "/vendor").unwrap();
+        assert_eq!(ordering.shadowed.len(), 1);
+        assert_eq!((ordering.shadowed[0].line, ordering.shadowed[0].stock_line), (2, 4));
+        assert_eq!(ordering.overriding, 0);
+    }
+
+    #[test]
+    fn same_label_is_no_conflict() {
+        let lines = ["/vendor/foo u:object_r:vendor_file:s0", "/vendor(/.*)? u:object_r:vendor_file:s0"];
+        let entries = [generated("/vendor/foo", "foo", "vendor_file")];
+        let ordering = check_ordering(&lines, &entries, "/vendor").unwrap();
+        assert!(ordering.shadowed.is_empty() && ordering.overridden.is_empty());
+    }
+
+    #[test]
+    fn insert_sorted_places_entries_between_general_and_specific_stock_entries() {
+        let stock = [
+            "/vendor(/.*)? u:object_r:vendor_file:s0",
+            "/vendor/lib64/hw(/.*)? u:object_r:same_process_hal_file:s0",
+            "# device entries",
+        ];
+        let entries = [
+            generated("/vendor/lib64(/.*)?", "lib64", "system_lib_file"),
+            generated(r"/vendor/etc/x\.xml", "etc/x.xml", "vendor_configs_file"),
+            generated(r"/vendor/lib64/hw/y\.so", "lib64/hw/y.so", "same_process_hal_file"),
+        ];
+        let output = insert_sorted(&stock, &entries, "/vendor", ToString::to_string).unwrap();
+        assert_eq!(
+            output,
+            [
+                "/vendor(/.*)? u:object_r:vendor_file:s0",
+                "/vendor/lib64(/.*)? u:object_r:system_lib_file:s0",
+                r"/vendor/etc/x\.xml u:object_r:vendor_configs_file:s0",
+                r"/vendor/lib64/hw/y\.so u:object_r:same_process_hal_file:s0",
+                "/vendor/lib64/hw(/.*)? u:object_r:same_process_hal_file:s0",
+                "# device entries",
+            ]
+        );
+
+        let lines: Vec<&str> = output.iter().map(String::as_str).collect();
+        let ordering = check_ordering(&lines, &entries, "/vendor").unwrap();
+        assert!(ordering.shadowed.is_empty() && ordering.overridden.is_empty());
+        assert_eq!(ordering.overriding, 3);
+    }
+}
@@ -0,0 +1,76 @@
+use crate::contexts_file::{parse_contexts, ContextLine, SecurityContext};
+use crate::error::{Error, Result};
+use crate::utils::fs_utils::{entry_path, slash_separated};
+use crate::utils::regex_utils::unescape_literal;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// The original labels of a stock image, read from the `*_file_contexts`
+/// file extraction tools write next to the extracted tree.
+///
+/// Only literal paths are kept, e.g. `/vendor/bin/hw/foo\@1\.0 u:object_r:...`;
+/// regex patterns such as `/vendor(/.*)?` cannot name a single path and
+/// are skipped.
+#[derive(Debug, Clone, Default)]
+pub struct SourceContexts {
+    /// Context and line, keyed by the path without its leading `/`.
+    labels: HashMap<String, (String, usize)>,
+}
+
+impl SourceContexts {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path).map_err(|e| Error::read(path, e))?;
+        Self::parse(&content).map_err(|message| Error::invalid_file("source contexts file", path, message))
+    }
+
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let mut labels = HashMap::new();
+
+        for (index, line) in parse_contexts(content).into_iter().enumerate() {
+            let entry = match line {
+                ContextLine::Entry(entry) => entry,
+                ContextLine::Invalid(line) if is_fs_config_line(&line) => {
+                    return Err(
+                        "this is an fs_config file (path uid gid mode capabilities), which has no SELinux contexts; \
+                         pass the file_contexts file written by the extraction tool instead"
+                            .to_string(),
+                    );
+                }
+                ContextLine::Invalid(line) => {
+                    return Err(format!("line {}: expected `path context`, found {:?}", index + 1, line));
+                }
+                ContextLine::Blank | ContextLine::Comment(_) => continue,
+            };
+            entry
+                .context
+                .parse::<SecurityContext>()
+                .map_err(|message| format!("line {}: {}", entry.line, message))?;
+            let Some(path) = unescape_literal(&entry.pattern) else {
+                continue;
+            };
+            // Like file_contexts, the last entry for a path wins.
+            labels.insert(path.trim_start_matches('/').to_string(), (entry.context, entry.line));
+        }
+
+        Ok(Self { labels })
+    }
+
+    /// The original context of `relative_path` below `mount_point`, and its
+    /// line in the source file.
+    pub fn lookup(&self, mount_point: &str, relative_path: &Path) -> Option<(&str, usize)> {
+        if self.labels.is_empty() {
+            return None;
+        }
+        let path = entry_path(mount_point, &slash_separated(relative_path.to_str()?));
+        self.labels.get(path.trim_start_matches('/')).map(|(context, line)| (context.as_str(), *line))
+    }
+}
+
+/// `system/bin/sh 0 2000 0755 0`: numeric owner and group, then an octal mode.
+fn is_fs_config_line(line: &str) -> bool {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    fields.len() >= 4
+        && fields[1..3].iter().all(|field| field.parse::<u32>().is_ok())
+        && u32::from_str_radix(fields[3], 8).is_ok()
+}
@@ -0,0 +1,28 @@
+use super::entry::ContextEntry;
+use crate::utils::fs_utils::{entry_path, slash_separated};
+use regex::Regex;
+
+pub struct ValidationFailure<'a> {
+    pub entry: &'a ContextEntry,
+    pub path: String,
+    pub reason: String,
+}
+
+/// Checks that each entry is an absolute pattern matching the path it was
+/// generated for below `mount_point`.
+pub fn validate_entries<'a>(entries: &'a [ContextEntry], mount_point: &str) -> Vec<ValidationFailure<'a>> {
+    entries
+        .iter()
+        .filter_map(|entry| {
+            let path = entry_path(mount_point, &slash_separated(&entry.source.to_string_lossy()));
+
+            let reason = match Regex::new(&format!("^(?:{})$", entry.pattern)) {
+                _ if !entry.pattern.starts_with('/') => "pattern does not start with '/'".to_string(),
+                Ok(regex) if regex.is_match(&path) => return None,
+                Ok(_) => "pattern does not match its source path".to_string(),
+                Err(e) => format!("pattern does not compile: {}", e),
+            };
+            Some(ValidationFailure { entry, path, reason })
+        })
+        .collect()
+}
@@ -0,0 +1,66 @@
+use crate::contexts_file::{find_conflicts, read_contexts_file, report_conflicts, ContextLine, FileType};
+use super::matcher::ContextMatcher;
+use super::overrides::OVERRIDE_FILE_NAME;
+use crate::error::Result;
+use crate::utils::fs_utils::slash_separated;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+#[derive(Debug, Default, Serialize)]
+pub struct VerifyReport {
+    pub partition: String,
+    pub checked: usize,
+    pub unmatched: Vec<String>,
+    pub conflicts: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_complete(&self) -> bool {
+        self.unmatched.is_empty()
+    }
+}
+
+/// Checks every path in the partition, root included, against the patterns
+/// of all given contexts files, honoring their type fields.
+pub fn verify_partition(extracted_dir: &Path, partition: &str, contexts: &[PathBuf]) -> Result<VerifyReport> {
+    let mut entries = Vec::new();
+    for path in contexts {
+        entries.extend(read_contexts_file(path)?.into_iter().filter_map(|line| match line {
+            ContextLine::Entry(entry) => Some((path.as_path(), entry)),
+            _ => None,
+        }));
+    }
+    let matcher = ContextMatcher::new(entries.iter().map(|(_, entry)| (entry.pattern.as_str(), entry.file_type)))?;
+
+    let conflicts = find_conflicts(entries.iter().map(|(path, entry)| (*path, entry)));
+    report_conflicts(&conflicts);
+
+    let mut report = VerifyReport {
+        partition: partition.to_string(),
+        conflicts: conflicts.iter().map(|conflict| conflict.pattern.to_string()).collect(),
+        ..Default::default()
+    };
+
+    for entry in WalkDir::new(extracted_dir).into_iter().filter_map(|e| e.ok()) {
+        // Override files never get entries of their own.
+        if entry.file_type().is_file() && entry.file_name() == OVERRIDE_FILE_NAME {
+            continue;
+        }
+        let Ok(relative_path) = entry.path().strip_prefix(extracted_dir) else {
+            continue;
+        };
+        let path = if relative_path.as_os_str().is_empty() {
+            format!("/{}", partition)
+        } else {
+            format!("/{}/{}", partition, slash_separated(&relative_path.to_string_lossy()))
+        };
+
+        report.checked += 1;
+        if !matcher.is_match(&path, Some(FileType::from_fs(&entry.file_type()))) {
+            report.unmatched.push(path);
+        }
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,366 @@
+use crate::config::{Config, Mode};
+use crate::contexts_file::FileType;
+use super::filter::{PathFilter, Pruned};
+use super::overrides::OVERRIDE_FILE_NAME;
+use super::path_list::PathList;
+use super::scan_cache::scan_with_cache;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// A path queued for labeling, with the file type seen during the walk so
+/// it does not have to be looked up again.
+#[derive(Debug, Clone)]
+pub struct WalkedPath {
+    pub relative_path: PathBuf,
+    pub file_type: FileType,
+}
+
+impl WalkedPath {
+    pub fn new(relative_path: &Path, file_type: FileType) -> Self {
+        Self { relative_path: relative_path.to_path_buf(), file_type }
+    }
+
+    /// Everything but directories gets a single entry.
+    pub fn is_file_like(&self) -> bool {
+        self.file_type != FileType::Directory
+    }
+}
+
+/// Paths pruned during a walk, directories counting once for everything
+/// below them.
+#[derive(Debug, Default)]
+pub struct Excluded {
+    /// By `--exclude` or the ignore file.
+    pub filtered: usize,
+    /// By the built-in list of extraction leftovers.
+    pub by_default: usize,
+    /// Non-empty directories at `--max-depth`, whose contents were not
+    /// walked.
+    pub depth_limited: usize,
+    /// Symlinks not followed with `--follow-symlinks` because they point
+    /// back to one of their parents, with that parent.
+    pub symlink_loops: Vec<(PathBuf, PathBuf)>,
+    /// `.file_contexts_override` files, which are read instead of visited.
+    pub override_files: Vec<PathBuf>,
+    /// Paths that could not be read, like folders without permission to
+    /// list them. With `--strict` the walk stops at the first one.
+    pub errors: Vec<(PathBuf, io::Error)>,
+}
+
+impl Excluded {
+    fn count(&mut self, pruned: Pruned) {
+        match pruned {
+            Pruned::Filtered => self.filtered += 1,
+            Pruned::Default => self.by_default += 1,
+        }
+    }
+}
+
+/// Walks the partition and calls `visit` with every path that passes the
+/// mode and path filters, relative to the partition root, along with the
+/// file type from the directory listing. Returns how many paths were pruned.
+///
+/// With a `--paths-from` list only the listed paths are visited. Symlinks to
+/// directories are only followed with `--follow-symlinks`, the paths below
+/// them being visited under the link, and nothing deeper than `--max-depth`
+/// is visited.
+pub fn walk_partition(
+    config: &Config,
+    filter: &PathFilter,
+    listed: Option<&PathList>,
+    mut visit: impl FnMut(&Path, FileType) -> ControlFlow<()>,
+) -> Excluded {
+    let mut excluded = Excluded::default();
+    // Directories at the depth limit with listed or cached paths below them.
+    let mut limited_dirs: HashSet<PathBuf> = HashSet::new();
+
+    let mut override_files = Vec::new();
+    let mut accept = |relative_path: &Path, file_type: FileType| {
+        if file_type == FileType::Regular && relative_path.file_name().is_some_and(|name| name == OVERRIDE_FILE_NAME) {
+            override_files.push(relative_path.to_path_buf());
+            return ControlFlow::Continue(());
+        }
+        if config.skip_symlinks && file_type == FileType::Symlink {
+            return ControlFlow::Continue(());
+        }
+        if filter.ignore_file() == Some(config.extracted_dir.join(relative_path).as_path()) {
+            return ControlFlow::Continue(());
+        }
+        if relative_path.as_os_str().is_empty() || !filter.is_included(relative_path) {
+            return ControlFlow::Continue(());
+        }
+        if config.modes.iter().any(|&mode| mode_matches(mode, relative_path, config.nested_bin)) {
+            return visit(relative_path, file_type);
+        }
+        ControlFlow::Continue(())
+    };
+
+    if let Some(listed) = listed {
+        for walked in listed.paths() {
+            let is_dir = walked.file_type == FileType::Directory;
+            let pruned = filter.prunes(&walked.relative_path, is_dir).or_else(|| {
+                walked.relative_path.ancestors().skip(1).find_map(|parent| {
+                    if parent.as_os_str().is_empty() { None } else { filter.prunes(parent, true) }
+                })
+            });
+            if let Some(pruned) = pruned {
+                excluded.count(pruned);
+                continue;
+            }
+            if let Some(dir) = depth_limited_dir(config, &walked.relative_path) {
+                limited_dirs.insert(dir);
+                continue;
+            }
+            if accept(&walked.relative_path, walked.file_type).is_break() {
+                break;
+            }
+        }
+        excluded.depth_limited = limited_dirs.len();
+        excluded.override_files = override_files;
+        return excluded;
+    }
+
+    if let Some(cache_path) = &config.cache {
+        let mut pruned: HashSet<PathBuf> = HashSet::new();
+        for cached in scan_with_cache(&config.extracted_dir, cache_path, config.fstype, &config.modes) {
+            // Cached paths come parents first, so a pruned directory is seen
+            // before anything below it.
+            if cached.path.parent().is_some_and(|parent| pruned.contains(parent)) {
+                if cached.is_dir {
+                    pruned.insert(cached.path);
+                }
+                continue;
+            }
+            if let Some(reason) = filter.prunes(&cached.path, cached.is_dir) {
+                excluded.count(reason);
+                if cached.is_dir {
+                    pruned.insert(cached.path);
+                }
+                continue;
+            }
+            if let Some(dir) = depth_limited_dir(config, &cached.path) {
+                limited_dirs.insert(dir);
+                continue;
+            }
+            if accept(&cached.path, cached.file_type).is_break() {
+                break;
+            }
+        }
+        excluded.depth_limited = limited_dirs.len();
+        excluded.override_files = override_files;
+        return excluded;
+    }
+
+    // Pruning here keeps excluded and ignored directories from being traversed at all.
+    let walker = WalkDir::new(&config.extracted_dir)
+        .min_depth(1)
+        .max_depth(config.max_depth.unwrap_or(usize::MAX))
+        .follow_links(config.follow_symlinks)
+        .into_iter()
+        .filter_entry(|entry| {
+            let relative_path = entry.path().strip_prefix(&config.extracted_dir).unwrap_or(entry.path());
+            if entry.depth() > 0 && let Some(pruned) = filter.prunes(relative_path, entry.file_type().is_dir()) {
+                excluded.count(pruned);
+                return false;
+            }
+            true
+        });
+
+    let mut depth_limited = 0;
+    let mut symlink_loops = Vec::new();
+    let mut errors = Vec::new();
+    for entry in walker {
+        let entry = match entry {
+            Ok(entry) => entry,
+            // Links that loop back or lead nowhere are labeled as links.
+            Err(error) => {
+                if let Some(relative_path) = unfollowed_link(config, &error, &mut symlink_loops) {
+                    if filter.prunes(relative_path, false).is_none() && accept(relative_path, FileType::Symlink).is_break() {
+                        break;
+                    }
+                    continue;
+                }
+                let relative_path = error
+                    .path()
+                    .map(|path| path.strip_prefix(&config.extracted_dir).unwrap_or(path).to_path_buf())
+                    .unwrap_or_default();
+                errors.push((relative_path, io::Error::from(error)));
+                if config.strict {
+                    break;
+                }
+                continue;
+            }
+        };
+        if config.max_depth == Some(entry.depth())
+            && entry.file_type().is_dir()
+            && fs::read_dir(entry.path()).is_ok_and(|mut contents| contents.next().is_some())
+        {
+            depth_limited += 1;
+        }
+        if let Ok(relative_path) = entry.path().strip_prefix(&config.extracted_dir)
+            && accept(relative_path, FileType::from_fs(&entry.file_type())).is_break()
+        {
+            break;
+        }
+    }
+    excluded.depth_limited = depth_limited;
+    excluded.symlink_loops = symlink_loops;
+    excluded.override_files = override_files;
+    excluded.errors = errors;
+    excluded
+}
+
+/// The symlink a walk error with `--follow-symlinks` is about, relative to
+/// the partition root, recording it in `loops` when it points back to one
+/// of its parents. Other errors are not about links.
+fn unfollowed_link<'a>(config: &Config, error: &'a walkdir::Error, loops: &mut Vec<(PathBuf, PathBuf)>) -> Option<&'a Path> {
+    let relative_path = error.path()?.strip_prefix(&config.extracted_dir).ok()?;
+    if let Some(ancestor) = error.loop_ancestor() {
+        let ancestor = ancestor.strip_prefix(&config.extracted_dir).unwrap_or(ancestor);
+        loops.push((relative_path.to_path_buf(), ancestor.to_path_buf()));
+        return Some(relative_path);
+    }
+    let metadata = fs::symlink_metadata(config.extracted_dir.join(relative_path)).ok()?;
+    metadata.file_type().is_symlink().then_some(relative_path)
+}
+
+/// The ancestor of `relative_path` at `--max-depth`, when the path lies
+/// below it.
+fn depth_limited_dir(config: &Config, relative_path: &Path) -> Option<PathBuf> {
+    let max_depth = config.max_depth?;
+    let depth = relative_path.components().count();
+    (depth > max_depth).then(|| relative_path.components().take(max_depth).collect())
+}
+
+pub fn mode_matches(mode: Mode, relative_path: &Path, nested_bin: bool) -> bool {
+    let dirs = mode.top_level_dirs();
+    let mut components = relative_path.components().map(|c| c.as_os_str());
+
+    match mode {
+        Mode::All => true,
+        Mode::Bin if nested_bin => components.any(|c| c == "bin"),
+        _ => components.next().is_some_and(|c| dirs.iter().any(|dir| c == *dir)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FilesystemType;
+    use tempfile::TempDir;
+
+    /// A `vendor` folder holding `files`, with an empty contexts file.
+    fn fixture(files: &[&str]) -> (TempDir, crate::config::ConfigBuilder) {
+        let dir = TempDir::new().unwrap();
+        let partition = dir.path().join("vendor");
+        fs::create_dir(&partition).unwrap();
+        for file in files {
+            let path = partition.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        let contexts = dir.path().join("vendor_file_contexts");
+        fs::write(&contexts, "").unwrap();
+        let builder = Config::builder().extracted_dir(&partition).file_contexts(&contexts).fstype(FilesystemType::Ext4);
+        (dir, builder)
+    }
+
+    /// The paths `config` visits, slash-separated and sorted.
+    fn walked(config: &Config) -> Vec<String> {
+        let filter = PathFilter::new(config).unwrap();
+        let mut paths = Vec::new();
+        walk_partition(config, &filter, None, |relative_path, _| {
+            paths.push(relative_path.to_string_lossy().replace('\\', "/"));
+            ControlFlow::Continue(())
+        });
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn bin_mode_takes_the_top_level_bin_folder() {
+        let (_dir, builder) = fixture(&["bin/foo", "xbin/foo", "etc/bin/foo"]);
+        let config = builder.mode(Mode::Bin).build().unwrap();
+        assert_eq!(walked(&config), ["bin", "bin/foo"]);
+    }
+
+    #[test]
+    fn nested_bin_also_takes_deeper_bin_folders() {
+        let (_dir, builder) = fixture(&["bin/foo", "xbin/foo", "etc/bin/foo"]);
+        let config = builder.mode(Mode::Bin).nested_bin(true).build().unwrap();
+        assert_eq!(walked(&config), ["bin", "bin/foo", "etc/bin", "etc/bin/foo"]);
+    }
+
+    #[test]
+    fn modes_compare_whole_components() {
+        assert!(mode_matches(Mode::Bin, Path::new("bin/hw/foo"), false));
+        assert!(!mode_matches(Mode::Bin, Path::new("bin.d/foo"), false));
+        assert!(!mode_matches(Mode::Bin, Path::new("xbin/foo"), true));
+        assert!(mode_matches(Mode::Lib, Path::new("lib64/libfoo.so"), false));
+        assert!(!mode_matches(Mode::App, Path::new("etc/app/foo"), false));
+        assert!(mode_matches(Mode::All, Path::new("anything"), false));
+    }
+
+    #[test]
+    fn modes_match_paths_built_from_components() {
+        let path: PathBuf = ["etc", "bin", "foo"].iter().collect();
+        assert!(!mode_matches(Mode::Bin, &path, false));
+        assert!(mode_matches(Mode::Bin, &path, true));
+        let path: PathBuf = ["bin", "hw", "foo"].iter().collect();
+        assert!(mode_matches(Mode::Bin, &path, false));
+    }
+
+    /// Makes `dir` unreadable, or returns false where permissions are not
+    /// enforced, as for root.
+    #[cfg(unix)]
+    fn deny_listing(dir: &Path) -> bool {
+        use std::os::unix::fs::PermissionsExt;
+
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o000)).unwrap();
+        if fs::read_dir(dir).is_ok() {
+            fs::set_permissions(dir, fs::Permissions::from_mode(0o755)).unwrap();
+            return false;
+        }
+        true
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unreadable_folders_are_recorded() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (dir, builder) = fixture(&["bin/foo", "etc/private/key", "lib/private/libfoo.so"]);
+        let private = [dir.path().join("vendor/etc/private"), dir.path().join("vendor/lib/private")];
+        if !private.iter().all(|dir| deny_listing(dir)) {
+            return;
+        }
+        let walk = |config: &Config| {
+            let filter = PathFilter::new(config).unwrap();
+            let mut paths = Vec::new();
+            let excluded = walk_partition(config, &filter, None, |relative_path, _| {
+                paths.push(relative_path.to_path_buf());
+                ControlFlow::Continue(())
+            });
+            (paths.len(), excluded.errors)
+        };
+
+        let (visited, errors) = walk(&builder.clone().mode(Mode::All).build().unwrap());
+        let mut denied: Vec<_> = errors.iter().map(|(path, error)| (path.to_str().unwrap(), error.kind())).collect();
+        denied.sort();
+        assert_eq!(denied, [("etc/private", io::ErrorKind::PermissionDenied), ("lib/private", io::ErrorKind::PermissionDenied)]);
+        // The folders themselves are still visited, along with everything else.
+        assert_eq!(visited, 6);
+
+        // Strict runs stop at the first error.
+        let (_, errors) = walk(&builder.mode(Mode::All).strict(true).build().unwrap());
+        assert_eq!(errors.len(), 1);
+        for dir in private {
+            fs::set_permissions(dir, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+    }
+}
@@ -1,91 +1,175 @@
-use std::io::Write;
-use std::sync::{Arc, Mutex};
-use std::thread;
+use clap::ValueEnum;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressState, ProgressStyle};
+use super::reporter::{outln, Reporter};
+use super::sink::{Phase, ProgressSink};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::IsTerminal;
+use std::sync::Mutex;
 use std::time::Duration;
 
-#[derive(Clone)]
-pub struct ProgressTracker {
-    total: usize,
-    current: Arc<Mutex<usize>>,
-    show_progress: bool,
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+const LINE_STEP_PERCENT: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProgressMode {
+    /// Draw a bar on a terminal, print a line every 10% otherwise
+    Always,
+    /// Draw a bar only when stdout is a terminal
+    Auto,
+    /// Never show progress
+    Never,
 }
 
-impl ProgressTracker {
-    pub fn new(total: usize, show_progress: bool) -> Self {
-        let tracker = Self {
-            total,
-            current: Arc::new(Mutex::new(0)),
-            show_progress,
-        };
+/// How progress ends up being shown.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProgressDisplay {
+    Bar,
+    Lines,
+    Hidden,
+}
 
-        if show_progress {
-            tracker.spawn_progress_thread();
+impl ProgressDisplay {
+    pub fn select(mode: ProgressMode, is_terminal: bool) -> Self {
+        match (mode, is_terminal) {
+            (ProgressMode::Never, _) => Self::Hidden,
+            (_, true) => Self::Bar,
+            (ProgressMode::Always, false) => Self::Lines,
+            // Redraws would fill logs and pipes with carriage returns.
+            (ProgressMode::Auto, false) => Self::Hidden,
         }
+    }
+}
+
+struct ActiveBar {
+    bar: ProgressBar,
+    display: ProgressDisplay,
+    last_line: u64,
+}
+
+/// Draws the progress of each phase as a bar on the terminal, or as
+/// percentage lines when progress is forced on without one.
+#[derive(Debug)]
+pub struct BarSink {
+    mode: ProgressMode,
+    active: Mutex<Option<ActiveBar>>,
+}
 
-        tracker
+impl fmt::Debug for ActiveBar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ActiveBar").field("display", &self.display).finish()
     }
+}
 
-    pub fn increment(&self) {
-        let mut current = self.current.lock().unwrap();
-        *current += 1;
+impl BarSink {
+    pub fn new(mode: ProgressMode) -> Self {
+        Self { mode, active: Mutex::new(None) }
     }
+}
+
+impl ProgressSink for BarSink {
+    fn phase(&self, phase: Phase, total: Option<u64>) {
+        let (bar, mode) = match total {
+            // Nothing is shown when there is nothing to do.
+            Some(0) => (ProgressBar::hidden(), ProgressMode::Never),
+            Some(total) => {
+                let style = ProgressStyle::with_template(
+                    "{prefix:>10} [{elapsed_precise}] [{wide_bar}] {pos}/{len} ({rate}, ETA {eta})",
+                )
+                .unwrap()
+                .with_key("rate", write_rate)
+                .progress_chars("=> ");
+                (ProgressBar::new(total).with_style(style), self.mode)
+            }
+            // A spinner only ever draws on a terminal.
+            None => {
+                let style = ProgressStyle::with_template("{prefix:>10} [{elapsed_precise}] {spinner} {pos} paths ({rate})")
+                    .unwrap()
+                    .with_key("rate", write_rate);
+                let mode = if self.mode == ProgressMode::Always { ProgressMode::Auto } else { self.mode };
+                (ProgressBar::no_length().with_style(style), mode)
+            }
+        };
 
-    pub fn finish(&self) {
-        if self.show_progress {
-            let current = *self.current.lock().unwrap();
-            Self::progress_bar(current, self.total);
-            println!();
+        let display = ProgressDisplay::select(mode, std::io::stdout().is_terminal());
+        if display == ProgressDisplay::Bar {
+            bar.set_draw_target(ProgressDrawTarget::stdout());
+            bar.set_prefix(phase.as_str());
+            bar.enable_steady_tick(TICK_INTERVAL);
+            Reporter::global().attach(bar.clone());
+        } else {
+            bar.set_draw_target(ProgressDrawTarget::hidden());
         }
+        *self.active.lock().unwrap() = Some(ActiveBar { bar, display, last_line: 0 });
     }
 
-    fn spawn_progress_thread(&self) {
-        let current = Arc::clone(&self.current);
-        let total = self.total;
-        let show_progress = self.show_progress;
-
-        thread::spawn(move || {
-            let mut last_count = 0;
-            while show_progress {
-                thread::sleep(Duration::from_millis(200));
-                let count = *current.lock().unwrap();
-                if count < last_count {
-                    Self::progress_bar(last_count, total);
-                } else {
-                    last_count = count;
-                    Self::progress_bar(count, total);
-                }
-                if count >= total {
-                    Self::progress_bar(total, total);
-                    break;
+    fn progress(&self, phase: Phase, done: u64, total: Option<u64>) {
+        let mut active = self.active.lock().unwrap();
+        let Some(active) = active.as_mut() else {
+            return;
+        };
+        match (active.display, total) {
+            (ProgressDisplay::Bar, _) => active.bar.set_position(done.max(active.bar.position())),
+            (ProgressDisplay::Lines, Some(total)) if total > 0 => {
+                let done = done.min(total);
+                let step = done * 100 / total / LINE_STEP_PERCENT * LINE_STEP_PERCENT;
+                if step > active.last_line {
+                    active.last_line = step;
+                    outln!("{}: {}% ({}/{})", phase, step, done, total);
                 }
             }
-        });
+            _ => {}
+        }
     }
 
-    fn progress_bar(progress: usize, total: usize) {
-        let width = 50;
-        let percent = (progress * 100) / total;
-        let filled = (width * progress) / total;
-        let empty = width - filled;
-    
-        print!("\r[");
-    
-        if percent < 100 {
-            if filled > 0 {
-                for _ in 0..filled-1 {
-                    print!("=");
-                }
-                print!(">");
-            }
-            for _ in 0..empty {
-                print!(" ");
+    /// Bars with a known total stay on screen, where they stopped when the
+    /// phase ended early; spinners are cleared.
+    fn phase_done(&self, _phase: Phase, done: u64, total: Option<u64>) {
+        let Some(active) = self.active.lock().unwrap().take() else {
+            return;
+        };
+        if active.display != ProgressDisplay::Bar {
+            return;
+        }
+        Reporter::global().detach();
+        if let Some(total) = total {
+            if done < total {
+                active.bar.abandon();
+            } else {
+                active.bar.finish();
             }
+            outln!();
         } else {
-            for _ in 0..width {
-                print!("=");
-            }
+            active.bar.finish_and_clear();
         }
-        print!("] {:3}% ({}/{})", percent, progress, total);
-        std::io::stdout().flush().unwrap();
     }
-}
\ No newline at end of file
+}
+
+fn write_rate(state: &ProgressState, w: &mut dyn std::fmt::Write) {
+    let _ = write!(w, "{:.0} paths/s", state.per_sec());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_follows_the_mode_and_terminal() {
+        assert_eq!(ProgressDisplay::select(ProgressMode::Never, true), ProgressDisplay::Hidden);
+        assert_eq!(ProgressDisplay::select(ProgressMode::Auto, true), ProgressDisplay::Bar);
+        assert_eq!(ProgressDisplay::select(ProgressMode::Auto, false), ProgressDisplay::Hidden);
+        assert_eq!(ProgressDisplay::select(ProgressMode::Always, false), ProgressDisplay::Lines);
+    }
+
+    #[test]
+    fn empty_phases_draw_nothing() {
+        let sink = BarSink::new(ProgressMode::Always);
+        sink.phase(Phase::Generating, Some(0));
+        sink.progress(Phase::Generating, 0, Some(0));
+        sink.progress(Phase::Generating, 1, Some(0));
+        assert_eq!(sink.active.lock().unwrap().as_ref().map(|active| active.display), Some(ProgressDisplay::Hidden));
+        sink.phase_done(Phase::Generating, 0, Some(0));
+        assert!(sink.active.lock().unwrap().is_none());
+    }
+}
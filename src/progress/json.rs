@@ -0,0 +1,73 @@
+use super::sink::{Phase, ProgressSink};
+use serde::Serialize;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const MIN_EVENT_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum Event<'a> {
+    Start { partition: &'a str },
+    Phase { phase: Phase, total: Option<u64> },
+    Progress { phase: Phase, done: u64, total: Option<u64> },
+    Finish { partition: &'a str },
+}
+
+/// Writes newline-delimited JSON events to stderr, for front-ends that
+/// track progress without parsing the terminal bar.
+///
+/// Progress events are throttled; the last one of a phase is always sent.
+#[derive(Debug, Default)]
+pub struct JsonSink {
+    last_progress: Mutex<Option<Instant>>,
+}
+
+impl JsonSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn emit(&self, event: Event) {
+        let Ok(line) = serde_json::to_string(&event) else {
+            return;
+        };
+        let mut stderr = std::io::stderr().lock();
+        let _ = writeln!(stderr, "{}", line);
+    }
+}
+
+impl ProgressSink for JsonSink {
+    fn start(&self, partition: &str) {
+        self.emit(Event::Start { partition });
+    }
+
+    fn phase(&self, phase: Phase, total: Option<u64>) {
+        *self.last_progress.lock().unwrap() = None;
+        self.emit(Event::Phase { phase, total });
+    }
+
+    fn progress(&self, phase: Phase, done: u64, total: Option<u64>) {
+        {
+            let mut last = self.last_progress.lock().unwrap();
+            let due = last.is_none_or(|last| last.elapsed() >= MIN_EVENT_INTERVAL);
+            if !due && Some(done) != total {
+                return;
+            }
+            *last = Some(Instant::now());
+        }
+        self.emit(Event::Progress { phase, done, total });
+    }
+
+    fn phase_done(&self, phase: Phase, done: u64, total: Option<u64>) {
+        // Progress events stop short of the end when the total is unknown.
+        if total != Some(done) {
+            self.emit(Event::Progress { phase, done, total });
+        }
+    }
+
+    fn finish(&self, partition: &str) {
+        self.emit(Event::Finish { partition });
+    }
+}
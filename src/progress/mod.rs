@@ -1 +1,4 @@
-pub mod bar;
\ No newline at end of file
+pub mod bar;
+pub mod json;
+pub mod reporter;
+pub mod sink;
@@ -0,0 +1,94 @@
+use indicatif::ProgressBar;
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+static REPORTER: Reporter = Reporter { bar: Mutex::new(None) };
+
+/// Prints messages from any thread without tearing the progress bar: the
+/// bar is cleared, the message written and the bar redrawn below it.
+///
+/// Messages are written whole, one at a time, so lines from different
+/// workers never interleave either.
+#[derive(Debug)]
+pub struct Reporter {
+    bar: Mutex<Option<ProgressBar>>,
+}
+
+impl Reporter {
+    pub fn global() -> &'static Self {
+        &REPORTER
+    }
+
+    /// Makes `bar` the one cleared around messages until [`Self::detach`].
+    pub(crate) fn attach(&self, bar: ProgressBar) {
+        *self.bar.lock().unwrap() = Some(bar);
+    }
+
+    pub(crate) fn detach(&self) -> Option<ProgressBar> {
+        self.bar.lock().unwrap().take()
+    }
+
+    /// Runs `f` with the bar hidden, for output written some other way.
+    pub fn suspend<R>(&self, f: impl FnOnce() -> R) -> R {
+        let bar = self.bar.lock().unwrap();
+        match bar.as_ref() {
+            Some(bar) => bar.suspend(f),
+            None => f(),
+        }
+    }
+
+    pub fn println(&self, args: fmt::Arguments) {
+        self.suspend(|| writeln!(io::stdout().lock(), "{}", args).ok());
+    }
+
+    pub fn eprintln(&self, args: fmt::Arguments) {
+        self.suspend(|| writeln!(io::stderr().lock(), "{}", args).ok());
+    }
+
+    /// A stderr writer for log events, which are buffered and written
+    /// through the reporter when the writer is dropped.
+    pub fn log_writer() -> LogWriter {
+        LogWriter(Vec::new())
+    }
+}
+
+pub struct LogWriter(Vec<u8>);
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for LogWriter {
+    fn drop(&mut self) {
+        if !self.0.is_empty() {
+            Reporter::global().suspend(|| io::stderr().lock().write_all(&self.0).ok());
+        }
+    }
+}
+
+/// Like `println!`, through the [`Reporter`].
+macro_rules! outln {
+    () => {
+        $crate::progress::reporter::Reporter::global().println(format_args!(""))
+    };
+    ($($arg:tt)*) => {
+        $crate::progress::reporter::Reporter::global().println(format_args!($($arg)*))
+    };
+}
+
+/// Like `eprintln!`, through the [`Reporter`].
+macro_rules! errln {
+    ($($arg:tt)*) => {
+        $crate::progress::reporter::Reporter::global().eprintln(format_args!($($arg)*))
+    };
+}
+
+pub(crate) use {errln, outln};
@@ -0,0 +1,79 @@
+use serde::Serialize;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    Scanning,
+    Generating,
+}
+
+impl Phase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Scanning => "scanning",
+            Self::Generating => "generating",
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Receives progress of a run. The terminal bar and the JSON event stream
+/// are the built-in implementations; embedders can set their own on
+/// [`Config::progress_sink`](crate::Config::progress_sink).
+///
+/// Calls can come from several worker threads at once.
+pub trait ProgressSink: Send + Sync {
+    /// Processing of a partition begins.
+    fn start(&self, _partition: &str) {}
+
+    /// A phase begins, with its total when it is known upfront.
+    fn phase(&self, phase: Phase, total: Option<u64>);
+
+    fn progress(&self, phase: Phase, done: u64, total: Option<u64>);
+
+    /// A phase ends.
+    fn phase_done(&self, _phase: Phase, _done: u64, _total: Option<u64>) {}
+
+    /// Processing of a partition ends.
+    fn finish(&self, _partition: &str) {}
+}
+
+impl fmt::Debug for dyn ProgressSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressSink")
+    }
+}
+
+/// Counts work done in one phase and forwards it to a sink.
+#[derive(Clone)]
+pub struct ProgressTracker {
+    sink: Arc<dyn ProgressSink>,
+    phase: Phase,
+    total: Option<u64>,
+    done: Arc<AtomicU64>,
+}
+
+impl ProgressTracker {
+    pub fn new(sink: Arc<dyn ProgressSink>, phase: Phase, total: Option<usize>) -> Self {
+        let total = total.map(|total| total as u64);
+        sink.phase(phase, total);
+        Self { sink, phase, total, done: Arc::new(AtomicU64::new(0)) }
+    }
+
+    pub fn increment(&self) {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        self.sink.progress(self.phase, done, self.total);
+    }
+
+    pub fn finish(&self) {
+        self.sink.phase_done(self.phase, self.done.load(Ordering::Relaxed), self.total);
+    }
+}
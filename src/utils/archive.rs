@@ -0,0 +1,283 @@
+use crate::contexts_file::FileType;
+use flate2::read::GzDecoder;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+use tar::EntryType;
+use zip::ZipArchive;
+
+const S_IFMT: u32 = 0o170000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArchiveKind {
+    Tar,
+    TarGz,
+    Zip,
+}
+
+impl ArchiveKind {
+    /// Recognizes `.tar`, `.tar.gz`, `.tgz` and `.zip` files by name.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+        match () {
+            _ if name.ends_with(".tar") => Some(Self::Tar),
+            _ if name.ends_with(".tar.gz") || name.ends_with(".tgz") => Some(Self::TarGz),
+            _ if name.ends_with(".zip") => Some(Self::Zip),
+            _ => None,
+        }
+    }
+}
+
+/// Lists the paths in a partition archive, parents before children, from
+/// the entry headers alone; contents are never unpacked.
+///
+/// A single top-level folder named like one of `root_names`, as left by
+/// `tar cf vendor.tar vendor/`, is treated as the partition root.
+/// Directories that only appear as parents of other entries are listed too.
+pub fn list_archive(path: &Path, kind: ArchiveKind, root_names: &[&str]) -> io::Result<Vec<(PathBuf, FileType)>> {
+    let file = File::open(path)?;
+    let raw = match kind {
+        ArchiveKind::Tar => list_tar(BufReader::new(file))?,
+        ArchiveKind::TarGz => list_tar(GzDecoder::new(BufReader::new(file)))?,
+        ArchiveKind::Zip => list_zip(file)?,
+    };
+
+    let mut paths: BTreeMap<PathBuf, FileType> = BTreeMap::new();
+    for (name, file_type) in &raw {
+        let relative_path = relative_entry_path(name)
+            .ok_or_else(|| invalid(format!("entry {:?} points outside the archive", name)))?;
+        if !relative_path.as_os_str().is_empty() {
+            paths.insert(relative_path, *file_type);
+        }
+    }
+
+    let wrapper = root_names.iter().find(|root_name| {
+        !paths.is_empty()
+            && paths.keys().all(|path| path.starts_with(root_name))
+            && paths.get(Path::new(root_name)).is_none_or(|file_type| *file_type == FileType::Directory)
+    });
+    if let Some(root_name) = wrapper {
+        paths = paths
+            .into_iter()
+            .filter_map(|(path, file_type)| {
+                let stripped = path.strip_prefix(root_name).ok()?.to_path_buf();
+                (!stripped.as_os_str().is_empty()).then_some((stripped, file_type))
+            })
+            .collect();
+    }
+
+    let parents: Vec<PathBuf> = paths
+        .keys()
+        .flat_map(|path| path.ancestors().skip(1).filter(|parent| !parent.as_os_str().is_empty()))
+        .map(Path::to_path_buf)
+        .collect();
+    for parent in parents {
+        paths.entry(parent).or_insert(FileType::Directory);
+    }
+
+    let mut entries: Vec<(PathBuf, FileType)> = paths.into_iter().collect();
+    // Component order puts every directory right before its contents.
+    entries.sort_by(|a, b| a.0.components().cmp(b.0.components()));
+    Ok(entries)
+}
+
+fn list_tar(reader: impl Read) -> io::Result<Vec<(PathBuf, FileType)>> {
+    let mut archive = tar::Archive::new(reader);
+    let mut entries = Vec::new();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let file_type = match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Continuous | EntryType::Link => FileType::Regular,
+            EntryType::Directory => FileType::Directory,
+            EntryType::Symlink => FileType::Symlink,
+            EntryType::Char => FileType::CharDevice,
+            EntryType::Block => FileType::BlockDevice,
+            EntryType::Fifo => FileType::Pipe,
+            _ => continue,
+        };
+        entries.push((entry.path()?.into_owned(), file_type));
+    }
+    Ok(entries)
+}
+
+fn list_zip(file: File) -> io::Result<Vec<(PathBuf, FileType)>> {
+    let mut archive = ZipArchive::new(BufReader::new(file)).map_err(io::Error::other)?;
+    let mut entries = Vec::with_capacity(archive.len());
+    for index in 0..archive.len() {
+        let entry = archive.by_index_raw(index).map_err(io::Error::other)?;
+        let file_type = match entry.unix_mode().map(|mode| mode & S_IFMT) {
+            _ if entry.is_dir() => FileType::Directory,
+            Some(0o120000) => FileType::Symlink,
+            Some(0o020000) => FileType::CharDevice,
+            Some(0o060000) => FileType::BlockDevice,
+            Some(0o010000) => FileType::Pipe,
+            _ => FileType::Regular,
+        };
+        entries.push((PathBuf::from(entry.name()), file_type));
+    }
+    Ok(entries)
+}
+
+/// The entry path without `./` and trailing separators; `None` for
+/// absolute paths and anything containing `..`.
+fn relative_entry_path(name: &Path) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in name.components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(path)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+    use tempfile::TempDir;
+    use zip::write::SimpleFileOptions;
+
+    /// Writes `entries` to an archive of `kind` named `name` in `dir`.
+    /// Names are stored as given, `..` and leading slashes included.
+    fn archive(dir: &TempDir, name: &str, kind: ArchiveKind, entries: &[(&str, FileType)]) -> PathBuf {
+        let path = dir.path().join(name);
+        let file = File::create(&path).unwrap();
+        match kind {
+            ArchiveKind::Tar => drop(write_tar(file, entries)),
+            ArchiveKind::TarGz => drop(write_tar(GzEncoder::new(file, Default::default()), entries).finish().unwrap()),
+            ArchiveKind::Zip => write_zip(file, entries),
+        }
+        path
+    }
+
+    fn write_tar<W: Write>(writer: W, entries: &[(&str, FileType)]) -> W {
+        let mut builder = tar::Builder::new(writer);
+        for (name, file_type) in entries {
+            let mut header = tar::Header::new_gnu();
+            // `set_path` refuses the names the tests need to check.
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_entry_type(match file_type {
+                FileType::Directory => EntryType::Directory,
+                FileType::Symlink => EntryType::Symlink,
+                FileType::CharDevice => EntryType::Char,
+                _ => EntryType::Regular,
+            });
+            if *file_type == FileType::Symlink {
+                header.set_link_name("target").unwrap();
+            }
+            header.set_mode(0o644);
+            header.set_size(0);
+            header.set_cksum();
+            builder.append(&header, io::empty()).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn write_zip(file: File, entries: &[(&str, FileType)]) {
+        let mut writer = zip::ZipWriter::new(file);
+        let options = SimpleFileOptions::default();
+        for (name, file_type) in entries {
+            match file_type {
+                FileType::Directory => writer.add_directory(*name, options).unwrap(),
+                FileType::Symlink => writer.add_symlink(*name, "target", options).unwrap(),
+                _ => writer.start_file(*name, options).unwrap(),
+            }
+        }
+        writer.finish().unwrap();
+    }
+
+    fn list(path: &Path, root_names: &[&str]) -> io::Result<Vec<(String, FileType)>> {
+        let kind = ArchiveKind::from_path(path).unwrap();
+        let entries = list_archive(path, kind, root_names)?;
+        Ok(entries.into_iter().map(|(path, file_type)| (path.to_string_lossy().replace('\\', "/"), file_type)).collect())
+    }
+
+    fn expected(entries: &[(&str, FileType)]) -> Vec<(String, FileType)> {
+        entries.iter().map(|(path, file_type)| (path.to_string(), *file_type)).collect()
+    }
+
+    const KINDS: [(&str, ArchiveKind); 3] =
+        [("vendor.tar", ArchiveKind::Tar), ("vendor.tar.gz", ArchiveKind::TarGz), ("vendor.zip", ArchiveKind::Zip)];
+
+    #[test]
+    fn kinds_are_recognized_by_name() {
+        for (name, kind) in KINDS.iter().copied().chain([("VENDOR.TGZ", ArchiveKind::TarGz)]) {
+            assert_eq!(ArchiveKind::from_path(Path::new(name)), Some(kind));
+        }
+        assert_eq!(ArchiveKind::from_path(Path::new("vendor.img")), None);
+        assert_eq!(ArchiveKind::from_path(Path::new("vendor.tar.xz")), None);
+    }
+
+    #[test]
+    fn entries_keep_their_types_with_implied_parents() {
+        let dir = TempDir::new().unwrap();
+        let entries = [
+            ("bin/", FileType::Directory),
+            ("bin/foo", FileType::Regular),
+            ("bin/sh", FileType::Symlink),
+            ("./etc/init/foo.rc", FileType::Regular),
+            ("lib64/", FileType::Directory),
+        ];
+        for (name, kind) in KINDS {
+            let path = archive(&dir, name, kind, &entries);
+            assert_eq!(
+                list(&path, &[]).unwrap(),
+                expected(&[
+                    ("bin", FileType::Directory),
+                    ("bin/foo", FileType::Regular),
+                    ("bin/sh", FileType::Symlink),
+                    ("etc", FileType::Directory),
+                    ("etc/init", FileType::Directory),
+                    ("etc/init/foo.rc", FileType::Regular),
+                    ("lib64", FileType::Directory),
+                ]),
+                "{name}"
+            );
+        }
+
+        let path = archive(&dir, "dev.tar", ArchiveKind::Tar, &[("dev/null", FileType::CharDevice)]);
+        assert_eq!(list(&path, &[]).unwrap(), expected(&[("dev", FileType::Directory), ("dev/null", FileType::CharDevice)]));
+    }
+
+    #[test]
+    fn a_single_wrapper_folder_is_the_root() {
+        let dir = TempDir::new().unwrap();
+        let wrapped = [("vendor/", FileType::Directory), ("vendor/bin/foo", FileType::Regular)];
+        let inside = expected(&[("bin", FileType::Directory), ("bin/foo", FileType::Regular)]);
+        for (name, kind) in KINDS {
+            let path = archive(&dir, name, kind, &wrapped);
+            assert_eq!(list(&path, &["odm", "vendor"]).unwrap(), inside, "{name}");
+            assert_eq!(list(&path, &["odm"]).unwrap().len(), 3, "{name}");
+        }
+
+        // Implied wrappers count too, but not ones next to other entries.
+        let path = archive(&dir, "implied.tar", ArchiveKind::Tar, &[("vendor/bin/foo", FileType::Regular)]);
+        assert_eq!(list(&path, &["vendor"]).unwrap(), inside);
+        let entries = [("vendor/bin/foo", FileType::Regular), ("README", FileType::Regular)];
+        let path = archive(&dir, "beside.tar", ArchiveKind::Tar, &entries);
+        assert_eq!(list(&path, &["vendor"]).unwrap().len(), 4);
+        let path = archive(&dir, "file.tar", ArchiveKind::Tar, &[("vendor", FileType::Regular)]);
+        assert_eq!(list(&path, &["vendor"]).unwrap(), expected(&[("vendor", FileType::Regular)]));
+    }
+
+    #[test]
+    fn entries_outside_the_archive_are_rejected() {
+        let dir = TempDir::new().unwrap();
+        for outside in ["../etc/passwd", "bin/../../etc/passwd", "/etc/passwd"] {
+            for (name, kind) in KINDS {
+                let path = archive(&dir, name, kind, &[("bin/foo", FileType::Regular), (outside, FileType::Regular)]);
+                let error = list(&path, &[]).unwrap_err();
+                assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{outside} in {name}");
+                assert!(error.to_string().contains("points outside the archive"), "{outside} in {name}: {error}");
+            }
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const PT_INTERP: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ElfKind {
+    Executable,
+    SharedObject,
+}
+
+pub fn detect_elf(path: &Path) -> io::Result<Option<ElfKind>> {
+    let mut file = File::open(path)?;
+    let mut header = [0u8; 64];
+    let len = read_up_to(&mut file, &mut header)?;
+
+    if len < 52 || &header[..4] != ELF_MAGIC {
+        return Ok(None);
+    }
+
+    let is_64 = match header[4] {
+        1 => false,
+        2 => true,
+        _ => return Ok(None),
+    };
+    let little_endian = match header[5] {
+        1 => true,
+        2 => false,
+        _ => return Ok(None),
+    };
+    if is_64 && len < 64 {
+        return Ok(None);
+    }
+
+    let read_u16 = |b: &[u8]| if little_endian { u16::from_le_bytes([b[0], b[1]]) } else { u16::from_be_bytes([b[0], b[1]]) };
+    let read_u32 = |b: &[u8]| {
+        let bytes = [b[0], b[1], b[2], b[3]];
+        if little_endian { u32::from_le_bytes(bytes) } else { u32::from_be_bytes(bytes) }
+    };
+    let read_u64 = |b: &[u8]| {
+        let bytes = [b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]];
+        if little_endian { u64::from_le_bytes(bytes) } else { u64::from_be_bytes(bytes) }
+    };
+
+    match read_u16(&header[16..18]) {
+        ET_EXEC => return Ok(Some(ElfKind::Executable)),
+        ET_DYN => {}
+        _ => return Ok(None),
+    }
+
+    let (phoff, phentsize, phnum) = if is_64 {
+        (read_u64(&header[32..40]), read_u16(&header[54..56]), read_u16(&header[56..58]))
+    } else {
+        (u64::from(read_u32(&header[28..32])), read_u16(&header[42..44]), read_u16(&header[44..46]))
+    };
+
+    let mut entry = vec![0u8; usize::from(phentsize).max(4)];
+    for index in 0..u64::from(phnum) {
+        file.seek(SeekFrom::Start(phoff + index * u64::from(phentsize)))?;
+        if read_up_to(&mut file, &mut entry)? < 4 {
+            break;
+        }
+        if read_u32(&entry[..4]) == PT_INTERP {
+            return Ok(Some(ElfKind::Executable));
+        }
+    }
+
+    Ok(Some(ElfKind::SharedObject))
+}
+
+fn read_up_to(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut total = 0;
+    while total < buf.len() {
+        match file.read(&mut buf[total..])? {
+            0 => break,
+            n => total += n,
+        }
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    /// A little-endian ELF64 header of type `e_type` with one program
+    /// header, an interpreter one when `interp` is set.
+    fn elf64(e_type: u16, interp: bool) -> Vec<u8> {
+        let mut bytes = vec![0u8; 64 + 56];
+        bytes[..4].copy_from_slice(ELF_MAGIC);
+        bytes[4] = 2;
+        bytes[5] = 1;
+        bytes[16..18].copy_from_slice(&e_type.to_le_bytes());
+        bytes[32..40].copy_from_slice(&64u64.to_le_bytes());
+        bytes[54..56].copy_from_slice(&56u16.to_le_bytes());
+        bytes[56..58].copy_from_slice(&1u16.to_le_bytes());
+        let p_type: u32 = if interp { PT_INTERP } else { 1 };
+        bytes[64..68].copy_from_slice(&p_type.to_le_bytes());
+        bytes
+    }
+
+    fn detect(bytes: &[u8]) -> Option<ElfKind> {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("file");
+        fs::write(&path, bytes).unwrap();
+        detect_elf(&path).unwrap()
+    }
+
+    #[test]
+    fn executables_and_shared_objects_are_told_apart() {
+        assert_eq!(detect(&elf64(ET_EXEC, false)), Some(ElfKind::Executable));
+        // Position-independent executables are ET_DYN with an interpreter.
+        assert_eq!(detect(&elf64(ET_DYN, true)), Some(ElfKind::Executable));
+        assert_eq!(detect(&elf64(ET_DYN, false)), Some(ElfKind::SharedObject));
+    }
+
+    #[test]
+    fn other_files_are_not_elf() {
+        assert_eq!(detect(b"#!/system/bin/sh\necho hello\n"), None);
+        assert_eq!(detect(b"\x7fELF"), None);
+        assert_eq!(detect(&elf64(1, false)), None);
+        assert!(detect_elf(Path::new("/nonexistent/file")).is_err());
+    }
+}
@@ -0,0 +1,416 @@
+use crate::contexts_file::FileType;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const EXT4_MAGIC: u16 = 0xEF53;
+const ROOT_INODE: u32 = 2;
+
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const INCOMPAT_64BIT: u32 = 0x80;
+
+const EXTENTS_FL: u32 = 0x8_0000;
+const INLINE_DATA_FL: u32 = 0x1000_0000;
+const EXTENT_MAGIC: u16 = 0xF30A;
+/// Extents longer than this are preallocated but unwritten.
+const EXTENT_INIT_MAX_LEN: u16 = 32768;
+
+const S_IFMT: u16 = 0o170000;
+
+/// A path found in an ext4 image, relative to its root.
+#[derive(Debug, Clone)]
+pub struct Ext4Entry {
+    pub path: PathBuf,
+    pub file_type: FileType,
+}
+
+/// Lists every path in an ext4 image, parents before children, reading
+/// only the superblock, inodes and directory blocks. File contents are
+/// never read.
+pub fn list_ext4_image(path: &Path) -> io::Result<Vec<Ext4Entry>> {
+    let mut image = Ext4Image::open(path)?;
+    let mut entries = Vec::new();
+    let mut visited = HashSet::from([ROOT_INODE]);
+    let mut pending = vec![(ROOT_INODE, PathBuf::new())];
+
+    while let Some((inode, dir_path)) = pending.pop() {
+        for (name, child, file_type) in image.read_dir(inode)? {
+            let path = dir_path.join(name);
+            if file_type == FileType::Directory && visited.insert(child) {
+                pending.push((child, path.clone()));
+            }
+            entries.push(Ext4Entry { path, file_type });
+        }
+    }
+    // Component order puts every directory right before its contents.
+    entries.sort_by(|a, b| a.path.components().cmp(b.path.components()));
+    Ok(entries)
+}
+
+struct Inode {
+    mode: u16,
+    size: u64,
+    flags: u32,
+    block: [u8; 60],
+}
+
+struct Ext4Image {
+    file: File,
+    block_size: u64,
+    inodes_per_group: u32,
+    inode_size: u64,
+    desc_size: u64,
+    descriptors_start: u64,
+    has_filetype: bool,
+    is_64bit: bool,
+}
+
+impl Ext4Image {
+    fn open(path: &Path) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let mut sb = [0u8; 1024];
+        file.seek(SeekFrom::Start(SUPERBLOCK_OFFSET))?;
+        file.read_exact(&mut sb)?;
+
+        if le16(&sb, 56) != EXT4_MAGIC {
+            return Err(invalid("no ext4 superblock"));
+        }
+        let log_block_size = le32(&sb, 24);
+        if log_block_size > 6 {
+            return Err(invalid("unsupported block size"));
+        }
+        let block_size = 1024u64 << log_block_size;
+        let first_data_block = u64::from(le32(&sb, 20));
+        let inodes_per_group = le32(&sb, 40);
+        let inode_size = if le32(&sb, 76) == 0 { 128 } else { u64::from(le16(&sb, 88)) };
+        let incompat = le32(&sb, 96);
+        let is_64bit = incompat & INCOMPAT_64BIT != 0;
+        let desc_size = if is_64bit { u64::from(le16(&sb, 254)).max(32) } else { 32 };
+        if inodes_per_group == 0 || inode_size < 128 {
+            return Err(invalid("corrupt superblock"));
+        }
+
+        Ok(Self {
+            file,
+            block_size,
+            inodes_per_group,
+            inode_size,
+            desc_size,
+            descriptors_start: offset_of(first_data_block + 1, block_size, 0)?,
+            has_filetype: incompat & INCOMPAT_FILETYPE != 0,
+            is_64bit,
+        })
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(buf)
+    }
+
+    fn read_block(&mut self, block: u64) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0u8; self.block_size as usize];
+        self.read_at(offset_of(block, self.block_size, 0)?, &mut buf)?;
+        Ok(buf)
+    }
+
+    fn read_inode(&mut self, inode: u32) -> io::Result<Inode> {
+        let group = u64::from((inode - 1) / self.inodes_per_group);
+        let index = u64::from((inode - 1) % self.inodes_per_group);
+
+        let mut desc = vec![0u8; self.desc_size as usize];
+        self.read_at(offset_of(group, self.desc_size, self.descriptors_start)?, &mut desc)?;
+        let mut table = u64::from(le32(&desc, 8));
+        if self.is_64bit && self.desc_size >= 64 {
+            table |= u64::from(le32(&desc, 0x28)) << 32;
+        }
+
+        let mut raw = [0u8; 128];
+        let table_start = offset_of(table, self.block_size, 0)?;
+        self.read_at(offset_of(index, self.inode_size, table_start)?, &mut raw)?;
+        let mut block = [0u8; 60];
+        block.copy_from_slice(&raw[0x28..0x64]);
+        Ok(Inode {
+            mode: le16(&raw, 0),
+            size: u64::from(le32(&raw, 4)) | u64::from(le32(&raw, 0x6C)) << 32,
+            flags: le32(&raw, 0x20),
+            block,
+        })
+    }
+
+    /// Name, inode and type of each entry in a directory, without `.` and `..`.
+    fn read_dir(&mut self, inode: u32) -> io::Result<Vec<(OsString, u32, FileType)>> {
+        let dir = self.read_inode(inode)?;
+        if dir.flags & INLINE_DATA_FL != 0 {
+            return Err(invalid("directories with inline data are not supported"));
+        }
+        let block_count = dir.size.div_ceil(self.block_size);
+        let blocks = if dir.flags & EXTENTS_FL != 0 {
+            self.extent_blocks(&dir.block, block_count)?
+        } else {
+            self.mapped_blocks(&dir.block, block_count)?
+        };
+
+        let mut children = Vec::new();
+        for block in blocks {
+            let data = self.read_block(block)?;
+            let mut offset = 0;
+            while offset + 8 <= data.len() {
+                let child = le32(&data, offset);
+                let rec_len = usize::from(le16(&data, offset + 4));
+                // Without the file type feature the type byte is the high byte of the length.
+                let name_len = match self.has_filetype {
+                    true => usize::from(data[offset + 6]),
+                    false => usize::from(le16(&data, offset + 6)),
+                };
+                if rec_len < 8 || offset + rec_len > data.len() || 8 + name_len > rec_len {
+                    return Err(invalid("corrupt directory entry"));
+                }
+                let name = &data[offset + 8..offset + 8 + name_len];
+                if child != 0 && name != b"." && name != b".." {
+                    let file_type = match self.has_filetype.then(|| dirent_type(data[offset + 7])).flatten() {
+                        Some(file_type) => file_type,
+                        None => mode_type(self.read_inode(child)?.mode),
+                    };
+                    children.push((entry_name(name)?, child, file_type));
+                }
+                offset += rec_len;
+            }
+        }
+        Ok(children)
+    }
+
+    /// Physical blocks of the first `count` logical blocks of an extent-mapped
+    /// inode. Holes and unwritten extents are skipped.
+    fn extent_blocks(&mut self, node: &[u8], count: u64) -> io::Result<Vec<u64>> {
+        let mut blocks = Vec::new();
+        self.walk_extents(node, count, &mut blocks, 0)?;
+        Ok(blocks)
+    }
+
+    fn walk_extents(&mut self, node: &[u8], count: u64, blocks: &mut Vec<u64>, level: u32) -> io::Result<()> {
+        if le16(node, 0) != EXTENT_MAGIC || level > 5 {
+            return Err(invalid("corrupt extent tree"));
+        }
+        let entries = usize::from(le16(node, 2));
+        let depth = le16(node, 6);
+        for index in 0..entries {
+            let entry = 12 + index * 12;
+            if entry + 12 > node.len() {
+                return Err(invalid("corrupt extent tree"));
+            }
+            let logical = u64::from(le32(node, entry));
+            if logical >= count {
+                break;
+            }
+            if depth == 0 {
+                let len = le16(node, entry + 4);
+                if len > EXTENT_INIT_MAX_LEN {
+                    continue;
+                }
+                let start = u64::from(le16(node, entry + 6)) << 32 | u64::from(le32(node, entry + 8));
+                let len = u64::from(len).min(count - logical);
+                blocks.extend(start..start + len);
+            } else {
+                let leaf = u64::from(le32(node, entry + 4)) | u64::from(le16(node, entry + 8)) << 32;
+                let child = self.read_block(leaf)?;
+                self.walk_extents(&child, count, blocks, level + 1)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Physical blocks of a block-mapped inode: twelve direct pointers, then
+    /// single, double and triple indirect blocks.
+    fn mapped_blocks(&mut self, i_block: &[u8], count: u64) -> io::Result<Vec<u64>> {
+        let mut blocks = Vec::new();
+        for index in 0..15 {
+            if blocks.len() as u64 >= count {
+                break;
+            }
+            let pointer = u64::from(le32(i_block, index * 4));
+            match index {
+                0..12 => blocks.push(pointer),
+                _ => self.indirect_blocks(pointer, index as u32 - 11, count, &mut blocks)?,
+            }
+        }
+        blocks.truncate(count as usize);
+        blocks.retain(|&block| block != 0);
+        Ok(blocks)
+    }
+
+    fn indirect_blocks(&mut self, pointer: u64, level: u32, count: u64, blocks: &mut Vec<u64>) -> io::Result<()> {
+        if pointer == 0 {
+            return Ok(());
+        }
+        let data = self.read_block(pointer)?;
+        for offset in (0..data.len()).step_by(4) {
+            if blocks.len() as u64 >= count {
+                break;
+            }
+            let next = u64::from(le32(&data, offset));
+            if level == 1 {
+                blocks.push(next);
+            } else {
+                self.indirect_blocks(next, level - 1, count, blocks)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A directory entry name as stored. On Unix names that are not valid
+/// UTF-8 are kept byte for byte, so they are reported like those found
+/// walking a folder; elsewhere they cannot be represented.
+fn entry_name(name: &[u8]) -> io::Result<OsString> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Ok(std::ffi::OsStr::from_bytes(name).to_os_string())
+    }
+    #[cfg(not(unix))]
+    {
+        String::from_utf8(name.to_vec())
+            .map(OsString::from)
+            .map_err(|_| invalid(&format!("name {:?} is not valid UTF-8", String::from_utf8_lossy(name))))
+    }
+}
+
+/// `base + index * size`, failing on values too large for any image.
+fn offset_of(index: u64, size: u64, base: u64) -> io::Result<u64> {
+    index
+        .checked_mul(size)
+        .and_then(|offset| offset.checked_add(base))
+        .ok_or_else(|| invalid("block or inode number out of range"))
+}
+
+fn dirent_type(file_type: u8) -> Option<FileType> {
+    match file_type {
+        1 => Some(FileType::Regular),
+        2 => Some(FileType::Directory),
+        3 => Some(FileType::CharDevice),
+        4 => Some(FileType::BlockDevice),
+        5 => Some(FileType::Pipe),
+        6 => Some(FileType::Socket),
+        7 => Some(FileType::Symlink),
+        _ => None,
+    }
+}
+
+fn mode_type(mode: u16) -> FileType {
+    match mode & S_IFMT {
+        0o040000 => FileType::Directory,
+        0o120000 => FileType::Symlink,
+        0o020000 => FileType::CharDevice,
+        0o060000 => FileType::BlockDevice,
+        0o010000 => FileType::Pipe,
+        0o140000 => FileType::Socket,
+        _ => FileType::Regular,
+    }
+}
+
+fn le16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn le32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([buf[offset], buf[offset + 1], buf[offset + 2], buf[offset + 3]])
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use tempfile::TempDir;
+
+    /// Made with `mke2fs -d` from a tree holding `etc/init/foo.rc`, a
+    /// `bin/sh` symlink, a HAL service below `bin/hw`, an empty folder and a
+    /// `many` folder whose entries span three extent-mapped blocks.
+    const EXT4_IMAGE: &[u8] = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/vendor.ext4.img.gz"));
+    /// The same tree without the file type feature, with block-mapped
+    /// directories.
+    const EXT2_IMAGE: &[u8] = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/vendor.ext2.img.gz"));
+
+    fn unpack(image: &[u8], dir: &TempDir) -> PathBuf {
+        let mut content = Vec::new();
+        GzDecoder::new(image).read_to_end(&mut content).unwrap();
+        let path = dir.path().join("vendor.img");
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn listing(entries: &[Ext4Entry]) -> Vec<(String, FileType)> {
+        entries
+            .iter()
+            .filter(|entry| !entry.path.starts_with("many") && !entry.path.starts_with("lost+found"))
+            .map(|entry| (entry.path.to_string_lossy().into_owned(), entry.file_type))
+            .collect()
+    }
+
+    #[test]
+    fn nested_folders_symlinks_and_multi_block_folders() {
+        let dir = TempDir::new().unwrap();
+        for image in [EXT4_IMAGE, EXT2_IMAGE] {
+            let entries = list_ext4_image(&unpack(image, &dir)).unwrap();
+            assert_eq!(
+                listing(&entries),
+                [
+                    ("bin".to_string(), FileType::Directory),
+                    ("bin/hw".to_string(), FileType::Directory),
+                    ("bin/hw/android.hardware.light-service".to_string(), FileType::Regular),
+                    ("bin/sh".to_string(), FileType::Symlink),
+                    ("empty".to_string(), FileType::Directory),
+                    ("etc".to_string(), FileType::Directory),
+                    ("etc/bad\u{FFFD}name".to_string(), FileType::Regular),
+                    ("etc/init".to_string(), FileType::Directory),
+                    ("etc/init/foo.rc".to_string(), FileType::Regular),
+                ]
+            );
+            let many = entries.iter().filter(|entry| entry.path.starts_with("many")).count();
+            assert_eq!(many, 61);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn invalid_utf8_names_are_kept_as_stored() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = TempDir::new().unwrap();
+        let entries = list_ext4_image(&unpack(EXT4_IMAGE, &dir)).unwrap();
+        let bad = entries.iter().find(|entry| entry.path.to_str().is_none()).unwrap();
+        assert_eq!(bad.path.as_os_str().as_bytes(), b"etc/bad\xffname");
+    }
+
+    #[test]
+    fn truncated_and_corrupt_images_are_errors() {
+        let dir = TempDir::new().unwrap();
+        let path = unpack(EXT4_IMAGE, &dir);
+        let content = std::fs::read(&path).unwrap();
+
+        std::fs::write(&path, &content[..1500]).unwrap();
+        assert_eq!(list_ext4_image(&path).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        // Only the superblock and the first group descriptor survive.
+        std::fs::write(&path, &content[..4096]).unwrap();
+        assert!(list_ext4_image(&path).is_err());
+
+        let mut corrupt = content.clone();
+        corrupt[1024 + 56] = 0;
+        std::fs::write(&path, &corrupt).unwrap();
+        assert_eq!(list_ext4_image(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn offsets_past_u64_are_invalid_data() {
+        assert_eq!(offset_of(3, 1024, 2048).unwrap(), 5120);
+        assert_eq!(offset_of(u64::MAX / 2, 4096, 0).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(offset_of(1, u64::MAX, 1).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,131 @@
+use crate::error::{Error, Result};
+use std::borrow::Cow;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+
+pub fn write_atomic(path: &Path, content: &[u8]) -> Result<()> {
+    let temp_path = temp_path_for(path);
+
+    let result = (|| -> io::Result<()> {
+        let mut file = File::create(&temp_path)?;
+        file.write_all(content)?;
+        file.sync_all()?;
+        if let Ok(metadata) = fs::metadata(path) {
+            fs::set_permissions(&temp_path, metadata.permissions())?;
+        }
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result.map_err(|e| Error::write(path, e))
+}
+
+pub fn temp_path_for(path: &Path) -> PathBuf {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{}.tmp", file_name))
+}
+
+pub fn ends_with_newline(path: &Path) -> io::Result<bool> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(e),
+    };
+    if file.metadata()?.len() == 0 {
+        return Ok(true);
+    }
+
+    let mut last = [0u8; 1];
+    file.seek(SeekFrom::End(-1))?;
+    file.read_exact(&mut last)?;
+    Ok(last[0] == b'\n')
+}
+
+/// `path` with `/` separators whatever the host uses, as file_contexts
+/// patterns and the lookups against them always use `/`.
+pub fn slash_separated(path: &str) -> Cow<'_, str> {
+    if MAIN_SEPARATOR == '/' {
+        Cow::Borrowed(path)
+    } else {
+        Cow::Owned(path.replace(MAIN_SEPARATOR, "/"))
+    }
+}
+
+/// `relative_path` as a `/`-separated string for patterns. Names that are
+/// not valid UTF-8 give `None`, or with `lossy` have their invalid bytes
+/// replaced by U+FFFD.
+pub fn pattern_path(relative_path: &Path, lossy: bool) -> Option<Cow<'_, str>> {
+    match relative_path.to_str() {
+        Some(path) => Some(slash_separated(path)),
+        None if lossy => Some(Cow::Owned(slash_separated(&relative_path.to_string_lossy()).into_owned())),
+        None => None,
+    }
+}
+
+/// The contexts path of `path`, `/`-separated and relative to the partition
+/// root, below `mount_point`. An empty mount point puts the partition root
+/// at `/`.
+pub fn entry_path(mount_point: &str, path: &str) -> String {
+    match (mount_point.is_empty(), path.is_empty()) {
+        (true, true) => "/".to_string(),
+        (false, true) => mount_point.to_string(),
+        _ => format!("{}/{}", mount_point, path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn ends_with_newline_treats_missing_and_empty_files_as_terminated() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("file_contexts");
+        assert!(ends_with_newline(&path).unwrap());
+        for (content, terminated) in [("", true), ("/vendor u:object_r:vendor_file:s0\n", true), ("/vendor u:object_r:vendor_file:s0", false)] {
+            fs::write(&path, content).unwrap();
+            assert_eq!(ends_with_newline(&path).unwrap(), terminated, "{:?}", content);
+        }
+    }
+
+    #[test]
+    fn paths_built_from_components_use_slashes() {
+        let path: PathBuf = ["etc", "wifi", "config"].iter().collect();
+        assert_eq!(pattern_path(&path, false).as_deref(), Some("etc/wifi/config"));
+        assert_eq!(slash_separated(&path.to_string_lossy()), "etc/wifi/config");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn backslashes_become_slashes() {
+        assert_eq!(slash_separated(r"etc\wifi\config"), "etc/wifi/config");
+        assert_eq!(pattern_path(Path::new(r"etc\wifi\config"), false).as_deref(), Some("etc/wifi/config"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn invalid_names_are_skipped_unless_lossy() {
+        use std::ffi::OsString;
+        use std::os::unix::ffi::OsStringExt;
+
+        let path = PathBuf::from(OsString::from_vec(b"etc/fo\xffo".to_vec()));
+        assert_eq!(pattern_path(&path, false), None);
+        assert_eq!(pattern_path(&path, true).as_deref(), Some("etc/fo\u{FFFD}o"));
+    }
+
+    #[test]
+    fn entry_paths_below_a_mount_point_or_the_root() {
+        assert_eq!(entry_path("/vendor", "bin/foo"), "/vendor/bin/foo");
+        assert_eq!(entry_path("/vendor", ""), "/vendor");
+        assert_eq!(entry_path("", "sbin/foo"), "/sbin/foo");
+        assert_eq!(entry_path("", ""), "/");
+    }
+}
@@ -1 +1,6 @@
-pub mod regex_utils;
\ No newline at end of file
+pub mod archive;
+pub mod elf;
+pub mod ext4;
+pub mod fs_utils;
+pub mod regex_utils;
+pub mod selinux_xattr;
\ No newline at end of file
@@ -1,11 +1,156 @@
-pub fn escape_regex(s: &str) -> String {
-    let mut result = String::with_capacity(s.len() * 2);
-    
-    for c in s.chars() {
-        if matches!(c, '[' | ']' | '.' | '^' | '$' | '*' | '+' | '?' | '{' | '}' | '|' | '(' | ')') {
+use std::borrow::Cow;
+
+fn is_regex_meta(c: char) -> bool {
+    matches!(c, '\\' | '[' | ']' | '.' | '^' | '$' | '*' | '+' | '?' | '{' | '}' | '|' | '(' | ')')
+}
+
+/// Escapes regex metacharacters, borrowing `s` when there are none.
+pub fn escape_regex(s: &str) -> Cow<'_, str> {
+    let Some(first) = s.find(is_regex_meta) else {
+        return Cow::Borrowed(s);
+    };
+    let mut result = String::with_capacity(s.len() + 8);
+    result.push_str(&s[..first]);
+
+    for c in s[first..].chars() {
+        if is_regex_meta(c) {
             result.push('\\');
         }
         result.push(c);
     }
+    Cow::Owned(result)
+}
+
+/// Matches the bytes of a name that were not valid UTF-8, which a lossy
+/// conversion leaves as U+FFFD.
+const INVALID_UTF8_PATTERN: &str = "[^/]+";
+
+/// Escapes a path for a pattern, matching each run of U+FFFD against
+/// whatever bytes it replaced instead of the character itself.
+pub fn escape_path(path: &str) -> Cow<'_, str> {
+    let escaped = escape_regex(path);
+    if !escaped.contains(char::REPLACEMENT_CHARACTER) {
+        return escaped;
+    }
+    let mut result = String::with_capacity(escaped.len() + 8);
+    let mut previous = None;
+    for c in escaped.chars() {
+        match c {
+            char::REPLACEMENT_CHARACTER if previous == Some(c) => {}
+            char::REPLACEMENT_CHARACTER => result.push_str(INVALID_UTF8_PATTERN),
+            _ => result.push(c),
+        }
+        previous = Some(c);
+    }
+    Cow::Owned(result)
+}
+
+pub fn unescape_literal(pattern: &str) -> Option<String> {
+    let mut result = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(next) if !next.is_ascii_alphanumeric() => result.push(next),
+                _ => return None,
+            },
+            '[' | ']' | '.' | '^' | '$' | '*' | '+' | '?' | '{' | '}' | '|' | '(' | ')' => return None,
+            _ => result.push(c),
+        }
+    }
+    Some(result)
+}
+
+/// The literal text every match of `pattern` starts with, unescaped, e.g.
+/// `/vendor/lib64/hw` for `/vendor/lib64/hw(/.*)?`. Empty when a top-level
+/// alternation makes the start unknown.
+pub fn literal_prefix(pattern: &str) -> String {
+    let mut depth = 0usize;
+    let mut escaped = false;
+    for c in pattern.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            '|' if depth == 0 => return String::new(),
+            _ => {}
+        }
+    }
+
+    let mut result = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        let literal = match c {
+            '\\' => match chars.next() {
+                Some(next) if !next.is_ascii_alphanumeric() => next,
+                _ => break,
+            },
+            c if is_regex_meta(c) => break,
+            c => c,
+        };
+        // A quantifier that allows zero repeats makes the character optional.
+        if matches!(chars.peek(), Some('?' | '*' | '{')) {
+            break;
+        }
+        result.push(literal);
+        if chars.peek() == Some(&'+') {
+            break;
+        }
+    }
     result
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literal_prefix_stops_at_the_first_metacharacter() {
+        assert_eq!(literal_prefix("/vendor/lib64/hw(/.*)?"), "/vendor/lib64/hw");
+        assert_eq!(literal_prefix(r"/vendor/bin/hw/android\.hardware\.foo@1\.0-service"), "/vendor/bin/hw/android.hardware.foo@1.0-service");
+        assert_eq!(literal_prefix("/vendor/lib(64)?/egl"), "/vendor/lib");
+        assert_eq!(literal_prefix("/vendor/libs?/x"), "/vendor/lib");
+        assert_eq!(literal_prefix(r"/data/\d+"), "/data/");
+        assert_eq!(literal_prefix("/vendor|/odm"), "");
+    }
+
+    #[test]
+    fn escape_regex_escapes_metacharacters_and_backslashes() {
+        assert_eq!(escape_regex("libfoo.so"), r"libfoo\.so");
+        assert_eq!(escape_regex("android.hardware.foo@1.0-service"), r"android\.hardware\.foo@1\.0-service");
+        assert_eq!(escape_regex("a+b(c)[d]{e}|f^g$h*i?"), r"a\+b\(c\)\[d\]\{e\}\|f\^g\$h\*i\?");
+        assert_eq!(escape_regex(r"dir\name"), r"dir\\name");
+        // Whitespace is never escaped; such paths are skipped instead.
+        assert_eq!(escape_regex("NOTICE FILE.txt"), r"NOTICE FILE\.txt");
+    }
+
+    #[test]
+    fn escaped_names_match_only_themselves() {
+        for name in ["libfoo.so", r"dir\name", "a+b(c)[d]{e}|f^g$h*i?"] {
+            let escaped = escape_regex(name);
+            assert_eq!(unescape_literal(&escaped).as_deref(), Some(name));
+            let regex = regex::Regex::new(&format!("^(?:{})$", escaped)).unwrap();
+            assert!(regex.is_match(name));
+        }
+    }
+
+    #[test]
+    fn escape_regex_borrows_names_without_metacharacters() {
+        assert!(matches!(escape_regex("bin/hw/foo-service"), Cow::Borrowed("bin/hw/foo-service")));
+        assert!(matches!(escape_regex("lib64/libfoo.so"), Cow::Owned(_)));
+        assert!(matches!(escape_path("etc/init"), Cow::Borrowed("etc/init")));
+    }
+
+    #[test]
+    fn replaced_bytes_match_any_name_characters() {
+        assert_eq!(escape_path("etc/fo\u{FFFD}\u{FFFD}o.bin"), "etc/fo[^/]+o\\.bin");
+        assert_eq!(escape_path("\u{FFFD}/a\u{FFFD}b"), "[^/]+/a[^/]+b");
+        assert!(matches!(escape_path("etc/foo"), Cow::Borrowed(_)));
+
+        let regex = regex::Regex::new(&format!("^{}$", escape_path("etc/fo\u{FFFD}o"))).unwrap();
+        assert!(regex.is_match("etc/fo\u{e9}o"));
+        assert!(!regex.is_match("etc/fo/o"));
+    }
+}
@@ -0,0 +1,25 @@
+use std::io;
+use std::path::Path;
+
+const SELINUX_XATTR: &str = "security.selinux";
+
+/// The `security.selinux` attribute of `path` itself, not of a symlink's
+/// target, without the trailing NUL the kernel stores. `None` when the
+/// attribute is not set.
+#[cfg(target_os = "linux")]
+pub fn read_selinux_label(path: &Path) -> io::Result<Option<String>> {
+    let Some(value) = xattr::get(path, SELINUX_XATTR)? else {
+        return Ok(None);
+    };
+    let value = value.strip_suffix(b"\0").unwrap_or(&value);
+    String::from_utf8(value.to_vec())
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Extended attributes are only read on Linux, `--from-xattr` is rejected
+/// elsewhere.
+#[cfg(not(target_os = "linux"))]
+pub fn read_selinux_label(_path: &Path) -> io::Result<Option<String>> {
+    Ok(None)
+}
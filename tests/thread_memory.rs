@@ -0,0 +1,92 @@
+//! Peak heap use while generating entries with one and with eight worker
+//! threads. Workers share the existing contexts, so extra threads must not
+//! add another copy of a large stock file_contexts.
+//!
+//! This file holds a single test, so nothing else allocates while it
+//! measures.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use gen_file_contexts::progress::bar::ProgressMode;
+use gen_file_contexts::{generate_missing_entries, Config, FilesystemType, Mode};
+
+struct PeakAlloc;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for PeakAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            let current = CURRENT.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(current, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: PeakAlloc = PeakAlloc;
+
+/// Bytes allocated at the peak of `f`, above what was live before it.
+fn peak_during(f: impl FnOnce()) -> usize {
+    let before = CURRENT.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+    f();
+    PEAK.load(Ordering::Relaxed) - before
+}
+
+fn generate(partition: &Path, contexts: &Path, threads: usize) {
+    let config = Config::builder()
+        .extracted_dir(partition)
+        .file_contexts(contexts)
+        .fstype(FilesystemType::Ext4)
+        .mode(Mode::All)
+        .threads(threads)
+        .quiet(true)
+        .progress(ProgressMode::Never)
+        .build()
+        .unwrap();
+    assert!(!generate_missing_entries(&config).unwrap().is_empty());
+}
+
+#[test]
+fn peak_memory_does_not_scale_with_the_thread_count() {
+    let dir = tempfile::tempdir().unwrap();
+    let partition = dir.path().join("vendor");
+    for folder in 0..10 {
+        let folder = partition.join(format!("bin/hw/{folder}"));
+        fs::create_dir_all(&folder).unwrap();
+        for file in 0..20 {
+            fs::write(folder.join(format!("vendor.foo{file}-service")), "").unwrap();
+        }
+    }
+    // A stock file about the size of a device's, covering none of the files.
+    let stock: String = (0..20_000)
+        .map(|n| format!("/vendor/etc/stock/file{n}\\.conf u:object_r:vendor_stock{n}_file:s0\n"))
+        .collect();
+    let contexts = dir.path().join("vendor_file_contexts");
+    fs::write(&contexts, &stock).unwrap();
+
+    // Warms up one-time allocations such as the global rayon registry.
+    generate(&partition, &contexts, 8);
+    let single = peak_during(|| generate(&partition, &contexts, 1));
+    let eight = peak_during(|| generate(&partition, &contexts, 8));
+
+    // A copy of the parsed contexts per worker would add several times
+    // the stock file's size; the workers' own buffers stay well below it.
+    assert!(
+        eight < single + stock.len(),
+        "peak with 8 threads is {eight} bytes, with 1 thread {single} bytes, stock file {} bytes",
+        stock.len()
+    );
+}